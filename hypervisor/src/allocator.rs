@@ -18,7 +18,7 @@ unsafe impl GlobalAlloc for BootTimeAllocator {
 
         // If the requested alignment is a multiple of 4KB, use `allocate_pages`
         // which allocates 4KB aligned memory with 4KB granularity.
-        if (align % 0x1000) == 0 {
+        if align == 0x1000 {
             system_table()
                 .boot_services()
                 .allocate_pages(
@@ -27,6 +27,31 @@ unsafe impl GlobalAlloc for BootTimeAllocator {
                     size_to_pages(size),
                 )
                 .unwrap_or(0) as *mut u8
+        } else if (align % 0x1000) == 0 {
+            // Allocate more pages for alignment, then free excess pages on both
+            // sides, so that the remaining pages can be freed as usual.
+            let page_count = size_to_pages(size);
+            let excess_page_count = align / 0x1000 - 1;
+            let st = system_table();
+            let bs = st.boot_services();
+            let Ok(base) = bs.allocate_pages(
+                AllocateType::AnyPages,
+                MemoryType::BOOT_SERVICES_DATA,
+                page_count + excess_page_count,
+            ) else {
+                return core::ptr::null_mut();
+            };
+            let aligned_base = base.next_multiple_of(align as u64);
+            let leading_page_count = ((aligned_base - base) / 0x1000) as usize;
+            let trailing_page_count = excess_page_count - leading_page_count;
+            if leading_page_count != 0 {
+                unsafe { bs.free_pages(base, leading_page_count).unwrap() };
+            }
+            if trailing_page_count != 0 {
+                let trailing_base = aligned_base + (page_count * 0x1000) as u64;
+                unsafe { bs.free_pages(trailing_base, trailing_page_count).unwrap() };
+            }
+            aligned_base as *mut u8
         } else if align > 8 {
             // Allocate more space for alignment.
             let Ok(ptr) = system_table()
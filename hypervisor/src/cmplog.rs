@@ -0,0 +1,116 @@
+//! The module containing functions for comparison logging (cmplog).
+//!
+//! Comparison instructions marked with [`crate::patch::PatchKind::Compare`] in
+//! the patch file are intercepted, and values of their operands are recorded,
+//! so that values the guest compares input data against (eg, magic values) can
+//! be inserted into input data by [`crate::mutation_engine::MutationEngine`].
+
+use crate::hardware_vt::GuestRegisters;
+
+/// The maximum length of an x86 instruction.
+pub(crate) const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// Decodes the `CMP` instruction `bytes`, and returns values of its two
+/// operands based on `registers`, or None if the instruction is not supported.
+///
+/// Only the forms without a memory operand are supported:
+/// - `38`, `39`, `3A` and `3B` with `ModRM.mod == 3` (CMP r/m, r and CMP r,
+///   r/m)
+/// - `3C` and `3D` (CMP AL/eAX/rAX, imm)
+/// - `80`, `81` and `83` with `ModRM.reg == 7` and `ModRM.mod == 3` (CMP r/m,
+///   imm)
+///
+/// See: CMP—Compare Two Operands
+pub(crate) fn decode_compare(bytes: &[u8], registers: &GuestRegisters) -> Option<[u64; 2]> {
+    // Parse the operand-size prefix and REX prefix.
+    let mut index = 0;
+    let mut operand_size_prefix = false;
+    if *bytes.get(index)? == 0x66 {
+        operand_size_prefix = true;
+        index += 1;
+    }
+    let rex_prefix = match *bytes.get(index)? {
+        rex @ 0x40..=0x4f => {
+            index += 1;
+            Some(rex)
+        }
+        _ => None,
+    };
+    let rex_w = rex_prefix.is_some_and(|rex| rex & 0b1000 != 0);
+    let rex_r = rex_prefix.map_or(0, |rex| usize::from((rex >> 2) & 1) << 3);
+    let rex_b = rex_prefix.map_or(0, |rex| usize::from(rex & 1) << 3);
+
+    let opcode = *bytes.get(index)?;
+    index += 1;
+    let size = match opcode {
+        0x38 | 0x3a | 0x3c | 0x80 => 1,
+        _ if rex_w => 8,
+        _ if operand_size_prefix => 2,
+        _ => 4,
+    };
+    let register = |number: usize| read_register(registers, number, size, rex_prefix.is_some());
+
+    // Decodes ModRM, and returns register numbers in the `reg` and `r/m`
+    // fields, if the `r/m` field is a register.
+    let modrm = |index: usize| -> Option<(usize, usize)> {
+        let modrm = *bytes.get(index)?;
+        if modrm >> 6 != 0b11 {
+            return None;
+        }
+        let reg = usize::from((modrm >> 3) & 0b111) | rex_r;
+        let rm = usize::from(modrm & 0b111) | rex_b;
+        Some((reg, rm))
+    };
+
+    let operands = match opcode {
+        0x38..=0x3b => {
+            let (reg, rm) = modrm(index)?;
+            [register(rm), register(reg)]
+        }
+        0x3c | 0x3d => [register(0), read_immediate(bytes, index, size)?],
+        0x80 | 0x81 | 0x83 => {
+            let (reg, rm) = modrm(index)?;
+            if reg & 0b111 != 7 {
+                return None;
+            }
+            let immediate = if opcode == 0x83 {
+                read_immediate(bytes, index + 1, 1)?
+            } else {
+                read_immediate(bytes, index + 1, size)?
+            };
+            [register(rm), immediate]
+        }
+        _ => return None,
+    };
+    let mask = if size == 8 {
+        u64::MAX
+    } else {
+        (1 << (size * 8)) - 1
+    };
+    Some([operands[0] & mask, operands[1] & mask])
+}
+
+// Reads the sign-extended immediate operand of `size` bytes (up to 4) at
+// `index`.
+fn read_immediate(bytes: &[u8], index: usize, size: usize) -> Option<u64> {
+    let size = size.min(4);
+    let mut immediate = [0u8; 8];
+    immediate[..size].copy_from_slice(bytes.get(index..index + size)?);
+    let value = u64::from_le_bytes(immediate);
+    let sign_bit = 1 << (size * 8 - 1);
+    if value & sign_bit == 0 {
+        Some(value)
+    } else {
+        Some(value | !(sign_bit * 2 - 1))
+    }
+}
+
+// Reads the general purpose register specified by `number` as encoded in an
+// instruction. For the 8-bit operand without the REX prefix, 4-7 are AH, CH, DH
+// and BH.
+fn read_register(registers: &GuestRegisters, number: usize, size: usize, rex: bool) -> u64 {
+    if size == 1 && !rex && (4..8).contains(&number) {
+        return read_register(registers, number - 4, 8, rex) >> 8;
+    }
+    registers.gpr(number)
+}
@@ -1,10 +1,42 @@
-//! The module containing various constants that may be modified by developers.
+//! The module containing various constants that may be modified by developers,
+//! and the [`Config`] type holding ones that may also be overridden with
+//! command line parameters.
 
-/// The logging level.
+use crate::{hardware_vt::GPR_NAMES, mutation_engine::MutationStrategy};
+use alloc::{format, string::String};
+use core::{fmt::Write, ops::RangeInclusive};
+
+/// The logging level. The default of [`Config::log_level`].
 pub(crate) const LOGGING_LEVEL: log::LevelFilter = log::LevelFilter::Debug;
 
+/// The length of the window for [`Config::log_rate_limit`], in TSC.
+pub(crate) const LOG_RATE_LIMIT_WINDOW_IN_TSC: u64 = 1_000_000_000;
+
+/// Whether processors should log their first message on startup in the
+/// ascending order of APIC IDs, instead of as they start, so that logs of
+/// multiple runs can be diffed. A processor that does not log it within
+/// [`ORDERED_STARTUP_TIMEOUT_IN_TSC`], eg, because it failed to start, is
+/// skipped.
+pub(crate) const ORDERED_STARTUP_LOG: bool = false;
+
+/// How long a processor waits for the processors with lower APIC IDs to log
+/// on startup with [`ORDERED_STARTUP_LOG`], in TSC.
+pub(crate) const ORDERED_STARTUP_TIMEOUT_IN_TSC: u64 = 1_000_000_000;
+
+/// Whether coverage information (`COVERAGE:`, `DRCOV:` and `COVBLOB` lines)
+/// should be sent to COM2 instead of COM1, so that tools can consume it
+/// separately from other logs. The serial port is assumed to be initialized by
+/// the firmware.
+pub(crate) const SEPARATE_COVERAGE_SERIAL_PORT: bool = false;
+
+/// The size of the in-memory buffer to keep the last log messages sent to COM1,
+/// in bytes. The contents are sent to COM1 again on panic. Ignored when
+/// `panic_log_buffer` is disabled.
+pub(crate) const PANIC_LOG_BUFFER_SIZE: usize = 0x4000;
+
 /// Once in how many iterations stats should be sent to the serial output.
-/// Ignored when [`LOGGING_LEVEL`] is `Trace`.
+/// Ignored when the logging level is `Trace`. The default of
+/// [`Config::serial_output_interval`].
 pub(crate) const SERIAL_OUTPUT_INTERVAL: u64 = 500;
 
 /// Once in how many iterations stats should be displayed on the console.
@@ -12,10 +44,554 @@ pub(crate) const SERIAL_OUTPUT_INTERVAL: u64 = 500;
 pub(crate) const CONSOLE_OUTPUT_INTERVAL: u64 = 1000;
 
 /// How long a single fuzzing iteration can spend within the guest-mode, in TSC.
-/// If the more than this is spent, a timer fires and aborts the VM.
+/// If the more than this is spent, a timer fires and aborts the VM. The default
+/// of [`Config::guest_exec_timeout_in_tsc`].
 pub(crate) const GUEST_EXEC_TIMEOUT_IN_TSC: u64 = 200_000_000;
 
+/// The shortest time a single fuzzing iteration can spend within the
+/// guest-mode, in TSC, when the timeout is scaled with the input size with
+/// [`Config::guest_exec_tsc_per_input_byte`].
+pub(crate) const MIN_GUEST_EXEC_TIMEOUT_IN_TSC: u64 = 10_000_000;
+
+/// How many times longer than [`Config::guest_exec_timeout_in_tsc`] a single
+/// fuzzing iteration can take before the watchdog reports the processor running
+/// it as stuck. This detects hangs that the timer cannot catch, eg, when the
+/// VMX-preemption timer is unavailable. Any processor completing an iteration,
+/// or handling VM exit due to an external interrupt or the timer, may check it,
+/// at most once per [`Config::guest_exec_timeout_in_tsc`]. On a single
+/// processor, a stuck iteration is thus reported only if it causes those VM
+/// exits, eg, on SVM where external interrupts are intercepted.
+pub(crate) const WATCHDOG_THRESHOLD_MULTIPLIER: u64 = 10;
+
+/// The percentage of the time spent in the host that, if taken by reverting
+/// dirty pages at the start of iterations, makes rhv log a one-time tuning
+/// hint. Reverting is costly for targets that write to many pages, mainly due
+/// to cache invalidation (INVEPT on VMX and a full TLB flush on SVM). Checked
+/// at the periodic serial output.
+pub(crate) const REVERT_COST_WARNING_PERCENT: u64 = 50;
+
+/// The percentage of the timeout of an iteration (see
+/// [`Config::exec_timeout_in_tsc`]) above which the iteration that did not hang
+/// is considered slow. A mutated input that made a slow iteration is added to
+/// the corpus, as it may lead to a hang, but only one per input file it was
+/// mutated from, and none from a slow input itself.
+pub(crate) const SLOW_INPUT_THRESHOLD_PERCENT: u64 = 90;
+
 /// The number of fuzzing iterations to be done for single input. The lower, the
-/// more frequently new files are selected, and it is slightly costly. Ignored
-/// when `random_byte_modification` is disabled.
+/// more frequently new files are selected, and it is slightly costly. This is
+/// scaled for each input file by its weight (see `Corpus::weight`). Used only
+/// with [`MutationStrategy::RandomByte`]. The default of
+/// [`Config::max_iteration_count_per_file`].
 pub(crate) const MAX_ITERATION_COUNT_PER_FILE: u64 = 10_000;
+
+/// The maximum size of an input file in bytes. The memory to map input data
+/// into the guest is reserved for the largest input file on each processor, so
+/// a single large file in the corpus would increase memory usage and mutation
+/// cost. Files larger than this in the corpus directory are skipped, or
+/// truncated if [`TRUNCATE_OVERSIZED_INPUT_FILES`] is enabled. The default of
+/// [`Config::max_input_file_size`].
+pub(crate) const MAX_INPUT_FILE_SIZE: u64 = 0x10_0000;
+
+/// Whether input files larger than [`Config::max_input_file_size`] in the
+/// corpus directory should be truncated to that size, instead of being
+/// skipped.
+pub(crate) const TRUNCATE_OVERSIZED_INPUT_FILES: bool = false;
+
+/// How deep subdirectories of the corpus directory are searched for input
+/// files. Input files in subdirectories are named with their relative paths,
+/// eg, `png\seed1`. Deeper subdirectories are skipped.
+pub(crate) const MAX_CORPUS_DIRECTORY_DEPTH: usize = 8;
+
+/// The maximum priority of an input file in percent accepted from its sidecar
+/// file. Larger values are capped to this, so that the weight in the power
+/// schedule does not overflow.
+pub(crate) const MAX_INPUT_PRIORITY: u64 = 10_000;
+
+/// Whether the Bochs magic breakpoint should be executed when the VM aborts due
+/// to a crash, so that the Bochs debugger breaks in with the guest state
+/// intact. Requires "magic_break: enabled=1" in the Bochs configuration file.
+/// The breakpoint is a no-op outside Bochs.
+pub(crate) const BREAK_ON_CRASH: bool = false;
+
+/// The number of bits in the edge coverage bitmap. Each edge between two basic
+/// blocks is hashed into one of those bits. The larger, the less collision
+/// between edges. Must be a multiple of 64.
+pub(crate) const COVERAGE_BITMAP_SIZE: usize = 0x1_0000;
+
+/// Whether edges between basic blocks should be tracked on every execution of
+/// basic blocks. If enabled, the #BP patches are kept instead of being reverted
+/// on first execution, as done with [`HIT_COUNT_COVERAGE`], so that an edge
+/// into an already executed basic block is also recorded. This costs VM exits
+/// on every execution of basic blocks. If disabled, a patch is reverted for all
+/// VMs on its first execution, and only the first edge into each basic block is
+/// recorded, which is no more precise than basic block coverage.
+pub(crate) const EDGE_COVERAGE: bool = true;
+
+/// Whether the number of times each basic block is executed should be taken
+/// into account as coverage. If enabled, the #BP patches are kept instead of
+/// being reverted on first execution, and an input is also considered
+/// interesting when it moves any basic block into a new hit count bucket (1, 2,
+/// 3, 4-7, 8-15, 16-31, 32-127 and 128+). This is much slower since every
+/// execution of basic blocks causes VM exits.
+pub(crate) const HIT_COUNT_COVERAGE: bool = false;
+
+/// The maximum number of distinct values observed as operands of comparison
+/// instructions to be kept (see `cmplog.rs`). Those values are inserted into
+/// input data with [`MutationStrategy::RandomByte`].
+pub(crate) const MAX_COMPARAND_COUNT: usize = 256;
+
+/// The percentage of offsets to modify with [`MutationStrategy::RandomByte`]
+/// that are picked from the offsets where earlier mutation of the same input
+/// file found new coverage (the effector map), if any. The rest are picked
+/// uniformly.
+pub(crate) const EFFECTIVE_OFFSET_PERCENT: usize = 50;
+
+/// The number of the most executed basic blocks marked with
+/// [`crate::patch::PatchKind::Profile`] to be reported at the end of fuzzing.
+pub(crate) const PROFILE_REPORT_COUNT: usize = 20;
+
+/// Once in how many iterations the accumulated coverage should be sent to the
+/// serial output in the drcov format. Each line is prefixed with `DRCOV:`.
+/// Zero disables the output. The output is large, so it is disabled by
+/// default.
+pub(crate) const DRCOV_OUTPUT_INTERVAL: u64 = 0;
+
+/// Whether the accumulated coverage should be sent to the serial output as a
+/// binary blob framed with `COVBLOB-BEGIN` and `COVBLOB-END` when fuzzing
+/// completes, so that coverage of two campaigns can be diffed offline.
+pub(crate) const EXPORT_COVERAGE_BLOB: bool = true;
+
+/// Whether the accumulated coverage should be saved into
+/// [`crate::corpus::COVERAGE_FILE_NAME`] in the corpus directory when fuzzing
+/// completes, and loaded from it at startup, so that a restarted campaign does
+/// not report already known basic blocks and edges as new.
+pub(crate) const PERSIST_COVERAGE: bool = true;
+
+/// Whether guest physical memory captured in the snapshot should be mapped
+/// with 2MB pages when the whole 2MB region is captured. This reduces the
+/// number of nested page faults and nested paging structures at the cost of
+/// reading 2MB of the snapshot file at once. 2MB pages are split into 4KB pages
+/// on copy-on-write unless [`USE_LARGE_DIRTY_PAGES`] is enabled.
+pub(crate) const USE_LARGE_PAGES: bool = true;
+
+/// The number of pages after a faulting page to read from the snapshot file
+/// together with it, as long as they are in the same memory range and not read
+/// yet. Reading them at once reduces the number of small serialized reads of
+/// the file while the snapshot is paged in. Zero disables this.
+pub(crate) const SNAPSHOT_PREFETCH_PAGE_COUNT: usize = 15;
+
+/// The number of fuzzing iterations, counted on each processor, to run against
+/// a snapshot before switching to the next one, when multiple snapshot files
+/// are given, eg, ones representing different states of a state machine.
+/// Switching discards all GPA -> PA translations built for the previous
+/// snapshot (see `Vm::reset`), so switching too often makes every iteration
+/// page in memory again through nested page faults. Replaying, minimization
+/// and `dry_run` use the first snapshot.
+pub(crate) const SNAPSHOT_SWITCH_INTERVAL: u64 = 100;
+
+/// Whether copy-on-write of memory mapped with a 2MB page should copy the whole
+/// 2MB region into a 2MB dirty page, instead of splitting the 2MB page and
+/// copying only the 4KB page being written. This lets the VM write to large
+/// contiguous regions without running out of 4KB dirty pages, at the cost of
+/// copying 2MB at once and reserving extra memory per processor. Falls back to
+/// splitting when 2MB dirty pages are used up.
+pub(crate) const USE_LARGE_DIRTY_PAGES: bool = false;
+
+/// The number of 4KB pages the VM may modify in a single iteration before it is
+/// aborted as `DirtyPageLimitExceeded`, even if preallocated dirty pages still
+/// remain. Runaway memory writes often indicate memory corruption bugs, so the
+/// input is reported as a crash. Targets legitimately writing to many pages
+/// would be reported as crashing, thus this is disabled (zero) by default, and
+/// should be set above the number of pages the target normally writes to. Each
+/// 2MB dirty page counts as 512 pages, so raise this when enabling
+/// [`USE_LARGE_DIRTY_PAGES`].
+pub(crate) const DIRTY_PAGE_SOFT_LIMIT: u64 = 0;
+
+/// Whether the VM should be aborted when it writes to a page containing any
+/// patch, ie, the code of the target. Such writes may overwrite `INT3` used for
+/// coverage tracking without notice, and self-modifying code is interesting on
+/// its own. Disable this if the target places writable data in the same pages
+/// as its code. Only the first write to each 4KB page is checked, and writes to
+/// pages already copied as part of a 2MB dirty page are not.
+pub(crate) const ABORT_ON_SELF_MODIFYING_CODE: bool = true;
+
+/// Whether the guest is expected to never write to memory other than its
+/// stack, as with a pure parser that keeps no global state. If enabled, the VM
+/// is aborted on the first write to any other page, instead of the page being
+/// copied, so that unexpected writes are detected, and dirty pages hardly need
+/// to be managed. Can be overridden with the `no_write_expected` parameter.
+/// See [`NO_WRITE_STACK_SIZE`].
+pub(crate) const NO_WRITE_EXPECTED: bool = false;
+
+/// The size of the stack in bytes below RSP in the snapshot, that the guest may
+/// write to even if [`Config::no_write_expected`] is enabled. The stack
+/// pointer is regarded as the physical address, as UEFI runs with identity
+/// mapping.
+pub(crate) const NO_WRITE_STACK_SIZE: u64 = 0x10000;
+
+/// Whether pages the guest writes to should be mapped as non-executable, so
+/// that the VM is aborted when it executes instructions in them, eg, after
+/// jumping into the stack or into input data. This is the same as DEP, and
+/// often indicates control flow hijacking. Pages containing patches and 2MB
+/// dirty pages are kept executable, as they contain code. Still, targets that
+/// legitimately generate code at runtime, or place writable data in the same
+/// pages as code the patch does not cover, would be reported as crashing, thus
+/// this is disabled by default.
+pub(crate) const ABORT_ON_DATA_EXECUTION: bool = false;
+
+/// The vectors of exceptions that should be delivered to the guest instead of
+/// aborting the VM, when they are not caused by patches. Useful for targets
+/// that legitimately take and handle faults, eg, #PF for demand paging. Only
+/// #DE, #DB, #BP, #UD, #GP and #PF (0, 1, 3, 6, 13 and 14) are intercepted.
+/// Empty by default, as those exceptions usually indicate bugs in the target.
+pub(crate) const REFLECTED_EXCEPTION_VECTORS: &[u8] = &[];
+
+/// The maximum distance in bytes of the faulting address below guest RSP for
+/// #PF to be reported as a stack overflow instead of an unexpected page fault.
+/// A stack overflow typically faults on the guard page right below the stack
+/// while pushing a value or a return address.
+pub(crate) const STACK_OVERFLOW_DISTANCE: u64 = 0x2000;
+
+/// The MSRs whose access by the guest should be intercepted and emulated, as
+/// their values are different on each run. Reads of other MSRs covered by MSR
+/// bitmaps are passed through to the processor, and writes to them abort the
+/// VM.
+pub(crate) const INTERCEPTED_MSRS: &[u32] = &[
+    x86::msr::IA32_TIME_STAMP_COUNTER,
+    x86::msr::IA32_TSC_DEADLINE,
+];
+
+/// The bits of CR0 whose modification by the guest should abort the VM, as it
+/// switches the processor mode, which the hypervisor does not model. Those are
+/// PG (bit 31) and PE (bit 0). Writes to IA32_EFER always abort the VM. On
+/// AMD processors without decode assists or the next RIP save, writes to CR0
+/// and CR4 are not intercepted, and this is not enforced.
+pub(crate) const INTERCEPTED_CR0_BITS: u64 = (1 << 31) | (1 << 0);
+
+/// The bits of CR4 whose modification by the guest should abort the VM, for
+/// the same reason as [`INTERCEPTED_CR0_BITS`]. Those are PAE (bit 5) and LA57
+/// (bit 12).
+pub(crate) const INTERCEPTED_CR4_BITS: u64 = (1 << 5) | (1 << 12);
+
+/// The I/O ports whose access by the guest should be intercepted and emulated,
+/// so that the guest never touches the host devices behind them and observes
+/// the same values on each run. Those are the serial ports COM1 to COM4, the
+/// master and slave PICs, and [`GUEST_PRINT_IO_PORT`]. Access to other ports is
+/// passed through to the processor.
+pub(crate) const INTERCEPTED_IO_PORTS: &[RangeInclusive<u16>] = &[
+    0x3f8..=0x3ff,
+    0x2f8..=0x2ff,
+    0x3e8..=0x3ef,
+    0x2e8..=0x2ef,
+    0x20..=0x21,
+    0xa0..=0xa1,
+    GUEST_PRINT_IO_PORT..=GUEST_PRINT_IO_PORT,
+];
+
+/// The I/O port the guest can write bytes to with `OUT` to print messages, such
+/// as assertion failures, on the log. Bytes are buffered and logged with the
+/// `GUEST:` prefix on each newline, when [`GUEST_PRINT_LINE_LENGTH`] bytes are
+/// buffered, or when the VM aborts. This is the port of the debug console of
+/// Bochs and QEMU.
+pub(crate) const GUEST_PRINT_IO_PORT: u16 = 0xe9;
+
+/// The maximum number of bytes buffered for a line printed through
+/// [`GUEST_PRINT_IO_PORT`].
+pub(crate) const GUEST_PRINT_LINE_LENGTH: usize = 256;
+
+/// How much the virtual TSC advances on each read of it by the guest with
+/// `RDTSC`, `RDTSCP` or the IA32_TSC MSR. The virtual TSC starts from the value
+/// captured in the snapshot at the beginning of each iteration, so that the
+/// same input observes the same TSC values.
+pub(crate) const VIRTUAL_TSC_INCREMENT: u64 = 1000;
+
+/// Whether RFLAGS.IF of the guest should be cleared at the beginning of each
+/// iteration. The hypervisor never delivers interrupts to the guest, but a
+/// snapshot taken with interrupts enabled, eg, with the local APIC timer armed,
+/// lets the guest run code that depends on IF, such as waiting for an interrupt
+/// with `HLT`, differently depending on timing. With this, the guest always
+/// starts as if interrupts are disabled. On AMD, this also makes physical
+/// interrupts controlled by the host RFLAGS.IF, so that they keep causing VM
+/// exits.
+pub(crate) const MASK_GUEST_INTERRUPTS: bool = false;
+
+/// The values that may be overridden at runtime with optional `key=value`
+/// command line parameters, where `key` is the name of a field below.
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    /// See [`GUEST_EXEC_TIMEOUT_IN_TSC`].
+    pub(crate) guest_exec_timeout_in_tsc: u64,
+    /// How long a single fuzzing iteration can spend within the guest-mode per
+    /// byte of input data, in TSC, or zero (the default) to use
+    /// [`Config::guest_exec_timeout_in_tsc`] for any input. The timeout is
+    /// bounded by [`MIN_GUEST_EXEC_TIMEOUT_IN_TSC`] and
+    /// [`Config::guest_exec_timeout_in_tsc`]. See
+    /// [`Config::exec_timeout_in_tsc`].
+    pub(crate) guest_exec_tsc_per_input_byte: u64,
+    /// See [`SERIAL_OUTPUT_INTERVAL`].
+    pub(crate) serial_output_interval: u64,
+    /// See [`MAX_ITERATION_COUNT_PER_FILE`].
+    pub(crate) max_iteration_count_per_file: u64,
+    /// See [`MAX_INPUT_FILE_SIZE`].
+    pub(crate) max_input_file_size: u64,
+    /// How input data is mutated. Given as `bit_flip`, `random_byte` or
+    /// `interesting_values`. The default depends on the
+    /// `random_byte_modification` feature.
+    pub(crate) mutation_strategy: MutationStrategy,
+    /// The base seed of the random number generators used for mutation. Each
+    /// processor uses this value XOR-ed with its APIC ID. When not specified,
+    /// the TSC at startup is used and printed out, so that the campaign can be
+    /// repeated with the same seed.
+    pub(crate) seed: u64,
+    /// The number of iterations after which fuzzing stops, or zero (the
+    /// default) for no limit.
+    pub(crate) max_iterations: u64,
+    /// The number of seconds after which fuzzing stops, or zero (the default)
+    /// for no limit. Requires `time_report`.
+    pub(crate) max_seconds: u64,
+    /// The logging level. Given as `off`, `error`, `warn`, `info`, `debug` or
+    /// `trace`. See [`LOGGING_LEVEL`].
+    pub(crate) log_level: log::LevelFilter,
+    /// The maximum number of bytes sent to COM1 per
+    /// [`LOG_RATE_LIMIT_WINDOW_IN_TSC`], or zero (the default) for no limit.
+    /// Messages beyond this are dropped except ones at the warn level or
+    /// higher, and the number of dropped messages is logged when the next
+    /// window starts.
+    pub(crate) log_rate_limit: u64,
+    /// The guest physical address of the snapshot memory input data is written
+    /// over, or zero (the default) to expose input data as the input data
+    /// pages instead. This is for fuzzing data already resident in the
+    /// snapshot, such as a parsed structure. Must be specified together with
+    /// [`Config::mutation_target_size`].
+    pub(crate) mutation_target_gpa: u64,
+    /// The size of the snapshot memory at [`Config::mutation_target_gpa`] in
+    /// bytes. Bytes of input data beyond this size are ignored.
+    pub(crate) mutation_target_size: u64,
+    /// Whether fuzzing should stop on all processors at the first crash, after
+    /// reporting the guest state, instead of continuing. Given as `true` or
+    /// `false` (the default). This is for quickly confirming that a snapshot,
+    /// patch and corpus reproduce a known bug.
+    pub(crate) stop_on_crash: bool,
+    /// Whether the VM is aborted on write to memory other than the stack. Given
+    /// as `true` or `false`. Defaults to [`NO_WRITE_EXPECTED`].
+    pub(crate) no_write_expected: bool,
+    /// The number of the general purpose register that receives the address
+    /// of input data, overriding the snapshot file. Given as a register name,
+    /// eg, `rcx`.
+    pub(crate) address_register: Option<usize>,
+    /// The number of the general purpose register that receives the size of
+    /// input data, overriding the snapshot file. Given as a register name.
+    pub(crate) size_register: Option<usize>,
+    /// The values to set to general purpose registers at the start of each
+    /// iteration, indexed by register numbers. Given as `<register name>=
+    /// <value>`, eg, `rdx=0x7fff0000` for a harness that expects a pointer to
+    /// a scratch buffer in RDX. The value may be zero.
+    pub(crate) register_values: [Option<u64>; 16],
+    /// The address to start each iteration at instead of RIP captured in the
+    /// snapshot, for example, the entry of the function to fuzz when the
+    /// snapshot was taken before setting up its arguments. Must be within the
+    /// snapshot. Zero uses the captured RIP.
+    pub(crate) entry_rip: u64,
+    /// The stack pointer to start each iteration with instead of RSP captured
+    /// in the snapshot. Must be within the snapshot. Zero uses the captured
+    /// RSP.
+    pub(crate) entry_rsp: u64,
+    /// See [`DIRTY_PAGE_SOFT_LIMIT`].
+    pub(crate) dirty_page_soft_limit: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            guest_exec_timeout_in_tsc: GUEST_EXEC_TIMEOUT_IN_TSC,
+            guest_exec_tsc_per_input_byte: 0,
+            serial_output_interval: SERIAL_OUTPUT_INTERVAL,
+            max_iteration_count_per_file: MAX_ITERATION_COUNT_PER_FILE,
+            max_input_file_size: MAX_INPUT_FILE_SIZE,
+            mutation_strategy: MutationStrategy::default(),
+            seed: 0,
+            max_iterations: 0,
+            max_seconds: 0,
+            log_level: LOGGING_LEVEL,
+            log_rate_limit: 0,
+            mutation_target_gpa: 0,
+            mutation_target_size: 0,
+            stop_on_crash: false,
+            no_write_expected: NO_WRITE_EXPECTED,
+            address_register: None,
+            size_register: None,
+            register_values: [None; 16],
+            entry_rip: 0,
+            entry_rsp: 0,
+            dirty_page_soft_limit: DIRTY_PAGE_SOFT_LIMIT,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from `key=value` parameters. Values are decimal
+    /// or hexadecimal with the `0x` prefix, and must not be zero, except for
+    /// `mutation_strategy`, `log_level`, `stop_on_crash`, `no_write_expected`,
+    /// `address_register`, `size_register` and register values. Keys that are
+    /// not specified keep the default values.
+    pub(crate) fn parse(params: &[String]) -> Result<Self, String> {
+        let mut config = Self::default();
+        for param in params {
+            let Some((key, value)) = param.split_once('=') else {
+                return Err(format!("Parameter {param:?} is not in the key=value form"));
+            };
+            if key == "mutation_strategy" {
+                config.mutation_strategy = MutationStrategy::from_name(value)
+                    .ok_or_else(|| format!("Invalid value {value:?} for {key:?}"))?;
+                continue;
+            }
+            if key == "stop_on_crash" || key == "no_write_expected" {
+                let value = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value {value:?} for {key:?}"))?;
+                if key == "stop_on_crash" {
+                    config.stop_on_crash = value;
+                } else {
+                    config.no_write_expected = value;
+                }
+                continue;
+            }
+            if key == "address_register" || key == "size_register" {
+                let register = gpr_number(value)
+                    .ok_or_else(|| format!("Invalid value {value:?} for {key:?}"))?;
+                if key == "address_register" {
+                    config.address_register = Some(register);
+                } else {
+                    config.size_register = Some(register);
+                }
+                continue;
+            }
+            if let Some(register) = gpr_number(key) {
+                config.register_values[register] = Some(
+                    parse_number(value)
+                        .ok_or_else(|| format!("Invalid value {value:?} for {key:?}"))?,
+                );
+                continue;
+            }
+            if key == "log_level" {
+                config.log_level = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value {value:?} for {key:?}"))?;
+                continue;
+            }
+            let field = match key {
+                "guest_exec_timeout_in_tsc" => &mut config.guest_exec_timeout_in_tsc,
+                "guest_exec_tsc_per_input_byte" => &mut config.guest_exec_tsc_per_input_byte,
+                "serial_output_interval" => &mut config.serial_output_interval,
+                "max_iteration_count_per_file" => &mut config.max_iteration_count_per_file,
+                "max_input_file_size" => &mut config.max_input_file_size,
+                "seed" => &mut config.seed,
+                "max_iterations" => &mut config.max_iterations,
+                "max_seconds" => &mut config.max_seconds,
+                "log_rate_limit" => &mut config.log_rate_limit,
+                "mutation_target_gpa" => &mut config.mutation_target_gpa,
+                "mutation_target_size" => &mut config.mutation_target_size,
+                "entry_rip" => &mut config.entry_rip,
+                "entry_rsp" => &mut config.entry_rsp,
+                "dirty_page_soft_limit" => &mut config.dirty_page_soft_limit,
+                _ => return Err(format!("Unknown parameter {key:?}")),
+            };
+            *field = parse_number(value)
+                .filter(|&value| value != 0)
+                .ok_or_else(|| format!("Invalid value {value:?} for {key:?}"))?;
+        }
+        if (config.mutation_target_gpa == 0) != (config.mutation_target_size == 0) {
+            return Err(
+                "mutation_target_gpa and mutation_target_size must be specified together".into()
+            );
+        }
+        Ok(config)
+    }
+
+    /// Returns how long a single fuzzing iteration with input data of
+    /// `input_size` bytes can spend within the guest-mode, in TSC.
+    pub(crate) fn exec_timeout_in_tsc(&self, input_size: u64) -> u64 {
+        if self.guest_exec_tsc_per_input_byte == 0 {
+            return self.guest_exec_timeout_in_tsc;
+        }
+        input_size
+            .saturating_mul(self.guest_exec_tsc_per_input_byte)
+            .clamp(
+                MIN_GUEST_EXEC_TIMEOUT_IN_TSC.min(self.guest_exec_timeout_in_tsc),
+                self.guest_exec_timeout_in_tsc,
+            )
+    }
+
+    /// Returns the parameters that make [`Config::parse`] build the same
+    /// configuration. Limits that are zero, thus cannot be parsed, are
+    /// omitted.
+    pub(crate) fn to_params(&self) -> String {
+        let mut params = format!(
+            "guest_exec_timeout_in_tsc={:#x} serial_output_interval={} max_iteration_count_per_file={} max_input_file_size={:#x} mutation_strategy={} seed={:#x} log_level={}",
+            self.guest_exec_timeout_in_tsc,
+            self.serial_output_interval,
+            self.max_iteration_count_per_file,
+            self.max_input_file_size,
+            self.mutation_strategy.name(),
+            self.seed,
+            self.log_level.as_str().to_ascii_lowercase(),
+        );
+        for (key, value) in [
+            ("max_iterations", self.max_iterations),
+            ("max_seconds", self.max_seconds),
+            ("log_rate_limit", self.log_rate_limit),
+            ("guest_exec_tsc_per_input_byte", self.guest_exec_tsc_per_input_byte),
+            ("mutation_target_gpa", self.mutation_target_gpa),
+            ("mutation_target_size", self.mutation_target_size),
+            ("entry_rip", self.entry_rip),
+            ("entry_rsp", self.entry_rsp),
+            ("dirty_page_soft_limit", self.dirty_page_soft_limit),
+        ] {
+            if value != 0 {
+                write!(params, " {key}={value}").unwrap();
+            }
+        }
+        if self.stop_on_crash {
+            params.push_str(" stop_on_crash=true");
+        }
+        if self.no_write_expected != NO_WRITE_EXPECTED {
+            write!(params, " no_write_expected={}", self.no_write_expected).unwrap();
+        }
+        for (key, register) in [
+            ("address_register", self.address_register),
+            ("size_register", self.size_register),
+        ] {
+            if let Some(register) = register {
+                write!(params, " {key}={}", GPR_NAMES[register]).unwrap();
+            }
+        }
+        for (name, value) in GPR_NAMES.iter().zip(self.register_values) {
+            if let Some(value) = value {
+                write!(params, " {name}={value:#x}").unwrap();
+            }
+        }
+        params
+    }
+}
+
+/// Parses `value` as a decimal number, or a hexadecimal number with the `0x`
+/// prefix.
+fn parse_number(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .ok()
+}
+
+/// Returns the number of the general purpose register `name`, eg, 7 for
+/// `rdi`. RSP is not supported as it is not saved in
+/// [`crate::hardware_vt::GuestRegisters`].
+fn gpr_number(name: &str) -> Option<usize> {
+    const RSP_REGISTER: usize = 4;
+
+    GPR_NAMES
+        .iter()
+        .position(|&gpr| gpr == name)
+        .filter(|&number| number != RSP_REGISTER)
+}
@@ -1,17 +1,27 @@
 //! The module containing the [`Corpus`] type.
 
 use crate::{
-    disk::{open_dir, open_file, read_file_to_vec},
+    config::{MAX_CORPUS_DIRECTORY_DEPTH, MAX_INPUT_PRIORITY, TRUNCATE_OVERSIZED_INPUT_FILES},
+    disk::{open_dir, open_file, read_file_to_vec, write_file},
+    repro::REPRO_DIR,
+    rng::Rng,
     size_to_pages,
     snapshot::Snapshot,
-    x86_instructions::rdtsc,
 };
-use alloc::{string::String, vec, vec::Vec};
+use alloc::{
+    collections::BTreeSet,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use bit_vec::BitVec;
 use core::{
     ops::Range,
-    sync::atomic::{AtomicU64, Ordering},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
 };
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use spin::RwLock;
 use uefi::proto::media::file::{Directory, FileAttribute};
 use x86::current::paging::BASE_PAGE_SHIFT;
@@ -28,6 +38,105 @@ pub(crate) struct InputFile {
     /// The name of input. It is a file name if it is read from a corpus
     /// directory. Otherwise, some symbolic name.
     pub(crate) name: String,
+    /// The number of new edges and hit count buckets found by this input when
+    /// it was added to the corpus and by mutated inputs derived from it. Used
+    /// for the power schedule (see [`Corpus::weight`]).
+    pub(crate) new_coverage: u64,
+    /// The guest TSC spent by running this input without mutation, or zero if
+    /// not measured yet. Used for the power schedule.
+    pub(crate) exec_tsc: u64,
+    /// The name of the input file this input was mutated from, if known.
+    pub(crate) parent: Option<String>,
+    /// The number of times this input was mutated and added to the corpus
+    /// since its original seed, that is, zero for the seed itself.
+    pub(crate) generation: u32,
+    /// The percentage to scale the weight of this input in the power schedule
+    /// (see [`Corpus::weight`]), if specified by the sidecar file. Capped to
+    /// [`MAX_INPUT_PRIORITY`].
+    pub(crate) priority: Option<u64>,
+    /// The number of times this input file was picked up from the corpus
+    /// without being removed. Used for [`Corpus::selection_entropy`].
+    pub(crate) selected_count: SelectedCount,
+    /// The offsets of the input data where mutation found new coverage, as
+    /// bits (the effector map). Empty until any is found. Inherited by mutated
+    /// inputs derived from this input.
+    pub(crate) effective_offsets: BitVec,
+}
+
+impl InputFile {
+    // Marks `offsets` in the input data as effective. Offsets beyond the input
+    // data, eg, ones appended with the `input_size_mutation` feature, are
+    // ignored.
+    fn mark_effective(&mut self, offsets: &[usize]) {
+        let len = self.effective_offsets.len();
+        if len < self.data.len() {
+            self.effective_offsets.grow(self.data.len() - len, false);
+        } else {
+            self.effective_offsets.truncate(self.data.len());
+        }
+        for &offset in offsets.iter().filter(|&&offset| offset < self.data.len()) {
+            self.effective_offsets.set(offset, true);
+        }
+    }
+}
+
+/// The number of times an input file was picked up from the corpus. Atomic so
+/// that [`Corpus::select_file`] and [`Corpus::next_file`] can count it under
+/// the read lock of the corpus.
+#[derive(Default, Debug)]
+pub(crate) struct SelectedCount(AtomicU64);
+
+impl SelectedCount {
+    fn increment(&self) {
+        let _ = self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Clone for SelectedCount {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.get()))
+    }
+}
+
+/// The annotations of an input file, stored in the sidecar file named
+/// `<name>.meta.json` in the corpus directory. All fields are optional.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct InputMetadata {
+    /// See [`InputFile::parent`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+    /// See [`InputFile::generation`].
+    generation: u32,
+    /// The number of new edges and hit count buckets the input is expected to
+    /// find. Used as the initial [`InputFile::new_coverage`].
+    new_coverage: u64,
+    /// See [`InputFile::priority`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<u64>,
+}
+
+/// The suffix of the sidecar file names. See [`InputMetadata`].
+const SIDECAR_SUFFIX: &str = ".meta.json";
+
+/// The name of the file in the corpus directory to save the accumulated
+/// coverage into. See [`crate::config::PERSIST_COVERAGE`].
+pub(crate) const COVERAGE_FILE_NAME: &str = "coverage.bin";
+
+/// The summary of input files in the corpus. See [`Corpus::summary`].
+#[derive(Debug)]
+pub(crate) struct CorpusSummary {
+    pub(crate) file_count: usize,
+    pub(crate) total_size: usize,
+    pub(crate) min_size: usize,
+    pub(crate) median_size: usize,
+    pub(crate) max_size: usize,
+    /// See [`Corpus::data_pages`].
+    pub(crate) data_pages: Range<usize>,
 }
 
 /// The singleton data structure containing a list of input files and memory
@@ -36,6 +145,9 @@ pub(crate) struct InputFile {
 pub(crate) struct Corpus {
     /// The list of immutable input files.
     files: RwLock<Vec<InputFile>>,
+    /// The path to the corpus directory, where sidecar files of new input
+    /// files are written.
+    path: String,
     /// The base address of the input data pages in guest VA.
     ///
     /// This address is made up by the hypervisor and contains mutated input
@@ -48,16 +160,39 @@ pub(crate) struct Corpus {
     /// rounded up to the 4KB granularity. For example, if the biggest input
     /// is 4100 bytes, this will be 2 page-size.
     data_pages: Range<usize>,
+    /// The sum of sizes of all input files ever added to the corpus.
+    total_size: AtomicU64,
+    /// The number of input files ever added to the corpus.
+    total_count: AtomicU64,
+    /// The sum of [`InputFile::exec_tsc`] of all measured input files.
+    total_exec_tsc: AtomicU64,
+    /// The number of measured input files.
+    exec_count: AtomicU64,
+    /// The index of the input file to be picked up next by
+    /// [`Corpus::next_file`].
+    cursor: AtomicUsize,
+    /// The sequence number of the input file to be named next by
+    /// [`Corpus::unique_name`].
+    next_file_number: AtomicU64,
+    /// The names of input files that have a slow input derived from them, and
+    /// of the slow inputs. See [`Corpus::claim_slow_input`].
+    slow_inputs: RwLock<BTreeSet<String>>,
 }
 
 impl Corpus {
-    /// Creates the corpus by reads all files from the specified path.
+    /// Creates the corpus by reads all files from the specified path. Files
+    /// larger than `max_file_size` are skipped or truncated. See
+    /// [`crate::config::MAX_INPUT_FILE_SIZE`].
+    ///
+    /// The input data pages are placed above the memory of all `snapshots`, or
+    /// at the address specified by the first snapshot.
     pub(crate) fn new(
         dir: &mut Directory,
         corpus_path: &str,
-        snapshot: &Snapshot,
+        snapshots: &[Snapshot],
+        max_file_size: u64,
     ) -> Result<Self, uefi::Error> {
-        let input_files = Self::read_files_in_directory(dir, corpus_path)?;
+        let input_files = Self::read_files_in_directory(dir, corpus_path, max_file_size)?;
 
         // Out of all input files, find the biggest one to reserve memory that is
         // large enough to fit it (and any others). This memory region is used to
@@ -91,13 +226,38 @@ impl Corpus {
         //      +---------------------+
         //      | (Inaccessible page) |
         //
+        //
+        // If the snapshot file specifies the address of input data, the input data
+        // pages are placed there instead, which must be above the snapshot memory.
         let size_in_pages = size_to_pages(largest);
-        let input_data_page_first = snapshot.memory.len() + 1;
+        let memory_page_count = snapshots
+            .iter()
+            .map(|snapshot| snapshot.memory.len())
+            .max()
+            .unwrap_or(0);
+        let input_data_page_first = match snapshots[0].harness.input_gva {
+            Some(gva) if (gva as usize >> BASE_PAGE_SHIFT) < memory_page_count => {
+                error!("Input data at {gva:#x} overlaps with the snapshot memory");
+                return Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER));
+            }
+            Some(gva) => gva as usize >> BASE_PAGE_SHIFT,
+            None => memory_page_count + 1,
+        };
         let input_data_page_end = input_data_page_first + size_in_pages;
+        let total_size = input_files.iter().map(|file| file.data.len() as u64).sum();
+        let total_count = input_files.len() as u64;
         Ok(Self {
             files: RwLock::new(input_files),
+            path: corpus_path.to_string(),
             data_gva: (input_data_page_first << BASE_PAGE_SHIFT) as u64,
             data_pages: input_data_page_first..input_data_page_end,
+            total_size: AtomicU64::new(total_size),
+            total_count: AtomicU64::new(total_count),
+            total_exec_tsc: AtomicU64::new(0),
+            exec_count: AtomicU64::new(0),
+            cursor: AtomicUsize::new(0),
+            next_file_number: AtomicU64::new(total_count),
+            slow_inputs: RwLock::new(BTreeSet::new()),
         })
     }
 
@@ -107,34 +267,65 @@ impl Corpus {
         self.data_gva
     }
 
+    /// Returns the path to the corpus directory.
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
     /// Returns the range of physical address that maps the mutated contents of
     /// input files.
     pub(crate) fn data_pages(&self) -> Range<usize> {
         self.data_pages.clone()
     }
 
+    /// Returns the summary of input files currently in the corpus.
+    pub(crate) fn summary(&self) -> CorpusSummary {
+        let mut sizes: Vec<usize> = self
+            .files
+            .read()
+            .iter()
+            .map(|input_file| input_file.data.len())
+            .collect();
+        sizes.sort_unstable();
+        CorpusSummary {
+            file_count: sizes.len(),
+            total_size: sizes.iter().sum(),
+            min_size: sizes.first().copied().unwrap_or(0),
+            median_size: sizes.get(sizes.len() / 2).copied().unwrap_or(0),
+            max_size: sizes.last().copied().unwrap_or(0),
+            data_pages: self.data_pages(),
+        }
+    }
+
     /// Returns the number of remaining input files.
     pub(crate) fn remaining_files_count(&self) -> usize {
         self.files.read().len()
     }
 
-    /// Picks up the next input file from the corpus.
+    /// Picks up the input file with the largest weight from the corpus.
     ///
     /// It removes an input file from the corpus. If there is no more input
     /// file, the calling thread will wait until a new input file is added.
     /// If the last active thread enters the wait state, fuzzing is complete
-    /// as it panics.
-    pub(crate) fn consume_file(&self, active_thread_count: &AtomicU64) -> InputFile {
+    /// and None is returned to all waiting threads.
+    pub(crate) fn consume_file(&self, active_thread_count: &AtomicU64) -> Option<InputFile> {
         let _ = active_thread_count.fetch_sub(1, Ordering::SeqCst);
         let input_file = loop {
             {
                 let mut input_files = self.files.write();
-                if let Some(input_file) = input_files.pop() {
-                    break input_file;
+                let heaviest = input_files
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, input_file)| self.weight(input_file))
+                    .map(|(index, _)| index);
+                if let Some(index) = heaviest {
+                    break input_files.swap_remove(index);
                 }
             }
             core::hint::spin_loop();
-            assert!(active_thread_count.load(Ordering::SeqCst) > 0, "No more input file");
+            if active_thread_count.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
         };
         let _ = active_thread_count.fetch_add(1, Ordering::SeqCst);
 
@@ -143,38 +334,314 @@ impl Corpus {
             input_file.name,
             self.remaining_files_count()
         );
+        Some(input_file)
+    }
+
+    /// Picks up the next input file from the corpus in a round-robin manner.
+    /// This function returns a copy of an input file and keeps the corpus
+    /// unchanged, so that it never waits unlike [`Corpus::consume_file`].
+    pub(crate) fn next_file(&self) -> InputFile {
+        let input_files = self.files.read();
+        let index = self.cursor.fetch_add(1, Ordering::Relaxed) % input_files.len();
+        input_files[index].selected_count.increment();
+        let input_file = input_files[index].clone();
+
+        debug!("Picking up an input file {:?}", input_file.name);
         input_file
     }
 
-    /// Picks up the next input file from the corpus in a random manner. This
-    /// function returns a copy of an input file and keeps the corpus unchanged.
-    pub(crate) fn select_file(&self) -> InputFile {
+    /// Picks up the next input file from the corpus in a random manner, with
+    /// probability proportional to its weight. This function returns a copy of
+    /// an input file and keeps the corpus unchanged. `rng` is used to make
+    /// the selection.
+    pub(crate) fn select_file(&self, rng: &mut Rng) -> InputFile {
+        let input_files = self.files.read();
+        let total_weight: u64 = input_files.iter().map(|file| self.weight(file)).sum();
+        let mut point = rng.next_u64() % total_weight;
+        for input_file in input_files.iter() {
+            let weight = self.weight(input_file);
+            if point < weight {
+                input_file.selected_count.increment();
+                return input_file.clone();
+            }
+            point -= weight;
+        }
+        unreachable!()
+    }
+
+    /// Returns how evenly input files have been picked up by
+    /// [`Corpus::select_file`] and [`Corpus::next_file`], as the Shannon
+    /// entropy of the selection counts normalized to 0-100%, or None if no
+    /// input file has been picked up yet. 100% means all input files are
+    /// picked up equally, and a low value means selection has collapsed
+    /// onto a few input files.
+    pub(crate) fn selection_entropy(&self) -> Option<u64> {
         let input_files = self.files.read();
-        let index = rdtsc() as usize % input_files.len();
-        input_files[index].clone()
+        let total: u64 = input_files
+            .iter()
+            .map(|file| file.selected_count.get())
+            .sum();
+        if total == 0 {
+            return None;
+        }
+        if input_files.len() < 2 {
+            return Some(100);
+        }
+
+        // H = log2(total) - sum(count * log2(count)) / total, computed in the
+        // fixed-point format, then divided by the maximum, log2(file count).
+        let weighted_sum: u128 = input_files
+            .iter()
+            .map(|file| file.selected_count.get())
+            .filter(|&count| count != 0)
+            .map(|count| u128::from(count) * u128::from(log2_fixed(count)))
+            .sum();
+        let entropy = log2_fixed(total).saturating_sub((weighted_sum / u128::from(total)) as u64);
+        Some(entropy * 100 / log2_fixed(input_files.len() as u64))
+    }
+
+    /// Returns the weight of `input` in percent, used to pick up input files
+    /// and decide how many times they are mutated (the power schedule).
+    ///
+    /// Like AFL, the weight favors input files that are faster and smaller than
+    /// average, and ones that found more new coverage. It is computed from
+    /// running totals, so that the cost is constant.
+    pub(crate) fn weight(&self, input: &InputFile) -> u64 {
+        let mut weight = 100;
+
+        // Favor fast files. Files not measured yet are considered average.
+        let exec_count = self.exec_count.load(Ordering::Relaxed);
+        if input.exec_tsc != 0 && exec_count != 0 {
+            let average = self.total_exec_tsc.load(Ordering::Relaxed) / exec_count;
+            weight = match input.exec_tsc {
+                tsc if tsc * 4 <= average => 300,
+                tsc if tsc * 2 <= average => 200,
+                tsc if tsc >= average * 4 => 25,
+                tsc if tsc >= average * 2 => 50,
+                _ => 100,
+            };
+        }
+
+        // Favor small files.
+        let size = input.data.len() as u64;
+        let average = self.total_size.load(Ordering::Relaxed)
+            / self.total_count.load(Ordering::Relaxed).max(1);
+        if size * 2 <= average {
+            weight = weight * 3 / 2;
+        } else if size >= average * 2 {
+            weight /= 2;
+        }
+
+        // Favor files that found new coverage, up to four times.
+        weight = weight * (4 + input.new_coverage.min(12)) / 4;
+
+        // Apply the priority given by the user, if any. Keep the weight non-zero
+        // even with the priority of zero, so that the total weight never is.
+        match input.priority {
+            Some(priority) => (weight * priority / 100).max(1),
+            None => weight,
+        }
+    }
+
+    /// Records that `input` ran without mutation spending `exec_tsc` in the
+    /// guest. The input file of the same name in the corpus is updated too, if
+    /// exists.
+    pub(crate) fn record_exec_tsc(&self, input: &mut InputFile, exec_tsc: u64) {
+        let exec_tsc = exec_tsc.max(1);
+        if input.exec_tsc == 0 {
+            let _ = self.total_exec_tsc.fetch_add(exec_tsc, Ordering::Relaxed);
+            let _ = self.exec_count.fetch_add(1, Ordering::Relaxed);
+        }
+        input.exec_tsc = exec_tsc;
+        self.update_file(&input.name, |file| file.exec_tsc = exec_tsc);
+    }
+
+    /// Returns `name` followed by a sequence number shared by all processors,
+    /// such as `sample.png_1234-5`, so that new input files found by different
+    /// processors, or from the same input file selected again, never overwrite
+    /// each other on disk. The number starts at the number of input files
+    /// loaded, to also avoid names written by the previous sessions.
+    pub(crate) fn unique_name(&self, name: &str) -> String {
+        let number = self.next_file_number.fetch_add(1, Ordering::SeqCst);
+        format!("{name}-{number}")
+    }
+
+    /// Checks whether the slow `input` should be added to the corpus, and if
+    /// so, records it. Only one slow input is kept per parent, and none is
+    /// kept from a slow input, so that slow inputs without new coverage do
+    /// not grow the corpus without bound.
+    pub(crate) fn claim_slow_input(&self, input: &InputFile) -> bool {
+        let Some(parent) = &input.parent else {
+            return false;
+        };
+        let mut slow_inputs = self.slow_inputs.write();
+        if slow_inputs.contains(parent) {
+            return false;
+        }
+        let _ = slow_inputs.insert(parent.clone());
+        let _ = slow_inputs.insert(input.name.clone());
+        true
     }
 
-    /// Adds a new input file into the corpus.
-    pub(crate) fn add_file(&self, input: InputFile) {
+    /// Records that a mutated input derived from `input` found `new_coverage`
+    /// new edges and hit count buckets by modifying `offsets`, if it found any.
+    /// The input file of the same name in the corpus is updated too, if exists.
+    pub(crate) fn record_new_coverage(
+        &self,
+        input: &mut InputFile,
+        new_coverage: u64,
+        offsets: &[usize],
+    ) {
+        input.new_coverage += new_coverage;
+        if new_coverage != 0 {
+            input.mark_effective(offsets);
+        }
+        self.update_file(&input.name, |file| {
+            file.new_coverage += new_coverage;
+            if new_coverage != 0 {
+                file.mark_effective(offsets);
+            }
+        });
+    }
+
+    // Applies `update` to the input file named `name` if exists.
+    fn update_file(&self, name: &str, update: impl FnOnce(&mut InputFile)) {
+        if let Some(file) = self.files.write().iter_mut().find(|file| file.name == name) {
+            update(file);
+        }
+    }
+
+    /// Returns a copy of the input file with the given name if exists. The
+    /// corpus is unchanged.
+    pub(crate) fn find_file(&self, name: &str) -> Option<InputFile> {
+        self.files
+            .read()
+            .iter()
+            .find(|input_file| input_file.name == name)
+            .cloned()
+    }
+
+    /// Returns the names of the ancestors of the input file `name` in the
+    /// corpus, from its parent to the original seed. The walk stops at a parent
+    /// not in the corpus, eg, an input file removed after being assigned.
+    pub(crate) fn ancestry(&self, name: &str) -> Vec<String> {
+        let files = self.files.read();
+        let mut ancestors: Vec<String> = Vec::new();
+        let mut current = name.to_string();
+        // Bound the walk by the number of input files in case sidecar files
+        // form a cycle.
+        while ancestors.len() < files.len() {
+            let Some(parent) = files
+                .iter()
+                .find(|input_file| input_file.name == current)
+                .and_then(|input_file| input_file.parent.clone())
+            else {
+                break;
+            };
+            ancestors.push(parent.clone());
+            current = parent;
+        }
+        ancestors
+    }
+
+    /// Returns copies of all input files in the corpus. The corpus is
+    /// unchanged.
+    pub(crate) fn clone_files(&self) -> Vec<InputFile> {
+        self.files.read().clone()
+    }
+
+    /// Replaces all input files in the corpus with `files`.
+    pub(crate) fn replace_files(&self, files: Vec<InputFile>) {
+        *self.files.write() = files;
+    }
+
+    /// Adds a new input file into the corpus, and writes it with its sidecar
+    /// file recording the parent and the new coverage into the corpus directory
+    /// under `volume`, so that it is loaded again on the next start.
+    pub(crate) fn add_file(&self, volume: &mut Directory, input: InputFile) {
+        // Reject the input file that does not fit in the input data pages, as
+        // they are never expanded after initialization.
+        if input.data.len() > self.data_pages.len() << BASE_PAGE_SHIFT {
+            warn!(
+                "Rejecting a new input file {:?} larger than the input data pages ({:#x} bytes)",
+                input.name,
+                input.data.len()
+            );
+            return;
+        }
+
         debug!(
             "Adding a new input file {:?}. Remaining {}",
             input.name,
             self.remaining_files_count() + 1
         );
 
+        let _ = self
+            .total_size
+            .fetch_add(input.data.len() as u64, Ordering::Relaxed);
+        let _ = self.total_count.fetch_add(1, Ordering::Relaxed);
+        if input.exec_tsc != 0 {
+            let _ = self
+                .total_exec_tsc
+                .fetch_add(input.exec_tsc, Ordering::Relaxed);
+            let _ = self.exec_count.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Err(err) = self.write_file_with_sidecar(volume, &input) {
+            warn!("Failed to write the input file {:?}: {err:#?}", input.name);
+        }
         self.files.write().push(input);
     }
 
-    // Reads the contents of all files in the specified corpus directory.
+    // Writes `input` and its sidecar file into the corpus directory. The
+    // sidecar file is written only after the input file is, so that it never
+    // exists alone.
+    fn write_file_with_sidecar(
+        &self,
+        volume: &mut Directory,
+        input: &InputFile,
+    ) -> Result<(), uefi::Error> {
+        let metadata = InputMetadata {
+            parent: input.parent.clone(),
+            generation: input.generation,
+            new_coverage: input.new_coverage,
+            priority: input.priority,
+        };
+        let contents = serde_json::to_vec(&metadata).map_err(|_err| uefi::Status::ABORTED)?;
+        let mut corpus_dir = open_dir(volume, &self.path)?;
+        write_file(&mut corpus_dir, &input.name, &input.data)?;
+        write_file(&mut corpus_dir, &format!("{}{SIDECAR_SUFFIX}", input.name), &contents)
+    }
+
+    // Reads the contents of all files in the specified corpus directory and its
+    // subdirectories, and their sidecar files if exist. Files larger than
+    // `max_file_size` are skipped or truncated.
     fn read_files_in_directory(
         dir: &mut Directory,
         corpus_path: &str,
+        max_file_size: u64,
     ) -> Result<Vec<InputFile>, uefi::Error> {
         let mut files: Vec<InputFile> = Vec::new();
         let mut corpus_dir = open_dir(dir, corpus_path)?;
+        Self::read_files_recursively(&mut corpus_dir, "", 0, max_file_size, &mut files)?;
+        Ok(files)
+    }
+
+    // Reads the contents of all files in `dir` into `files`, and recursively
+    // does the same for subdirectories up to `MAX_CORPUS_DIRECTORY_DEPTH`.
+    // `prefix` is the relative path of `dir` from the corpus directory, and is
+    // prepended to the names of input files.
+    fn read_files_recursively(
+        dir: &mut Directory,
+        prefix: &str,
+        depth: usize,
+        max_file_size: u64,
+        files: &mut Vec<InputFile>,
+    ) -> Result<(), uefi::Error> {
+        let mut dir_files: Vec<InputFile> = Vec::new();
+        let mut sidecar_names = BTreeSet::new();
         let mut buffer = vec![0; 128];
         loop {
-            let file_info = match corpus_dir.read_entry(&mut buffer) {
+            let file_info = match dir.read_entry(&mut buffer) {
                 Ok(info) => {
                     if let Some(info) = info {
                         info
@@ -191,22 +658,146 @@ impl Corpus {
                 }
             };
 
-            // Non recursive search for simplicity.
-            if file_info.attribute().contains(FileAttribute::DIRECTORY) {
-                continue;
-            }
-
             let mut name = String::new();
             file_info
                 .file_name()
                 .as_str_in_buf(&mut name)
                 .map_err(|_err| uefi::Status::INVALID_PARAMETER)?;
-            let mut file = open_file(&mut corpus_dir, &name)?;
+            let path = format!("{prefix}{name}");
+
+            // Search subdirectories, except for the current and parent
+            // directories. The depth is limited in case the file system is
+            // corrupted and has a loop.
+            if file_info.attribute().contains(FileAttribute::DIRECTORY) {
+                if name == "." || name == ".." {
+                    continue;
+                }
+                // The reproduction files are not input files, eg, when the
+                // corpus directory is the root of the volume.
+                if prefix.is_empty() && name.eq_ignore_ascii_case(REPRO_DIR) {
+                    continue;
+                }
+                if depth >= MAX_CORPUS_DIRECTORY_DEPTH {
+                    warn!("Skipping a directory {path:?} nested too deep");
+                    continue;
+                }
+                let mut sub_dir = open_dir(dir, &name)?;
+                let sub_prefix = format!("{path}\\");
+                Self::read_files_recursively(
+                    &mut sub_dir,
+                    &sub_prefix,
+                    depth + 1,
+                    max_file_size,
+                    files,
+                )?;
+                continue;
+            }
+
+            // Nor are the coverage file and UEFI shell scripts, such as
+            // `startup.nsh` in a reproduction directory copied into the corpus.
+            if (prefix.is_empty() && name == COVERAGE_FILE_NAME)
+                || name.to_ascii_lowercase().ends_with(".nsh")
+            {
+                continue;
+            }
+            if name.ends_with(SIDECAR_SUFFIX) {
+                let _ = sidecar_names.insert(name);
+                continue;
+            }
+            let oversized = file_info.file_size() > max_file_size;
+            if oversized && !TRUNCATE_OVERSIZED_INPUT_FILES {
+                warn!(
+                    "Skipping an input file {path:?} larger than {max_file_size:#x} bytes ({:#x} bytes)",
+                    file_info.file_size()
+                );
+                continue;
+            }
+            let mut file = open_file(dir, &name)?;
             // Safety: Code is single threaded.
-            let data = unsafe { read_file_to_vec(&mut file) }?;
-            info!("Adding an input file {name:?}");
-            files.push(InputFile { data, name });
+            let mut data = unsafe { read_file_to_vec(&mut file) }?;
+            if oversized {
+                warn!("Truncating an input file {path:?} to {max_file_size:#x} bytes");
+                data.truncate(max_file_size as usize);
+            }
+            info!("Adding an input file {path:?}");
+            dir_files.push(InputFile {
+                data,
+                name: path,
+                ..Default::default()
+            });
         }
-        Ok(files)
+
+        // Apply the sidecar files. Input files without them are left as-is.
+        for file in &mut dir_files {
+            let sidecar_name = format!("{}{SIDECAR_SUFFIX}", &file.name[prefix.len()..]);
+            if sidecar_names.contains(&sidecar_name) {
+                let mut sidecar = open_file(dir, &sidecar_name)?;
+                // Safety: Code is single threaded.
+                let contents = unsafe { read_file_to_vec(&mut sidecar) }?;
+                match serde_json::from_slice::<InputMetadata>(&contents) {
+                    Ok(metadata) => {
+                        file.parent = metadata.parent;
+                        file.generation = metadata.generation;
+                        file.new_coverage = metadata.new_coverage;
+                        file.priority = metadata
+                            .priority
+                            .map(|priority| priority.min(MAX_INPUT_PRIORITY));
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Ignoring a corrupted sidecar file \"{prefix}{sidecar_name}\": {err}"
+                        );
+                    }
+                }
+            }
+        }
+        files.append(&mut dir_files);
+        Ok(())
+    }
+}
+
+/// Returns log2(`value`) in the fixed-point format with 16 fractional bits.
+/// `value` must not be zero.
+fn log2_fixed(value: u64) -> u64 {
+    // The integer part is the position of the highest set bit. For the
+    // fractional part, normalize `value` into [1, 2) with 32 fractional bits,
+    // and square it repeatedly. Each time it reaches 2, the next bit is 1.
+    let integer = value.ilog2();
+    let mut x = (u128::from(value) << 32) >> integer;
+    let mut result = u64::from(integer) << 16;
+    for bit in (0..16).rev() {
+        x = (x * x) >> 32;
+        if x >= 2 << 32 {
+            x >>= 1;
+            result |= 1 << bit;
+        }
+    }
+    result
+}
+
+/// Selects the subset of input files that covers all basic blocks covered by
+/// `coverage`, which pairs each input file with basic blocks it executed.
+///
+/// This greedily takes the input file that covers the most basic blocks not
+/// yet covered, until all basic blocks are covered. Input files that cover
+/// nothing are never selected.
+pub(crate) fn minimize_files(mut coverage: Vec<(InputFile, BTreeSet<u64>)>) -> Vec<InputFile> {
+    let mut uncovered: BTreeSet<u64> = coverage
+        .iter()
+        .flat_map(|(_, basic_blks)| basic_blks.iter().copied())
+        .collect();
+
+    let mut files = Vec::new();
+    while !uncovered.is_empty() {
+        let (index, _) = coverage
+            .iter()
+            .enumerate()
+            .map(|(i, (_, basic_blks))| (i, basic_blks.intersection(&uncovered).count()))
+            .max_by_key(|&(_, count)| count)
+            .unwrap();
+        let (input, basic_blks) = coverage.swap_remove(index);
+        uncovered.retain(|basic_blk| !basic_blks.contains(basic_blk));
+        files.push(input);
     }
+    files
 }
@@ -6,7 +6,7 @@
 //! dependency on the UEFI system table. Hence, some functions are serialized
 //! internally, and some are marked as `unsafe`.
 
-use crate::{system_table::system_table, Page};
+use crate::{inflate::inflate, system_table::system_table, Page};
 use alloc::{boxed::Box, vec, vec::Vec};
 use log::error;
 use uefi::proto::media::file::{
@@ -36,6 +36,52 @@ pub(crate) fn open_dir(dir: &mut Directory, dirname: &str) -> Result<Directory,
     }
 }
 
+/// Opens a directory specified by `dirname`, creating it if it does not exist.
+pub(crate) fn create_dir(dir: &mut Directory, dirname: &str) -> Result<Directory, uefi::Error> {
+    match open_with_mode(dir, dirname, FileMode::CreateReadWrite, FileAttribute::DIRECTORY)? {
+        FileType::Regular(_) => {
+            error!("{dirname:#?} is not a directory");
+            Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER))
+        }
+        FileType::Dir(dir) => Ok(dir),
+    }
+}
+
+/// Writes `data` into a file specified by `filename`. The file is created if it
+/// does not exist, and is overwritten otherwise.
+pub(crate) fn write_file(
+    dir: &mut Directory,
+    filename: &str,
+    data: &[u8],
+) -> Result<(), uefi::Error> {
+    // Opening an existing file does not truncate it. Delete it first.
+    let file = open_with_mode(dir, filename, FileMode::CreateReadWrite, FileAttribute::empty())?;
+    {
+        let _lock = system_table();
+        match file {
+            FileType::Regular(file) => file.delete()?,
+            FileType::Dir(_) => {
+                error!("{filename:#?} is not a file");
+                return Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER));
+            }
+        }
+    }
+
+    let FileType::Regular(mut file) =
+        open_with_mode(dir, filename, FileMode::CreateReadWrite, FileAttribute::empty())?
+    else {
+        return Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER));
+    };
+
+    // Acquire the UEFI system table lock before use of the file API.
+    let _lock = system_table();
+    file.write(data).map_err(|err| {
+        error!("File write error: {err:#?}");
+        uefi::Status::DEVICE_ERROR
+    })?;
+    file.flush()
+}
+
 /// Returns the details of the file.
 ///
 /// # Safety
@@ -70,21 +116,101 @@ pub(crate) unsafe fn read_file_to_vec(file: &mut RegularFile) -> Result<Vec<u8>,
     }
 }
 
+/// Reads the whole contents of a file specified by `filename` into the vector,
+/// or returns `None` if the file does not exist.
+///
+/// # Safety
+///
+/// The caller must ensure no other thread use the UEFI system table
+/// concurrently. Implementation calls the global allocator, which uses the UEFI
+/// system table.
+pub(crate) unsafe fn read_file_if_exists(
+    dir: &mut Directory,
+    filename: &str,
+) -> Result<Option<Vec<u8>>, uefi::Error> {
+    match open_quietly(dir, filename, FileMode::Read, FileAttribute::empty()) {
+        Ok(FileType::Regular(mut file)) => Ok(Some(unsafe { read_file_to_vec(&mut file) }?)),
+        Ok(FileType::Dir(_)) => {
+            error!("{filename:#?} is not a file");
+            Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER))
+        }
+        Err(err) if err.status() == uefi::Status::NOT_FOUND => Ok(None),
+        Err(err) => {
+            error!("{filename:#?}: {:#?}", err.status());
+            Err(err)
+        }
+    }
+}
+
 // Reads a single page from the snapshot file.
 pub(crate) fn read_page_from_snapshot(
     snapshot_file: &mut RegularFile,
     page: &mut Page,
     pfn: usize,
+) -> Result<(), uefi::Error> {
+    read_pages_from_snapshot(snapshot_file, core::slice::from_mut(page), pfn)
+}
+
+// Reads contiguous pages starting from `pfn` from the snapshot file with a
+// single read.
+pub(crate) fn read_pages_from_snapshot(
+    snapshot_file: &mut RegularFile,
+    pages: &mut [Page],
+    pfn: usize,
+) -> Result<(), uefi::Error> {
+    // Safety: `Page` is plain data and valid for any bytes.
+    let buffer = unsafe {
+        core::slice::from_raw_parts_mut(pages.as_mut_ptr().cast::<u8>(), size_of_val(pages))
+    };
+    read_from_file(snapshot_file, (pfn << BASE_PAGE_SHIFT) as u64, buffer)
+}
+
+// Reads a single page compressed in the DEFLATE format from the snapshot file,
+// and decompresses it.
+pub(crate) fn read_compressed_page_from_snapshot(
+    snapshot_file: &mut RegularFile,
+    page: &mut Page,
+    offset: u64,
+    length: usize,
+) -> Result<(), uefi::Error> {
+    // Large enough to hold a page stored without compression, including the
+    // DEFLATE block headers. Allocated on the heap, as this runs on small
+    // stacks of application processors too.
+    let mut buffer = vec![0u8; BASE_PAGE_SIZE + 0x100];
+    let Some(buffer) = buffer.get_mut(..length) else {
+        error!("Compressed page at {offset:#x} is too large ({length:#x} bytes)");
+        return Err(uefi::Error::from(uefi::Status::VOLUME_CORRUPTED));
+    };
+    read_from_file(snapshot_file, offset, buffer)?;
+
+    match inflate(buffer, &mut page.0) {
+        Ok(BASE_PAGE_SIZE) => Ok(()),
+        Ok(size) => {
+            error!("Compressed page at {offset:#x} is decompressed to {size:#x} bytes");
+            Err(uefi::Error::from(uefi::Status::VOLUME_CORRUPTED))
+        }
+        Err(err) => {
+            error!("Compressed page at {offset:#x} is corrupted: {err:?}");
+            Err(uefi::Error::from(uefi::Status::VOLUME_CORRUPTED))
+        }
+    }
+}
+
+// Reads exactly `buffer.len()` bytes from `file` at `offset`.
+pub(crate) fn read_from_file(
+    file: &mut RegularFile,
+    offset: u64,
+    buffer: &mut [u8],
 ) -> Result<(), uefi::Error> {
     // Acquire the UEFI system table lock before use of the file API.
     let _lock = system_table();
-    snapshot_file.set_position((pfn << BASE_PAGE_SHIFT) as u64)?;
-    let bytes_read = snapshot_file.read(&mut page.0).map_err(|err| {
+    file.set_position(offset)?;
+    let bytes_read = file.read(buffer).map_err(|err| {
         error!("File read error: {err:#?}");
         uefi::Status::DEVICE_ERROR
     })?;
 
-    if bytes_read == BASE_PAGE_SIZE {
+    if bytes_read == buffer.len() {
         Ok(())
     } else {
         Err(uefi::Error::from(uefi::Status::END_OF_FILE))
@@ -93,6 +219,28 @@ pub(crate) fn read_page_from_snapshot(
 
 // Opens any kind of "file" specified by `filename`.
 fn open(dir: &mut Directory, filename: &str) -> Result<FileType, uefi::Error> {
+    open_with_mode(dir, filename, FileMode::Read, FileAttribute::empty())
+}
+
+// Opens any kind of "file" specified by `filename` with `mode`. `attributes`
+// are used only when the file is created.
+fn open_with_mode(
+    dir: &mut Directory,
+    filename: &str,
+    mode: FileMode,
+    attributes: FileAttribute,
+) -> Result<FileType, uefi::Error> {
+    open_quietly(dir, filename, mode, attributes)
+        .inspect_err(|err| error!("{filename:#?}: {:#?}", err.status()))
+}
+
+// Same as `open_with_mode`, but does not report an error.
+fn open_quietly(
+    dir: &mut Directory,
+    filename: &str,
+    mode: FileMode,
+    attributes: FileAttribute,
+) -> Result<FileType, uefi::Error> {
     const BUF_SIZE: usize = 255;
     let mut buf = [0; BUF_SIZE + 1];
     let name = uefi::CStr16::from_str_with_buf(filename, &mut buf)
@@ -100,7 +248,5 @@ fn open(dir: &mut Directory, filename: &str) -> Result<FileType, uefi::Error> {
 
     // Acquire the UEFI system table lock before use of the file API.
     let _lock = system_table();
-    dir.open(name, FileMode::Read, FileAttribute::empty())
-        .inspect_err(|err| error!("{filename:#?}: {:#?}", err.status()))?
-        .into_type()
+    dir.open(name, mode, attributes)?.into_type()
 }
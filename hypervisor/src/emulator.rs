@@ -0,0 +1,50 @@
+//! The module containing a minimal instruction emulator.
+//!
+//! VM exits due to instructions that do not have a dedicated
+//! [`crate::hardware_vt::VmExitReason`] are reported as
+//! [`crate::hardware_vt::VmExitReason::Instruction`]. Instead of aborting the
+//! VM on them, the instruction at the guest RIP is decoded with
+//! [`decode_instruction`], and emulated if it is one of the few supported
+//! instructions.
+
+/// The instructions the emulator supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EmulatedInstruction {
+    /// `HLT`. Ends the iteration without being reported as a crash, as
+    /// nothing would wake the guest up.
+    Hlt,
+    /// `INVD`. Skipped, as it would affect the whole system.
+    Invd,
+    /// `CLTS`. Clears CR0.TS.
+    Clts,
+    /// `LMSW` with the general purpose register specified by the number as
+    /// encoded in instructions. Loads bits 0 to 3 of CR0 from it, except that
+    /// CR0.PE is never cleared.
+    Lmsw(usize),
+}
+
+/// Decodes the instruction `bytes`, and returns it with its length, or None if
+/// the instruction is not supported.
+///
+/// Only the forms without prefixes are supported:
+/// - `F4` (HLT)
+/// - `0F 08` (INVD)
+/// - `0F 06` (CLTS)
+/// - `0F 01 /6` (LMSW) with a register operand
+///
+/// See: HLT—Halt
+/// See: INVD—Invalidate Internal Caches
+/// See: CLTS—Clear Task-Switched Flag in CR0
+/// See: LMSW—Load Machine Status Word
+pub(crate) fn decode_instruction(bytes: &[u8]) -> Option<(EmulatedInstruction, u64)> {
+    match bytes {
+        [0xf4, ..] => Some((EmulatedInstruction::Hlt, 1)),
+        [0x0f, 0x08, ..] => Some((EmulatedInstruction::Invd, 2)),
+        [0x0f, 0x06, ..] => Some((EmulatedInstruction::Clts, 2)),
+        // ModR/M with Mod == 11b (register) and Reg == 6.
+        [0x0f, 0x01, modrm, ..] if modrm >> 3 == 0b11_110 => {
+            Some((EmulatedInstruction::Lmsw(usize::from(modrm & 0b111)), 3))
+        }
+        _ => None,
+    }
+}
@@ -1,18 +1,40 @@
 //! The module containing the [`GlobalState`] type.
 
 use crate::{
-    corpus::Corpus,
-    patch::PatchSet,
+    config::{
+        Config, COVERAGE_BITMAP_SIZE, EXPORT_COVERAGE_BLOB, MAX_COMPARAND_COUNT,
+        ORDERED_STARTUP_LOG, ORDERED_STARTUP_TIMEOUT_IN_TSC, PERSIST_COVERAGE,
+        PROFILE_REPORT_COUNT, REVERT_COST_WARNING_PERCENT, SNAPSHOT_SWITCH_INTERVAL,
+        WATCHDOG_THRESHOLD_MULTIPLIER,
+    },
+    corpus::{Corpus, COVERAGE_FILE_NAME},
+    crc32,
+    disk::{open_dir, read_file_if_exists, write_file},
+    logger::apic_id,
+    mutation_engine::MutatingInput,
+    patch::{PatchKind, PatchSet},
     snapshot::Snapshot,
-    stats::{time, time_to_u64, RunStats},
+    stats::{time, time_to_u64, CoreStats, RunStats},
     system_table::system_table_unsafe,
+    x86_instructions::rdtsc,
 };
-use core::sync::atomic::{AtomicU64, Ordering};
-use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+use log::{error, info, warn};
+use spin::{Mutex, MutexGuard, Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use uefi::{
-    proto::pi::mp::MpServices,
+    proto::{media::file::Directory, pi::mp::MpServices},
     table::boot::{OpenProtocolAttributes, OpenProtocolParams},
 };
+use x86::current::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE};
 
 /// The singleton data structure that is used across all processors. Any write
 /// access to this structure must be synchronized.
@@ -20,22 +42,91 @@ pub(crate) struct GlobalState {
     /// The number of logical processors currently performing fuzzing.
     // Incremented when a logical processor starts fuzzing. Decremented when it
     // waits for new input file. If this becomes zero, fuzzing is complete and
-    // the hypervisor panics.
+    // all processors halt after reporting the final stats.
     pub(crate) active_thread_count: AtomicU64,
-    snapshot: RwLock<Snapshot>,
+    /// Completed when the corpus is minimized with the `minimize` feature.
+    pub(crate) corpus_minimized: Once,
+    /// Whether [`Config::max_iterations`] or [`Config::max_seconds`] is
+    /// reached. Once set, all processors halt after reporting the final stats.
+    budget_reached: AtomicBool,
+    /// Whether a crash is found with [`Config::stop_on_crash`]. Once set, all
+    /// processors halt at the beginning of the next iteration.
+    stopped_on_crash: AtomicBool,
+    /// Whether the tuning hint for the cost of reverting dirty pages has been
+    /// logged. See [`GlobalState::check_revert_cost`].
+    revert_cost_reported: AtomicBool,
+    /// The snapshots given through command line parameters. Each VM runs one
+    /// of them at a time. See [`crate::vm::Vm::snapshot_index`].
+    snapshots: Vec<RwLock<Snapshot>>,
     corpus: Corpus,
     overall_stats: RwLock<RunStats>,
     patch_set: PatchSet,
+    /// The bitmap of edges between basic blocks executed by any VM. Indexed by
+    /// an edge ID (see [`GlobalState::record_edge`]).
+    coverage_bitmap: Box<[AtomicU64]>,
+    /// The hit count buckets reached by any VM for each basic block, as bits.
+    /// Used only when [`crate::config::HIT_COUNT_COVERAGE`] is enabled.
+    hit_count_buckets: RwLock<BTreeMap<u64, u8>>,
+    /// The basic blocks executed in the previous sessions, loaded from
+    /// [`COVERAGE_FILE_NAME`]. See [`PERSIST_COVERAGE`].
+    known_basic_blks: BTreeSet<u64>,
+    /// The identity of the snapshot and patch files that the coverage is
+    /// collected with. See [`coverage_identity`].
+    coverage_identity: u64,
+    /// Values observed as operands of comparison instructions. Used as a
+    /// dictionary by [`crate::mutation_engine::MutationEngine`].
+    comparands: RwLock<Vec<u64>>,
+    /// The number of times each basic block marked with [`PatchKind::Profile`]
+    /// is executed across all iterations.
+    profile_hit_counts: BTreeMap<u64, AtomicU64>,
     iteration_count: AtomicU64,
+    /// The number of basic blocks executed so far as of the last periodic
+    /// report. See [`GlobalState::basic_blk_delta`].
+    reported_basic_blk_count: AtomicUsize,
     number_of_cores: u64,
+    /// The state and statistics of each processor running the hypervisor,
+    /// indexed by the value returned by [`GlobalState::register_core`].
+    cores: Box<[CoreState]>,
+    /// The TSC when the watchdog last checked for stuck processors. Any
+    /// processor may run the check, and the one that updates this value runs
+    /// it. See [`GlobalState::check_stuck_cores`].
+    watchdog_tsc: AtomicU64,
+    registered_core_count: AtomicUsize,
+    /// The APIC IDs of the processors that log on startup in the ascending
+    /// order, if [`ORDERED_STARTUP_LOG`] is enabled and all processors are
+    /// started.
+    /// See [`GlobalState::log_in_startup_order`].
+    startup_order: Vec<u32>,
+    /// The index of [`GlobalState::startup_order`] of the processor to log
+    /// next.
+    startup_turn: AtomicUsize,
     start_time: u64,
+    /// The name of the input file in the corpus to run once, instead of
+    /// fuzzing.
+    replay_file_name: Option<String>,
+    /// The configuration given through command line parameters.
+    config: Config,
+    /// The root directory of the volume this module is loaded from. Files are
+    /// written into it.
+    volume: Mutex<Directory>,
+    /// The paths to the snapshot and patch files given through command line
+    /// parameters.
+    snapshot_paths: Vec<String>,
+    patch_path: String,
+    /// The IDs of crashes found so far. See [`GlobalState::record_crash`].
+    crash_ids: RwLock<BTreeSet<String>>,
+    /// Completed when the final stats are reported. See
+    /// [`GlobalState::report_final`].
+    final_report: Once,
 }
 
 impl GlobalState {
     pub(crate) fn new(
-        snapshot_path: &str,
+        snapshot_paths: &[&str],
         patch_path: &str,
         corpus_path: &str,
+        replay_file_name: Option<&str>,
+        mut config: Config,
     ) -> Result<Self, uefi::Error> {
         // Safety: Code is single threaded.
         let st = unsafe { system_table_unsafe() };
@@ -51,26 +142,211 @@ impl GlobalState {
             )?
         };
         let mut dir = bs.get_image_file_system(bs.image_handle())?.open_volume()?;
-        let snapshot = Snapshot::new(&mut dir, snapshot_path)?;
-        let corpus = Corpus::new(&mut dir, corpus_path, &snapshot)?;
+        let snapshots = Self::read_snapshots(&mut dir, snapshot_paths, &config)?;
+        let corpus = Corpus::new(&mut dir, corpus_path, &snapshots, config.max_input_file_size)?;
+        let summary = corpus.summary();
+        info!(
+            "Corpus: {} files, {:#x} bytes in total, {:#x} / {:#x} / {:#x} bytes at min / median / max",
+            summary.file_count,
+            summary.total_size,
+            summary.min_size,
+            summary.median_size,
+            summary.max_size,
+        );
+        info!(
+            "Input data pages: PFN {:#x} - {:#x} ({} pages)",
+            summary.data_pages.start,
+            summary.data_pages.end,
+            summary.data_pages.len(),
+        );
+        Self::validate_mutation_target(&config, &snapshots)?;
+        let mut patch_set = PatchSet::new(&mut dir, patch_path, &snapshots[0])?;
+        for snapshot in &snapshots {
+            if let Some(end_marker) = snapshot.harness.end_marker {
+                patch_set.add_end_marker(end_marker);
+            }
+        }
+        if config.seed == 0 {
+            config.seed = rdtsc();
+        }
+        info!("Random seed: {:#x}", config.seed);
+        if config.max_seconds != 0 && !cfg!(feature = "time_report") {
+            warn!("max_seconds is ignored as `time_report` is disabled");
+        }
+        let number_of_cores = mp.get_number_of_processors()?.enabled;
+        let profile_hit_counts = patch_set
+            .addresses_of(PatchKind::Profile)
+            .map(|address| (address, AtomicU64::new(0)))
+            .collect();
+        let coverage_identity = coverage_identity(snapshot_paths, &snapshots, &patch_set);
+        let saved_coverage = if PERSIST_COVERAGE && replay_file_name.is_none() {
+            SavedCoverage::load(&mut dir, corpus_path, coverage_identity)
+        } else {
+            SavedCoverage::default()
+        };
         Ok(Self {
             active_thread_count: AtomicU64::new(0),
-            snapshot: RwLock::new(snapshot),
+            corpus_minimized: Once::new(),
+            budget_reached: AtomicBool::new(false),
+            stopped_on_crash: AtomicBool::new(false),
+            revert_cost_reported: AtomicBool::new(false),
+            snapshots: snapshots.into_iter().map(RwLock::new).collect(),
             corpus,
-            overall_stats: RwLock::new(RunStats::new()),
-            patch_set: PatchSet::new(&mut dir, patch_path)?,
+            overall_stats: RwLock::new(saved_coverage.run_stats()),
+            patch_set,
+            coverage_bitmap: saved_coverage
+                .bitmap
+                .iter()
+                .map(|&bits| AtomicU64::new(bits))
+                .collect(),
+            hit_count_buckets: RwLock::new(saved_coverage.hit_count_buckets),
+            known_basic_blks: saved_coverage.basic_blks.iter().copied().collect(),
+            coverage_identity,
+            comparands: RwLock::new(Vec::new()),
+            profile_hit_counts,
             iteration_count: AtomicU64::new(0),
-            number_of_cores: mp.get_number_of_processors()?.enabled as u64,
+            reported_basic_blk_count: AtomicUsize::new(saved_coverage.basic_blks.len()),
+            number_of_cores: number_of_cores as u64,
+            cores: (0..number_of_cores).map(|_| CoreState::default()).collect(),
+            watchdog_tsc: AtomicU64::new(0),
+            registered_core_count: AtomicUsize::new(0),
+            startup_order: Self::startup_order(&mp, replay_file_name)?,
+            startup_turn: AtomicUsize::new(0),
             start_time: time_to_u64(time()),
+            replay_file_name: replay_file_name.map(ToString::to_string),
+            config,
+            volume: Mutex::new(dir),
+            snapshot_paths: snapshot_paths.iter().map(ToString::to_string).collect(),
+            patch_path: patch_path.to_string(),
+            crash_ids: RwLock::new(BTreeSet::new()),
+            final_report: Once::new(),
         })
     }
 
-    pub(crate) fn snapshot(&self) -> RwLockReadGuard<'_, Snapshot> {
-        self.snapshot.read()
+    // Reads all snapshot files in `snapshot_paths`, overriding their harness
+    // registers and entry points with `config`. Each snapshot allocates a
+    // buffer as large as its guest memory, so the system needs enough memory
+    // for all of them. The snapshots must capture the guest in the same mode
+    // and specify the same input data address, as the VMs are initialized and
+    // input data pages are placed based on the first one.
+    fn read_snapshots(
+        dir: &mut Directory,
+        snapshot_paths: &[&str],
+        config: &Config,
+    ) -> Result<Vec<Snapshot>, uefi::Error> {
+        let mut snapshots = Vec::with_capacity(snapshot_paths.len());
+        for snapshot_path in snapshot_paths {
+            let mut snapshot = Snapshot::new(dir, snapshot_path)?;
+            snapshot.harness.override_with(config);
+            Self::validate_entry_point(snapshot_path, &snapshot)?;
+            if let Some(first) = snapshots.first() {
+                Self::validate_compatibility(snapshot_path, first, &snapshot)?;
+            }
+            snapshots.push(snapshot);
+        }
+        if snapshots.len() > 1 {
+            let total_size: usize = snapshots.iter().map(|snapshot| snapshot.memory.len()).sum();
+            info!(
+                "Snapshots: {}, {}MB in total, switched every {SNAPSHOT_SWITCH_INTERVAL} iterations",
+                snapshots.len(),
+                total_size * BASE_PAGE_SIZE / 1024 / 1024
+            );
+        }
+        Ok(snapshots)
+    }
+
+    // Checks that `snapshot` captures the guest in the same mode and specifies
+    // the same input data address as the `first` snapshot.
+    fn validate_compatibility(
+        snapshot_path: &str,
+        first: &Snapshot,
+        snapshot: &Snapshot,
+    ) -> Result<(), uefi::Error> {
+        if snapshot.registers.mode() != first.registers.mode() {
+            error!(
+                "{snapshot_path} captures the guest in a different mode (CR0 {:#x}, EFER {:#x}) than the first snapshot (CR0 {:#x}, EFER {:#x})",
+                snapshot.registers.cr0, snapshot.registers.efer, first.registers.cr0, first.registers.efer
+            );
+            return Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER));
+        }
+        if snapshot.harness.input_gva != first.harness.input_gva {
+            error!(
+                "{snapshot_path} specifies a different input data address {:#x?} than the first snapshot {:#x?}",
+                snapshot.harness.input_gva, first.harness.input_gva
+            );
+            return Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER));
+        }
+        Ok(())
+    }
+
+    // Checks that the entry point of `snapshot`, if overridden, is captured in
+    // it. UEFI runs with identity mapping, so RIP and RSP are also GPAs. For
+    // RSP, the page of the first byte the guest pushes is checked.
+    fn validate_entry_point(snapshot_path: &str, snapshot: &Snapshot) -> Result<(), uefi::Error> {
+        if snapshot.harness.entry_rip.is_none() && snapshot.harness.entry_rsp.is_none() {
+            return Ok(());
+        }
+        let rip = snapshot.entry_rip();
+        let stack_pointer = snapshot.entry_rsp();
+        for (name, address) in [("RIP", rip), ("RSP", stack_pointer.wrapping_sub(1))] {
+            if !snapshot.contains(address as usize >> BASE_PAGE_SHIFT) {
+                error!("Entry {name} {address:#x} is outside {snapshot_path:?}");
+                return Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER));
+            }
+        }
+        info!("Entry point: RIP {rip:#x}, RSP {stack_pointer:#x}");
+        Ok(())
+    }
+
+    // Checks that the mutation target range given through `config`, if any, is
+    // within all `snapshots`.
+    fn validate_mutation_target(
+        config: &Config,
+        snapshots: &[Snapshot],
+    ) -> Result<(), uefi::Error> {
+        if config.mutation_target_size != 0 {
+            let start = config.mutation_target_gpa;
+            let end = start.saturating_add(config.mutation_target_size);
+            let pfns =
+                (start as usize >> BASE_PAGE_SHIFT)..=((end - 1) as usize >> BASE_PAGE_SHIFT);
+            if !pfns
+                .into_iter()
+                .all(|pfn| snapshots.iter().all(|snapshot| snapshot.contains(pfn)))
+            {
+                error!("Mutation target {start:#x} - {end:#x} is outside the snapshot");
+                return Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER));
+            }
+            info!("Mutation target: GPA {start:#x} - {end:#x}");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn snapshot(&self, index: usize) -> RwLockReadGuard<'_, Snapshot> {
+        self.snapshots[index].read()
+    }
+
+    pub(crate) fn snapshot_mut(&self, index: usize) -> RwLockWriteGuard<'_, Snapshot> {
+        self.snapshots[index].write()
+    }
+
+    pub(crate) fn snapshot_count(&self) -> usize {
+        self.snapshots.len()
     }
 
-    pub(crate) fn snapshot_mut(&self) -> RwLockWriteGuard<'_, Snapshot> {
-        self.snapshot.write()
+    /// Returns the index of the snapshot to run in the iteration `iter_count`
+    /// of a processor. The snapshots are switched round-robin every
+    /// [`SNAPSHOT_SWITCH_INTERVAL`] iterations of each processor, so that
+    /// processors do not switch all at once.
+    pub(crate) fn snapshot_index_for(&self, iter_count: u64) -> usize {
+        (iter_count / SNAPSHOT_SWITCH_INTERVAL) as usize % self.snapshots.len()
+    }
+
+    /// Returns the number of pages read from all snapshot files so far.
+    pub(crate) fn resolved_page_count(&self) -> u64 {
+        self.snapshots
+            .iter()
+            .map(|snapshot| snapshot.read().resolved_page_count())
+            .sum()
     }
 
     pub(crate) fn corpus(&self) -> &Corpus {
@@ -81,6 +357,22 @@ impl GlobalState {
         self.overall_stats.read().clone()
     }
 
+    /// Returns addresses of all basic blocks executed so far, relative to the
+    /// base of the target module, in the ascending order without duplicates.
+    pub(crate) fn export_coverage_blob(&self) -> Vec<u64> {
+        let module_base = self.patch_set.module().1.start;
+        let mut blocks: Vec<u64> = self
+            .overall_stats
+            .read()
+            .newly_executed_basic_blks
+            .iter()
+            .map(|block| block.wrapping_sub(module_base))
+            .collect();
+        blocks.sort_unstable();
+        blocks.dedup();
+        blocks
+    }
+
     pub(crate) fn patch_set(&self) -> &PatchSet {
         &self.patch_set
     }
@@ -97,16 +389,601 @@ impl GlobalState {
         self.start_time
     }
 
-    /// Updates the overall statistics with the new statistics `stats`.
-    pub(crate) fn update_stats(&self, stats: &RunStats) -> u64 {
+    pub(crate) fn replay_file_name(&self) -> Option<&str> {
+        self.replay_file_name.as_deref()
+    }
+
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub(crate) fn volume(&self) -> MutexGuard<'_, Directory> {
+        self.volume.lock()
+    }
+
+    pub(crate) fn snapshot_path(&self, index: usize) -> &str {
+        &self.snapshot_paths[index]
+    }
+
+    pub(crate) fn patch_path(&self) -> &str {
+        &self.patch_path
+    }
+
+    pub(crate) fn comparands(&self) -> RwLockReadGuard<'_, Vec<u64>> {
+        self.comparands.read()
+    }
+
+    /// Records `value` as an operand of a comparison instruction, unless it is
+    /// already recorded, fits in a byte, or [`MAX_COMPARAND_COUNT`] values are
+    /// already recorded. Values that fit in a byte are easily found by random
+    /// byte modification.
+    pub(crate) fn record_comparand(&self, value: u64) {
+        if value <= 0xff || self.comparands.read().contains(&value) {
+            return;
+        }
+        let mut comparands = self.comparands.write();
+        if comparands.len() < MAX_COMPARAND_COUNT && !comparands.contains(&value) {
+            comparands.push(value);
+        }
+    }
+
+    /// Records that the basic block at `address` marked with
+    /// [`PatchKind::Profile`] is executed.
+    pub(crate) fn record_profile_hit(&self, address: u64) {
+        if let Some(count) = self.profile_hit_counts.get(&address) {
+            let _ = count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records the crash `crash_id`, and returns whether it has never been
+    /// recorded before.
+    pub(crate) fn record_crash(&self, crash_id: &str) -> bool {
+        if self.crash_ids.read().contains(crash_id) {
+            return false;
+        }
+        self.crash_ids.write().insert(crash_id.to_string())
+    }
+
+    /// Returns whether fuzzing should stop because [`Config::max_iterations`]
+    /// or [`Config::max_seconds`] is reached. Once it is, this returns true on
+    /// all processors.
+    ///
+    /// The elapsed time is checked only by the processor `core` 0 once in a
+    /// while, as getting the current time is slow.
+    pub(crate) fn budget_reached(&self, core: usize) -> bool {
+        const TIME_CHECK_INTERVAL: u64 = 100;
+
+        if self.budget_reached.load(Ordering::SeqCst) {
+            return true;
+        }
+        let max_iterations = self.config.max_iterations;
+        let max_seconds = self.config.max_seconds;
+        let reached = (max_iterations != 0 && self.iter_count() >= max_iterations)
+            || (max_seconds != 0
+                && core == 0
+                && self.cores[core].iteration_count.load(Ordering::SeqCst) % TIME_CHECK_INTERVAL
+                    == 0
+                && time_to_u64(time()).saturating_sub(self.start_time) >= max_seconds);
+        if reached {
+            self.budget_reached.store(true, Ordering::SeqCst);
+        }
+        reached
+    }
+
+    /// Requests all processors to stop because of a crash found with
+    /// [`Config::stop_on_crash`], and returns whether this is the first
+    /// request.
+    pub(crate) fn stop_on_crash(&self) -> bool {
+        !self.stopped_on_crash.swap(true, Ordering::SeqCst)
+    }
+
+    /// Returns whether [`GlobalState::stop_on_crash`] has been requested.
+    pub(crate) fn stopped_on_crash(&self) -> bool {
+        self.stopped_on_crash.load(Ordering::SeqCst)
+    }
+
+    /// Reports the summary of the whole fuzzing session when fuzzing is
+    /// complete. Only the first processor calling this reports it.
+    pub(crate) fn report_final(&self) {
+        let () = self.final_report.call_once(|| {
+            let stats = self.clone_stats();
+            let elapsed_seconds = time_to_u64(time()).saturating_sub(self.start_time);
+            if self.stopped_on_crash() {
+                info!("Fuzzing stopped on the first crash");
+            } else if self.budget_reached.load(Ordering::SeqCst) {
+                info!("Fuzzing stopped as the iteration or time limit is reached");
+            } else {
+                info!("Fuzzing completed as no more input file is available");
+            }
+            info!("Total iterations          : {}", self.iter_count());
+            info!("Unique crashes            : {}", self.crash_ids.read().len());
+            info!("Total executed basic block: {}", stats.newly_executed_basic_blks.len());
+            info!("Total executed edges      : {}", stats.newly_executed_edge_count);
+            info!("Total hang count          : {}", stats.hang_count);
+            info!("Total slow count          : {}", stats.slow_count);
+            info!("Elapsed time              : {elapsed_seconds} seconds");
+            info!("Elapsed TSC               : {}", rdtsc() - stats.start_tsc);
+            self.report_hottest_blocks();
+            if EXPORT_COVERAGE_BLOB {
+                RunStats::coverage_blob(self);
+            }
+            if PERSIST_COVERAGE {
+                self.save_coverage();
+            }
+        });
+    }
+
+    // Saves the accumulated coverage into `COVERAGE_FILE_NAME` in the corpus
+    // directory, so that the next session can resume from it.
+    fn save_coverage(&self) {
+        let mut basic_blks = self.clone_stats().newly_executed_basic_blks;
+        basic_blks.sort_unstable();
+        basic_blks.dedup();
+        let coverage = SavedCoverage {
+            identity: self.coverage_identity,
+            basic_blks,
+            hit_count_buckets: self.hit_count_buckets.read().clone(),
+            bitmap: self
+                .coverage_bitmap
+                .iter()
+                .map(|bits| bits.load(Ordering::SeqCst))
+                .collect(),
+        };
+        let result = open_dir(&mut self.volume(), self.corpus.path()).and_then(|mut corpus_dir| {
+            write_file(&mut corpus_dir, COVERAGE_FILE_NAME, &coverage.to_bytes())
+        });
+        if let Err(err) = result {
+            warn!("Failed to save {COVERAGE_FILE_NAME}: {err:#?}");
+        } else {
+            info!("Saved {} basic blocks into {COVERAGE_FILE_NAME}", coverage.basic_blks.len());
+        }
+    }
+
+    // Reports up to `PROFILE_REPORT_COUNT` basic blocks marked with
+    // `PatchKind::Profile` in the descending order of their hit counts.
+    fn report_hottest_blocks(&self) {
+        if self.profile_hit_counts.is_empty() {
+            return;
+        }
+        let mut hit_counts: Vec<(u64, u64)> = self
+            .profile_hit_counts
+            .iter()
+            .map(|(&address, count)| (address, count.load(Ordering::Relaxed)))
+            .collect();
+        hit_counts.sort_by(|a, b| b.1.cmp(&a.1));
+        info!("Hottest blocks:");
+        for (address, count) in hit_counts.into_iter().take(PROFILE_REPORT_COUNT) {
+            info!("  {address:#x}: {count}");
+        }
+    }
+
+    // Returns the APIC IDs of the processors that enter `start_hypervisor` in
+    // the ascending order if `ORDERED_STARTUP_LOG` is enabled and all processors
+    // are started, that is, unless replaying or with `dry_run`. Otherwise, an
+    // empty list.
+    //
+    // Those are the enabled APs, which `startup_all_aps` dispatches to it, and
+    // the current processor, which enters it after dispatching them. Disabled
+    // processors never log, and must not be waited for.
+    fn startup_order(
+        mp: &MpServices,
+        replay_file_name: Option<&str>,
+    ) -> Result<Vec<u32>, uefi::Error> {
+        if !ORDERED_STARTUP_LOG || replay_file_name.is_some() || cfg!(feature = "dry_run") {
+            return Ok(Vec::new());
+        }
+        let current = mp.who_am_i()?;
+        let mut apic_ids = Vec::new();
+        for number in 0..mp.get_number_of_processors()?.total {
+            let info = mp.get_processor_info(number)?;
+            let dispatched = !info.is_bsp() && info.is_enabled();
+            if number == current || dispatched {
+                apic_ids.push(info.processor_id as u32);
+            }
+        }
+        apic_ids.sort_unstable();
+        Ok(apic_ids)
+    }
+
+    /// Calls `log` after the processors with lower APIC IDs did so, if
+    /// [`ORDERED_STARTUP_LOG`] is enabled. Otherwise, calls `log` immediately.
+    ///
+    /// To avoid deadlock, a processor waits for its turn only up to
+    /// [`ORDERED_STARTUP_TIMEOUT_IN_TSC`], then calls `log` anyway and lets
+    /// later ones go.
+    pub(crate) fn log_in_startup_order(&self, log: impl FnOnce()) {
+        let Some(turn) = self.startup_order.iter().position(|&id| id == apic_id()) else {
+            log();
+            return;
+        };
+        let start_tsc = rdtsc();
+        while self.startup_turn.load(Ordering::SeqCst) < turn
+            && rdtsc() - start_tsc < ORDERED_STARTUP_TIMEOUT_IN_TSC
+        {
+            spin_loop();
+        }
+        log();
+        let _ = self.startup_turn.fetch_max(turn + 1, Ordering::SeqCst);
+    }
+
+    /// Assigns the current processor a slot in [`GlobalState::cores`], and
+    /// returns its index. Must be called exactly once per processor.
+    pub(crate) fn register_core(&self) -> usize {
+        let index = self.registered_core_count.fetch_add(1, Ordering::SeqCst);
+        assert!(index < self.cores.len(), "Too many processors");
+        self.cores[index].apic_id.store(apic_id(), Ordering::SeqCst);
+        index
+    }
+
+    /// Returns the statistics of each registered processor.
+    pub(crate) fn core_stats(&self) -> Vec<CoreStats> {
+        let count = self.registered_core_count.load(Ordering::SeqCst);
+        self.cores[..count.min(self.cores.len())]
+            .iter()
+            .map(|state| CoreStats {
+                apic_id: state.apic_id.load(Ordering::SeqCst),
+                iteration_count: state.iteration_count.load(Ordering::SeqCst),
+                hang_count: state.hang_count.load(Ordering::SeqCst),
+                slow_count: state.slow_count.load(Ordering::SeqCst),
+            })
+            .collect()
+    }
+
+    /// Records that the processor `core` started an iteration with `input`.
+    pub(crate) fn start_iteration(&self, core: usize, input: &MutatingInput) {
+        let state = &self.cores[core];
+        if state.input_name.read().as_str() != input.name() {
+            *state.input_name.write() = input.name().to_string();
+        }
+        state
+            .mutation_count
+            .store(input.mutation_count(), Ordering::SeqCst);
+        state.reported.store(false, Ordering::SeqCst);
+        state.iteration_start_tsc.store(rdtsc(), Ordering::SeqCst);
+    }
+
+    /// Records that the processor `core` completed the iteration.
+    pub(crate) fn end_iteration(&self, core: usize) {
+        self.cores[core]
+            .iteration_start_tsc
+            .store(0, Ordering::SeqCst);
+    }
+
+    /// Reports processors that have been running a single iteration for longer
+    /// than [`WATCHDOG_THRESHOLD_MULTIPLIER`] times the guest execution
+    /// timeout. Each stuck iteration is reported once.
+    ///
+    /// Any processor may call this. The check is run at most once per the
+    /// guest execution timeout across all processors, so that a stuck
+    /// processor is still detected when any other processor makes progress.
+    pub(crate) fn check_stuck_cores(&self) {
+        let now = rdtsc();
+        let last_tsc = self.watchdog_tsc.load(Ordering::SeqCst);
+        if now.saturating_sub(last_tsc) < self.config.guest_exec_timeout_in_tsc
+            || self
+                .watchdog_tsc
+                .compare_exchange(last_tsc, now, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+        {
+            return;
+        }
+
+        let threshold = self.config.guest_exec_timeout_in_tsc * WATCHDOG_THRESHOLD_MULTIPLIER;
+        for (index, state) in self.cores.iter().enumerate() {
+            let start_tsc = state.iteration_start_tsc.load(Ordering::SeqCst);
+            if start_tsc == 0
+                || now.saturating_sub(start_tsc) < threshold
+                || state.reported.swap(true, Ordering::SeqCst)
+            {
+                continue;
+            }
+            warn!(
+                "STUCK CORE : #{index} has been running {:?} #{} for {} TSC",
+                state.input_name.read().as_str(),
+                state.mutation_count.load(Ordering::SeqCst),
+                now - start_tsc,
+            );
+        }
+    }
+
+    /// Logs a tuning hint once if reverting dirty pages takes more than
+    /// [`REVERT_COST_WARNING_PERCENT`] of the time spent in the host.
+    pub(crate) fn check_revert_cost(&self) {
+        let (revert_tsc, host_spent_tsc) = {
+            let stats = self.overall_stats.read();
+            (stats.revert_tsc, stats.host_spent_tsc)
+        };
+        if host_spent_tsc == 0
+            || revert_tsc * 100 / host_spent_tsc < REVERT_COST_WARNING_PERCENT
+            || self.revert_cost_reported.swap(true, Ordering::SeqCst)
+        {
+            return;
+        }
+        warn!(
+            "Reverting dirty pages takes {}% of the time spent in the host. The target writes to many pages on each iteration",
+            revert_tsc * 100 / host_spent_tsc
+        );
+        warn!("Consider a snapshot taken closer to the code to fuzz, or patching out code that writes to memory but is irrelevant to fuzzing");
+    }
+
+    /// Marks the edge `edge_id` as executed, and returns whether it has never
+    /// been executed before by any VM.
+    ///
+    /// `edge_id` is computed as `(previous basic block >> 1) ^ current basic
+    /// block`, and wraps around the size of the bitmap. This means that
+    /// different edges may collide and be considered as the same.
+    pub(crate) fn record_edge(&self, edge_id: u64) -> bool {
+        let index = edge_id as usize % COVERAGE_BITMAP_SIZE;
+        let mask = 1u64 << (index % 64);
+        let old_bits = self.coverage_bitmap[index / 64].fetch_or(mask, Ordering::SeqCst);
+        (old_bits & mask) == 0
+    }
+
+    /// Merges the hit counts of basic blocks in a single iteration
+    /// `hit_counts`, and returns how many basic blocks reached a hit count
+    /// bucket that has never been reached by any VM.
+    pub(crate) fn record_hit_counts(&self, hit_counts: &BTreeMap<u64, u64>) -> u64 {
+        let mut buckets = self.hit_count_buckets.write();
+        hit_counts
+            .iter()
+            .filter(|(&basic_blk, &count)| {
+                let bucket = hit_count_bucket(count);
+                let reached = buckets.entry(basic_blk).or_insert(0);
+                let is_new = (*reached & bucket) == 0;
+                *reached |= bucket;
+                is_new
+            })
+            .count() as u64
+    }
+
+    /// Checks whether the basic block `basic_blk` has been executed in any
+    /// previous iteration by any VM, according to the hit counts.
+    pub(crate) fn is_basic_blk_executed(&self, basic_blk: u64) -> bool {
+        self.hit_count_buckets.read().contains_key(&basic_blk)
+    }
+
+    /// Checks whether the basic block `basic_blk` was executed in the previous
+    /// sessions. See [`PERSIST_COVERAGE`].
+    pub(crate) fn is_basic_blk_known(&self, basic_blk: u64) -> bool {
+        self.known_basic_blks.contains(&basic_blk)
+    }
+
+    /// Returns the number of basic blocks executed for the first time since the
+    /// last periodic report. If `reset` is true, this is a periodic report, and
+    /// the next call counts from now.
+    pub(crate) fn basic_blk_delta(&self, reset: bool) -> usize {
+        let count = self.overall_stats.read().newly_executed_basic_blks.len();
+        let reported = if reset {
+            self.reported_basic_blk_count.swap(count, Ordering::SeqCst)
+        } else {
+            self.reported_basic_blk_count.load(Ordering::SeqCst)
+        };
+        count.saturating_sub(reported)
+    }
+
+    /// Updates the overall statistics and those of the processor `core` with
+    /// the new statistics `stats`.
+    pub(crate) fn update_stats(&self, core: usize, stats: &RunStats) -> u64 {
+        let core_state = &self.cores[core];
+        let _ = core_state.iteration_count.fetch_add(1, Ordering::SeqCst);
+        let _ = core_state
+            .hang_count
+            .fetch_add(stats.hang_count, Ordering::SeqCst);
+        let _ = core_state
+            .slow_count
+            .fetch_add(stats.slow_count, Ordering::SeqCst);
+
         let mut total_stats = self.overall_stats.write();
         total_stats.total_tsc += stats.total_tsc;
         total_stats.host_spent_tsc += stats.host_spent_tsc;
+        total_stats.revert_tsc += stats.revert_tsc;
+        for (total, count) in total_stats
+            .exit_latency_histogram
+            .iter_mut()
+            .zip(stats.exit_latency_histogram)
+        {
+            *total += count;
+        }
         total_stats.vmexit_count += stats.vmexit_count;
+        for (total, count) in total_stats
+            .vmexit_counts
+            .iter_mut()
+            .zip(stats.vmexit_counts)
+        {
+            *total += count;
+        }
         total_stats
             .newly_executed_basic_blks
             .extend(&stats.newly_executed_basic_blks);
+        total_stats.newly_executed_edge_count += stats.newly_executed_edge_count;
+        total_stats.newly_reached_bucket_count += stats.newly_reached_bucket_count;
         total_stats.hang_count += stats.hang_count;
+        total_stats.slow_count += stats.slow_count;
+        total_stats.max_guest_tsc = total_stats.max_guest_tsc.max(stats.max_guest_tsc);
         self.iteration_count.fetch_add(1, Ordering::SeqCst) + 1
     }
 }
+
+/// The state of a single processor running the hypervisor, observed by other
+/// processors to detect a stuck processor and report per-processor statistics.
+#[derive(Default)]
+struct CoreState {
+    /// The APIC ID of the processor.
+    apic_id: AtomicU32,
+    /// The number of iterations completed by the processor.
+    iteration_count: AtomicU64,
+    /// The number of iterations that ended with hang on the processor.
+    hang_count: AtomicU64,
+    /// The number of iterations that ended without hang but close to it on the
+    /// processor.
+    slow_count: AtomicU64,
+    /// The TSC when the current iteration started, or zero if the processor is
+    /// not running an iteration.
+    iteration_start_tsc: AtomicU64,
+    /// The name of the input file used in the current iteration.
+    input_name: RwLock<String>,
+    /// The mutation count of the input file in the current iteration.
+    mutation_count: AtomicU64,
+    /// Whether the current iteration has been reported as stuck.
+    reported: AtomicBool,
+}
+
+/// The coverage accumulated by sessions, saved into [`COVERAGE_FILE_NAME`] in
+/// the corpus directory. See [`PERSIST_COVERAGE`].
+///
+/// The file is the array of little-endian 64-bit values as below:
+/// ```text
+/// COVERAGE_FILE_MAGIC
+/// COVERAGE_BITMAP_SIZE
+/// <the identity of the snapshot and patch files>
+/// <the number of basic blocks> <address>...
+/// <the number of basic blocks with hit count buckets> (<address> <buckets>)...
+/// <the edge coverage bitmap>
+/// ```
+struct SavedCoverage {
+    identity: u64,
+    basic_blks: Vec<u64>,
+    hit_count_buckets: BTreeMap<u64, u8>,
+    bitmap: Vec<u64>,
+}
+
+/// The value at the start of [`COVERAGE_FILE_NAME`], "HVCOV002".
+const COVERAGE_FILE_MAGIC: u64 = u64::from_le_bytes(*b"HVCOV002");
+
+impl Default for SavedCoverage {
+    fn default() -> Self {
+        Self {
+            identity: 0,
+            basic_blks: Vec::new(),
+            hit_count_buckets: BTreeMap::new(),
+            bitmap: alloc::vec![0; COVERAGE_BITMAP_SIZE / 64],
+        }
+    }
+}
+
+impl SavedCoverage {
+    // Loads the coverage from `COVERAGE_FILE_NAME` in the corpus directory.
+    // Returns the empty coverage if the file does not exist, is invalid, or was
+    // saved with snapshot or patch files other than ones of `identity`.
+    fn load(dir: &mut Directory, corpus_path: &str, identity: u64) -> Self {
+        let result = open_dir(dir, corpus_path).and_then(|mut corpus_dir| {
+            // Safety: Code is single threaded.
+            unsafe { read_file_if_exists(&mut corpus_dir, COVERAGE_FILE_NAME) }
+        });
+        let contents = match result {
+            Ok(Some(contents)) => contents,
+            Ok(None) => return Self::default(),
+            Err(err) => {
+                warn!("Ignoring {COVERAGE_FILE_NAME}: {err:#?}");
+                return Self::default();
+            }
+        };
+        let Some(coverage) = Self::from_bytes(&contents) else {
+            warn!("Ignoring a corrupted or incompatible {COVERAGE_FILE_NAME}");
+            return Self::default();
+        };
+        if coverage.identity != identity {
+            warn!("Ignoring {COVERAGE_FILE_NAME} saved with different snapshot or patch files");
+            return Self::default();
+        }
+        info!("Resuming from {COVERAGE_FILE_NAME}: {} basic blocks", coverage.basic_blks.len());
+        coverage
+    }
+
+    // Returns the overall statistics to start with, which counts the saved
+    // basic blocks and edges as executed.
+    fn run_stats(&self) -> RunStats {
+        let mut stats = RunStats::new();
+        stats.newly_executed_basic_blks.clone_from(&self.basic_blks);
+        stats.newly_executed_edge_count = self
+            .bitmap
+            .iter()
+            .map(|bits| u64::from(bits.count_ones()))
+            .sum();
+        stats
+    }
+
+    // Parses the contents of `COVERAGE_FILE_NAME`.
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() % 8 != 0 {
+            return None;
+        }
+        let mut values = bytes
+            .chunks_exact(8)
+            .map(|value| u64::from_le_bytes(value.try_into().unwrap()));
+        if values.next()? != COVERAGE_FILE_MAGIC || values.next()? != COVERAGE_BITMAP_SIZE as u64 {
+            return None;
+        }
+        let identity = values.next()?;
+        let count = values.next()?;
+        let mut basic_blks = Vec::new();
+        for _ in 0..count {
+            basic_blks.push(values.next()?);
+        }
+        let count = values.next()?;
+        let mut hit_count_buckets = BTreeMap::new();
+        for _ in 0..count {
+            let basic_blk = values.next()?;
+            let _ = hit_count_buckets.insert(basic_blk, values.next()? as u8);
+        }
+        let bitmap: Vec<u64> = values.collect();
+        if bitmap.len() != COVERAGE_BITMAP_SIZE / 64 {
+            return None;
+        }
+        Some(Self {
+            identity,
+            basic_blks,
+            hit_count_buckets,
+            bitmap,
+        })
+    }
+
+    // Serializes the coverage into the contents of `COVERAGE_FILE_NAME`.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut values = alloc::vec![
+            COVERAGE_FILE_MAGIC,
+            COVERAGE_BITMAP_SIZE as u64,
+            self.identity,
+            self.basic_blks.len() as u64
+        ];
+        values.extend(&self.basic_blks);
+        values.push(self.hit_count_buckets.len() as u64);
+        for (&basic_blk, &buckets) in &self.hit_count_buckets {
+            values.push(basic_blk);
+            values.push(u64::from(buckets));
+        }
+        values.extend(&self.bitmap);
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+}
+
+/// Returns the identity of the snapshot and patch files, that is, the CRC-32 of
+/// the snapshot file names and sizes, and the CRC-32 of the patch file. Saved
+/// coverage is only valid for the same identity, as addresses of basic blocks
+/// and edge IDs are meaningless with other files.
+fn coverage_identity(snapshot_paths: &[&str], snapshots: &[Snapshot], patch_set: &PatchSet) -> u64 {
+    let mut bytes = Vec::new();
+    for (path, snapshot) in snapshot_paths.iter().zip(snapshots) {
+        bytes.extend(path.as_bytes());
+        bytes.extend(snapshot.memory.len().to_le_bytes());
+    }
+    (u64::from(crc32(&bytes)) << 32) | u64::from(patch_set.checksum())
+}
+
+/// Returns the AFL-style bucket of the hit count `count` as a bit.
+fn hit_count_bucket(count: u64) -> u8 {
+    match count {
+        0 => 0,
+        1 => 1 << 0,
+        2 => 1 << 1,
+        3 => 1 << 2,
+        4..=7 => 1 << 3,
+        8..=15 => 1 << 4,
+        16..=31 => 1 << 5,
+        32..=127 => 1 << 6,
+        _ => 1 << 7,
+    }
+}
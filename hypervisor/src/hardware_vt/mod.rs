@@ -4,7 +4,10 @@
 pub(crate) mod svm;
 pub(crate) mod vmx;
 
-use crate::snapshot::Snapshot;
+use crate::{
+    snapshot::{Harness, Snapshot, SnapshotRegisters},
+    x86_instructions::{xcr0, xcr0_write},
+};
 use bitfield::bitfield;
 use core::fmt;
 use x86::{
@@ -20,14 +23,24 @@ pub(crate) trait HardwareVt: fmt::Debug {
     fn enable(&mut self);
 
     /// Configures HW VT such as enabling nested paging and exception
-    /// interception.
-    fn initialize(&mut self, nested_pml4_addr: u64);
+    /// interception. `registers` decide the mode the guest runs in, such as
+    /// long mode or real mode. Fails if the processor cannot run the guest in
+    /// that mode.
+    fn initialize(
+        &mut self,
+        nested_pml4_addr: u64,
+        registers: &SnapshotRegisters,
+    ) -> Result<(), UnsupportedGuestMode>;
 
-    /// Configures the guest states based on the snapshot.
-    fn revert_registers(&mut self, snapshot: &Snapshot);
+    /// Configures the guest states based on the snapshot, starting at its entry
+    /// point (see [`Snapshot::entry_rip`]). `exec_timeout_in_tsc` is how long
+    /// the guest may run in this iteration, if the implementation has a
+    /// timer for it.
+    fn revert_registers(&mut self, snapshot: &Snapshot, exec_timeout_in_tsc: u64);
 
-    /// Updates the guest states to make the guest use input data.
-    fn adjust_registers(&mut self, input_addr: u64, input_size: u64);
+    /// Updates the guest states to make the guest use input data, according
+    /// to `harness`.
+    fn adjust_registers(&mut self, input_addr: u64, input_size: u64, harness: &Harness);
 
     /// Executes the guest until it triggers VM exit.
     fn run(&mut self) -> VmExitReason;
@@ -41,6 +54,58 @@ pub(crate) trait HardwareVt: fmt::Debug {
         &self,
         entry_type: NestedPagingStructureEntryType,
     ) -> NestedPagingStructureEntryFlags;
+
+    /// Checks whether nested paging structures can map 2MB pages.
+    fn large_page_supported(&self) -> bool;
+
+    /// Checks whether nested paging structures can map pages as execute-only,
+    /// that is, [`NestedPagingStructureEntryType::XOnly`] is honored.
+    fn execute_only_supported(&self) -> bool;
+
+    /// Moves the guest RIP forward by `length` bytes to skip the instruction
+    /// that caused VM exit.
+    fn advance_rip(&mut self, length: u64);
+
+    /// Sets the guest XCR0. The change takes effect on the next
+    /// [`HardwareVt::run`]. `xcr0` must be valid (see [`is_valid_xcr0`]).
+    fn set_xcr0(&mut self, xcr0: u64);
+
+    /// Returns the guest control register `cr`, which is either 0 or 4, as the
+    /// guest observes it.
+    fn guest_cr(&self, cr: u8) -> u64;
+
+    /// Sets the guest control register `cr`, which is either 0 or 4. The
+    /// change takes effect on the next [`HardwareVt::run`].
+    fn set_guest_cr(&mut self, cr: u8, value: u64);
+
+    /// Injects the exception `vector` with `error_code` into the guest, so that
+    /// the guest handles it through its IDT on the next [`HardwareVt::run`].
+    /// The injection is cleared when the next VM exit occurs.
+    fn inject_event(&mut self, vector: u8, error_code: Option<u32>);
+
+    /// Returns the base address of the guest CS segment, which RIP is relative
+    /// to, eg, CS * 16 in real mode.
+    fn cs_base(&self) -> u64;
+
+    /// Gets the guest general purpose registers. Changes made through the
+    /// returned reference take effect on the next [`HardwareVt::run`], except
+    /// RIP, RSP and RFLAGS.
+    fn registers_mut(&mut self) -> &mut GuestRegisters;
+
+    /// Makes the guest execute only the next instruction on the next
+    /// [`HardwareVt::run`] by setting RFLAGS.TF, so that the caller can act
+    /// after the instruction is executed.
+    ///
+    /// Completion of the instruction is reported as [`VmExitReason::Exception`]
+    /// with [`GuestException::Debug`], and RFLAGS.TF is restored to the guest's
+    /// value at that point. If the instruction causes other VM exit first,
+    /// single-stepping remains in effect until it completes.
+    fn step_over(&mut self);
+
+    /// Logs the HW VT technology and its capabilities the hypervisor relies on,
+    /// such as the availability of a timer to abort the guest and nested
+    /// paging features. Useful for bug reports.
+    fn report_capabilities(&self);
 }
 
 /// Reasons of VM exit.
@@ -60,13 +125,139 @@ pub(crate) enum VmExitReason {
     /// The guest ran long enough to use up its time slice.
     TimerExpiration,
 
+    /// The guest executed `VMCALL` or `VMMCALL`. Contains the hypercall number
+    /// (RAX) and the argument (RCX). RIP still points to the instruction.
+    Hypercall { nr: u64, arg: u64 },
+
+    /// The guest executed `CPUID`. Contains the leaf (EAX) and the subleaf
+    /// (ECX). RIP still points to the instruction.
+    Cpuid { leaf: u32, subleaf: u32 },
+
+    /// The guest executed `RDMSR` for one of the MSRs in
+    /// [`crate::config::INTERCEPTED_MSRS`] or not covered by MSR bitmaps, or
+    /// `WRMSR` for any MSR. Contains the MSR (ECX), whether it is write access,
+    /// and the value to write (EDX:EAX) if so. RIP still points to the
+    /// instruction.
+    MsrAccess { msr: u32, write: bool, value: u64 },
+
+    /// The guest executed `RDTSC` or `RDTSCP`. Contains whether it is `RDTSCP`.
+    /// RIP still points to the instruction.
+    Rdtsc { rdtscp: bool },
+
+    /// The guest executed `XSETBV`. Contains the extended control register
+    /// number (ECX) and the value to write (EDX:EAX). RIP still points to the
+    /// instruction.
+    Xsetbv { xcr: u32, value: u64 },
+
+    /// The guest executed `IN` or `OUT` for one of the I/O ports in
+    /// [`crate::config::INTERCEPTED_IO_PORTS`]. Contains the port (DX or the
+    /// immediate), whether it is write access, the access size in bytes (1, 2
+    /// or 4), the value to write (EAX) if so, and the length of the
+    /// instruction. RIP still points to the instruction. String I/O
+    /// instructions are reported as [`VmExitReason::Unexpected`].
+    IoAccess {
+        port: u16,
+        write: bool,
+        size: u8,
+        value: u32,
+        length: u64,
+    },
+
+    /// The guest executed `INVLPG`. Contains the length of the instruction.
+    /// RIP still points to the instruction. Only on Intel, and only when the
+    /// processor does not allow `INVLPG` to be executed without VM exit.
+    Invlpg { length: u64 },
+
+    /// The guest executed `WBINVD`. RIP still points to the instruction.
+    Wbinvd,
+
+    /// The guest executed `MONITOR` or `MWAIT`. RIP still points to the
+    /// instruction.
+    MonitorOrMwait,
+
+    /// The guest executed `MOV` to CR0 or CR4. Contains the control register
+    /// number, its current value, the value to write, and the length of the
+    /// instruction. RIP still points to the instruction. On Intel, this occurs
+    /// only when any of [`crate::config::INTERCEPTED_CR0_BITS`] or
+    /// [`crate::config::INTERCEPTED_CR4_BITS`], or the VMX-fixed bits, is
+    /// modified. On AMD, this occurs
+    /// on any write when the processor supports decode assists.
+    CrAccess {
+        cr: u8,
+        current: u64,
+        value: u64,
+        length: u64,
+    },
+
     /// The logical processor entered the shutdown state, eg, triple fault.
-    Shutdown(u64),
+    Shutdown(ShutdownQualification),
+
+    /// The guest executed an instruction that is intercepted but has no
+    /// dedicated variant, such as `HLT`. Contains a vendor specific VM exit
+    /// code. RIP still points to the instruction, which is decoded and
+    /// emulated with [`crate::emulator`].
+    Instruction(u64),
 
     /// An unhandled VM exit happened. Contains a vendor specific VM exit code.
     Unexpected(u64),
 }
 
+impl VmExitReason {
+    /// The number of the variants.
+    pub(crate) const COUNT: usize = 17;
+
+    /// The names of the variants, in the order of [`VmExitReason::index`].
+    pub(crate) const NAMES: [&'static str; Self::COUNT] = [
+        "NestedPageFault",
+        "Exception",
+        "ExternalInterruptOrPause",
+        "TimerExpiration",
+        "Hypercall",
+        "Cpuid",
+        "MsrAccess",
+        "Rdtsc",
+        "Xsetbv",
+        "IoAccess",
+        "Invlpg",
+        "Wbinvd",
+        "MonitorOrMwait",
+        "CrAccess",
+        "Shutdown",
+        "Instruction",
+        "Unexpected",
+    ];
+
+    /// Returns the index of the variant, which is less than
+    /// [`VmExitReason::COUNT`].
+    pub(crate) fn index(&self) -> usize {
+        match self {
+            Self::NestedPageFault(_) => 0,
+            Self::Exception(_) => 1,
+            Self::ExternalInterruptOrPause => 2,
+            Self::TimerExpiration => 3,
+            Self::Hypercall { .. } => 4,
+            Self::Cpuid { .. } => 5,
+            Self::MsrAccess { .. } => 6,
+            Self::Rdtsc { .. } => 7,
+            Self::Xsetbv { .. } => 8,
+            Self::IoAccess { .. } => 9,
+            Self::Invlpg { .. } => 10,
+            Self::Wbinvd => 11,
+            Self::MonitorOrMwait => 12,
+            Self::CrAccess { .. } => 13,
+            Self::Shutdown(_) => 14,
+            Self::Instruction(_) => 15,
+            Self::Unexpected(_) => 16,
+        }
+    }
+}
+
+/// The error indicating that the processor cannot run the guest in the mode
+/// captured in the snapshot, for example, real mode without the unrestricted
+/// guest feature of VMX.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct UnsupportedGuestMode;
+
 /// Details of the cause of nested page fault.
 #[derive(Debug)]
 pub(crate) struct NestedPageFaultQualification {
@@ -75,28 +266,88 @@ pub(crate) struct NestedPageFaultQualification {
     pub(crate) gpa: u64,
     pub(crate) missing_translation: bool,
     pub(crate) write_access: bool,
+    /// Whether the fault is due to an instruction fetch, as opposed to a data
+    /// read or write.
+    pub(crate) execute_access: bool,
+}
+
+/// Details of the cause of shutdown.
+#[derive(Debug)]
+pub(crate) struct ShutdownQualification {
+    pub(crate) rip: u64,
+    /// The vendor specific VM exit code.
+    pub(crate) exit_code: u64,
+    /// The vector of the event that was being delivered when the shutdown
+    /// happened, if any. For example, 8 (#DF) for a typical triple fault.
+    pub(crate) vector: Option<u8>,
+    /// The error code of the above event, if any.
+    pub(crate) error_code: Option<u32>,
+}
+
+impl ShutdownQualification {
+    /// Decodes the information of the event being delivered at the time of VM
+    /// exit, which has the same format on Intel (IDT-vectoring information) and
+    /// AMD (EXITINTINFO) for bits we use.
+    ///
+    /// See: (Intel) Table 25-18. Format of the IDT-Vectoring Information Field
+    /// See: (AMD) 15.7.2 Intercepts During IDT Interrupt Delivery
+    fn new(rip: u64, exit_code: u64, event_info: u64, error_code: u32) -> Self {
+        const VALID_FLAG: u64 = 1 << 31;
+        const ERROR_CODE_VALID_FLAG: u64 = 1 << 11;
+
+        let valid = (event_info & VALID_FLAG) != 0;
+        Self {
+            rip,
+            exit_code,
+            vector: valid.then_some(event_info as u8),
+            error_code: (valid && (event_info & ERROR_CODE_VALID_FLAG) != 0).then_some(error_code),
+        }
+    }
 }
 
 pub(crate) struct ExceptionQualification {
     pub(crate) rip: u64,
     pub(crate) exception_code: GuestException,
+    pub(crate) error_code: Option<u32>,
+    /// The faulting address (ie, the value CR2 is updated with) for #PF.
+    pub(crate) cr2: Option<u64>,
 }
 
 /// The cause of guest exception.
 #[derive(Clone, Copy, PartialEq)]
 pub(crate) enum GuestException {
+    DivideError,
+    Debug,
     BreakPoint,
     InvalidOpcode,
+    GeneralProtection,
     PageFault,
 }
 
+impl GuestException {
+    /// Returns the vector number of the exception.
+    pub(crate) fn vector(self) -> u8 {
+        match self {
+            GuestException::DivideError => irq::DIVIDE_ERROR_VECTOR,
+            GuestException::Debug => irq::DEBUG_VECTOR,
+            GuestException::BreakPoint => irq::BREAKPOINT_VECTOR,
+            GuestException::InvalidOpcode => irq::INVALID_OPCODE_VECTOR,
+            GuestException::GeneralProtection => irq::GENERAL_PROTECTION_FAULT_VECTOR,
+            GuestException::PageFault => irq::PAGE_FAULT_VECTOR,
+        }
+    }
+}
+
 impl TryFrom<u8> for GuestException {
     type Error = &'static str;
 
     fn try_from(vector: u8) -> Result<Self, Self::Error> {
         match vector {
+            irq::DIVIDE_ERROR_VECTOR => Ok(GuestException::DivideError),
+            irq::DEBUG_VECTOR => Ok(GuestException::Debug),
             irq::BREAKPOINT_VECTOR => Ok(GuestException::BreakPoint),
             irq::INVALID_OPCODE_VECTOR => Ok(GuestException::InvalidOpcode),
+            irq::GENERAL_PROTECTION_FAULT_VECTOR => Ok(GuestException::GeneralProtection),
             irq::PAGE_FAULT_VECTOR => Ok(GuestException::PageFault),
             _ => Err("Vector of the exception that is not intercepted"),
         }
@@ -114,6 +365,14 @@ pub(crate) enum NestedPagingStructureEntryType {
 
     /// Readable, NON writable, executable, with the write-back memory type.
     RxWriteBack,
+
+    /// Readable, writable, NON executable, with the write-back memory type.
+    RwWriteBack,
+
+    /// NON readable, NON writable, executable, with the write-back memory type.
+    /// Treated as [`NestedPagingStructureEntryType::RxWriteBack`] if
+    /// [`HardwareVt::execute_only_supported`] returns false.
+    XOnly,
 }
 
 /// The values used to initialize [`NestedPagingStructureEntry`].
@@ -121,12 +380,13 @@ pub(crate) enum NestedPagingStructureEntryType {
 pub(crate) struct NestedPagingStructureEntryFlags {
     pub(crate) permission: u8,
     pub(crate) memory_type: u8,
+    pub(crate) no_execute: bool,
 }
 
 /// The collection of the guest general purpose register values.
 #[derive(Debug, Default)]
 #[repr(C)]
-struct GuestRegisters {
+pub(crate) struct GuestRegisters {
     pub(crate) rax: u64,
     pub(crate) rbx: u64,
     pub(crate) rcx: u64,
@@ -147,6 +407,62 @@ struct GuestRegisters {
     pub(crate) rflags: u64,
 }
 
+/// The names of the general purpose registers, indexed by the numbers as
+/// encoded in instructions.
+pub(crate) const GPR_NAMES: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+
+impl GuestRegisters {
+    /// Returns the value of the general purpose register specified by `number`
+    /// as encoded in instructions, eg, 0 for RAX and 7 for RDI.
+    pub(crate) fn gpr(&self, number: usize) -> u64 {
+        match number {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            4 => self.rsp,
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            _ => self.r15,
+        }
+    }
+
+    /// Returns the general purpose register specified by `number` as
+    /// [`GuestRegisters::gpr`] does. Changing RSP (4) through this does not
+    /// take effect (see [`HardwareVt::registers_mut`]).
+    pub(crate) fn gpr_mut(&mut self, number: usize) -> &mut u64 {
+        match number {
+            0 => &mut self.rax,
+            1 => &mut self.rcx,
+            2 => &mut self.rdx,
+            3 => &mut self.rbx,
+            4 => &mut self.rsp,
+            5 => &mut self.rbp,
+            6 => &mut self.rsi,
+            7 => &mut self.rdi,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            _ => &mut self.r15,
+        }
+    }
+}
+
 /// A single nested paging structure.
 ///
 /// This is a extended page table on Intel and a nested page table on AMD. The
@@ -165,13 +481,14 @@ bitfield! {
     ///
     /// Because it is platform independent, the layout is not exactly correct.
     /// For example, bit 5:3 `memory_type` exists only on Intel. On AMD, those are
-    /// other bits and we set zeros.
+    /// other bits and we set zeros. Likewise, bit 63 `no_execute` exists only on
+    /// AMD, and we never set it on Intel.
     /*
-         66665 5     1 110000 000 000
-         32109 8.....2 109876 543 210
-        +-----+-------+------+---+---+
-        |xxxxx|  PFN  |xxxxxx| M | P |
-        +-----+-------+------+---+---+
+        6 6666 5     1 1100 0 0 000 000
+        3 2109 8.....2 1098 7 6 543 210
+        +-+----+-------+----+-+-+---+---+
+        |N|xxxx|  PFN  |xxxx|L|x| M | P |
+        +-+----+-------+----+-+-+---+---+
     */
     #[derive(Clone, Copy)]
     pub struct NestedPagingStructureEntry(u64);
@@ -179,15 +496,29 @@ bitfield! {
     permission, set_permission: 2, 0;
     memory_type, set_memory_type: 5, 3;
     flags1, _: 11, 6;
+    /// Whether the entry maps a large page instead of pointing to the next
+    /// table. Valid only for PDEs (and PDPTEs).
+    pub large_page, set_large_page: 7;
     pub pfn, set_pfn: 58, 12;
-    flags2, _: 63, 59;
+    flags2, _: 62, 59;
+    no_execute, set_no_execute: 63;
 }
 
 impl NestedPagingStructureEntry {
+    /// Returns the next nested paging structures.
+    pub(crate) fn next_table(&self) -> &NestedPagingStructure {
+        let next_table_addr = self.pfn() << BASE_PAGE_SHIFT;
+        assert!(next_table_addr != 0);
+        assert!(!self.large_page());
+        let next_table_ptr = next_table_addr as *const NestedPagingStructure;
+        unsafe { next_table_ptr.as_ref() }.unwrap()
+    }
+
     /// Returns the next nested paging structures.
     pub(crate) fn next_table_mut(&mut self) -> &mut NestedPagingStructure {
         let next_table_addr = self.pfn() << BASE_PAGE_SHIFT;
         assert!(next_table_addr != 0);
+        assert!(!self.large_page());
         let next_table_ptr = next_table_addr as *mut NestedPagingStructure;
         unsafe { next_table_ptr.as_mut() }.unwrap()
     }
@@ -198,7 +529,58 @@ impl NestedPagingStructureEntry {
         self.set_pfn(pa >> BASE_PAGE_SHIFT);
         self.set_permission(u64::from(flags.permission));
         self.set_memory_type(u64::from(flags.memory_type));
+        self.set_no_execute(flags.no_execute);
+    }
+
+    /// Returns the permissions and memory type currently set to the entry.
+    pub(crate) fn flags(self) -> NestedPagingStructureEntryFlags {
+        NestedPagingStructureEntryFlags {
+            permission: self.permission() as u8,
+            memory_type: self.memory_type() as u8,
+            no_execute: self.no_execute(),
+        }
+    }
+}
+
+/// Returns whether the exception `vector` is raised by an instruction, that is,
+/// #BP by `INT3` or #OF by `INTO`. Those are injected as software exceptions,
+/// so that the guest handler returns to the next instruction instead of the one
+/// that raised the exception.
+///
+/// See: (Intel) 27.6.1.1 Details of Vectored-Event Injection
+/// See: (AMD) 15.20 Event Injection
+fn is_software_exception(vector: u8) -> bool {
+    matches!(vector, irq::BREAKPOINT_VECTOR | irq::OVERFLOW_VECTOR)
+}
+
+/// Checks whether `xcr0` can be written to XCR0 on the current processor.
+///
+/// See: (Intel) 13.3 ENABLING THE XSAVE FEATURE SET AND XSAVE-ENABLED FEATURES
+/// See: (AMD) 11.5.2 XFEATURE_ENABLED_MASK
+pub(crate) fn is_valid_xcr0(xcr0: u64) -> bool {
+    const XCR0_X87: u64 = 1 << 0;
+    const XCR0_SSE: u64 = 1 << 1;
+    const XCR0_AVX: u64 = 1 << 2;
+
+    // CPUID.(EAX=0DH,ECX=0):EDX:EAX reports the bits that may be set.
+    let cpuid = x86::cpuid::cpuid!(0xd, 0);
+    let supported = (u64::from(cpuid.edx) << 32) | u64::from(cpuid.eax);
+    (xcr0 & XCR0_X87) != 0
+        && (xcr0 & !supported) == 0
+        && ((xcr0 & XCR0_AVX) == 0 || (xcr0 & XCR0_SSE) != 0)
+}
+
+/// Loads `guest_xcr0` into XCR0 and returns the current value, if it differs
+/// from the current value. XCR0 is not switched on VM entry or VM exit by the
+/// processor on either platform. If CR4.OSXSAVE is not set on the host, the
+/// guest runs with the current value, as XCR0 is not accessible.
+fn swap_xcr0(guest_xcr0: u64) -> Option<u64> {
+    let host_xcr0 = xcr0()?;
+    if host_xcr0 == guest_xcr0 {
+        return None;
     }
+    xcr0_write(guest_xcr0);
+    Some(host_xcr0)
 }
 
 /// Returns the segment descriptor casted as a 64bit integer for the given
@@ -15,16 +15,24 @@ use super::{
     NestedPagingStructureEntryFlags, NestedPagingStructureEntryType, VmExitReason,
 };
 use crate::{
-    hardware_vt::{self, ExceptionQualification, GuestException, NestedPageFaultQualification},
-    snapshot::Snapshot,
-    x86_instructions::{rdmsr, wrmsr},
+    config::{INTERCEPTED_IO_PORTS, INTERCEPTED_MSRS, MASK_GUEST_INTERRUPTS},
+    hardware_vt::{
+        self, ExceptionQualification, GuestException, NestedPageFaultQualification,
+        ShutdownQualification, UnsupportedGuestMode,
+    },
+    snapshot::{Harness, Snapshot, SnapshotRegisters},
+    x86_instructions::{rdmsr, wrmsr, xcr0_write},
 };
 use alloc::boxed::Box;
 use core::{
     arch::global_asm,
     ptr::{addr_of, addr_of_mut},
 };
-use x86::{current::paging::BASE_PAGE_SHIFT, irq};
+use log::info;
+use x86::{
+    current::{paging::BASE_PAGE_SHIFT, rflags::RFlags},
+    irq,
+};
 
 /// SVM-specific data to represent a guest.
 #[derive(derivative::Derivative)]
@@ -33,27 +41,62 @@ pub(crate) struct Svm {
     vmcb: Box<Vmcb>,
     #[derivative(Debug = "ignore")]
     host_state: Box<HostStateArea>,
+    #[derivative(Debug = "ignore")]
+    msr_permission_map: Box<MsrPermissionMap>,
+    #[derivative(Debug = "ignore")]
+    io_permission_map: Box<IoPermissionMap>,
     registers: GuestRegisters,
+    /// The guest RFLAGS.TF before [`hardware_vt::HardwareVt::step_over`], if
+    /// single-stepping is in effect.
+    guest_tf_before_step: Option<bool>,
+    /// The guest XCR0, which is loaded into XCR0 only while the guest runs.
+    guest_xcr0: u64,
 }
 
 impl hardware_vt::HardwareVt for Svm {
     /// Enables SVM on the current processor.
     fn enable(&mut self) {
+        const EFER_NXE: u64 = 1 << 11;
         const EFER_SVME: u64 = 1 << 12;
 
-        // Enable SVM. We assume the processor is compatible with this.
+        // Enable SVM. We assume the processor is compatible with this. Also
+        // enable the no-execute bit, as nested paging interprets it according
+        // to the host EFER.NXE.
         // See: 15.4 Enabling SVM
-        wrmsr(x86::msr::IA32_EFER, rdmsr(x86::msr::IA32_EFER) | EFER_SVME);
+        // See: 15.25.5 Nested Table Walk
+        wrmsr(x86::msr::IA32_EFER, rdmsr(x86::msr::IA32_EFER) | EFER_SVME | EFER_NXE);
     }
 
-    /// Configures SVM. We intercept #BP, #UD, #PF, external interrupt, the
-    /// PAUSE instruction, shutdown, and enable nested paging.
-    fn initialize(&mut self, nested_pml4_addr: u64) {
+    /// Configures SVM. We intercept #DE, #BP, #UD, #GP, #PF, external
+    /// interrupt, the PAUSE instruction, shutdown, the CPUID, RDTSC,
+    /// RDTSCP, VMMCALL, XSETBV, WBINVD, MONITOR, MWAIT and HLT instructions,
+    /// writes to CR0 and CR4 if decode assists and the next RIP save are
+    /// available, access to selected MSRs and I/O ports, and enable nested
+    /// paging. SVM can run the guest in any mode with nested paging.
+    fn initialize(
+        &mut self,
+        nested_pml4_addr: u64,
+        _registers: &SnapshotRegisters,
+    ) -> Result<(), UnsupportedGuestMode> {
+        const SVM_INTERCEPT_CR_WRITE_CR0: u16 = 1 << 0;
+        const SVM_INTERCEPT_CR_WRITE_CR4: u16 = 1 << 4;
         const SVM_INTERCEPT_MISC1_INTR: u32 = 1 << 0;
+        const SVM_INTERCEPT_MISC1_RDTSC: u32 = 1 << 14;
+        const SVM_INTERCEPT_MISC1_CPUID: u32 = 1 << 18;
         const SVM_INTERCEPT_MISC1_PAUSE: u32 = 1 << 23;
+        const SVM_INTERCEPT_MISC1_HLT: u32 = 1 << 24;
+        const SVM_INTERCEPT_MISC1_IOIO_PROT: u32 = 1 << 27;
+        const SVM_INTERCEPT_MISC1_MSR_PROT: u32 = 1 << 28;
         const SVM_INTERCEPT_MISC1_SHUTDOWN: u32 = 1 << 31;
         const SVM_INTERCEPT_MISC2_VMRUN: u32 = 1 << 0;
+        const SVM_INTERCEPT_MISC2_VMMCALL: u32 = 1 << 1;
+        const SVM_INTERCEPT_MISC2_RDTSCP: u32 = 1 << 7;
+        const SVM_INTERCEPT_MISC2_WBINVD: u32 = 1 << 9;
+        const SVM_INTERCEPT_MISC2_MONITOR: u32 = 1 << 10;
+        const SVM_INTERCEPT_MISC2_MWAIT: u32 = 1 << 11;
+        const SVM_INTERCEPT_MISC2_XSETBV: u32 = 1 << 13;
         const SVM_NP_ENABLE_NP_ENABLE: u64 = 1 << 0;
+        const SVM_VINTR_V_INTR_MASKING: u64 = 1 << 24;
         const SVM_MSR_VM_HSAVE_PA: u32 = 0xc001_0117;
 
         // Need to specify the address of the host state-save area before executing
@@ -67,7 +110,22 @@ impl hardware_vt::HardwareVt for Svm {
         wrmsr(SVM_MSR_VM_HSAVE_PA, addr_of!(*self.host_state) as u64);
 
         // Intercept external interrupts, the PAUSE instruction and shutdown.
-        // Additionally, intercept the VMRUN instruction which is a HW requirement.
+        // Additionally, intercept the VMRUN instruction which is a HW requirement,
+        // and the VMMCALL instruction which the guest uses for hypercalls.
+        // Without the intercept, VMMCALL causes #UD.
+        //
+        // We also intercept the CPUID instruction to present the fixed set of
+        // processor features to the guest, the RDTSC and RDTSCP instructions
+        // to present the deterministic TSC values, and the XSETBV instruction to
+        // keep track of the guest XCR0, which is not switched by the processor.
+        // Note that XSETBV is intercepted unconditionally on Intel.
+        //
+        // The WBINVD, MONITOR and MWAIT instructions are intercepted to skip
+        // them. MWAIT may put the processor into a sleep state, and WBINVD affects
+        // the whole system.
+        //
+        // HLT is intercepted to end the iteration, as nothing would wake the guest
+        // up. It is emulated with `crate::emulator`.
         //
         // We intercept external interrupts and PAUSE as an attempt to gain control
         // even if the guest is in an infinite loop, although this is not a perfect
@@ -81,11 +139,68 @@ impl hardware_vt::HardwareVt for Svm {
         // See: 15.13.1 INTR Intercept
         // See: 15.14.3 Shutdown Intercept
         // See: 15.14.4 Pause Intercept Filtering
-        self.vmcb.control_area.intercept_misc1 =
-            SVM_INTERCEPT_MISC1_INTR | SVM_INTERCEPT_MISC1_PAUSE | SVM_INTERCEPT_MISC1_SHUTDOWN;
-        self.vmcb.control_area.intercept_misc2 = SVM_INTERCEPT_MISC2_VMRUN;
+        // See: 15.9 Instruction Intercepts
+        self.vmcb.control_area.intercept_misc1 = SVM_INTERCEPT_MISC1_INTR
+            | SVM_INTERCEPT_MISC1_RDTSC
+            | SVM_INTERCEPT_MISC1_CPUID
+            | SVM_INTERCEPT_MISC1_PAUSE
+            | SVM_INTERCEPT_MISC1_HLT
+            | SVM_INTERCEPT_MISC1_MSR_PROT
+            | SVM_INTERCEPT_MISC1_IOIO_PROT
+            | SVM_INTERCEPT_MISC1_SHUTDOWN;
+        self.vmcb.control_area.intercept_misc2 = SVM_INTERCEPT_MISC2_VMRUN
+            | SVM_INTERCEPT_MISC2_VMMCALL
+            | SVM_INTERCEPT_MISC2_RDTSCP
+            | SVM_INTERCEPT_MISC2_XSETBV
+            | SVM_INTERCEPT_MISC2_WBINVD
+            | SVM_INTERCEPT_MISC2_MONITOR
+            | SVM_INTERCEPT_MISC2_MWAIT;
         self.vmcb.control_area.pause_filter_count = u16::MAX;
 
+        // If the guest runs with RFLAGS.IF cleared, let the host RFLAGS.IF,
+        // instead of the guest's, control physical interrupts, so that external
+        // interrupts still cause #VMEXIT.
+        // See: 15.21.1 Physical Interrupt Masking
+        if MASK_GUEST_INTERRUPTS {
+            self.vmcb.control_area.vintr = SVM_VINTR_V_INTR_MASKING;
+        }
+
+        // Intercept writes to CR0 and CR4 to abort the guest when it attempts to
+        // modify bits in `INTERCEPTED_CR0_BITS` and `INTERCEPTED_CR4_BITS`. Unlike
+        // on Intel, writes that do not modify them are intercepted too, and
+        // reflected by the hypervisor. This requires the decode assists and next
+        // RIP save features to know the written value and the instruction
+        // length. Without them, every write would abort the guest, so writes are
+        // not intercepted at all.
+        // See: 15.9 Instruction Intercepts
+        // See: 15.33.1 MOV CRx Instructions
+        if x86::cpuid::CpuId::new()
+            .get_svm_info()
+            .is_some_and(|svm| svm.has_decode_assists() && svm.has_nrip())
+        {
+            self.vmcb.control_area.intercept_cr_write =
+                SVM_INTERCEPT_CR_WRITE_CR0 | SVM_INTERCEPT_CR_WRITE_CR4;
+        }
+
+        // Intercept RDMSR only for the selected MSRs, and WRMSR for all MSRs so
+        // that the guest never modifies the host MSRs.
+        // See: 15.11 MSR Intercepts
+        self.msr_permission_map.intercept_all_writes();
+        for msr in INTERCEPTED_MSRS {
+            self.msr_permission_map.intercept(*msr);
+        }
+        self.vmcb.control_area.msrpm_base_pa = addr_of!(*self.msr_permission_map) as u64;
+
+        // Intercept IN and OUT only for the selected I/O ports so that the guest
+        // never touches the host devices behind them.
+        // See: 15.10.1 I/O Permissions Map
+        for ports in INTERCEPTED_IO_PORTS {
+            for port in ports.clone() {
+                self.io_permission_map.intercept(port);
+            }
+        }
+        self.vmcb.control_area.iopm_base_pa = addr_of!(*self.io_permission_map) as u64;
+
         // Address Space Identifier (ASID) is useful when the given logical processor
         // runs more than one guests. We do not but still need to set non-zero value.
         // See: 15.16 TLB Control
@@ -99,19 +214,24 @@ impl hardware_vt::HardwareVt for Svm {
         self.vmcb.control_area.np_enable = SVM_NP_ENABLE_NP_ENABLE;
         self.vmcb.control_area.ncr3 = nested_pml4_addr;
 
-        // Intercept #BP, #UD, #PF.
+        // Intercept #DE, #DB, #BP, #UD, #GP, #PF.
         // See: 15.12 Exception Intercepts
-        self.vmcb.control_area.intercept_exception = (1u32 << irq::BREAKPOINT_VECTOR)
+        self.vmcb.control_area.intercept_exception = (1u32 << irq::DIVIDE_ERROR_VECTOR)
+            | (1u32 << irq::DEBUG_VECTOR)
+            | (1u32 << irq::BREAKPOINT_VECTOR)
             | (1u32 << irq::INVALID_OPCODE_VECTOR)
+            | (1u32 << irq::GENERAL_PROTECTION_FAULT_VECTOR)
             | (1u32 << irq::PAGE_FAULT_VECTOR);
+        Ok(())
     }
 
     /// Configures the guest states based on the snapshot.
-    fn revert_registers(&mut self, snapshot: &Snapshot) {
+    fn revert_registers(&mut self, snapshot: &Snapshot, _exec_timeout_in_tsc: u64) {
         const EFER_SVME: u64 = 1 << 12;
         const CR4_VMXE: u64 = 1 << 13;
 
         let registers = &snapshot.registers;
+        self.guest_xcr0 = registers.guest_xcr0();
 
         // Initialize guest registers from the snapshot through VMCB. Note that:
         // - SVME bit has to be set in the EFER.
@@ -160,16 +280,17 @@ impl hardware_vt::HardwareVt for Svm {
         self.vmcb.state_save_area.cr0 = registers.cr0;
         self.vmcb.state_save_area.cr3 = registers.cr3;
         self.vmcb.state_save_area.cr4 = registers.cr4 & !CR4_VMXE;
-        self.vmcb.state_save_area.rip = registers.rip;
-        self.vmcb.state_save_area.rsp = registers.rsp;
-        self.vmcb.state_save_area.rflags = registers.rflags;
-        self.vmcb.state_save_area.rax = registers.rax;
-        self.vmcb.state_save_area.gpat = rdmsr(x86::msr::IA32_PAT); // FIXME; use snapshot
+        self.vmcb.state_save_area.rip = snapshot.entry_rip();
+        self.vmcb.state_save_area.rsp = snapshot.entry_rsp();
+        self.vmcb.state_save_area.rflags = registers.guest_rflags();
+        self.vmcb.state_save_area.gpat = registers.guest_pat();
+        self.guest_tf_before_step = None;
 
         // Some registers are not managed by VMCB and needed to be manually saved
         // and loaded by software. General purpose registers are such examples.
         //
-        // Note that RAX is managed within VMCB. See `StateSaveArea` and just above.
+        // Note that RAX is managed within VMCB and copied into VMCB in `run`.
+        self.registers.rax = registers.rax;
         self.registers.rbx = registers.rbx;
         self.registers.rcx = registers.rcx;
         self.registers.rdx = registers.rdx;
@@ -187,24 +308,45 @@ impl hardware_vt::HardwareVt for Svm {
     }
 
     /// Updates the guest states to have the guest use input data.
-    fn adjust_registers(&mut self, input_addr: u64, input_size: u64) {
+    fn adjust_registers(&mut self, input_addr: u64, input_size: u64, harness: &Harness) {
         // For the snapshot being used for testing, we know RDI points to the
-        // address of the buffer to be parsed, and RSI contains the size of it.
-        self.registers.rdi = input_addr;
-        self.registers.rsi = input_size;
+        // address of the buffer to be parsed, and RSI contains the size of it,
+        // unless the snapshot file or parameters specify otherwise.
+        *self.registers.gpr_mut(harness.address_register) = input_addr;
+        *self.registers.gpr_mut(harness.size_register) = input_size;
     }
 
     /// Executes the guest until it triggers #VMEXIT.
+    #[allow(clippy::too_many_lines)]
     fn run(&mut self) -> VmExitReason {
         const VMEXIT_EXCP0: u64 = 0x40;
         const VMEXIT_EXCP31: u64 = 0x5f;
         const VMEXIT_INTR: u64 = 0x60;
+        const VMEXIT_RDTSC: u64 = 0x6e;
+        const VMEXIT_CPUID: u64 = 0x72;
         const VMEXIT_PAUSE: u64 = 0x77;
+        const VMEXIT_HLT: u64 = 0x78;
+        const VMEXIT_IOIO: u64 = 0x7b;
+        const VMEXIT_MSR: u64 = 0x7c;
         const VMEXIT_RESET: u64 = 0x7f;
+        const VMEXIT_VMMCALL: u64 = 0x81;
+        const VMEXIT_RDTSCP: u64 = 0x87;
+        const VMEXIT_WBINVD: u64 = 0x89;
+        const VMEXIT_MONITOR: u64 = 0x8a;
+        const VMEXIT_MWAIT: u64 = 0x8b;
+        const VMEXIT_XSETBV: u64 = 0x8d;
         const VMEXIT_NPF: u64 = 0x400;
 
+        // RAX is loaded from VMCB on VMRUN. Copy the value which may have been
+        // updated through `registers_mut`.
+        self.vmcb.state_save_area.rax = self.registers.rax;
+
         // Run the VM until the #VMEXIT occurs.
+        let host_xcr0 = hardware_vt::swap_xcr0(self.guest_xcr0);
         unsafe { run_vm_svm(&mut self.registers, addr_of_mut!(*self.vmcb)) };
+        if let Some(host_xcr0) = host_xcr0 {
+            xcr0_write(host_xcr0);
+        }
 
         // #VMEXIT occurred. Copy the guest register values from VMCB so that
         // `self.registers` is complete and up to date.
@@ -213,8 +355,10 @@ impl hardware_vt::HardwareVt for Svm {
         self.registers.rsp = self.vmcb.state_save_area.rsp;
         self.registers.rflags = self.vmcb.state_save_area.rflags;
 
-        // We might have requested flushing TLB. Clear the request.
+        // We might have requested flushing TLB or injecting an event. Clear the
+        // requests.
         self.vmcb.control_area.tlb_control = 0;
+        self.vmcb.control_area.event_inj = 0;
 
         // Handle #VMEXIT by translating it to the `VmExitReason` type.
         //
@@ -227,25 +371,98 @@ impl hardware_vt::HardwareVt for Svm {
         // See: Appendix C SVM Intercept Exit Codes
         match self.vmcb.control_area.exit_code {
             // See: 15.12 Exception Intercepts
-            VMEXIT_EXCP0..=VMEXIT_EXCP31 => VmExitReason::Exception(ExceptionQualification {
-                rip: self.registers.rip,
-                exception_code: GuestException::try_from(
+            VMEXIT_EXCP0..=VMEXIT_EXCP31 => {
+                let exception_code = GuestException::try_from(
                     (self.vmcb.control_area.exit_code - VMEXIT_EXCP0) as u8,
                 )
-                .unwrap(),
-            }),
+                .unwrap();
+                self.complete_step_over(exception_code);
+
+                // "For those exceptions that push an error code onto the stack,
+                //  the error code is saved in EXITINFO1. For page faults, the
+                //  faulting address is saved in EXITINFO2."
+                //
+                // CR2 is not updated on intercepted #PF. Update it so that the
+                // guest sees the correct value if the exception is injected.
+                let error_code = match exception_code {
+                    GuestException::GeneralProtection => {
+                        Some(self.vmcb.control_area.exit_info1 as u32)
+                    }
+                    GuestException::PageFault => {
+                        self.vmcb.state_save_area.cr2 = self.vmcb.control_area.exit_info2;
+                        Some(self.vmcb.control_area.exit_info1 as u32)
+                    }
+                    _ => None,
+                };
+                VmExitReason::Exception(ExceptionQualification {
+                    rip: self.registers.rip,
+                    exception_code,
+                    error_code,
+                    cr2: (exception_code == GuestException::PageFault)
+                        .then_some(self.vmcb.control_area.exit_info2),
+                })
+            }
             // See: 15.25.6 Nested versus Guest Page Faults, Fault Ordering
+            //      8.4.2 Page-Fault Error Code
             VMEXIT_NPF => VmExitReason::NestedPageFault(NestedPageFaultQualification {
                 rip: self.registers.rip,
                 gpa: self.vmcb.control_area.exit_info2,
                 missing_translation: (self.vmcb.control_area.exit_info1 & 0b1) == 0,
                 write_access: (self.vmcb.control_area.exit_info1 & 0b10) != 0,
+                execute_access: (self.vmcb.control_area.exit_info1 & 0b1_0000) != 0,
             }),
             // See: 15.13.1 INTR Intercept
             // See: 15.14.4 Pause Intercept Filtering
             VMEXIT_INTR | VMEXIT_PAUSE => VmExitReason::ExternalInterruptOrPause,
+            // See: 15.9 Instruction Intercepts
+            VMEXIT_CPUID => VmExitReason::Cpuid {
+                leaf: self.registers.rax as u32,
+                subleaf: self.registers.rcx as u32,
+            },
+            // See: 15.9 Instruction Intercepts
+            VMEXIT_RDTSC => VmExitReason::Rdtsc { rdtscp: false },
+            VMEXIT_RDTSCP => VmExitReason::Rdtsc { rdtscp: true },
+            // See: 15.9 Instruction Intercepts
+            VMEXIT_WBINVD => VmExitReason::Wbinvd,
+            VMEXIT_MONITOR | VMEXIT_MWAIT => VmExitReason::MonitorOrMwait,
+            VMEXIT_HLT => VmExitReason::Instruction(self.vmcb.control_area.exit_code),
+            VMEXIT_XSETBV => VmExitReason::Xsetbv {
+                xcr: self.registers.rcx as u32,
+                value: (self.registers.rdx << 32) | (self.registers.rax & 0xffff_ffff),
+            },
+            // See: 15.9 Instruction Intercepts
+            VMEXIT_VMMCALL => VmExitReason::Hypercall {
+                nr: self.registers.rax,
+                arg: self.registers.rcx,
+            },
+            // See: 15.11 MSR Intercepts
+            VMEXIT_MSR => VmExitReason::MsrAccess {
+                msr: self.registers.rcx as u32,
+                write: self.vmcb.control_area.exit_info1 == 1,
+                value: (self.registers.rdx << 32) | (self.registers.rax & 0xffff_ffff),
+            },
+            // See: 15.9 Instruction Intercepts
+            VMEXIT_CR0_WRITE | VMEXIT_CR4_WRITE => self.cr_access(),
+            // See: 15.10.2 IN and OUT Behavior
+            //      Figure 15-2. EXITINFO1 for IOIO Intercept
+            VMEXIT_IOIO if (self.vmcb.control_area.exit_info1 & 0b100) == 0 => {
+                let info = self.vmcb.control_area.exit_info1;
+                VmExitReason::IoAccess {
+                    port: (info >> 16) as u16,
+                    write: (info & 0b1) == 0,
+                    size: ((info >> 4) & 0b111) as u8,
+                    value: self.registers.rax as u32,
+                    length: self.vmcb.control_area.exit_info2 - self.registers.rip,
+                }
+            }
             // See: 15.14.3 Shutdown Intercept
-            VMEXIT_RESET => VmExitReason::Shutdown(self.vmcb.control_area.exit_code),
+            //      15.7.2 Intercepts During IDT Interrupt Delivery
+            VMEXIT_RESET => VmExitReason::Shutdown(ShutdownQualification::new(
+                self.registers.rip,
+                self.vmcb.control_area.exit_code,
+                self.vmcb.control_area.exit_int_info,
+                (self.vmcb.control_area.exit_int_info >> 32) as u32,
+            )),
             // Anything else.
             _ => VmExitReason::Unexpected(self.vmcb.control_area.exit_code),
         }
@@ -275,24 +492,206 @@ impl hardware_vt::HardwareVt for Svm {
                 NestedPagingStructureEntryFlags {
                     permission: 0b111,
                     memory_type: 0,
+                    no_execute: false,
+                }
+            }
+            // Valid, NON writable, User. Execute-only is not expressible because
+            // a valid entry is always readable.
+            NestedPagingStructureEntryType::RxWriteBack | NestedPagingStructureEntryType::XOnly => {
+                NestedPagingStructureEntryFlags {
+                    permission: 0b101,
+                    memory_type: 0,
+                    no_execute: false,
                 }
             }
-            // Valid, NON writable, User
-            NestedPagingStructureEntryType::RxWriteBack => NestedPagingStructureEntryFlags {
-                permission: 0b101,
+            // Valid, Writeable, User, No-execute. Honored as the host EFER.NXE
+            // is set (see `enable`).
+            // See: 15.25.5 Nested Table Walk
+            NestedPagingStructureEntryType::RwWriteBack => NestedPagingStructureEntryFlags {
+                permission: 0b111,
                 memory_type: 0,
+                no_execute: true,
             },
         }
     }
+
+    /// Checks whether nested paging can map 2MB pages. Always supported.
+    ///
+    /// See: 15.25.1 Traditional Paging versus Nested Paging
+    fn large_page_supported(&self) -> bool {
+        true
+    }
+
+    /// Checks whether nested paging can map pages as execute-only. Never
+    /// supported, as there is no bit to make a page non-readable.
+    ///
+    /// See: 15.25.5 Nested Table Walk
+    fn execute_only_supported(&self) -> bool {
+        false
+    }
+
+    /// Moves the guest RIP forward by `length` bytes.
+    fn advance_rip(&mut self, length: u64) {
+        self.vmcb.state_save_area.rip += length;
+        self.registers.rip = self.vmcb.state_save_area.rip;
+    }
+
+    /// Returns the base address of the guest CS segment.
+    fn cs_base(&self) -> u64 {
+        self.vmcb.state_save_area.cs_base
+    }
+
+    /// Sets the guest XCR0.
+    fn set_xcr0(&mut self, xcr0: u64) {
+        self.guest_xcr0 = xcr0;
+    }
+
+    /// Returns the guest control register `cr`, which is either 0 or 4.
+    fn guest_cr(&self, cr: u8) -> u64 {
+        if cr == 0 {
+            self.vmcb.state_save_area.cr0
+        } else {
+            self.vmcb.state_save_area.cr4
+        }
+    }
+
+    /// Sets the guest control register `cr`, which is either 0 or 4.
+    fn set_guest_cr(&mut self, cr: u8, value: u64) {
+        if cr == 0 {
+            self.vmcb.state_save_area.cr0 = value;
+        } else {
+            self.vmcb.state_save_area.cr4 = value;
+        }
+    }
+
+    /// Injects the exception `vector` with `error_code` into the guest. #BP and
+    /// #OF are injected as software interrupts with RIP advanced past `INT3` or
+    /// `INTO`, which are both 1 byte long, so that the guest handler returns to
+    /// the next instruction.
+    ///
+    /// See: 15.20 Event Injection
+    fn inject_event(&mut self, vector: u8, error_code: Option<u32>) {
+        const EVENT_TYPE_EXCEPTION: u64 = 3 << 8;
+        const EVENT_TYPE_SOFTWARE_INTERRUPT: u64 = 4 << 8;
+        const EVENT_ERROR_CODE_VALID: u64 = 1 << 11;
+        const EVENT_VALID: u64 = 1 << 31;
+        const INT3_OR_INTO_INSTRUCTION_LENGTH: u64 = 1;
+
+        let event_type = if hardware_vt::is_software_exception(vector) {
+            self.advance_rip(INT3_OR_INTO_INSTRUCTION_LENGTH);
+            EVENT_TYPE_SOFTWARE_INTERRUPT
+        } else {
+            EVENT_TYPE_EXCEPTION
+        };
+        let mut event = u64::from(vector) | event_type | EVENT_VALID;
+        if let Some(error_code) = error_code {
+            event |= EVENT_ERROR_CODE_VALID | (u64::from(error_code) << 32);
+        }
+        self.vmcb.control_area.event_inj = event;
+    }
+
+    /// Gets the guest general purpose registers.
+    fn registers_mut(&mut self) -> &mut GuestRegisters {
+        &mut self.registers
+    }
+
+    /// Sets the guest RFLAGS.TF, saving the original value.
+    fn step_over(&mut self) {
+        let flags = RFlags::from_raw(self.vmcb.state_save_area.rflags);
+        self.guest_tf_before_step = Some(flags.contains(RFlags::FLAGS_TF));
+        self.set_guest_tf(true);
+    }
+
+    /// Logs the SVM revision, the number of ASIDs, and availability of nested
+    /// paging, the next RIP save and the PAUSE filter. SVM has no timer to
+    /// abort the guest, and relies on intercepting external interrupts and
+    /// PAUSE instead.
+    ///
+    /// See: 15.4 Enabling SVM
+    fn report_capabilities(&self) {
+        info!("Hardware VT                : SVM");
+        let Some(svm) = x86::cpuid::CpuId::new().get_svm_info() else {
+            return;
+        };
+        info!("SVM revision               : {}", svm.revision());
+        info!("Number of ASIDs            : {}", svm.supported_asids());
+        info!("Nested paging              : {}", svm.has_nested_paging());
+        info!("Next RIP save              : {}", svm.has_nrip());
+        info!("Decode assists             : {}", svm.has_decode_assists());
+        info!("PAUSE filter               : {}", svm.has_pause_filter());
+    }
 }
 
+// The #VMEXIT codes of writes to CR0 and CR4.
+// See: Appendix C SVM Intercept Exit Codes
+const VMEXIT_CR0_WRITE: u64 = 0x10;
+const VMEXIT_CR4_WRITE: u64 = 0x14;
+
 impl Svm {
+    /// Translates #VMEXIT due to writes to CR0 or CR4 into [`VmExitReason`].
+    ///
+    /// With decode assists, EXITINFO1 contains the number of the source general
+    /// purpose register, and bit 63 indicates that it is valid, ie, the
+    /// instruction is `MOV` to CR. NRIP is zero without the next RIP save
+    /// feature. Writes are intercepted only when both features are available.
+    /// Writes to CR0 by other instructions, that is, `CLTS` and `LMSW`, are
+    /// reported as [`VmExitReason::Instruction`] to be emulated, as neither the
+    /// value nor the instruction is decoded for them.
+    ///
+    /// See: 15.33.1 MOV CRx Instructions
+    fn cr_access(&self) -> VmExitReason {
+        let control_area = &self.vmcb.control_area;
+        if control_area.nrip == 0 {
+            return VmExitReason::Unexpected(control_area.exit_code);
+        }
+        if (control_area.exit_info1 & (1 << 63)) == 0 {
+            return if control_area.exit_code == VMEXIT_CR0_WRITE {
+                VmExitReason::Instruction(control_area.exit_code)
+            } else {
+                VmExitReason::Unexpected(control_area.exit_code)
+            };
+        }
+
+        let cr = (control_area.exit_code - VMEXIT_CR0_WRITE) as u8;
+        VmExitReason::CrAccess {
+            cr,
+            current: hardware_vt::HardwareVt::guest_cr(self, cr),
+            value: self
+                .registers
+                .gpr((control_area.exit_info1 & 0b1111) as usize),
+            length: control_area.nrip - self.registers.rip,
+        }
+    }
+
+    /// Ends single-stepping started with
+    /// [`hardware_vt::HardwareVt::step_over`] if `exception_code` is #DB, by
+    /// restoring the guest RFLAGS.TF.
+    fn complete_step_over(&mut self, exception_code: GuestException) {
+        if exception_code == GuestException::Debug {
+            if let Some(guest_tf) = self.guest_tf_before_step.take() {
+                self.set_guest_tf(guest_tf);
+            }
+        }
+    }
+
+    /// Sets or clears the guest RFLAGS.TF.
+    fn set_guest_tf(&mut self, enable: bool) {
+        let mut flags = RFlags::from_raw(self.vmcb.state_save_area.rflags);
+        flags.set(RFlags::FLAGS_TF, enable);
+        self.registers.rflags = flags.bits();
+        self.vmcb.state_save_area.rflags = self.registers.rflags;
+    }
+
     pub(crate) fn new() -> Self {
         let vmcb = unsafe { Box::<Vmcb>::new_zeroed().assume_init() };
         let host_state = unsafe { Box::<HostStateArea>::new_zeroed().assume_init() };
+        let msr_permission_map = unsafe { Box::<MsrPermissionMap>::new_zeroed().assume_init() };
+        let io_permission_map = unsafe { Box::<IoPermissionMap>::new_zeroed().assume_init() };
         Self {
             vmcb,
             host_state,
+            msr_permission_map,
+            io_permission_map,
             ..Default::default()
         }
     }
@@ -473,6 +872,63 @@ impl Default for HostStateArea {
     }
 }
 
+/// 8KB block of memory to specify which MSR access causes #VMEXIT. Each MSR
+/// has two bits; the lower bit for read and the higher bit for write.
+///
+/// See: 15.11 MSR Intercepts
+#[repr(C, align(4096))]
+struct MsrPermissionMap([u8; 0x2000]);
+const _: () = assert!(size_of::<MsrPermissionMap>() == 0x2000);
+
+impl Default for MsrPermissionMap {
+    fn default() -> Self {
+        Self([0; 0x2000])
+    }
+}
+
+impl MsrPermissionMap {
+    /// Makes both read and write access to `msr` cause #VMEXIT. Access to MSRs
+    /// outside the ranges covered by the map always causes #VMEXIT.
+    fn intercept(&mut self, msr: u32) {
+        // See: Table 15-11. MSR Ranges of the MSRPM
+        let (offset, index) = match msr {
+            0..=0x1fff => (0x0, msr),
+            0xc000_0000..=0xc000_1fff => (0x800, msr - 0xc000_0000),
+            0xc001_0000..=0xc001_1fff => (0x1000, msr - 0xc001_0000),
+            _ => return,
+        };
+        let (byte, bit) = (offset + (index / 4) as usize, (index % 4) * 2);
+        self.0[byte] |= 0b11 << bit;
+    }
+
+    /// Makes write access to any MSR cause #VMEXIT.
+    fn intercept_all_writes(&mut self) {
+        // The higher bit of each 2-bit entry is for write access.
+        self.0[..0x1800].fill(0b1010_1010);
+    }
+}
+
+/// 12KB block of memory to specify which I/O port access causes #VMEXIT. Each
+/// port has one bit for both read and write.
+///
+/// See: 15.10.1 I/O Permissions Map
+#[repr(C, align(4096))]
+struct IoPermissionMap([u8; 0x3000]);
+const _: () = assert!(size_of::<IoPermissionMap>() == 0x3000);
+
+impl Default for IoPermissionMap {
+    fn default() -> Self {
+        Self([0; 0x3000])
+    }
+}
+
+impl IoPermissionMap {
+    /// Makes access to `port` cause #VMEXIT.
+    fn intercept(&mut self, port: u16) {
+        self.0[usize::from(port / 8)] |= 1 << (port % 8);
+    }
+}
+
 unsafe extern "efiapi" {
     /// Runs the guest until #VMEXIT occurs.
     fn run_vm_svm(registers: &mut GuestRegisters, guest_vmcb_pa: *mut Vmcb);
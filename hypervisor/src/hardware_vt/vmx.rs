@@ -15,10 +15,16 @@ use super::{
     NestedPagingStructureEntryFlags, NestedPagingStructureEntryType, VmExitReason,
 };
 use crate::{
-    config::GUEST_EXEC_TIMEOUT_IN_TSC,
-    hardware_vt::{self, ExceptionQualification, GuestException, NestedPageFaultQualification},
-    snapshot::Snapshot,
-    x86_instructions::{cr0, cr0_write, cr3, cr4, cr4_write, rdmsr, sgdt, sidt, wrmsr},
+    config::{INTERCEPTED_CR0_BITS, INTERCEPTED_CR4_BITS, INTERCEPTED_IO_PORTS, INTERCEPTED_MSRS},
+    hardware_vt::{
+        self, ExceptionQualification, GuestException, NestedPageFaultQualification,
+        ShutdownQualification, UnsupportedGuestMode,
+    },
+    snapshot::{Harness, Snapshot, SnapshotRegisters},
+    x86_instructions::{
+        cr0, cr0_write, cr2_write, cr3, cr4, cr4_write, dr6_write, rdmsr, sgdt, sidt, wrmsr,
+        xcr0_write,
+    },
 };
 use alloc::{
     boxed::Box,
@@ -31,7 +37,7 @@ use core::{
     fmt,
     ptr::addr_of,
 };
-use log::{debug, warn};
+use log::{debug, error, info, warn};
 use x86::{
     controlregs::{Cr0, Cr4},
     current::{paging::BASE_PAGE_SHIFT, rflags::RFlags},
@@ -51,6 +57,10 @@ pub(crate) struct Vmx {
     vmxon_region: Box<Vmxon>,
     vmcs_region: Box<Vmcs>,
     #[derivative(Debug = "ignore")]
+    msr_bitmaps: Box<MsrBitmaps>,
+    #[derivative(Debug = "ignore")]
+    io_bitmaps: Box<IoBitmaps>,
+    #[derivative(Debug = "ignore")]
     host_gdt: HostGdt,
     registers: GuestRegisters,
     /// Whether [`Vmx::vmcs_region`] is already in the launched state.
@@ -58,6 +68,11 @@ pub(crate) struct Vmx {
     /// The scale to convert TSC into the unit used for VMX-preemption timer.
     /// If VMX-preemption timer is not supported, None.
     timer_scale: Option<u64>,
+    /// The guest RFLAGS.TF before [`hardware_vt::HardwareVt::step_over`], if
+    /// single-stepping is in effect.
+    guest_tf_before_step: Option<bool>,
+    /// The guest XCR0, which is loaded into XCR0 only while the guest runs.
+    guest_xcr0: u64,
 }
 
 impl hardware_vt::HardwareVt for Vmx {
@@ -107,16 +122,37 @@ impl hardware_vt::HardwareVt for Vmx {
         vmxon(&mut self.vmxon_region);
     }
 
-    /// Configures VMX. We intercept #BP, #UD, #PF, enable VMX-preemption timer
-    /// and extended page tables.
-    fn initialize(&mut self, nested_pml4_addr: u64) {
+    /// Configures VMX. We intercept #DE, #BP, #UD, #GP, #PF, RDTSC, RDTSCP,
+    /// HLT, access to selected MSRs and I/O ports, enable VMX-preemption timer
+    /// and extended page tables. The guest in real mode or protected mode
+    /// without paging is run with the unrestricted guest feature, and fails if
+    /// it is not supported.
+    #[allow(clippy::too_many_lines)]
+    fn initialize(
+        &mut self,
+        nested_pml4_addr: u64,
+        registers: &SnapshotRegisters,
+    ) -> Result<(), UnsupportedGuestMode> {
+        const IA32_VMX_PROCBASED_CTLS_HLT_EXITING_FLAG: u64 = 1 << 7;
+        const IA32_VMX_PROCBASED_CTLS_MWAIT_EXITING_FLAG: u64 = 1 << 10;
+        const IA32_VMX_PROCBASED_CTLS_RDTSC_EXITING_FLAG: u64 = 1 << 12;
+        const IA32_VMX_PROCBASED_CTLS_USE_IO_BITMAPS_FLAG: u64 = 1 << 25;
+        const IA32_VMX_PROCBASED_CTLS_USE_MSR_BITMAPS_FLAG: u64 = 1 << 28;
+        const IA32_VMX_PROCBASED_CTLS_MONITOR_EXITING_FLAG: u64 = 1 << 29;
         const IA32_VMX_PROCBASED_CTLS_ACTIVATE_SECONDARY_CONTROLS_FLAG: u64 = 1 << 31;
         const IA32_VMX_EXIT_CTLS_HOST_ADDRESS_SPACE_SIZE_FLAG: u64 = 1 << 9;
-        const IA32_VMX_ENTRY_CTLS_IA32E_MODE_GUEST_FLAG: u64 = 1 << 9;
+        const IA32_VMX_EXIT_CTLS_LOAD_IA32_PAT_FLAG: u64 = 1 << 19;
+        const IA32_VMX_ENTRY_CTLS_LOAD_IA32_PAT_FLAG: u64 = 1 << 14;
         const IA32_VMX_PROCBASED_CTLS2_ENABLE_EPT_FLAG: u64 = 1 << 1;
+        const IA32_VMX_PROCBASED_CTLS2_ENABLE_RDTSCP_FLAG: u64 = 1 << 3;
+        const IA32_VMX_PROCBASED_CTLS2_WBINVD_EXITING_FLAG: u64 = 1 << 6;
         const EPT_POINTER_MEMORY_TYPE_WRITE_BACK: u64 = 6 /* << 0 */;
         const EPT_POINTER_PAGE_WALK_LENGTH_4: u64 = 3 << 3;
 
+        // Determine the mode the guest runs in, and fail clearly if the
+        // processor cannot run it, instead of failing VM entry.
+        let (ia32e_mode_guest, unrestricted_guest) = guest_mode_controls(registers)?;
+
         // The processor is now in VMX root operation. This means that the
         // processor can execute other VMX instructions and almost ready for
         // configuring a VMCS with the VMREAD and VMWRITE instructions. Before
@@ -173,22 +209,37 @@ impl hardware_vt::HardwareVt for Vmx {
         vmwrite(vmcs::host::TR_BASE, self.host_gdt.tss.0.as_ptr() as u64);
         vmwrite(vmcs::host::GDTR_BASE, self.host_gdt.gdtr.base as u64);
         vmwrite(vmcs::host::IDTR_BASE, idtr.base as u64);
+        vmwrite(vmcs::host::IA32_PAT_FULL, rdmsr(x86::msr::IA32_PAT));
 
         // Control Field. We configure as follows:
         // - Specify that the host should run in the long-mode.
-        // - Specify that the guest should run in the long-mode.
+        // - Specify that the guest should run in the long-mode if it is in the
+        //   snapshot. Otherwise, enable the unrestricted guest feature as needed.
+        // - Switch IA32_PAT between the guest and host on VM-entry and VM-exit.
         // - Enable VMX-preemption timer.
         // - Enable extended page tables.
-        // - Intercept #BP, #UD, #PF as they can be indicator of bugs found by fuzzing.
+        // - Intercept #DE, #BP, #UD, #GP, #PF as they can be indicator of bugs found by
+        //   fuzzing.
+        // - Intercept access to MSRs in `INTERCEPTED_MSRS` with MSR bitmaps.
+        // - Intercept access to I/O ports in `INTERCEPTED_IO_PORTS` with I/O bitmaps.
+        // - Intercept modification of CR0 and CR4 bits in `INTERCEPTED_CR0_BITS` and
+        //   `INTERCEPTED_CR4_BITS` (see `revert_registers`).
 
         vmwrite(
             vmcs::control::VMEXIT_CONTROLS,
-            adjust_vmx_control(VmxControl::VmExit, IA32_VMX_EXIT_CTLS_HOST_ADDRESS_SPACE_SIZE_FLAG),
+            adjust_vmx_control(
+                VmxControl::VmExit,
+                IA32_VMX_EXIT_CTLS_HOST_ADDRESS_SPACE_SIZE_FLAG
+                    | IA32_VMX_EXIT_CTLS_LOAD_IA32_PAT_FLAG,
+            ),
         );
 
         vmwrite(
             vmcs::control::VMENTRY_CONTROLS,
-            adjust_vmx_control(VmxControl::VmEntry, IA32_VMX_ENTRY_CTLS_IA32E_MODE_GUEST_FLAG),
+            adjust_vmx_control(
+                VmxControl::VmEntry,
+                ia32e_mode_guest | IA32_VMX_ENTRY_CTLS_LOAD_IA32_PAT_FLAG,
+            ),
         );
 
         // Enable VMX-preemption timer if available. We enable this feature to
@@ -202,14 +253,56 @@ impl hardware_vt::HardwareVt for Vmx {
             ),
         );
 
+        // Intercept RDTSC to present the deterministic TSC values. RDTSCP is
+        // also intercepted when it is enabled with the secondary processor-based
+        // VM-execution control. Otherwise, RDTSCP causes #UD.
+        //
+        // Also intercept MONITOR and MWAIT, as well as WBINVD with the secondary
+        // processor-based VM-execution control, to skip them. MWAIT may put the
+        // processor into a sleep state, and WBINVD affects the whole system.
+        //
+        // HLT is intercepted to end the iteration, as nothing would wake the guest
+        // up. It is emulated with `crate::emulator`, as is INVD, which causes VM
+        // exit unconditionally.
+        // See: 26.1.3 Instructions That Cause VM Exits Conditionally
         vmwrite(
             vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
             adjust_vmx_control(
                 VmxControl::ProcessorBased,
-                IA32_VMX_PROCBASED_CTLS_ACTIVATE_SECONDARY_CONTROLS_FLAG,
+                IA32_VMX_PROCBASED_CTLS_HLT_EXITING_FLAG
+                    | IA32_VMX_PROCBASED_CTLS_MWAIT_EXITING_FLAG
+                    | IA32_VMX_PROCBASED_CTLS_MONITOR_EXITING_FLAG
+                    | IA32_VMX_PROCBASED_CTLS_RDTSC_EXITING_FLAG
+                    | IA32_VMX_PROCBASED_CTLS_USE_IO_BITMAPS_FLAG
+                    | IA32_VMX_PROCBASED_CTLS_USE_MSR_BITMAPS_FLAG
+                    | IA32_VMX_PROCBASED_CTLS_ACTIVATE_SECONDARY_CONTROLS_FLAG,
             ),
         );
 
+        // Intercept RDMSR only for the selected MSRs, and WRMSR for all MSRs so
+        // that the guest never modifies the host MSRs. Without MSR bitmaps,
+        // access to any MSR causes VM exit.
+        // See: 25.6.9 MSR-Bitmap Address
+        self.msr_bitmaps.write_low.fill(0xff);
+        self.msr_bitmaps.write_high.fill(0xff);
+        for msr in INTERCEPTED_MSRS {
+            self.msr_bitmaps.intercept(*msr);
+        }
+        vmwrite(vmcs::control::MSR_BITMAPS_ADDR_FULL, addr_of!(*self.msr_bitmaps) as u64);
+
+        // Intercept IN and OUT only for the selected I/O ports so that the guest
+        // never touches the host devices behind them. Without I/O bitmaps, access
+        // to any I/O port causes VM exit when unconditional I/O exiting is set,
+        // or none otherwise.
+        // See: 25.6.4 I/O-Bitmap Addresses
+        for ports in INTERCEPTED_IO_PORTS {
+            for port in ports.clone() {
+                self.io_bitmaps.intercept(port);
+            }
+        }
+        vmwrite(vmcs::control::IO_BITMAP_A_ADDR_FULL, addr_of!(self.io_bitmaps.a) as u64);
+        vmwrite(vmcs::control::IO_BITMAP_B_ADDR_FULL, addr_of!(self.io_bitmaps.b) as u64);
+
         // Enable EPTs. This is a two-steps process at minimum:
         // - Set bit[1] of the secondary processor-based VM-execution controls.
         // See: Table 25-7. Definitions of Secondary Processor-Based VM-Execution
@@ -233,7 +326,10 @@ impl hardware_vt::HardwareVt for Vmx {
             vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS,
             adjust_vmx_control(
                 VmxControl::ProcessorBased2,
-                IA32_VMX_PROCBASED_CTLS2_ENABLE_EPT_FLAG,
+                IA32_VMX_PROCBASED_CTLS2_ENABLE_EPT_FLAG
+                    | IA32_VMX_PROCBASED_CTLS2_ENABLE_RDTSCP_FLAG
+                    | IA32_VMX_PROCBASED_CTLS2_WBINVD_EXITING_FLAG
+                    | unrestricted_guest,
             ),
         );
         vmwrite(
@@ -241,19 +337,24 @@ impl hardware_vt::HardwareVt for Vmx {
             nested_pml4_addr | EPT_POINTER_PAGE_WALK_LENGTH_4 | EPT_POINTER_MEMORY_TYPE_WRITE_BACK,
         );
 
-        // Intercept #BP, #UD, #PF.
+        // Intercept #DE, #DB, #BP, #UD, #GP, #PF.
         // See: 25.6.3 Exception Bitmap
         vmwrite(
             vmcs::control::EXCEPTION_BITMAP,
-            (1u64 << irq::BREAKPOINT_VECTOR)
+            (1u64 << irq::DIVIDE_ERROR_VECTOR)
+                | (1u64 << irq::DEBUG_VECTOR)
+                | (1u64 << irq::BREAKPOINT_VECTOR)
                 | (1u64 << irq::INVALID_OPCODE_VECTOR)
+                | (1u64 << irq::GENERAL_PROTECTION_FAULT_VECTOR)
                 | (1u64 << irq::PAGE_FAULT_VECTOR),
         );
+        Ok(())
     }
 
     /// Configures the guest states based on the snapshot.
-    fn revert_registers(&mut self, snapshot: &Snapshot) {
+    fn revert_registers(&mut self, snapshot: &Snapshot, exec_timeout_in_tsc: u64) {
         let registers = &snapshot.registers;
+        self.guest_xcr0 = registers.guest_xcr0();
 
         // Guest-State Fields. We configure the guest based on the snapshot.
         // Some fields that are known to be zero are not explicitly set. For
@@ -291,6 +392,9 @@ impl hardware_vt::HardwareVt for Vmx {
         vmwrite(vmcs::guest::GS_BASE, registers.gs_base);
         vmwrite(vmcs::guest::TR_BASE, registers.tr_base);
         vmwrite(vmcs::guest::LDTR_BASE, registers.ldtr_base);
+        if registers.cr0 & Cr0::CR0_PROTECTED_MODE.bits() as u64 == 0 {
+            write_real_mode_segments(registers);
+        }
         vmwrite(vmcs::guest::GDTR_BASE, registers.gdtr.base as u64);
         vmwrite(vmcs::guest::GDTR_LIMIT, registers.gdtr.limit);
         vmwrite(vmcs::guest::IDTR_BASE, registers.idtr.base as u64);
@@ -299,13 +403,26 @@ impl hardware_vt::HardwareVt for Vmx {
         vmwrite(vmcs::guest::IA32_SYSENTER_ESP, registers.sysenter_esp);
         vmwrite(vmcs::guest::IA32_SYSENTER_EIP, registers.sysenter_eip);
         vmwrite(vmcs::guest::IA32_EFER_FULL, registers.efer);
-        vmwrite(vmcs::guest::CR0, registers.cr0);
+        vmwrite(vmcs::guest::IA32_PAT_FULL, registers.guest_pat());
+        vmwrite(vmcs::guest::CR0, adjust_guest_cr(0, registers.cr0));
         vmwrite(vmcs::guest::CR3, registers.cr3);
-        vmwrite(vmcs::guest::CR4, registers.cr4);
-        vmwrite(vmcs::guest::RIP, registers.rip);
-        vmwrite(vmcs::guest::RSP, registers.rsp);
-        vmwrite(vmcs::guest::RFLAGS, registers.rflags);
+        vmwrite(vmcs::guest::CR4, adjust_guest_cr(4, registers.cr4));
+
+        // Intercept `MOV` to CR0 and CR4 that modifies the selected bits. Writing a
+        // value different from the read shadow to a bit set in the guest/host mask
+        // causes VM exit. The guest also reads such bits from the read shadow.
+        // The VMX-fixed bits are masked too, so that the guest keeps observing
+        // the original values captured in the snapshot.
+        // See: 25.6.6 Guest/Host Masks and Read Shadows for CR0 and CR4
+        vmwrite(vmcs::control::CR0_GUEST_HOST_MASK, INTERCEPTED_CR0_BITS | vmx_fixed_cr_bits(0));
+        vmwrite(vmcs::control::CR4_GUEST_HOST_MASK, INTERCEPTED_CR4_BITS | vmx_fixed_cr_bits(4));
+        vmwrite(vmcs::control::CR0_READ_SHADOW, registers.cr0);
+        vmwrite(vmcs::control::CR4_READ_SHADOW, registers.cr4);
+        vmwrite(vmcs::guest::RIP, snapshot.entry_rip());
+        vmwrite(vmcs::guest::RSP, snapshot.entry_rsp());
+        vmwrite(vmcs::guest::RFLAGS, registers.guest_rflags());
         vmwrite(vmcs::guest::LINK_PTR_FULL, u64::MAX);
+        self.guest_tf_before_step = None;
 
         // Set VMX-preemption timer counter if the processor supports it. Convert
         // TSC to the equivalent VMX-preemption timer count. The processor counts
@@ -313,10 +430,7 @@ impl hardware_vt::HardwareVt for Vmx {
         // zero.
         // See: 26.5.1 VMX-Preemption Timer
         if let Some(timer_scale) = self.timer_scale {
-            vmwrite(
-                vmcs::guest::VMX_PREEMPTION_TIMER_VALUE,
-                GUEST_EXEC_TIMEOUT_IN_TSC / timer_scale,
-            );
+            vmwrite(vmcs::guest::VMX_PREEMPTION_TIMER_VALUE, exec_timeout_in_tsc / timer_scale);
         };
 
         // Some registers are not managed by VMCS and needed to be manually saved
@@ -339,23 +453,43 @@ impl hardware_vt::HardwareVt for Vmx {
     }
 
     /// Updates the guest states to have the guest use input data.
-    fn adjust_registers(&mut self, input_addr: u64, input_size: u64) {
+    fn adjust_registers(&mut self, input_addr: u64, input_size: u64, harness: &Harness) {
         // For the snapshot being used for testing, we know RDI points to the
-        // address of the buffer to be parsed, and RSI contains the size of it.
-        self.registers.rdi = input_addr;
-        self.registers.rsi = input_size;
+        // address of the buffer to be parsed, and RSI contains the size of it,
+        // unless the snapshot file or parameters specify otherwise.
+        *self.registers.gpr_mut(harness.address_register) = input_addr;
+        *self.registers.gpr_mut(harness.size_register) = input_size;
     }
 
     /// Executes the guest until it triggers VM-exit.
     fn run(&mut self) -> VmExitReason {
         const VMX_EXIT_REASON_EXCEPTION_OR_NMI: u16 = 0;
         const VMX_EXIT_REASON_TRIPLE_FAULT: u16 = 2;
+        const VMX_EXIT_REASON_CPUID: u16 = 10;
+        const VMX_EXIT_REASON_HLT: u16 = 12;
+        const VMX_EXIT_REASON_INVD: u16 = 13;
+        const VMX_EXIT_REASON_CR_ACCESS: u16 = 28;
+        const VMX_EXIT_REASON_INVLPG: u16 = 14;
+        const VMX_EXIT_REASON_RDTSC: u16 = 16;
+        const VMX_EXIT_REASON_VMCALL: u16 = 18;
+        const VMX_EXIT_REASON_RDMSR: u16 = 31;
+        const VMX_EXIT_REASON_IO_INSTRUCTION: u16 = 30;
+        const VMX_EXIT_REASON_WRMSR: u16 = 32;
+        const VMX_EXIT_REASON_MWAIT: u16 = 36;
+        const VMX_EXIT_REASON_MONITOR: u16 = 39;
         const VMX_EXIT_REASON_EPT_VIOLATION: u16 = 48;
+        const VMX_EXIT_REASON_RDTSCP: u16 = 51;
         const VMX_EXIT_REASON_VMX_PREEMPTION_TIMER: u16 = 52;
+        const VMX_EXIT_REASON_WBINVD: u16 = 54;
+        const VMX_EXIT_REASON_XSETBV: u16 = 55;
 
         // Run the VM until the VM-exit occurs.
+        let host_xcr0 = hardware_vt::swap_xcr0(self.guest_xcr0);
         let flags = unsafe { run_vm_vmx(&mut self.registers, u64::from(self.launched)) };
-        vm_succeed(RFlags::from_raw(flags)).unwrap();
+        if let Some(host_xcr0) = host_xcr0 {
+            xcr0_write(host_xcr0);
+        }
+        self.check_vm_entry(RFlags::from_raw(flags));
         self.launched = true;
 
         // VM-exit occurred. Copy the guest register values from VMCS so that
@@ -376,13 +510,7 @@ impl hardware_vt::HardwareVt for Vmx {
         match vmread(vmcs::ro::EXIT_REASON) as u16 {
             // See: 26.2 OTHER CAUSES OF VM EXITS
             //      25.9.2 Information for VM Exits Due to Vectored Events
-            VMX_EXIT_REASON_EXCEPTION_OR_NMI => VmExitReason::Exception(ExceptionQualification {
-                rip: self.registers.rip,
-                exception_code: GuestException::try_from(
-                    vmread(vmcs::ro::VMEXIT_INTERRUPTION_INFO) as u8,
-                )
-                .unwrap(),
-            }),
+            VMX_EXIT_REASON_EXCEPTION_OR_NMI => self.exception(),
             // See: 29.3.3.2 EPT Violations
             //      28.2.1 Basic VM-Exit Information
             //      Table 28-7. Exit Qualification for EPT Violations
@@ -393,12 +521,76 @@ impl hardware_vt::HardwareVt for Vmx {
                     gpa: vmread(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL),
                     missing_translation: (qualification & 0b11_1000) == 0,
                     write_access: (qualification & 0b10) != 0,
+                    execute_access: (qualification & 0b100) != 0,
                 })
             }
             // See: 26.5.1 VMX-Preemption Timer
             VMX_EXIT_REASON_VMX_PREEMPTION_TIMER => VmExitReason::TimerExpiration,
+            // See: 26.1.2 Instructions That Cause VM Exits Unconditionally
+            VMX_EXIT_REASON_CPUID => VmExitReason::Cpuid {
+                leaf: self.registers.rax as u32,
+                subleaf: self.registers.rcx as u32,
+            },
+            // See: 26.1.2 Instructions That Cause VM Exits Unconditionally
+            VMX_EXIT_REASON_VMCALL => VmExitReason::Hypercall {
+                nr: self.registers.rax,
+                arg: self.registers.rcx,
+            },
+            // See: 26.1.3 Instructions That Cause VM Exits Conditionally
+            VMX_EXIT_REASON_RDMSR => VmExitReason::MsrAccess {
+                msr: self.registers.rcx as u32,
+                write: false,
+                value: 0,
+            },
+            VMX_EXIT_REASON_WRMSR => VmExitReason::MsrAccess {
+                msr: self.registers.rcx as u32,
+                write: true,
+                value: (self.registers.rdx << 32) | (self.registers.rax & 0xffff_ffff),
+            },
+            // See: 26.1.3 Instructions That Cause VM Exits Conditionally
+            VMX_EXIT_REASON_RDTSC => VmExitReason::Rdtsc { rdtscp: false },
+            VMX_EXIT_REASON_RDTSCP => VmExitReason::Rdtsc { rdtscp: true },
+            // See: 26.1.3 Instructions That Cause VM Exits Conditionally
+            //      28.2.5 Information for VM Exits Due to Instruction Execution
+            VMX_EXIT_REASON_INVLPG => VmExitReason::Invlpg {
+                length: vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
+            },
+            VMX_EXIT_REASON_WBINVD => VmExitReason::Wbinvd,
+            // See: 26.1.3 Instructions That Cause VM Exits Conditionally
+            VMX_EXIT_REASON_CR_ACCESS => self.cr_access(),
+            VMX_EXIT_REASON_MONITOR | VMX_EXIT_REASON_MWAIT => VmExitReason::MonitorOrMwait,
+            // INVD causes VM exit unconditionally.
+            // See: 26.1.2 Instructions That Cause VM Exits Unconditionally
+            VMX_EXIT_REASON_HLT | VMX_EXIT_REASON_INVD => {
+                VmExitReason::Instruction(vmread(vmcs::ro::EXIT_REASON))
+            }
+            // See: 26.1.2 Instructions That Cause VM Exits Unconditionally
+            VMX_EXIT_REASON_XSETBV => VmExitReason::Xsetbv {
+                xcr: self.registers.rcx as u32,
+                value: (self.registers.rdx << 32) | (self.registers.rax & 0xffff_ffff),
+            },
+            // See: 26.1.3 Instructions That Cause VM Exits Conditionally
+            //      Table 28-5. Exit Qualification for I/O Instructions
+            VMX_EXIT_REASON_IO_INSTRUCTION
+                if (vmread(vmcs::ro::EXIT_QUALIFICATION) & 0b1_0000) == 0 =>
+            {
+                let qualification = vmread(vmcs::ro::EXIT_QUALIFICATION);
+                VmExitReason::IoAccess {
+                    port: (qualification >> 16) as u16,
+                    write: (qualification & 0b1000) == 0,
+                    size: ((qualification & 0b111) + 1) as u8,
+                    value: self.registers.rax as u32,
+                    length: vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
+                }
+            }
             // See: 26.2 OTHER CAUSES OF VM EXITS
-            VMX_EXIT_REASON_TRIPLE_FAULT => VmExitReason::Shutdown(vmread(vmcs::ro::EXIT_REASON)),
+            //      28.2.4 Information for VM Exits During Event Delivery
+            VMX_EXIT_REASON_TRIPLE_FAULT => VmExitReason::Shutdown(ShutdownQualification::new(
+                self.registers.rip,
+                vmread(vmcs::ro::EXIT_REASON),
+                vmread(vmcs::ro::IDT_VECTORING_INFO),
+                vmread(vmcs::ro::IDT_VECTORING_ERR_CODE) as u32,
+            )),
             // Anything else.
             _ => VmExitReason::Unexpected(vmread(vmcs::ro::EXIT_REASON)),
         }
@@ -425,24 +617,279 @@ impl hardware_vt::HardwareVt for Vmx {
             NestedPagingStructureEntryType::Rwx => NestedPagingStructureEntryFlags {
                 permission: 0b111,
                 memory_type: 0,
+                no_execute: false,
             },
             // RWX | WB
             NestedPagingStructureEntryType::RwxWriteBack => NestedPagingStructureEntryFlags {
                 permission: 0b111,
                 memory_type: 6,
+                no_execute: false,
             },
             // R-X | WB
             NestedPagingStructureEntryType::RxWriteBack => NestedPagingStructureEntryFlags {
                 permission: 0b101,
                 memory_type: 6,
+                no_execute: false,
+            },
+            // RW- | WB
+            NestedPagingStructureEntryType::RwWriteBack => NestedPagingStructureEntryFlags {
+                permission: 0b011,
+                memory_type: 6,
+                no_execute: false,
+            },
+            // --X | WB, or R-X | WB if execute-only translation is not supported
+            NestedPagingStructureEntryType::XOnly => NestedPagingStructureEntryFlags {
+                permission: if self.execute_only_supported() {
+                    0b100
+                } else {
+                    0b101
+                },
+                memory_type: 6,
+                no_execute: false,
             },
         }
     }
+
+    /// Checks whether EPT can map 2MB pages.
+    fn large_page_supported(&self) -> bool {
+        const IA32_VMX_EPT_VPID_CAP_PDE_2MB_PAGES_FLAG: u64 = 1 << 16;
+
+        // See: A.10 VPID AND EPT CAPABILITIES
+        (rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP) & IA32_VMX_EPT_VPID_CAP_PDE_2MB_PAGES_FLAG) != 0
+    }
+
+    /// Checks whether EPT can map pages as execute-only.
+    fn execute_only_supported(&self) -> bool {
+        const IA32_VMX_EPT_VPID_CAP_EXECUTE_ONLY_FLAG: u64 = 1 << 0;
+
+        // See: A.10 VPID AND EPT CAPABILITIES
+        (rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP) & IA32_VMX_EPT_VPID_CAP_EXECUTE_ONLY_FLAG) != 0
+    }
+
+    /// Moves the guest RIP forward by `length` bytes.
+    fn advance_rip(&mut self, length: u64) {
+        self.registers.rip += length;
+        vmwrite(vmcs::guest::RIP, self.registers.rip);
+    }
+
+    /// Returns the base address of the guest CS segment.
+    fn cs_base(&self) -> u64 {
+        vmread(vmcs::guest::CS_BASE)
+    }
+
+    /// Sets the guest XCR0.
+    fn set_xcr0(&mut self, xcr0: u64) {
+        self.guest_xcr0 = xcr0;
+    }
+
+    /// Returns the guest control register `cr`, which is either 0 or 4. The
+    /// guest observes the bits owned by the host in the read shadow.
+    fn guest_cr(&self, cr: u8) -> u64 {
+        let (guest_cr, read_shadow, mask) = if cr == 0 {
+            (vmcs::guest::CR0, vmcs::control::CR0_READ_SHADOW, vmcs::control::CR0_GUEST_HOST_MASK)
+        } else {
+            (vmcs::guest::CR4, vmcs::control::CR4_READ_SHADOW, vmcs::control::CR4_GUEST_HOST_MASK)
+        };
+        let mask = vmread(mask);
+        (vmread(guest_cr) & !mask) | (vmread(read_shadow) & mask)
+    }
+
+    /// Sets the guest control register `cr`, which is either 0 or 4, as well
+    /// as its read shadow. The control register is adjusted for the VMX-fixed
+    /// bits, while the read shadow keeps `value` as is.
+    fn set_guest_cr(&mut self, cr: u8, value: u64) {
+        if cr == 0 {
+            vmwrite(vmcs::guest::CR0, adjust_guest_cr(0, value));
+            vmwrite(vmcs::control::CR0_READ_SHADOW, value);
+        } else {
+            vmwrite(vmcs::guest::CR4, adjust_guest_cr(4, value));
+            vmwrite(vmcs::control::CR4_READ_SHADOW, value);
+        }
+    }
+
+    /// Injects the exception `vector` with `error_code` into the guest. The
+    /// valid bit of the field is cleared on every VM exit. #BP and #OF are
+    /// injected as software exceptions with the length of the instruction that
+    /// caused the current VM exit, and #DB updates DR6 as the processor would
+    /// have without the VM exit.
+    ///
+    /// See: 25.8.3 VM-Entry Controls for Event Injection
+    ///      27.6 EVENT INJECTION
+    fn inject_event(&mut self, vector: u8, error_code: Option<u32>) {
+        const INTERRUPTION_TYPE_HARDWARE_EXCEPTION: u64 = 3 << 8;
+        const INTERRUPTION_TYPE_SOFTWARE_EXCEPTION: u64 = 6 << 8;
+        const INTERRUPTION_INFO_ERROR_CODE_VALID: u64 = 1 << 11;
+        const INTERRUPTION_INFO_VALID: u64 = 1 << 31;
+
+        // "The instruction length is saved for VM exits due to (...) a software
+        //  exception (due to an execution of INT3 or INTO)"
+        // See: 28.2.4 Information for VM Exits Due to Instruction Execution
+        let interruption_type = if hardware_vt::is_software_exception(vector) {
+            vmwrite(
+                vmcs::control::VMENTRY_INSTRUCTION_LEN,
+                vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
+            );
+            INTERRUPTION_TYPE_SOFTWARE_EXCEPTION
+        } else {
+            INTERRUPTION_TYPE_HARDWARE_EXCEPTION
+        };
+        if vector == irq::DEBUG_VECTOR {
+            update_dr6_for_debug_exception();
+        }
+
+        let mut info = u64::from(vector) | interruption_type | INTERRUPTION_INFO_VALID;
+        if let Some(error_code) = error_code {
+            info |= INTERRUPTION_INFO_ERROR_CODE_VALID;
+            vmwrite(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code);
+        }
+        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, info);
+    }
+
+    /// Gets the guest general purpose registers.
+    fn registers_mut(&mut self) -> &mut GuestRegisters {
+        &mut self.registers
+    }
+
+    /// Sets the guest RFLAGS.TF, saving the original value.
+    fn step_over(&mut self) {
+        let flags = RFlags::from_raw(vmread(vmcs::guest::RFLAGS));
+        self.guest_tf_before_step = Some(flags.contains(RFlags::FLAGS_TF));
+        self.set_guest_tf(true);
+    }
+
+    /// Logs availability of the VMX-preemption timer, EPT features and the
+    /// unrestricted guest feature.
+    fn report_capabilities(&self) {
+        info!("Hardware VT                : VMX");
+        if let Some(scale) = self.timer_scale {
+            info!("VMX-preemption timer       : supported (scale {scale})");
+        } else {
+            info!("VMX-preemption timer       : not supported");
+        }
+        info!("EPT 2MB pages              : {}", self.large_page_supported());
+        info!("EPT execute-only           : {}", self.execute_only_supported());
+        let unrestricted_guest = adjust_vmx_control(
+            VmxControl::ProcessorBased2,
+            IA32_VMX_PROCBASED_CTLS2_UNRESTRICTED_GUEST_FLAG,
+        ) != 0;
+        info!("Unrestricted guest         : {unrestricted_guest}");
+    }
 }
 
 const IA32_VMX_PINBASED_CTLS_ACTIVATE_VMX_PREEMPTION_TIMER_FLAG: u64 = 1 << 6;
+const IA32_VMX_PROCBASED_CTLS2_UNRESTRICTED_GUEST_FLAG: u64 = 1 << 7;
 
 impl Vmx {
+    /// Translates VM exit due to an exception into [`VmExitReason`].
+    ///
+    /// See: 25.9.2 Information for VM Exits Due to Vectored Events
+    fn exception(&mut self) -> VmExitReason {
+        const INTERRUPTION_INFO_ERROR_CODE_VALID: u64 = 1 << 11;
+
+        let info = vmread(vmcs::ro::VMEXIT_INTERRUPTION_INFO);
+        let exception_code = GuestException::try_from(info as u8).unwrap();
+        self.complete_step_over(exception_code);
+        let error_code = ((info & INTERRUPTION_INFO_ERROR_CODE_VALID) != 0)
+            .then(|| vmread(vmcs::ro::VMEXIT_INTERRUPTION_ERR_CODE) as u32);
+
+        // CR2 is not updated on intercepted #PF, and the faulting address is
+        // saved in the exit qualification instead. Update it so that the guest
+        // sees the correct value if the exception is injected. CR2 is not
+        // switched on VM entry and exit, so the register is the guest's.
+        // See: 28.1 ARCHITECTURAL STATE BEFORE A VM EXIT
+        let cr2 = (exception_code == GuestException::PageFault)
+            .then(|| vmread(vmcs::ro::EXIT_QUALIFICATION));
+        if let Some(cr2) = cr2 {
+            cr2_write(cr2);
+        }
+        VmExitReason::Exception(ExceptionQualification {
+            rip: self.registers.rip,
+            exception_code,
+            error_code,
+            cr2,
+        })
+    }
+
+    /// Translates VM exit due to control-register access into [`VmExitReason`].
+    /// `MOV` to CR0 and CR4 is reported as [`VmExitReason::CrAccess`]. `CLTS`
+    /// and `LMSW`, which write to CR0 too, are reported as
+    /// [`VmExitReason::Instruction`] to be emulated.
+    ///
+    /// See: Table 28-3. Exit Qualification for Control-Register Accesses
+    fn cr_access(&self) -> VmExitReason {
+        const MOV_TO_CR: u64 = 0;
+        const CLTS: u64 = 2;
+        const LMSW: u64 = 3;
+
+        let qualification = vmread(vmcs::ro::EXIT_QUALIFICATION);
+        let cr = (qualification & 0b1111) as u8;
+        match (cr, (qualification >> 4) & 0b11) {
+            (0 | 4, MOV_TO_CR) => {}
+            (0, CLTS | LMSW) => return VmExitReason::Instruction(vmread(vmcs::ro::EXIT_REASON)),
+            _ => return VmExitReason::Unexpected(vmread(vmcs::ro::EXIT_REASON)),
+        }
+
+        VmExitReason::CrAccess {
+            cr,
+            current: hardware_vt::HardwareVt::guest_cr(self, cr),
+            value: self.registers.gpr(((qualification >> 8) & 0b1111) as usize),
+            length: vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
+        }
+    }
+
+    /// Checks that VMLAUNCH or VMRESUME with the resulting `flags` entered the
+    /// guest. If not, logs the contents of the VMCS, including the guest-state
+    /// fields checked on VM entry, to help identify the invalid field, and
+    /// panics.
+    fn check_vm_entry(&self, flags: RFlags) {
+        const VMX_EXIT_REASON_VM_ENTRY_FAILURE_FLAG: u64 = 1 << 31;
+
+        // VM entry fails without entering the guest if checks on the VM-execution,
+        // VM-exit and VM-entry control fields or the host-state area fail.
+        // See: 27.2 CHECKS ON VMX CONTROLS AND HOST-STATE AREA
+        let err = if let Err(err) = vm_succeed(flags) {
+            err
+        } else {
+            // VM entry may also fail after those checks, eg, due to the invalid
+            // guest state. This is reported as VM exit with bit 31 set in the exit
+            // reason. Either way, this never recovers as every iteration starts
+            // with the same guest state.
+            // See: 27.8 VM-ENTRY FAILURES DURING OR AFTER LOADING GUEST STATE
+            let exit_reason = vmread(vmcs::ro::EXIT_REASON);
+            if exit_reason & VMX_EXIT_REASON_VM_ENTRY_FAILURE_FLAG == 0 {
+                return;
+            }
+            format!(
+                "VM entry failure with the exit reason {} ({})",
+                exit_reason as u16,
+                vm_entry_failure_name(exit_reason as u16)
+            )
+        };
+        if vmptrst() == core::ptr::from_ref(self.vmcs_region.as_ref()) {
+            error!("{:#x?}", self.vmcs_region);
+        }
+        panic!("{err}");
+    }
+
+    /// Ends single-stepping started with
+    /// [`hardware_vt::HardwareVt::step_over`] if `exception_code` is #DB, by
+    /// restoring the guest RFLAGS.TF.
+    fn complete_step_over(&mut self, exception_code: GuestException) {
+        if exception_code == GuestException::Debug {
+            if let Some(guest_tf) = self.guest_tf_before_step.take() {
+                self.set_guest_tf(guest_tf);
+            }
+        }
+    }
+
+    /// Sets or clears the guest RFLAGS.TF.
+    fn set_guest_tf(&mut self, enable: bool) {
+        let mut flags = RFlags::from_raw(vmread(vmcs::guest::RFLAGS));
+        flags.set(RFlags::FLAGS_TF, enable);
+        self.registers.rflags = flags.bits();
+        vmwrite(vmcs::guest::RFLAGS, self.registers.rflags);
+    }
+
     pub(crate) fn new() -> Self {
         /// Returns the scale value to convert TSC to the unit where
         /// VMX-preemption timer VMCS expects.
@@ -468,9 +915,13 @@ impl Vmx {
 
         let vmxon_region = unsafe { Box::<Vmxon>::new_zeroed().assume_init() };
         let vmcs_region = unsafe { Box::<Vmcs>::new_zeroed().assume_init() };
+        let msr_bitmaps = unsafe { Box::<MsrBitmaps>::new_zeroed().assume_init() };
+        let io_bitmaps = unsafe { Box::<IoBitmaps>::new_zeroed().assume_init() };
         Self {
             vmxon_region,
             vmcs_region,
+            msr_bitmaps,
+            io_bitmaps,
             timer_scale: vmx_preemption_timer_scale(),
             ..Default::default()
         }
@@ -506,6 +957,71 @@ struct Vmcs {
 }
 const _: () = assert!(size_of::<Vmcs>() == 0x1000);
 
+/// The bitmaps to specify which MSR access causes VM exit.
+///
+/// See: 25.6.9 MSR-Bitmap Address
+#[derive(derivative::Derivative)]
+#[derivative(Default)]
+#[repr(C, align(4096))]
+struct MsrBitmaps {
+    #[derivative(Default(value = "[0; 1024]"))]
+    read_low: [u8; 1024],
+    #[derivative(Default(value = "[0; 1024]"))]
+    read_high: [u8; 1024],
+    #[derivative(Default(value = "[0; 1024]"))]
+    write_low: [u8; 1024],
+    #[derivative(Default(value = "[0; 1024]"))]
+    write_high: [u8; 1024],
+}
+const _: () = assert!(size_of::<MsrBitmaps>() == 0x1000);
+
+impl MsrBitmaps {
+    /// Makes both read and write access to `msr` cause VM exit. Access to MSRs
+    /// outside the low (0 - 0x1fff) and high (0xc0000000 - 0xc0001fff) ranges
+    /// always causes VM exit.
+    fn intercept(&mut self, msr: u32) {
+        let (read, write, index) = match msr {
+            0..=0x1fff => (&mut self.read_low, &mut self.write_low, msr),
+            0xc000_0000..=0xc000_1fff => {
+                (&mut self.read_high, &mut self.write_high, msr - 0xc000_0000)
+            }
+            _ => return,
+        };
+        let (byte, bit) = ((index / 8) as usize, index % 8);
+        read[byte] |= 1 << bit;
+        write[byte] |= 1 << bit;
+    }
+}
+
+/// The pair of 4KB I/O bitmaps to specify which I/O port access causes VM
+/// exit. Bitmap A is for ports 0 - 0x7fff, and bitmap B is for ports 0x8000 -
+/// 0xffff.
+///
+/// See: 25.6.4 I/O-Bitmap Addresses
+#[derive(derivative::Derivative)]
+#[derivative(Default)]
+#[repr(C, align(4096))]
+struct IoBitmaps {
+    #[derivative(Default(value = "[0; 0x1000]"))]
+    a: [u8; 0x1000],
+    #[derivative(Default(value = "[0; 0x1000]"))]
+    b: [u8; 0x1000],
+}
+const _: () = assert!(size_of::<IoBitmaps>() == 0x2000);
+
+impl IoBitmaps {
+    /// Makes access to `port` cause VM exit.
+    fn intercept(&mut self, port: u16) {
+        let bitmap = if port < 0x8000 {
+            &mut self.a
+        } else {
+            &mut self.b
+        };
+        let index = port & 0x7fff;
+        bitmap[usize::from(index / 8)] |= 1 << (index % 8);
+    }
+}
+
 /// The types of the control field.
 #[derive(Clone, Copy)]
 enum VmxControl {
@@ -719,6 +1235,119 @@ fn adjust_cr0() {
     cr0_write(new_cr0);
 }
 
+/// Returns the bits of the control register `cr`, which is either 0 or 4, that
+/// are fixed to 0 or 1 in VMX operation. CR0.PE and CR0.PG are not fixed when
+/// the unrestricted guest feature is enabled.
+///
+/// See: A.7 VMX-FIXED BITS IN CR0
+/// See: A.8 VMX-FIXED BITS IN CR4
+fn vmx_fixed_cr_bits(cr: u8) -> u64 {
+    let (fixed0, fixed1) = vmx_fixed_cr_values(cr);
+    fixed0 | (!fixed1 & u64::from(u32::MAX))
+}
+
+/// Returns `value` of the guest control register `cr`, which is either 0 or 4,
+/// with the VMX-fixed bits set or cleared, so that VM entry does not fail with
+/// the values captured in the snapshot, such as CR0 without NE or CR4 without
+/// VMXE.
+///
+/// See: 27.3.1.1 Checks on Guest Control Registers, Debug Registers, and MSRs
+fn adjust_guest_cr(cr: u8, value: u64) -> u64 {
+    let (fixed0, fixed1) = vmx_fixed_cr_values(cr);
+    (value | fixed0) & fixed1
+}
+
+/// Returns the values of the FIXED0 and FIXED1 MSRs for the control register
+/// `cr`, which is either 0 or 4, excluding CR0.PE and CR0.PG from FIXED0 when
+/// the unrestricted guest feature is enabled. See [`adjust_cr0`].
+fn vmx_fixed_cr_values(cr: u8) -> (u64, u64) {
+    if cr == 0 {
+        let mut fixed0 = rdmsr(x86::msr::IA32_VMX_CR0_FIXED0);
+        if vmread(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS)
+            & IA32_VMX_PROCBASED_CTLS2_UNRESTRICTED_GUEST_FLAG
+            != 0
+        {
+            fixed0 &= !(Cr0::CR0_PROTECTED_MODE | Cr0::CR0_ENABLE_PAGING).bits() as u64;
+        }
+        (fixed0, rdmsr(x86::msr::IA32_VMX_CR0_FIXED1))
+    } else {
+        (rdmsr(x86::msr::IA32_VMX_CR4_FIXED0), rdmsr(x86::msr::IA32_VMX_CR4_FIXED1))
+    }
+}
+
+/// Returns the IA-32e mode guest VM-entry control and the unrestricted guest
+/// secondary processor-based VM-execution control for the guest mode captured
+/// in `registers`.
+///
+/// VM entry requires CR0.PE and CR0.PG to be set, unless the unrestricted guest
+/// feature is enabled. Thus, the guest in real mode or protected mode without
+/// paging can only run with it. The IA-32e mode guest control is set only when
+/// the guest is in long mode.
+///
+/// See: 27.3.1.1 Checks on Guest Control Registers, Debug Registers, and MSRs
+fn guest_mode_controls(registers: &SnapshotRegisters) -> Result<(u64, u64), UnsupportedGuestMode> {
+    const IA32_VMX_ENTRY_CTLS_IA32E_MODE_GUEST_FLAG: u64 = 1 << 9;
+    const EFER_LMA: u64 = 1 << 10;
+
+    let ia32e_mode_guest = if registers.efer & EFER_LMA == 0 {
+        0
+    } else {
+        IA32_VMX_ENTRY_CTLS_IA32E_MODE_GUEST_FLAG
+    };
+    if Cr0::from_bits_truncate(registers.cr0 as usize)
+        .contains(Cr0::CR0_PROTECTED_MODE | Cr0::CR0_ENABLE_PAGING)
+    {
+        return Ok((ia32e_mode_guest, 0));
+    }
+    if adjust_vmx_control(
+        VmxControl::ProcessorBased2,
+        IA32_VMX_PROCBASED_CTLS2_UNRESTRICTED_GUEST_FLAG,
+    ) == 0
+    {
+        error!(
+            "Snapshot guest mode unsupported: CR0 {:#x} requires the unrestricted guest feature, which the processor does not support",
+            registers.cr0
+        );
+        return Err(UnsupportedGuestMode);
+    }
+    Ok((ia32e_mode_guest, IA32_VMX_PROCBASED_CTLS2_UNRESTRICTED_GUEST_FLAG))
+}
+
+/// Configures the guest segment registers for real mode, where segments are
+/// not described by the GDT. They are set up as they are after reset, with the
+/// bases derived from the selectors. TR still has to be a busy TSS, and LDTR is
+/// made unusable.
+///
+/// See: 27.3.1.2 Checks on Guest Segment Registers
+fn write_real_mode_segments(registers: &SnapshotRegisters) {
+    const ACCESS_RIGHTS_READ_WRITE_DATA: u32 = 0x93;
+    const ACCESS_RIGHTS_EXECUTE_READ_CODE: u32 = 0x9b;
+    const ACCESS_RIGHTS_BUSY_TSS: u32 = 0x8b;
+    const ACCESS_RIGHTS_UNUSABLE: u32 = 1 << 16;
+    const REAL_MODE_SEGMENT_LIMIT: u32 = 0xffff;
+
+    let segments = [
+        (registers.es, vmcs::guest::ES_BASE, vmcs::guest::ES_LIMIT, vmcs::guest::ES_ACCESS_RIGHTS),
+        (registers.cs, vmcs::guest::CS_BASE, vmcs::guest::CS_LIMIT, vmcs::guest::CS_ACCESS_RIGHTS),
+        (registers.ss, vmcs::guest::SS_BASE, vmcs::guest::SS_LIMIT, vmcs::guest::SS_ACCESS_RIGHTS),
+        (registers.ds, vmcs::guest::DS_BASE, vmcs::guest::DS_LIMIT, vmcs::guest::DS_ACCESS_RIGHTS),
+        (registers.fs, vmcs::guest::FS_BASE, vmcs::guest::FS_LIMIT, vmcs::guest::FS_ACCESS_RIGHTS),
+        (registers.gs, vmcs::guest::GS_BASE, vmcs::guest::GS_LIMIT, vmcs::guest::GS_ACCESS_RIGHTS),
+    ];
+    for (selector, base, limit, access_rights) in segments {
+        vmwrite(base, u64::from(selector) << 4);
+        vmwrite(limit, REAL_MODE_SEGMENT_LIMIT);
+        if base == vmcs::guest::CS_BASE {
+            vmwrite(access_rights, ACCESS_RIGHTS_EXECUTE_READ_CODE);
+        } else {
+            vmwrite(access_rights, ACCESS_RIGHTS_READ_WRITE_DATA);
+        }
+    }
+    vmwrite(vmcs::guest::TR_LIMIT, REAL_MODE_SEGMENT_LIMIT);
+    vmwrite(vmcs::guest::TR_ACCESS_RIGHTS, ACCESS_RIGHTS_BUSY_TSS);
+    vmwrite(vmcs::guest::LDTR_ACCESS_RIGHTS, ACCESS_RIGHTS_UNUSABLE);
+}
+
 /// Returns the access rights of the given segment for VMX.
 fn get_segment_access_right(table_base: u64, selector: u16) -> u32 {
     const VMX_SEGMENT_ACCESS_RIGHTS_UNUSABLE_FLAG: u32 = 1 << 16;
@@ -765,6 +1394,27 @@ fn vmptrst() -> *const Vmcs {
     unsafe { x86::bits64::vmx::vmptrst().unwrap() as *const Vmcs }
 }
 
+/// Updates DR6 for the #DB that caused the current VM exit. The processor does
+/// not update DR6 on #DB that causes VM exit, and saves the bits it would have
+/// set in the exit qualification instead. DR6 is not switched on VM entry and
+/// exit, so the register is the guest's.
+///
+/// See: 28.1 ARCHITECTURAL STATE BEFORE A VM EXIT
+///      Table 28-1. Exit Qualification for Debug Exceptions
+fn update_dr6_for_debug_exception() {
+    const DR6_INIT: u64 = 0xffff_0ff0;
+    const DR6_B0_TO_B3_BD_BS: u64 = 0b0110_0000_0000_1111;
+    const DR6_RTM: u64 = 1 << 16;
+
+    // Unlike the other bits, DR6.RTM is cleared to indicate the condition.
+    let qualification = vmread(vmcs::ro::EXIT_QUALIFICATION);
+    let mut dr6 = DR6_INIT | (qualification & DR6_B0_TO_B3_BD_BS);
+    if (qualification & DR6_RTM) != 0 {
+        dr6 &= !DR6_RTM;
+    }
+    dr6_write(dr6);
+}
+
 /// The wrapper of the VMREAD instruction. Returns zero on error.
 fn vmread(field: u32) -> u64 {
     // Safety: this project runs at CPL0.
@@ -807,15 +1457,62 @@ fn invept(invalidation: InveptType, eptp: u64) {
 /// See: 31.2 CONVENTIONS
 fn vm_succeed(flags: RFlags) -> Result<(), String> {
     if flags.contains(RFlags::FLAGS_ZF) {
-        // See: 31.4 VM INSTRUCTION ERROR NUMBERS
-        Err(format!("VmFailValid with {}", vmread(vmcs::ro::VM_INSTRUCTION_ERROR)))
+        let error = vmread(vmcs::ro::VM_INSTRUCTION_ERROR);
+        Err(format!("VmFailValid with {error} ({})", vm_instruction_error_name(error)))
     } else if flags.contains(RFlags::FLAGS_CF) {
-        Err("VmFailInvalid".to_string())
+        Err("VmFailInvalid (no current VMCS)".to_string())
     } else {
         Ok(())
     }
 }
 
+/// Returns the description of the VM-instruction error number `error`.
+///
+/// See: Table 31-1. VM-Instruction Error Numbers
+fn vm_instruction_error_name(error: u64) -> &'static str {
+    match error {
+        1 => "VMCALL executed in VMX root operation",
+        2 => "VMCLEAR with invalid physical address",
+        3 => "VMCLEAR with VMXON pointer",
+        4 => "VMLAUNCH with non-clear VMCS",
+        5 => "VMRESUME with non-launched VMCS",
+        6 => "VMRESUME after VMXOFF",
+        7 => "VM entry with invalid control field(s)",
+        8 => "VM entry with invalid host-state field(s)",
+        9 => "VMPTRLD with invalid physical address",
+        10 => "VMPTRLD with VMXON pointer",
+        11 => "VMPTRLD with incorrect VMCS revision identifier",
+        12 => "VMREAD/VMWRITE from/to unsupported VMCS component",
+        13 => "VMWRITE to read-only VMCS component",
+        15 => "VMXON executed in VMX root operation",
+        16 => "VM entry with invalid executive-VMCS pointer",
+        17 => "VM entry with non-launched executive VMCS",
+        18 => "VM entry with executive-VMCS pointer not VMXON pointer",
+        19 => "VMCALL with non-clear VMCS",
+        20 => "VMCALL with invalid VM-exit control fields",
+        22 => "VMCALL with incorrect MSEG revision identifier",
+        23 => "VMXOFF under dual-monitor treatment of SMIs and SMM",
+        24 => "VMCALL with invalid SMM-monitor features",
+        25 => "VM entry with invalid VM-execution control fields in executive VMCS",
+        26 => "VM entry with events blocked by MOV SS",
+        28 => "Invalid operand to INVEPT/INVVPID",
+        _ => "Unknown error",
+    }
+}
+
+/// Returns the description of the basic exit reason `reason` of VM exit due to
+/// VM-entry failure.
+///
+/// See: 27.8 VM-ENTRY FAILURES DURING OR AFTER LOADING GUEST STATE
+fn vm_entry_failure_name(reason: u16) -> &'static str {
+    match reason {
+        33 => "invalid guest state",
+        34 => "MSR loading",
+        41 => "machine-check event",
+        _ => "unknown",
+    }
+}
+
 impl fmt::Debug for Vmcs {
     #[rustfmt::skip]
     #[allow(clippy::too_many_lines)]
@@ -13,21 +13,41 @@
 //! stack.
 
 use crate::{
-    config::GUEST_EXEC_TIMEOUT_IN_TSC,
+    cmplog::{decode_compare, MAX_INSTRUCTION_LENGTH},
+    config::{
+        ABORT_ON_DATA_EXECUTION, ABORT_ON_SELF_MODIFYING_CODE, BREAK_ON_CRASH, EDGE_COVERAGE,
+        GUEST_PRINT_IO_PORT, HIT_COUNT_COVERAGE, INTERCEPTED_CR0_BITS, INTERCEPTED_CR4_BITS,
+        INTERCEPTED_MSRS, NO_WRITE_STACK_SIZE, REFLECTED_EXCEPTION_VECTORS,
+        SLOW_INPUT_THRESHOLD_PERCENT, STACK_OVERFLOW_DISTANCE, USE_LARGE_DIRTY_PAGES,
+        USE_LARGE_PAGES, VIRTUAL_TSC_INCREMENT,
+    },
+    corpus::{minimize_files, Corpus, InputFile},
+    emulator::{decode_instruction, EmulatedInstruction},
     global_state::GlobalState,
     hardware_vt::{
-        ExceptionQualification, GuestException, NestedPageFaultQualification, VmExitReason,
+        is_valid_xcr0, ExceptionQualification, GuestException, NestedPageFaultQualification,
+        NestedPagingStructureEntryType, ShutdownQualification, VmExitReason,
     },
+    logger::log_raw,
     mutation_engine::{resolve_page_from_input_data, MutatingInput, MutationEngine},
-    snapshot::resolve_page_from_snapshot,
+    patch::{PatchEntry, PatchKind},
+    repro::save_repro_bundle,
+    snapshot::{
+        resolve_large_page_from_snapshot, resolve_page_from_mmio, resolve_page_from_snapshot,
+    },
     stats::RunStats,
-    vm::Vm,
-    x86_instructions::rdtsc,
+    vm::{PoolExhausted, Vm},
+    x86_instructions::{bochs_breakpoint, cli, hlt, rdtsc},
     Page,
 };
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
 use core::sync::atomic::Ordering;
 use log::{debug, error, info, trace, warn};
-use x86::current::paging::BASE_PAGE_SHIFT;
+use x86::current::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE};
 
 /// Prepares a VM and enters the infinite fuzzing loop with the VM.
 ///
@@ -35,71 +55,452 @@ use x86::current::paging::BASE_PAGE_SHIFT;
 /// the hypervisor and VM, and executes the VM with the given corpus
 /// semi-indefinitely.
 pub(crate) fn start_hypervisor(global: &GlobalState) -> ! {
-    info!("Starting the hypervisor");
+    global.log_in_startup_order(|| info!("Starting the hypervisor"));
 
     // Create an instance of a VM, enable hardware-assisted virtualization, and
     // set up the hypervisor.
     let mut vm = Vm::new();
     vm.vt.enable();
     let nested_pml4_addr = vm.nested_pml4_addr() as u64;
-    vm.vt.initialize(nested_pml4_addr);
+    if vm
+        .vt
+        .initialize(nested_pml4_addr, &global.snapshot(0).registers)
+        .is_err()
+    {
+        halt();
+    }
 
     // Initialize the component that is responsible for selecting an input file
     // from the corpus and mutating it.
-    let mut mutation_engine = MutationEngine::new(global.corpus());
+    let mut mutation_engine = MutationEngine::new(global.corpus(), global.config());
+
+    // Take a slot to let other processors observe progress of this processor.
+    let core = global.register_core();
+
+    // Report the processor capabilities once on the first processor, for bug
+    // reports.
+    if core == 0 {
+        info!("Logical processors         : {}", global.number_of_cores());
+        vm.vt.report_capabilities();
+    }
+
+    // Execute-only pages are mapped as readable if the processor cannot map
+    // them otherwise, and only write access to them is detected.
+    if core == 0 && global.patch_set().has_execute_only_ranges() && !vm.vt.execute_only_supported()
+    {
+        warn!("Execute-only mapping is not supported. Falling back to read-execute");
+    }
+
+    // If requested, run the specified input file just once instead of fuzzing,
+    // or minimize it while it keeps crashing the same way.
+    if let Some(name) = global.replay_file_name() {
+        if cfg!(feature = "minimize_crash") {
+            minimize_crash(&mut vm, &mut mutation_engine, global, name);
+        }
+        replay(&mut vm, &mut mutation_engine, global, core, name);
+    }
+
+    // If requested, run every input file once to validate the snapshot and
+    // patch files instead of fuzzing.
+    if cfg!(feature = "dry_run") {
+        dry_run(&mut vm, &mut mutation_engine, global, core);
+    }
+
+    // If requested, minimize the corpus before any processor starts fuzzing.
+    // Only the first processor reaching here does this, and the others wait for
+    // its completion.
+    if cfg!(feature = "minimize") {
+        let () = global
+            .corpus_minimized
+            .call_once(|| minimize_corpus(&mut vm, &mut mutation_engine, global));
+    }
 
     // Enter the fuzzing loop, that is: running the VM from a snapshot until it
     // aborts, printing out the stats, reverting dirty pages and repeating those.
     info!("Entering the fuzzing loop🐇");
     let _ = global.active_thread_count.fetch_add(1, Ordering::SeqCst);
     loop {
-        // Run the VM.
-        let (stats, abort_reason) = start_vm(&mut vm, &mut mutation_engine, global);
+        // If another processor found a crash with `stop_on_crash`, stop here.
+        if global.stopped_on_crash() {
+            halt();
+        }
+
+        // Inject mutated input data into VM's memory, and run the VM. If no
+        // more input file is available, or the iteration or time limit is
+        // reached, fuzzing is complete.
+        if global.budget_reached(core)
+            || !mutation_engine.map_and_mutate_input(
+                global.corpus(),
+                &global.active_thread_count,
+                &global.comparands(),
+            )
+        {
+            global.report_final();
+            halt();
+        }
+        // Switch the snapshot every `SNAPSHOT_SWITCH_INTERVAL` iterations of
+        // this loop.
+        global.start_iteration(core, &mutation_engine.current_input);
+        let snapshot_index = global.snapshot_index_for(vm.iter_count);
+        vm.iter_count += 1;
+        let (stats, abort_reason) = start_vm(&mut vm, &mutation_engine, global, snapshot_index);
+        global.end_iteration(core);
 
         // The VM has aborted. Update overall stats, report them and the reason
         // of abort. There are two types of stats: stats about this particular
         // fuzzing iteration (`stats`) and stats about all fuzzing iterations
         // including ones that ran by other logical processors (within `global`).
-        let iter_count = global.update_stats(&stats);
+        let iter_count = global.update_stats(core, &stats);
         stats.report(global, vm.used_dirty_page_count(), iter_count);
-        abort_reason.report(&mutation_engine.current_input);
+        abort_reason.report(&mutation_engine.current_input, global.corpus());
+
+        // If this is a crash never seen before, save files to reproduce it.
+        if abort_reason.is_crash() {
+            let crash_id = crash_id(&abort_reason, &mut vm);
+            if global.record_crash(&crash_id) {
+                let input = mutation_engine.data();
+                if let Err(err) = save_repro_bundle(global, vm.snapshot_index, &crash_id, &input) {
+                    error!("Failed to save {crash_id}: {err:#?}");
+                }
+            }
+
+            // If requested, stop fuzzing on all processors with the guest state
+            // at the crash. Only the first processor finding a crash reports it.
+            if global.config().stop_on_crash {
+                if global.stop_on_crash() {
+                    warn!("Stopping on {crash_id}: {:?}", mutation_engine.current_input);
+                    warn!("{:#x?}", vm.vt);
+                    global.report_final();
+                }
+                halt();
+            }
+        }
+
+        // Record how fast the input file runs without mutation, for the power
+        // schedule.
+        if !mutation_engine.current_input.is_mutated() {
+            mutation_engine.record_baseline(global.corpus(), stats.max_guest_tsc);
+        }
 
         // Add the current input file to the corpus if it caused execution of
-        // new basic block(s).
-        if !stats.newly_executed_basic_blks.is_empty() && mutation_engine.current_input.is_mutated()
+        // new edge(s) between basic blocks, moved basic block(s) into new hit
+        // count buckets, or nearly caused hang. Slow inputs without new
+        // coverage are kept only up to one per parent.
+        if stats.slow_count != 0 {
+            debug!("Slow input : {:?}", mutation_engine.current_input);
+        }
+        let new_coverage = stats.newly_executed_edge_count + stats.newly_reached_bucket_count;
+        if (new_coverage != 0 || stats.slow_count != 0)
+            && mutation_engine.current_input.is_mutated()
         {
-            global
-                .corpus()
-                .add_file(mutation_engine.current_input.data());
+            mutation_engine.record_new_coverage(global.corpus(), new_coverage);
+            let mut input = mutation_engine.data();
+            input.name = global.corpus().unique_name(&input.name);
+            if new_coverage == 0 && !global.corpus().claim_slow_input(&input) {
+                continue;
+            }
+            if !stats.newly_executed_basic_blks.is_empty() {
+                // Tie the new basic blocks to the input that found them. The
+                // name is made up of the names of its ancestors, mutation
+                // counts, which tell the mutation that reached them, and
+                // sequence numbers.
+                debug!("SEED {} -> NEWBB {:x?}", input.name, stats.newly_executed_basic_blks);
+            }
+            input.new_coverage = new_coverage;
+            input.exec_tsc = stats.max_guest_tsc;
+            global.corpus().add_file(&mut global.volume(), input);
         }
     }
 }
 
+/// Runs the input file `name` in the corpus exactly once without mutation,
+/// reports the result with full tracing, and halts the processor.
+///
+/// This is for triaging an input file found by fuzzing. Only the current
+/// processor runs this.
+fn replay(
+    vm: &mut Vm,
+    mutation_engine: &mut MutationEngine,
+    global: &GlobalState,
+    core: usize,
+    name: &str,
+) -> ! {
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("Replaying {name:?}");
+
+    let Some(input) = global.corpus().find_file(name) else {
+        error!("{name:?} is not found in the corpus");
+        halt();
+    };
+    mutation_engine.map_input(input, global.corpus().data_gva());
+    let (stats, abort_reason) = start_vm(vm, mutation_engine, global, 0);
+
+    let iter_count = global.update_stats(core, &stats);
+    stats.report(global, vm.used_dirty_page_count(), iter_count);
+    info!("{:#x?}", vm.vt);
+    info!("Aborted with {abort_reason:?}");
+    abort_reason.report(&mutation_engine.current_input, global.corpus());
+    halt();
+}
+
+/// Minimizes the input file `name` in the corpus by repeatedly removing and
+/// then zeroing chunks of it, keeping changes that preserve its crash ID, saves
+/// the result as a bundle to reproduce the crash, and halts the processor.
+///
+/// Chunks start at half of the input size and are halved down to 1 byte, and
+/// rounds are repeated until neither removes nor zeroes any byte. The crash ID
+/// is compared instead of the exact guest state, so that the input can keep
+/// shrinking even if it changes, for example, register values at the crash.
+/// Only the current processor runs this.
+fn minimize_crash(
+    vm: &mut Vm,
+    mutation_engine: &mut MutationEngine,
+    global: &GlobalState,
+    name: &str,
+) -> ! {
+    info!("Minimizing {name:?}");
+
+    let Some(input) = global.corpus().find_file(name) else {
+        error!("{name:?} is not found in the corpus");
+        halt();
+    };
+    let mut run = |data: &[u8]| {
+        let input = InputFile {
+            data: data.to_vec(),
+            name: name.to_string(),
+            ..Default::default()
+        };
+        mutation_engine.map_input(input, global.corpus().data_gva());
+        let (_, abort_reason) = start_vm(vm, mutation_engine, global, 0);
+        abort_reason.is_crash().then(|| crash_id(&abort_reason, vm))
+    };
+
+    let mut data = input.data;
+    let Some(original_crash_id) = run(&data) else {
+        error!("{name:?} does not crash");
+        halt();
+    };
+    info!("Crash ID: {original_crash_id}, size: {}", data.len());
+
+    let mut run_count = 1;
+    loop {
+        let mut reduced = false;
+
+        // Remove chunks, keeping at least 1 byte.
+        let mut chunk_size = data.len() / 2;
+        while chunk_size != 0 {
+            let mut offset = 0;
+            while offset < data.len() {
+                let end = (offset + chunk_size).min(data.len());
+                if end - offset == data.len() {
+                    break;
+                }
+                let candidate = [&data[..offset], &data[end..]].concat();
+                run_count += 1;
+                if run(&candidate).as_ref() == Some(&original_crash_id) {
+                    debug!("Removed {:#x} bytes at {offset:#x}", end - offset);
+                    data = candidate;
+                    reduced = true;
+                } else {
+                    offset = end;
+                }
+            }
+            chunk_size /= 2;
+        }
+
+        // Zero chunks that are not zero yet.
+        let mut chunk_size = data.len().div_ceil(2);
+        while chunk_size != 0 {
+            for offset in (0..data.len()).step_by(chunk_size) {
+                let end = (offset + chunk_size).min(data.len());
+                if data[offset..end].iter().all(|&byte| byte == 0) {
+                    continue;
+                }
+                let mut candidate = data.clone();
+                candidate[offset..end].fill(0);
+                run_count += 1;
+                if run(&candidate).as_ref() == Some(&original_crash_id) {
+                    debug!("Zeroed {:#x} bytes at {offset:#x}", end - offset);
+                    data = candidate;
+                    reduced = true;
+                }
+            }
+            chunk_size /= 2;
+        }
+
+        if !reduced {
+            break;
+        }
+    }
+    info!("Minimized {name:?} to {} bytes with {run_count} runs", data.len());
+
+    let input = InputFile {
+        data,
+        name: format!("{name}.min"),
+        ..Default::default()
+    };
+    let bundle_id = format!("{original_crash_id}_min");
+    if let Err(err) = save_repro_bundle(global, vm.snapshot_index, &bundle_id, &input) {
+        error!("Failed to save {bundle_id}: {err:#?}");
+    }
+    halt();
+}
+
+/// Returns the ID of the crash `abort_reason` to dedupe crashes, which is made
+/// up of the reason of abort and the guest RIP.
+fn crash_id(abort_reason: &AbortReason, vm: &mut Vm) -> String {
+    format!("{abort_reason:?}_{:x}", vm.vt.registers_mut().rip)
+}
+
+/// Runs every input file in the corpus once without mutation, reports ones that
+/// did not reach the end marker, and halts the processor.
+///
+/// This is for validating the snapshot and patch files, eg, a wrong address of
+/// input data or a patch, before fuzzing. Only the current processor runs this.
+fn dry_run(
+    vm: &mut Vm,
+    mutation_engine: &mut MutationEngine,
+    global: &GlobalState,
+    core: usize,
+) -> ! {
+    info!("Running each input file once without mutation");
+
+    let files = global.corpus().clone_files();
+    let file_count = files.len();
+    let mut failure_count = 0;
+    for input in files {
+        let name = input.name.clone();
+        mutation_engine.map_input(input, global.corpus().data_gva());
+        let (stats, abort_reason) = start_vm(vm, mutation_engine, global, 0);
+        let _ = global.update_stats(core, &stats);
+        if matches!(abort_reason, AbortReason::EndMarker) {
+            debug!("{name:?} reached the end marker");
+        } else {
+            failure_count += 1;
+            let rip = vm.vt.registers_mut().rip;
+            warn!("{name:?} aborted with {abort_reason:?} at {rip:#x}");
+        }
+    }
+
+    if failure_count == 0 {
+        info!("All {file_count} input files reached the end marker");
+    } else {
+        error!("{failure_count} of {file_count} input files did not reach the end marker");
+    }
+
+    // Emit the marker `cargo xtask --dry-run` looks for to determine its exit
+    // code.
+    let result = if failure_count == 0 { "OK" } else { "FAIL" };
+    log_raw(format_args!("REPLAY RESULT: {result}"));
+    halt();
+}
+
+/// Runs every input file in the corpus once without mutation, and keeps only
+/// the minimal subset of them that covers all basic blocks executed by any of
+/// them.
+fn minimize_corpus(vm: &mut Vm, mutation_engine: &mut MutationEngine, global: &GlobalState) {
+    info!("Minimizing the corpus");
+
+    // Keep the patches while running input files, so that basic blocks
+    // executed by each input file are recorded even if another input file
+    // executed them before. The hit counts are only used for minimization, and
+    // not recorded into the global hit count buckets.
+    vm.keep_patches = true;
+    vm.minimizing = true;
+    let files = global.corpus().clone_files();
+    let file_count = files.len();
+    let coverage = files
+        .into_iter()
+        .map(|input| {
+            mutation_engine.map_input(input.clone(), global.corpus().data_gva());
+            let _unused = start_vm(vm, mutation_engine, global, 0);
+            (input, vm.hit_counts.keys().copied().collect())
+        })
+        .collect();
+    vm.keep_patches = HIT_COUNT_COVERAGE || EDGE_COVERAGE;
+    vm.minimizing = false;
+    mutation_engine.current_input = MutatingInput::default();
+
+    let files = minimize_files(coverage);
+    if files.is_empty() {
+        warn!("No coverage is observed. Keeping the corpus as-is");
+        return;
+    }
+    info!("Minimized the corpus from {file_count} to {} files", files.len());
+    global.corpus().replace_files(files);
+}
+
+/// Stops execution of the current processor.
+fn halt() -> ! {
+    loop {
+        cli();
+        hlt();
+    }
+}
+
 /// Runs a fuzzing iteration and returns stats and a reason of the end of the
 /// iteration.
 ///
-/// This function resets the VM based on the snapshot, mutates input data,
-/// and runs the VM until it encounters one of abort conditions.
+/// This function resets the VM based on the snapshot specified by
+/// `snapshot_index`, makes the VM use the input data already mapped, and runs
+/// the VM until it encounters one of abort conditions.
+#[allow(clippy::too_many_lines)]
 fn start_vm(
     vm: &mut Vm,
-    mutation_engine: &mut MutationEngine,
+    mutation_engine: &MutationEngine,
     global: &GlobalState,
+    snapshot_index: usize,
 ) -> (RunStats, AbortReason) {
     // Configure the VM based on the snapshot. Memory is paged-in from snapshot
     // on nested page fault. `revert_dirty_memory` only reverts pages that are
     // already paged in AND modified by the guest in the previous iteration.
+    // This is measured separately as it can dominate the time spent in the
+    // host for targets that write to many pages.
+    let stats = &mut RunStats::new();
     vm.revert_dirty_memory();
-    vm.vt.revert_registers(&global.snapshot());
-
-    // Inject mutated input data into VM's memory.
-    mutation_engine.map_and_mutate_input(global.corpus(), &global.active_thread_count);
+    stats.revert_tsc = rdtsc() - stats.start_tsc;
 
-    // Update VM's registers to point to the mutated input data.
+    // Translations built in previous iterations persist. If they have used up
+    // all nested paging structures, start over, instead of aborting every later
+    // iteration that accesses memory not mapped yet. Also start over when
+    // switching to another snapshot, as they map the previous snapshot.
+    if vm.nps_exhausted() || snapshot_index != vm.snapshot_index {
+        debug!("Resetting nested paging structures");
+        vm.reset();
+        vm.snapshot_index = snapshot_index;
+    }
+    vm.exec_timeout_in_tsc = global
+        .config()
+        .exec_timeout_in_tsc(mutation_engine.current_input.size());
     vm.vt
-        .adjust_registers(global.corpus().data_gva(), mutation_engine.current_input.size());
+        .revert_registers(&global.snapshot(snapshot_index), vm.exec_timeout_in_tsc);
+    vm.prev_basic_blk = 0;
+    vm.virtual_tsc = global.snapshot(snapshot_index).registers.tsc;
+    vm.msr_values = [0; INTERCEPTED_MSRS.len()];
+    vm.hit_counts.clear();
+    vm.stepping_over = None;
+    vm.guest_output.clear();
 
-    // Run the VM until it reaches one of abort conditions.
-    let stats = &mut RunStats::new();
+    // Set registers the harness expects, then update VM's registers to point
+    // to the mutated input data, or write it over the snapshot memory if
+    // requested.
+    global
+        .snapshot(snapshot_index)
+        .harness
+        .seed_registers(vm.vt.registers_mut());
+    if mutation_engine.target_data().is_none() {
+        vm.vt.adjust_registers(
+            global.corpus().data_gva(),
+            mutation_engine.current_input.size(),
+            &global.snapshot(snapshot_index).harness,
+        );
+    } else if let Err(reason) = write_input_to_target(vm, global, mutation_engine) {
+        return (RunStats::new(), reason);
+    }
+
+    // Run the VM until it reaches one of abort conditions. Time spent so far
+    // is also in the host.
+    stats.host_spent_tsc = rdtsc() - stats.start_tsc;
     loop {
         // Run the VM until VM exit happens.
         let exit_reason = vm.vt.run();
@@ -107,37 +508,85 @@ fn start_vm(
         // VM exit happened and execution of the VM is suspended. The hypervisor
         // needs to handle VM exit according to `exit_reason`.
         let host_start_tsc = rdtsc();
+        stats.vmexit_counts[exit_reason.index()] += 1;
         let exit_handling_result = match exit_reason {
             VmExitReason::NestedPageFault(qualification) => {
                 handle_nested_page_fault(vm, global, mutation_engine, &qualification)
             }
             VmExitReason::Exception(qualification) => {
-                handle_interrupt_or_exception(global, stats, &qualification)
+                handle_interrupt_or_exception(vm, global, stats, &qualification)
+            }
+            VmExitReason::ExternalInterruptOrPause => {
+                handle_external_interrupt_or_pause(vm, global, stats)
+            }
+            VmExitReason::TimerExpiration => {
+                // Run the watchdog as on external interrupt.
+                global.check_stuck_cores();
+                handle_timer_expiration(stats)
             }
-            VmExitReason::ExternalInterruptOrPause => handle_external_interrupt_or_pause(stats),
-            VmExitReason::TimerExpiration => handle_timer_expiration(stats),
-            VmExitReason::Shutdown(exit_code) => VmExitResult::Panic(exit_code),
+            VmExitReason::Hypercall { nr, arg } => handle_hypercall(vm, global, stats, nr, arg),
+            VmExitReason::Cpuid { leaf, subleaf } => handle_cpuid(vm, leaf, subleaf),
+            VmExitReason::MsrAccess { msr, write, value } => {
+                handle_msr_access(vm, msr, write, value)
+            }
+            VmExitReason::IoAccess {
+                port,
+                write,
+                size,
+                value,
+                length,
+            } => handle_io_access(vm, port, write, size, value, length),
+            VmExitReason::Rdtsc { rdtscp } => handle_rdtsc(vm, rdtscp),
+            VmExitReason::Xsetbv { xcr, value } => handle_xsetbv(vm, xcr, value),
+            VmExitReason::Invlpg { length } => skip_instruction(vm, length),
+            // The length of `WBINVD` (0f 09), and `MONITOR` (0f 01 c8) and
+            // `MWAIT` (0f 01 c9).
+            VmExitReason::Wbinvd => skip_instruction(vm, 2),
+            VmExitReason::MonitorOrMwait => skip_instruction(vm, 3),
+            VmExitReason::CrAccess {
+                cr,
+                current,
+                value,
+                length,
+            } => handle_cr_access(vm, cr, current, value, length),
+            VmExitReason::Shutdown(qualification) => handle_shutdown(vm, &qualification),
+            VmExitReason::Instruction(exit_code) => handle_instruction(vm, exit_code),
             VmExitReason::Unexpected(exit_code) => {
                 error!("🐈 Unhandled VM exit {exit_code:#x}");
                 VmExitResult::AbortVm(AbortReason::UnhandledVmExit)
             }
         };
+        let host_spent_tsc = rdtsc() - host_start_tsc;
         stats.vmexit_count += 1;
-        stats.host_spent_tsc += rdtsc() - host_start_tsc;
+        stats.host_spent_tsc += host_spent_tsc;
+        stats.record_exit_latency(host_spent_tsc);
 
         // Either resume the VM, abort the VM, or panic the hypervisor according
         // to the result of VM exit handling.
         match exit_handling_result {
             VmExitResult::ResumeVm => continue,
             VmExitResult::AbortVm(reason) => {
-                // An abort condition reached. Return the stats and reason.
+                // An abort condition reached. Log what the guest printed but
+                // did not terminate with a newline, as it may explain the abort.
+                vm.flush_guest_output();
+
+                // If requested, break into the Bochs debugger on crash before
+                // the guest state is discarded.
+                if BREAK_ON_CRASH && reason.is_crash() {
+                    bochs_breakpoint();
+                }
+
+                // Return the stats and reason.
                 stats.total_tsc = rdtsc() - stats.start_tsc;
+                stats.max_guest_tsc = stats.total_tsc - stats.host_spent_tsc;
+                if stats.hang_count == 0 && is_slow(vm, stats.max_guest_tsc) {
+                    stats.slow_count = 1;
+                }
+                if vm.count_hits && !vm.minimizing {
+                    stats.newly_reached_bucket_count = global.record_hit_counts(&vm.hit_counts);
+                }
                 return (stats.clone(), reason);
             }
-            VmExitResult::Panic(exit_code) => {
-                error!("{:#x?}", vm.vt);
-                panic!("🐛 Non continuable VM exit {exit_code:#x}");
-            }
         }
     }
 }
@@ -155,8 +604,9 @@ fn start_vm(
 ///    further write access within this iteration. At the end of iteration, all
 ///    "dirty" pages are discarded with [`Vm::revert_dirty_memory`].
 /// 3. The VM accesses memory that is not captured in the snapshot. This is
-///    possible and common because of MMIO. In this situation,
-///    [`resolve_pa_for_gpa`] fails, and this function returns
+///    possible and common because of MMIO. If the GPA is within the MMIO ranges
+///    captured in the snapshot, the GPA is backed by a zero-filled page.
+///    Otherwise, [`resolve_pa_for_gpa`] fails, and this function returns
 ///    [`VmExitResult::AbortVm`] to abort the VM. This is the most common reason
 ///    of aborting the VM.
 fn handle_nested_page_fault(
@@ -176,29 +626,102 @@ fn handle_nested_page_fault(
     // 2. If the GPA is outside the snapshot but within the input data pages, the
     //    GPA should be backed by the input data pages.
     let gpa = qualification.gpa as usize;
-    let pa = match resolve_pa_for_gpa(gpa, mutation_engine, global) {
+    let pa = match resolve_pa_for_gpa(vm, gpa, mutation_engine, global) {
         Ok(pa) => pa,
         Err(err) => return err,
     };
 
+    // Pages marked as execute-only are only ever fetched. Reading from or
+    // writing to them, eg, through a wild pointer, is likely a bug. This is
+    // detected on the first access of any type, and on later read and write
+    // access if the execute-only mapping is supported.
+    let execute_only = global
+        .patch_set()
+        .is_execute_only(gpa >> BASE_PAGE_SHIFT, 1);
+    if execute_only && !qualification.execute_access {
+        return VmExitResult::AbortVm(AbortReason::ExecuteOnlyViolation);
+    }
+
+    // Fetching an instruction from a page that is already mapped can only fail
+    // when the page is a non-executable dirty page, ie, the guest jumped into
+    // data it wrote, such as the stack or a buffer filled with input data.
+    if ABORT_ON_DATA_EXECUTION && qualification.execute_access && !qualification.missing_translation
+    {
+        return VmExitResult::AbortVm(AbortReason::ExecuteData);
+    }
+
     // If this VM exit is due to missing GPA -> PA translation, build GPA -> PA
     // translation. Note that the PA resolved by `resolve_pa_for_gpa` is either
     // in the snapshot or an input file, and contents of the snapshot is shared
     // across all VMs. VMs should never be able to modify that, or changes made
     // by one VM would be visible from other VMs. We enforces this restriction
     // via copy-on-write mechanism (see below).
+    //
+    // If the whole 2MB region containing the GPA is captured in the snapshot,
+    // map it with a single large page to reduce later nested page faults.
+    //
+    // Running out of preallocated nested paging structures warrants aborting
+    // the VM.
     if qualification.missing_translation {
-        vm.build_translation(gpa, pa);
+        let entry_type = if execute_only {
+            NestedPagingStructureEntryType::XOnly
+        } else {
+            NestedPagingStructureEntryType::RxWriteBack
+        };
+        let large_page = if USE_LARGE_PAGES && vm.vt.large_page_supported() {
+            resolve_large_page_from_snapshot(global, vm.snapshot_index, gpa >> BASE_PAGE_SHIFT)
+        } else {
+            None
+        };
+        let result = match large_page {
+            Some(large_page) => vm
+                .build_large_translation(gpa, large_page)
+                .and_then(|mapped| {
+                    if mapped {
+                        Ok(())
+                    } else {
+                        vm.build_translation(gpa, pa, entry_type)
+                    }
+                }),
+            None => vm.build_translation(gpa, pa, entry_type),
+        };
+        if let Err(exhausted) = result {
+            return abort_on_pool_exhaustion(exhausted);
+        }
     }
 
-    // If this is a write memory access, trigger copy-on-write. That is, with
-    // `copy_on_write`, update GPA -> PA translation to map the GPA to one of
-    // preallocated dirty pages instead of a snapshot or an input file, `pa`.
+    // If this is a write memory access, trigger copy-on-write. Instruction
+    // fetches and data reads never need it, as the translation built above
+    // suffices for them. That is, with `copy_on_write`, update GPA -> PA
+    // translation to map the GPA to one of preallocated dirty pages instead of
+    // a snapshot or an input file, `pa`.
     // Then, copy current contents of memory at `pa` to the new dirty page. This
     // effectively isolate the effect of memory write into this current guest.
-    // Failure of copy-on-write warrants aborting the VM.
-    if qualification.write_access && !vm.copy_on_write(gpa, pa) {
-        return VmExitResult::AbortVm(AbortReason::ExcessiveMemoryWrite);
+    // Failure of copy-on-write, or modifying more pages than
+    // `Config::dirty_page_soft_limit`, warrants aborting the VM.
+    //
+    // Writing to a page containing patches means the guest modifies its own
+    // code, which may also overwrite the patches. Writing to anywhere but the
+    // stack is unexpected if so configured.
+    if qualification.write_access {
+        if ABORT_ON_SELF_MODIFYING_CODE && global.patch_set().is_patched(gpa >> BASE_PAGE_SHIFT) {
+            return VmExitResult::AbortVm(AbortReason::SelfModifyingCode);
+        }
+        if global.config().no_write_expected && !is_stack(vm, global, gpa as u64) {
+            return VmExitResult::AbortVm(AbortReason::UnexpectedWrite);
+        }
+        let entry_type = dirty_data_page_type(vm, global, gpa);
+        if let Err(exhausted) = vm.copy_on_write(gpa, pa, entry_type) {
+            return abort_on_pool_exhaustion(exhausted);
+        }
+        let soft_limit = global.config().dirty_page_soft_limit;
+        if soft_limit != 0 && vm.used_dirty_page_count() as u64 > soft_limit {
+            info!(
+                "Dirty page soft limit {soft_limit} exceeded ({} pages)",
+                vm.used_dirty_page_count()
+            );
+            return VmExitResult::AbortVm(AbortReason::DirtyPageLimitExceeded);
+        }
     }
 
     // Since we changed nested paging structure entries, cache invalidation may be
@@ -207,11 +730,61 @@ fn handle_nested_page_fault(
     VmExitResult::ResumeVm
 }
 
+/// Checks whether `gpa` is within [`NO_WRITE_STACK_SIZE`] bytes below RSP the
+/// iteration started with, or in the same page as it.
+fn is_stack(vm: &Vm, global: &GlobalState, gpa: u64) -> bool {
+    // UEFI runs with identity mapping, so RSP is also the GPA.
+    let rsp = global.snapshot(vm.snapshot_index).entry_rsp();
+    let stack_top = (rsp & !(BASE_PAGE_SIZE as u64 - 1)) + BASE_PAGE_SIZE as u64;
+    (rsp.saturating_sub(NO_WRITE_STACK_SIZE)..stack_top).contains(&gpa)
+}
+
+/// Returns the type of the nested paging structure entry to map a page the
+/// guest writes data to at `gpa`, which is made non-executable if
+/// [`ABORT_ON_DATA_EXECUTION`] is enabled. Pages containing patches, and 2MB
+/// pages that would be copied as a whole, are kept executable, as they contain
+/// code, and making them non-executable would abort the VM when it executes
+/// code next to data it writes to.
+fn dirty_data_page_type(
+    vm: &mut Vm,
+    global: &GlobalState,
+    gpa: usize,
+) -> NestedPagingStructureEntryType {
+    if ABORT_ON_DATA_EXECUTION
+        && !global.patch_set().is_patched(gpa >> BASE_PAGE_SHIFT)
+        && !(USE_LARGE_DIRTY_PAGES && vm.is_mapped_with_large_page(gpa))
+    {
+        NestedPagingStructureEntryType::RwWriteBack
+    } else {
+        NestedPagingStructureEntryType::RwxWriteBack
+    }
+}
+
+/// Aborts the VM as it has used up preallocated resources to map or modify
+/// memory.
+fn abort_on_pool_exhaustion(exhausted: PoolExhausted) -> VmExitResult {
+    VmExitResult::AbortVm(pool_exhaustion_reason(exhausted))
+}
+
+/// Returns the reason of abort for `exhausted`. Running out of nested paging
+/// structures is not a problem of the input, and they are reset by
+/// [`start_vm`] in the next iteration. Running out of dirty pages means the
+/// input made the VM modify too much memory.
+fn pool_exhaustion_reason(exhausted: PoolExhausted) -> AbortReason {
+    debug!("{exhausted:?} exhausted");
+    match exhausted {
+        PoolExhausted::NestedPagingStructures => AbortReason::NestedPagingExhausted,
+        PoolExhausted::DirtyPages => AbortReason::ExcessiveMemoryWrite,
+    }
+}
+
 /// Returns the physical address that backs the GPA specified by `gpa`.
 ///
 /// This function checks if the GPA is within the snapshot or the input data
-/// pages. If so, returns a PA within those. Otherwise, returns [`Err`].
+/// pages. If so, returns a PA within those. If the GPA is within the MMIO
+/// ranges, returns the PA of a zero-filled page. Otherwise, returns [`Err`].
 fn resolve_pa_for_gpa(
+    vm: &Vm,
     gpa: usize,
     mutation_engine: &MutationEngine,
     global: &GlobalState,
@@ -220,76 +793,693 @@ fn resolve_pa_for_gpa(
 
     // If the GPA being accessed is captured within the snapshot, resolve the
     // page from the snapshot. If not, check if it is within the input data pages.
-    if let Some(page) = resolve_page_from_snapshot(global, pfn) {
+    // Failure to read the snapshot file aborts the VM.
+    if let Some(page) = resolve_page_from_snapshot(global, vm.snapshot_index, pfn) {
         Ok(page)
+    } else if global.snapshot(vm.snapshot_index).contains(pfn) {
+        Err(VmExitResult::AbortVm(AbortReason::InvalidPageAccess))
     } else if let Some(page) = resolve_page_from_input_data(global, pfn, mutation_engine) {
         Ok(page)
+    } else if let Some(page) = resolve_page_from_mmio(global, vm.snapshot_index, pfn) {
+        // Access to known MMIO. Reading it returns zero, and writing to it is
+        // handled with copy-on-write as if it were normal memory.
+        Ok(page)
     } else if pfn == 0 {
         Err(VmExitResult::AbortVm(AbortReason::NullPageAccess))
     } else if pfn == 0xf_ffff_ffff_ffff {
         Err(VmExitResult::AbortVm(AbortReason::NegativePageAccess))
     } else {
-        // Access to the outside of any guest physical memory ranges. This can be
-        // normal due to MMIO.
-        //
-        // NOTE: We should detect if this is actually within MMIO regions or random
-        // memory access as a result of triggering a bug. We could do that by capturing
-        // MMIO physical memory ranges within the snapshot, although enumerating those
-        // ranges most likely require platform specific API calls.
+        // Access to the outside of any guest physical memory ranges. This can
+        // still be normal due to MMIO, if the snapshot file does not capture
+        // MMIO ranges.
         Err(VmExitResult::AbortVm(AbortReason::InvalidPageAccess))
     }
 }
 
 /// Handles VM exit due to exceptions happened in the VM.
 ///
-/// Those can happen because of our patch (eg, 0xCC) or a bug discovered by
-/// fuzzing. This function determines the cause and recovers or aborts the VM.
+/// Those can happen because of our patch (eg, 0xCC), single-stepping over the
+/// patch, or a bug discovered by fuzzing. This function determines the cause
+/// and recovers or aborts the VM.
 fn handle_interrupt_or_exception(
+    vm: &mut Vm,
     global: &GlobalState,
     stats: &mut RunStats,
     qualification: &ExceptionQualification,
 ) -> VmExitResult {
+    // If this is #DB while stepping over a patch, the original instruction has
+    // been executed. Re-apply the patch and resume the VM. Otherwise, #DB is
+    // originated by the guest, and handled below.
+    if qualification.exception_code == GuestException::Debug {
+        if let Some(entry) = vm
+            .stepping_over
+            .take()
+            .and_then(|address| global.patch_set().find(address))
+        {
+            if let Err(exhausted) = write_patch_to_vm(vm, global, entry, true) {
+                return abort_on_pool_exhaustion(exhausted);
+            }
+            return VmExitResult::ResumeVm;
+        }
+    }
+
     match global.patch_set().find(qualification.rip) {
         // There is a patch entry for RIP.
         Some(entry) => match qualification.exception_code {
-            // If this is #BP, the exception is because of our coverage tracking
-            // patch. Revert the patch, increase coverage, and resume the VM.
-            GuestException::BreakPoint => {
-                entry.revert(global.snapshot_mut().memory.as_mut());
-                stats.newly_executed_basic_blks.push(qualification.rip);
-                VmExitResult::ResumeVm
-            }
+            // If this is #BP, the exception is because of our patch. The meaning
+            // depends on the kind of the patch.
+            GuestException::BreakPoint => match entry.kind() {
+                PatchKind::Breakpoint => handle_coverage_breakpoint(vm, global, stats, entry),
+                PatchKind::EndMarker => VmExitResult::AbortVm(AbortReason::EndMarker),
+                PatchKind::Abort => VmExitResult::AbortVm(AbortReason::AbortMarker),
+                PatchKind::Compare => handle_compare_breakpoint(vm, global, entry),
+                PatchKind::Profile => handle_profile_breakpoint(vm, global, entry),
+            },
             // If this is #UD, it is our end marker. Abort the VM. This is the most
             // common abort reason.
             GuestException::InvalidOpcode => VmExitResult::AbortVm(AbortReason::EndMarker),
             // If this is #PF, it may be a bug found by fuzzing. Abort the VM.
-            GuestException::PageFault => VmExitResult::AbortVm(AbortReason::UnexpectedPageFault),
+            GuestException::PageFault => {
+                VmExitResult::AbortVm(page_fault_reason(vm, qualification))
+            }
+            // If this is #DB, it is not because of single-stepping. Abort the VM.
+            GuestException::Debug => VmExitResult::AbortVm(AbortReason::UnexpectedDebugException),
+            // If this is #GP or #DE, it may be a bug found by fuzzing. Abort the
+            // VM.
+            GuestException::GeneralProtection => {
+                VmExitResult::AbortVm(AbortReason::GeneralProtectionFault)
+            }
+            GuestException::DivideError => VmExitResult::AbortVm(AbortReason::DivideError),
         },
 
         // There is no patch entry for RIP. Exception is not because of the patch.
-        // Abort the VM.
+        // Deliver it to the guest if configured so.
+        None if REFLECTED_EXCEPTION_VECTORS.contains(&qualification.exception_code.vector()) => {
+            vm.vt
+                .inject_event(qualification.exception_code.vector(), qualification.error_code);
+            VmExitResult::ResumeVm
+        }
+
+        // Otherwise, abort the VM.
         None => match qualification.exception_code {
+            GuestException::Debug => VmExitResult::AbortVm(AbortReason::UnexpectedDebugException),
             GuestException::BreakPoint => VmExitResult::AbortVm(AbortReason::UnexpectedBreakpoint),
             GuestException::InvalidOpcode => VmExitResult::AbortVm(AbortReason::InvalidInstruction),
-            GuestException::PageFault => VmExitResult::AbortVm(AbortReason::UnexpectedPageFault),
+            GuestException::PageFault => {
+                VmExitResult::AbortVm(page_fault_reason(vm, qualification))
+            }
+            GuestException::GeneralProtection => {
+                VmExitResult::AbortVm(AbortReason::GeneralProtectionFault)
+            }
+            GuestException::DivideError => VmExitResult::AbortVm(AbortReason::DivideError),
         },
     }
 }
 
+/// Classifies #PF that aborts the VM. #PF at or up to
+/// [`STACK_OVERFLOW_DISTANCE`] bytes below guest RSP is a stack overflow, and
+/// any other is an unexpected page fault.
+fn page_fault_reason(vm: &mut Vm, qualification: &ExceptionQualification) -> AbortReason {
+    let rsp = vm.vt.registers_mut().rsp;
+    match qualification.cr2 {
+        Some(cr2) if cr2 <= rsp && rsp - cr2 <= STACK_OVERFLOW_DISTANCE => {
+            AbortReason::StackOverflow
+        }
+        _ => AbortReason::UnexpectedPageFault,
+    }
+}
+
+/// Handles #BP caused by the coverage tracking patch `entry`. Records the basic
+/// block and the edge to it as executed, and lets the VM resume.
+fn handle_coverage_breakpoint(
+    vm: &mut Vm,
+    global: &GlobalState,
+    stats: &mut RunStats,
+    entry: &PatchEntry,
+) -> VmExitResult {
+    let rip = entry.address();
+    if vm.keep_patches {
+        // Count the hit and keep the patch. To execute the original
+        // instruction, revert the patch only in this VM's memory, and
+        // step over it. The patch is re-applied on #DB.
+        let hit_count = vm.hit_counts.entry(rip).or_insert(0);
+        *hit_count += 1;
+        if *hit_count == 1 && !vm.minimizing && vm.is_basic_blk_new(global, rip) {
+            stats.newly_executed_basic_blks.push(rip);
+        }
+        if let Err(exhausted) = write_patch_to_vm(vm, global, entry, false) {
+            return abort_on_pool_exhaustion(exhausted);
+        }
+        vm.vt.step_over();
+        vm.stepping_over = Some(rip);
+    } else {
+        // Revert the patch for all VMs and snapshots, as it is no longer needed.
+        // Make sure all pages the patch spans are read from the snapshot files
+        // first, so that the patch is not applied again when they are read
+        // later.
+        for index in 0..global.snapshot_count() {
+            if entry
+                .pfns()
+                .all(|pfn| resolve_page_from_snapshot(global, index, pfn).is_some())
+            {
+                entry.revert(global.snapshot_mut(index).memory.as_mut());
+            }
+        }
+        if !global.is_basic_blk_known(rip) {
+            stats.newly_executed_basic_blks.push(rip);
+        }
+    }
+
+    // Also record the edge from the previously executed basic block
+    // to this one. An input is considered interesting only when it
+    // executes a new edge.
+    let edge_id = (vm.prev_basic_blk >> 1) ^ rip;
+    if vm.record_edge(global, edge_id) {
+        stats.newly_executed_edge_count += 1;
+    }
+    vm.prev_basic_blk = rip;
+    VmExitResult::ResumeVm
+}
+
+/// Handles #BP caused by the cmplog patch `entry` placed on a comparison
+/// instruction. Records values of the operands, and lets the VM execute the
+/// original instruction with single-stepping. The patch is re-applied on #DB.
+fn handle_compare_breakpoint(
+    vm: &mut Vm,
+    global: &GlobalState,
+    entry: &PatchEntry,
+) -> VmExitResult {
+    let instruction = read_original_instruction(vm, global, entry);
+    if let Some(operands) = decode_compare(&instruction, vm.vt.registers_mut()) {
+        trace!("Compared {operands:#x?} at {:#x}", entry.address());
+        operands
+            .into_iter()
+            .for_each(|value| global.record_comparand(value));
+    }
+    step_over_patch(vm, global, entry)
+}
+
+/// Handles #BP caused by the patch marked as [`PatchKind::Profile`].
+///
+/// Counts the execution of the basic block, then executes the original
+/// instruction with single-stepping so that the patch is kept for the next
+/// execution.
+fn handle_profile_breakpoint(
+    vm: &mut Vm,
+    global: &GlobalState,
+    entry: &PatchEntry,
+) -> VmExitResult {
+    global.record_profile_hit(entry.address());
+    step_over_patch(vm, global, entry)
+}
+
+/// Reverts the patch `entry` in this VM's memory, and executes the original
+/// instruction with single-stepping. The patch is re-applied on completion of
+/// the single-step.
+fn step_over_patch(vm: &mut Vm, global: &GlobalState, entry: &PatchEntry) -> VmExitResult {
+    if let Err(exhausted) = write_patch_to_vm(vm, global, entry, false) {
+        return abort_on_pool_exhaustion(exhausted);
+    }
+    vm.vt.step_over();
+    vm.stepping_over = Some(entry.address());
+    VmExitResult::ResumeVm
+}
+
+/// Returns the bytes of the instruction at the patch `entry` as they were
+/// before patching. Bytes outside the snapshot are zero.
+fn read_original_instruction(
+    vm: &Vm,
+    global: &GlobalState,
+    entry: &PatchEntry,
+) -> [u8; MAX_INSTRUCTION_LENGTH] {
+    let mut bytes = [0u8; MAX_INSTRUCTION_LENGTH];
+    let address = entry.address() as usize;
+    let first_pfn = address >> BASE_PAGE_SHIFT;
+    let pages = [
+        resolve_page_from_snapshot(global, vm.snapshot_index, first_pfn),
+        resolve_page_from_snapshot(global, vm.snapshot_index, first_pfn + 1),
+    ];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let gpa = address + i;
+        if let Some(page) = pages[(gpa >> BASE_PAGE_SHIFT) - first_pfn] {
+            *byte = unsafe { (*page).0[gpa & 0xfff] };
+        }
+    }
+
+    // The snapshot may contain the patch. Overwrite it with the original bytes.
+    let original = entry.original();
+    let length = original.len().min(bytes.len());
+    bytes[..length].copy_from_slice(&original[..length]);
+    bytes
+}
+
+/// Writes either the patch or the original bytes of `entry` into this VM's
+/// memory, which is isolated from other VMs with copy-on-write.
+fn write_patch_to_vm(
+    vm: &mut Vm,
+    global: &GlobalState,
+    entry: &PatchEntry,
+    patch: bool,
+) -> Result<(), PoolExhausted> {
+    // Patches are always within the snapshot.
+    for pfn in entry.pfns() {
+        let copy_from = resolve_page_from_snapshot(global, vm.snapshot_index, pfn).unwrap();
+        let page = vm.dirty_page_mut(
+            pfn << BASE_PAGE_SHIFT,
+            copy_from,
+            NestedPagingStructureEntryType::RwxWriteBack,
+        )?;
+        if patch {
+            entry.write_patch(pfn, page);
+        } else {
+            entry.write_original(pfn, page);
+        }
+    }
+
+    // Copy-on-write may have changed nested paging structure entries.
+    vm.vt.invalidate_caches();
+    Ok(())
+}
+
+/// Writes the mutated input data over the snapshot memory at
+/// [`crate::config::Config::mutation_target_gpa`] in this VM's memory, which is
+/// isolated from other VMs with copy-on-write and reverted on the next
+/// iteration.
+///
+/// If nested paging structures or dirty pages are used up, this function
+/// discards all translations with [`Vm::reset`] and retries once, as they may
+/// have been used up by the previous iterations.
+fn write_input_to_target(
+    vm: &mut Vm,
+    global: &GlobalState,
+    mutation_engine: &MutationEngine,
+) -> Result<(), AbortReason> {
+    let Some((target_gpa, data)) = mutation_engine.target_data() else {
+        return Ok(());
+    };
+
+    match copy_to_guest_memory(vm, global, target_gpa, data) {
+        Err(AbortReason::ExcessiveMemoryWrite | AbortReason::NestedPagingExhausted) => {
+            debug!("Resetting nested paging structures to write input data");
+            vm.reset();
+            copy_to_guest_memory(vm, global, target_gpa, data).map_err(|reason| {
+                error!("Failed to write input data over {target_gpa:#x}: {reason:?}");
+                AbortReason::InputNotWritten
+            })
+        }
+        result => result,
+    }
+}
+
+/// Copies `data` into this VM's memory at `target_gpa` through dirty pages.
+/// Returns [`AbortReason::InvalidPageAccess`] if the memory cannot be read
+/// from the snapshot, or the reason of [`pool_exhaustion_reason`] if nested
+/// paging structures or dirty pages are used up.
+fn copy_to_guest_memory(
+    vm: &mut Vm,
+    global: &GlobalState,
+    target_gpa: usize,
+    data: &[u8],
+) -> Result<(), AbortReason> {
+    // The target is validated to be within the snapshot at startup. Map pages
+    // not accessed yet as the nested page fault handler does, and copy the
+    // input data into dirty pages page by page.
+    let mut offset = 0;
+    while offset < data.len() {
+        let gpa = target_gpa + offset;
+        let offset_in_page = gpa & (BASE_PAGE_SIZE - 1);
+        let length = (BASE_PAGE_SIZE - offset_in_page).min(data.len() - offset);
+        let copy_from =
+            resolve_page_from_snapshot(global, vm.snapshot_index, gpa >> BASE_PAGE_SHIFT)
+                .ok_or(AbortReason::InvalidPageAccess)?;
+        if !vm.is_mapped(gpa) {
+            vm.build_translation(gpa, copy_from, NestedPagingStructureEntryType::RxWriteBack)
+                .map_err(pool_exhaustion_reason)?;
+        }
+        let entry_type = dirty_data_page_type(vm, global, gpa);
+        let page = vm
+            .dirty_page_mut(gpa, copy_from, entry_type)
+            .map_err(pool_exhaustion_reason)?;
+        page.0[offset_in_page..offset_in_page + length]
+            .copy_from_slice(&data[offset..offset + length]);
+        offset += length;
+    }
+
+    // Copy-on-write may have changed nested paging structure entries.
+    vm.vt.invalidate_caches();
+    Ok(())
+}
+
+/// Handles VM exit due to the hypercall instruction (`VMCALL` or `VMMCALL`).
+///
+/// The guest agent may use hypercalls instead of the patch to signal events to
+/// the hypervisor. The hypercall number is passed through RAX, and the argument
+/// is passed through RCX.
+fn handle_hypercall(
+    vm: &mut Vm,
+    global: &GlobalState,
+    stats: &mut RunStats,
+    nr: u64,
+    arg: u64,
+) -> VmExitResult {
+    // The length of `VMCALL` (0f 01 c1) and `VMMCALL` (0f 01 d9).
+    const HYPERCALL_INSTRUCTION_LENGTH: u64 = 3;
+    // The guest reached the end of the target logic. Same as the #UD end marker.
+    const HYPERCALL_END_MARKER: u64 = 0;
+    // The guest reports an arbitrary ID as coverage in RCX.
+    const HYPERCALL_REPORT_COVERAGE: u64 = 1;
+
+    match nr {
+        HYPERCALL_END_MARKER => VmExitResult::AbortVm(AbortReason::EndMarker),
+        HYPERCALL_REPORT_COVERAGE => {
+            // Treat the ID like an edge, so that an input that reports a new ID
+            // is added to the corpus.
+            if vm.record_edge(global, arg) {
+                stats.newly_executed_edge_count += 1;
+            }
+            vm.vt.advance_rip(HYPERCALL_INSTRUCTION_LENGTH);
+            VmExitResult::ResumeVm
+        }
+        _ => {
+            debug!("Unknown hypercall {nr:#x} with {arg:#x}");
+            VmExitResult::AbortVm(AbortReason::UnhandledVmExit)
+        }
+    }
+}
+
+/// Handles VM exit due to an instruction without a dedicated [`VmExitReason`].
+///
+/// This function fetches the instruction at the guest RIP as the VM currently
+/// observes it, including modifications in its dirty pages, and emulates it if
+/// it is supported by [`decode_instruction`], such as `HLT`. Writes to CR0 with
+/// `CLTS` and `LMSW` are completed as [`handle_cr_access`] does. The
+/// instruction is fetched from the linear address made up of the CS base and
+/// RIP, eg, the sum of CS * 16 and IP in real mode. UEFI runs with identity
+/// mapping, so the linear address is also the GPA.
+fn handle_instruction(vm: &mut Vm, exit_code: u64) -> VmExitResult {
+    const CR0_PE: u64 = 1 << 0;
+    const CR0_TS: u64 = 1 << 3;
+    // PE, MP, EM and TS.
+    const LMSW_BITS: u64 = 0b1111;
+
+    let rip = vm.vt.registers_mut().rip;
+    let address = vm.vt.cs_base().wrapping_add(rip);
+    let mut instruction = [0u8; MAX_INSTRUCTION_LENGTH];
+    let length = vm.read_guest_memory(address as usize, &mut instruction);
+    let instruction = &instruction[..length];
+    match decode_instruction(instruction) {
+        Some((EmulatedInstruction::Hlt, length)) => {
+            trace!("Emulating HLT at {address:#x}");
+            vm.vt.advance_rip(length);
+            VmExitResult::AbortVm(AbortReason::GuestHalted)
+        }
+        Some((EmulatedInstruction::Invd, length)) => {
+            vm.vt.advance_rip(length);
+            VmExitResult::ResumeVm
+        }
+        Some((EmulatedInstruction::Clts, length)) => {
+            let cr0 = vm.vt.guest_cr(0);
+            handle_cr_access(vm, 0, cr0, cr0 & !CR0_TS, length)
+        }
+        Some((EmulatedInstruction::Lmsw(gpr), length)) => {
+            let cr0 = vm.vt.guest_cr(0);
+            let source = vm.vt.registers_mut().gpr(gpr);
+            let value = (cr0 & !LMSW_BITS) | (source & LMSW_BITS) | (cr0 & CR0_PE);
+            handle_cr_access(vm, 0, cr0, value, length)
+        }
+        None => {
+            error!("🐈 Unhandled VM exit {exit_code:#x} at {address:#x}: {instruction:02x?}");
+            VmExitResult::AbortVm(AbortReason::UnhandledVmExit)
+        }
+    }
+}
+
+/// Handles VM exit due to the `CPUID` instruction.
+///
+/// This function returns the processor's CPUID results with some features
+/// hidden, so that the guest neither detects the hypervisor nor takes code
+/// paths that yield different results on each run.
+fn handle_cpuid(vm: &mut Vm, leaf: u32, subleaf: u32) -> VmExitResult {
+    // The length of `CPUID` (0f a2).
+    const CPUID_INSTRUCTION_LENGTH: u64 = 2;
+    const CPUID_FEATURE_INFO: u32 = 0x1;
+    const CPUID_EXTENDED_FEATURES: u32 = 0x7;
+    const CPUID_HYPERVISOR_LEAVES: core::ops::RangeInclusive<u32> = 0x4000_0000..=0x4fff_ffff;
+    const CPUID_FEATURE_INFO_EBX_INITIAL_APIC_ID: u32 = 0xff << 24;
+    const CPUID_FEATURE_INFO_ECX_RDRAND: u32 = 1 << 30;
+    const CPUID_FEATURE_INFO_ECX_HYPERVISOR: u32 = 1 << 31;
+    const CPUID_EXTENDED_FEATURES_EBX_RDSEED: u32 = 1 << 18;
+
+    let mut result = x86::cpuid::cpuid!(leaf, subleaf);
+    match leaf {
+        // Hide the hypervisor-present bit, and RDRAND as it returns a different
+        // value on each run. Also, report the same APIC ID regardless of the
+        // processor the guest runs on.
+        CPUID_FEATURE_INFO => {
+            result.ebx &= !CPUID_FEATURE_INFO_EBX_INITIAL_APIC_ID;
+            result.ecx &= !(CPUID_FEATURE_INFO_ECX_RDRAND | CPUID_FEATURE_INFO_ECX_HYPERVISOR);
+        }
+        // Hide RDSEED for the same reason as RDRAND.
+        CPUID_EXTENDED_FEATURES if subleaf == 0 => {
+            result.ebx &= !CPUID_EXTENDED_FEATURES_EBX_RDSEED;
+        }
+        // Hide any hypervisor the host may be running on.
+        leaf if CPUID_HYPERVISOR_LEAVES.contains(&leaf) => {
+            result = x86::cpuid::CpuIdResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            };
+        }
+        _ => {}
+    }
+
+    let registers = vm.vt.registers_mut();
+    registers.rax = u64::from(result.eax);
+    registers.rbx = u64::from(result.ebx);
+    registers.rcx = u64::from(result.ecx);
+    registers.rdx = u64::from(result.edx);
+    vm.vt.advance_rip(CPUID_INSTRUCTION_LENGTH);
+    VmExitResult::ResumeVm
+}
+
+/// Handles VM exit due to shutdown.
+///
+/// The VM is aborted as a crash. The event that was being delivered, if any,
+/// tells whether it is due to the guest (eg, a fault while handling #DF) or a
+/// guest state the hypervisor set up incorrectly.
+fn handle_shutdown(vm: &Vm, qualification: &ShutdownQualification) -> VmExitResult {
+    debug!(
+        "Shutdown at {:#x} (exit code {:#x}, vector {:x?}, error code {:x?})",
+        qualification.rip, qualification.exit_code, qualification.vector, qualification.error_code,
+    );
+    debug!("{:#x?}", vm.vt);
+    // UEFI runs with identity mapping, so RIP is also the GPA.
+    vm.dump_translation(qualification.rip as usize);
+    VmExitResult::AbortVm(AbortReason::TripleFault)
+}
+
+/// Handles VM exit due to `RDTSC` or `RDTSCP`.
+///
+/// This function returns the virtual TSC that advances by
+/// [`VIRTUAL_TSC_INCREMENT`] on each read, as done for reads of IA32_TSC, so
+/// that the VM observes the same values on each run for the same input.
+/// `RDTSCP` reads zero as IA32_TSC_AUX, instead of the processor specific
+/// value.
+fn handle_rdtsc(vm: &mut Vm, rdtscp: bool) -> VmExitResult {
+    // The length of `RDTSC` (0f 31) and `RDTSCP` (0f 01 f9).
+    const RDTSC_INSTRUCTION_LENGTH: u64 = 2;
+    const RDTSCP_INSTRUCTION_LENGTH: u64 = 3;
+
+    vm.virtual_tsc += VIRTUAL_TSC_INCREMENT;
+    let value = vm.virtual_tsc;
+    let registers = vm.vt.registers_mut();
+    registers.rax = value & 0xffff_ffff;
+    registers.rdx = value >> 32;
+    if rdtscp {
+        registers.rcx = 0;
+        vm.vt.advance_rip(RDTSCP_INSTRUCTION_LENGTH);
+    } else {
+        vm.vt.advance_rip(RDTSC_INSTRUCTION_LENGTH);
+    }
+    VmExitResult::ResumeVm
+}
+
+/// Handles VM exit due to instructions that have no effect the VM needs to
+/// observe, ie, `INVLPG`, `WBINVD`, `MONITOR` and `MWAIT`, by skipping them.
+///
+/// `INVLPG` needs not be emulated since all TLB entries of the VM are flushed
+/// on VM exit, as VPID is not enabled. Caches are coherent with memory the VM
+/// accesses, so `WBINVD` is not required either. `MWAIT` returns immediately,
+/// which is allowed by the architecture.
+fn skip_instruction(vm: &mut Vm, length: u64) -> VmExitResult {
+    vm.vt.advance_rip(length);
+    VmExitResult::ResumeVm
+}
+
+/// Handles VM exit due to `XSETBV`.
+///
+/// This function emulates writes to XCR0 with valid values. Any other write
+/// would cause #GP, which is not emulated, so the VM is aborted instead.
+fn handle_xsetbv(vm: &mut Vm, xcr: u32, value: u64) -> VmExitResult {
+    // The length of `XSETBV` (0f 01 d1).
+    const XSETBV_INSTRUCTION_LENGTH: u64 = 3;
+
+    if xcr != 0 || !is_valid_xcr0(value) {
+        debug!("Unhandled XSETBV {xcr:#x} {value:#x}");
+        return VmExitResult::AbortVm(AbortReason::UnhandledVmExit);
+    }
+    vm.vt.set_xcr0(value);
+    vm.vt.advance_rip(XSETBV_INSTRUCTION_LENGTH);
+    VmExitResult::ResumeVm
+}
+
+/// Handles VM exit due to `RDMSR` or `WRMSR`.
+///
+/// This function emulates access to [`INTERCEPTED_MSRS`] so that the VM
+/// observes the same values on each run for the same input. IA32_TSC reads
+/// return the virtual TSC that advances by [`VIRTUAL_TSC_INCREMENT`] on each
+/// read. Other MSRs read the last written value in the iteration, or zero.
+fn handle_msr_access(vm: &mut Vm, msr: u32, write: bool, value: u64) -> VmExitResult {
+    // The length of `RDMSR` (0f 32) and `WRMSR` (0f 30).
+    const MSR_INSTRUCTION_LENGTH: u64 = 2;
+
+    if write && msr == x86::msr::IA32_EFER {
+        debug!("IA32_EFER write {value:#x}");
+        return VmExitResult::AbortVm(AbortReason::ModeSwitch);
+    }
+
+    let Some(index) = INTERCEPTED_MSRS.iter().position(|&m| m == msr) else {
+        // Write access to any other MSR, or read access to an MSR not covered by
+        // MSR bitmaps. Abort the VM as we neither let the VM modify the host MSR
+        // nor know what value to present.
+        debug!("Unhandled MSR {msr:#x} access");
+        return VmExitResult::AbortVm(AbortReason::UnhandledVmExit);
+    };
+
+    if write {
+        if msr == x86::msr::IA32_TIME_STAMP_COUNTER {
+            vm.virtual_tsc = value;
+        } else {
+            vm.msr_values[index] = value;
+        }
+    } else {
+        let value = if msr == x86::msr::IA32_TIME_STAMP_COUNTER {
+            vm.virtual_tsc += VIRTUAL_TSC_INCREMENT;
+            vm.virtual_tsc
+        } else {
+            vm.msr_values[index]
+        };
+        let registers = vm.vt.registers_mut();
+        registers.rax = value & 0xffff_ffff;
+        registers.rdx = value >> 32;
+    }
+    vm.vt.advance_rip(MSR_INSTRUCTION_LENGTH);
+    VmExitResult::ResumeVm
+}
+
+/// Handles VM exit due to `MOV` to the control register `cr` (CR0 or CR4).
+///
+/// Aborts the VM if the write modifies any of [`INTERCEPTED_CR0_BITS`] or
+/// [`INTERCEPTED_CR4_BITS`], as the hypervisor does not model other processor
+/// modes. Otherwise, completes the write on behalf of the VM.
+fn handle_cr_access(vm: &mut Vm, cr: u8, current: u64, value: u64, length: u64) -> VmExitResult {
+    let intercepted_bits = if cr == 0 {
+        INTERCEPTED_CR0_BITS
+    } else {
+        INTERCEPTED_CR4_BITS
+    };
+    if (current ^ value) & intercepted_bits != 0 {
+        debug!("CR{cr} write {current:#x} -> {value:#x}");
+        return VmExitResult::AbortVm(AbortReason::ModeSwitch);
+    }
+    vm.vt.set_guest_cr(cr, value);
+    vm.vt.advance_rip(length);
+    VmExitResult::ResumeVm
+}
+
+/// The values the VM reads from the intercepted I/O ports. Each entry is an
+/// I/O port and the value read from it. Reads of other intercepted ports return
+/// all ones, as if no device responded.
+const IO_PORT_READ_VALUES: &[(u16, u32)] = &[
+    // The line status registers of COM1 to COM4. The transmitter holding
+    // register is always empty, so that the VM does not spin waiting for it.
+    (0x3fd, 0x60),
+    (0x2fd, 0x60),
+    (0x3ed, 0x60),
+    (0x2ed, 0x60),
+    // The interrupt mask registers of the master and slave PICs. All
+    // interrupts are masked.
+    (0x21, 0xff),
+    (0xa1, 0xff),
+];
+
+/// Handles VM exit due to `IN` or `OUT`.
+///
+/// This function emulates access to [`crate::config::INTERCEPTED_IO_PORTS`] so
+/// that the VM never touches the host devices and observes the same values on
+/// each run. Reads return values from [`IO_PORT_READ_VALUES`], and writes are
+/// ignored, except ones to [`GUEST_PRINT_IO_PORT`], which are logged.
+fn handle_io_access(
+    vm: &mut Vm,
+    port: u16,
+    write: bool,
+    size: u8,
+    value: u32,
+    length: u64,
+) -> VmExitResult {
+    if write && port == GUEST_PRINT_IO_PORT {
+        vm.print_guest_output(&value.to_le_bytes()[..usize::from(size)]);
+    } else if write {
+        trace!("I/O port {port:#x} write {value:#x} ignored");
+    } else {
+        let value = IO_PORT_READ_VALUES
+            .iter()
+            .find_map(|&(p, value)| (p == port).then_some(value))
+            .unwrap_or(u32::MAX);
+
+        // 8-bit and 16-bit `IN` only update AL and AX, while 32-bit `IN` clears
+        // the upper 32 bits of RAX.
+        let registers = vm.vt.registers_mut();
+        registers.rax = match size {
+            1 => (registers.rax & !0xff) | u64::from(value & 0xff),
+            2 => (registers.rax & !0xffff) | u64::from(value & 0xffff),
+            _ => u64::from(value),
+        };
+    }
+    vm.vt.advance_rip(length);
+    VmExitResult::ResumeVm
+}
+
 /// Handles VM exit due to external interrupt, such as timer interrupt, or
 /// `PAUSE`.
 ///
-/// This functions determines if the quantum given to the VM has expired.
-fn handle_external_interrupt_or_pause(stats: &mut RunStats) -> VmExitResult {
+/// This functions determines if the quantum given to the VM for the current
+/// input has expired. It also runs the watchdog, so that the iteration stuck
+/// on this processor is reported even if no other processor completes an
+/// iteration, eg, when only one processor is available.
+fn handle_external_interrupt_or_pause(
+    vm: &Vm,
+    global: &GlobalState,
+    stats: &mut RunStats,
+) -> VmExitResult {
+    global.check_stuck_cores();
     let total_elapsed_tsc = rdtsc() - stats.start_tsc;
     let guest_spent_tsc = total_elapsed_tsc - stats.host_spent_tsc;
-    if guest_spent_tsc < GUEST_EXEC_TIMEOUT_IN_TSC {
+    if guest_spent_tsc < vm.exec_timeout_in_tsc {
         VmExitResult::ResumeVm
     } else {
         handle_timer_expiration(stats)
     }
 }
 
+/// Checks whether the guest spent long enough time to be considered slow,
+/// that is, close to hang.
+fn is_slow(vm: &Vm, guest_spent_tsc: u64) -> bool {
+    guest_spent_tsc >= vm.exec_timeout_in_tsc / 100 * SLOW_INPUT_THRESHOLD_PERCENT
+}
+
 /// Handles VM exit due to expiration of the quantum given to the VM.
 fn handle_timer_expiration(stats: &mut RunStats) -> VmExitResult {
     stats.hang_count = 1;
@@ -302,20 +1492,26 @@ enum VmExitResult {
     ResumeVm,
     /// The VM should abort, and the new fuzzing iteration should start.
     AbortVm(AbortReason),
-    /// The current processor should panic.
-    Panic(u64),
 }
 
 /// The detailed reason of [`VmExitResult::AbortVm`].
+#[derive(Debug)]
 enum AbortReason {
     /// The VM caused VM exit that is not handled.
-    /// Source: [`VmExitReason::Unexpected`].
+    /// Source: [`VmExitReason::Unexpected`], [`VmExitReason::Hypercall`],
+    /// [`VmExitReason::MsrAccess`] or [`VmExitReason::Xsetbv`].
     UnhandledVmExit,
 
-    /// The VM reached to the end marker UD instruction.
-    /// Source: [`VmExitReason::Exception`].
+    /// The VM reached to the end marker UD instruction or hypercall.
+    /// Source: [`VmExitReason::Exception`] or [`VmExitReason::Hypercall`].
     EndMarker,
 
+    /// The VM executed `HLT`. Nothing would wake it up, so the iteration ends
+    /// there, but unlike [`AbortReason::EndMarker`], the guest may not have
+    /// completed the fuzzing target.
+    /// Source: [`VmExitReason::Unexpected`].
+    GuestHalted,
+
     /// The VM attempted to access memory that is not backed by the snapshot or
     /// input data. Source: [`VmExitReason::NestedPageFault`].
     InvalidPageAccess,
@@ -328,6 +1524,10 @@ enum AbortReason {
     /// bug. Source: [`VmExitReason::NestedPageFault`].
     NegativePageAccess,
 
+    /// The VM reached the patch marked as [`PatchKind::Abort`]. An indicator
+    /// of a bug. Source: [`VmExitReason::Exception`].
+    AbortMarker,
+
     /// The VM attempted to execute an invalid instruction. An indicator of a
     /// bug. Source: [`VmExitReason::Exception`].
     InvalidInstruction,
@@ -337,38 +1537,180 @@ enum AbortReason {
     /// Source: [`VmExitReason::Exception`].
     UnexpectedBreakpoint,
 
+    /// The VM generated #DB that is not because of single-stepping over the
+    /// patch. Source: [`VmExitReason::Exception`].
+    UnexpectedDebugException,
+
     /// The VM generated #PF, which is not expected with _our snapshot_, which
     /// is taken at the UEFI phase. Maybe a bug.
     /// Source: [`VmExitReason::Exception`].
     UnexpectedPageFault,
 
-    /// The VM has modified too many pages. Maybe a bug.
+    /// The VM generated #PF right below the stack pointer, ie, exhausted the
+    /// stack. An indicator of a bug, eg, unbounded recursion.
+    /// See [`STACK_OVERFLOW_DISTANCE`]. Source: [`VmExitReason::Exception`].
+    StackOverflow,
+
+    /// The VM generated #GP. An indicator of a bug, eg, use of a non-canonical
+    /// address. Source: [`VmExitReason::Exception`].
+    GeneralProtectionFault,
+
+    /// The VM generated #DE. An indicator of a bug, eg, division by zero.
+    /// Source: [`VmExitReason::Exception`].
+    DivideError,
+
+    /// The VM attempted to read from or write to a page marked as
+    /// execute-only in the patch file. An indicator of a bug.
+    /// Source: [`VmExitReason::NestedPageFault`].
+    ExecuteOnlyViolation,
+
+    /// The VM attempted to execute instructions in a page it wrote to, eg, the
+    /// stack. An indicator of a bug. See [`ABORT_ON_DATA_EXECUTION`].
+    /// Source: [`VmExitReason::NestedPageFault`].
+    ExecuteData,
+
+    /// The VM attempted to write to IA32_EFER, or to modify any of
+    /// [`INTERCEPTED_CR0_BITS`] or [`INTERCEPTED_CR4_BITS`], which switches
+    /// the processor mode. Maybe a bug.
+    /// Source: [`VmExitReason::MsrAccess`] or [`VmExitReason::CrAccess`].
+    ModeSwitch,
+
+    /// The VM attempted to write to a page containing a patch. Maybe a bug.
+    /// Source: [`VmExitReason::NestedPageFault`].
+    SelfModifyingCode,
+
+    /// The VM attempted to write to a page other than the stack while
+    /// [`crate::config::Config::no_write_expected`] is enabled. Maybe a bug.
+    /// Source: [`VmExitReason::NestedPageFault`].
+    UnexpectedWrite,
+
+    /// The VM has modified more pages than preallocated dirty pages. Maybe a
+    /// bug.
     /// Source: [`VmExitReason::NestedPageFault`].
     ExcessiveMemoryWrite,
 
+    /// The VM has modified more pages than
+    /// [`crate::config::Config::dirty_page_soft_limit`]. Maybe a bug.
+    /// Source: [`VmExitReason::NestedPageFault`].
+    DirtyPageLimitExceeded,
+
+    /// The VM has accessed too many distinct memory regions to be mapped with
+    /// preallocated nested paging structures. Not an indicator of a bug, as
+    /// they accumulate across iterations. They are reset before the next
+    /// iteration.
+    /// Source: [`VmExitReason::NestedPageFault`].
+    NestedPagingExhausted,
+
+    /// The VM entered the shutdown state, eg, due to triple fault. An indicator
+    /// of a bug, or misconfiguration of the guest state by the hypervisor.
+    /// Source: [`VmExitReason::Shutdown`].
+    TripleFault,
+
     /// The VM has used up its quantum. Maybe a bug.
     /// Source: [`VmExitReason::ExternalInterruptOrPause`] or
     /// [`VmExitReason::TimerExpiration`] .
     Hang,
+
+    /// The mutated input data could not be written over the snapshot memory
+    /// at [`crate::config::Config::mutation_target_gpa`], even after
+    /// discarding all translations. The VM did not run.
+    InputNotWritten,
 }
 
 impl AbortReason {
+    /// Returns whether this is an indicator of a bug, that is, reported as
+    /// warning by [`AbortReason::report`].
+    fn is_crash(&self) -> bool {
+        matches!(
+            self,
+            Self::NullPageAccess
+                | Self::NegativePageAccess
+                | Self::AbortMarker
+                | Self::InvalidInstruction
+                | Self::UnexpectedBreakpoint
+                | Self::UnexpectedDebugException
+                | Self::UnexpectedPageFault
+                | Self::StackOverflow
+                | Self::GeneralProtectionFault
+                | Self::DivideError
+                | Self::ExecuteOnlyViolation
+                | Self::ExecuteData
+                | Self::ModeSwitch
+                | Self::SelfModifyingCode
+                | Self::UnexpectedWrite
+                | Self::ExcessiveMemoryWrite
+                | Self::DirtyPageLimitExceeded
+                | Self::TripleFault
+        )
+    }
+
     /// Prints out the reason of abort if needed.
     ///
     /// Those may be indicators of bugs found as a result of fuzzing are
-    /// reported as warning.
-    fn report(&self, current_input: &MutatingInput) {
+    /// reported as warning, followed by the provenance of the input.
+    fn report(&self, current_input: &MutatingInput, corpus: &Corpus) {
         match self {
-            Self::UnhandledVmExit | Self::InvalidPageAccess => (),
+            Self::UnhandledVmExit | Self::InvalidPageAccess | Self::InputNotWritten => (),
+            Self::NestedPagingExhausted => debug!("Nested paging structures exhausted"),
             Self::EndMarker => trace!("Reached the end marker"),
+            Self::GuestHalted => trace!("Halted"),
             Self::NullPageAccess => warn!("NULL PAGE ACCESS : {current_input:?}"),
             Self::NegativePageAccess => warn!("NEGATIVE PAGE ACCESS : {current_input:?}"),
+            Self::AbortMarker => warn!("ABORT MARKER : {current_input:?}"),
             Self::InvalidInstruction => warn!("INVALID INSTRUCTION : {current_input:?}"),
             Self::UnexpectedBreakpoint => warn!("UNEXPECTED BREAKPOINT : {current_input:?}"),
+            Self::UnexpectedDebugException => {
+                warn!("UNEXPECTED DEBUG EXCEPTION : {current_input:?}");
+            }
             Self::UnexpectedPageFault => warn!("UNEXPECTED PAGE FAULT : {current_input:?}"),
+            Self::StackOverflow => warn!("STACK OVERFLOW : {current_input:?}"),
+            Self::GeneralProtectionFault => {
+                warn!("GENERAL PROTECTION FAULT : {current_input:?}");
+            }
+            Self::DivideError => warn!("DIVIDE ERROR : {current_input:?}"),
+            Self::ExecuteOnlyViolation => {
+                warn!("EXECUTE-ONLY PAGE ACCESS : {current_input:?}");
+            }
+            Self::ModeSwitch => warn!("MODE SWITCH : {current_input:?}"),
+            Self::ExecuteData => warn!("DATA EXECUTION : {current_input:?}"),
+            Self::SelfModifyingCode => warn!("SELF-MODIFYING CODE : {current_input:?}"),
+            Self::UnexpectedWrite => warn!("UNEXPECTED WRITE : {current_input:?}"),
             Self::ExcessiveMemoryWrite => warn!("EXCESSIVE MEMORY WRITES : {current_input:?}"),
+            Self::DirtyPageLimitExceeded => {
+                warn!("DIRTY PAGE LIMIT EXCEEDED : {current_input:?}");
+            }
+            Self::TripleFault => warn!("TRIPLE FAULT : {current_input:?}"),
             Self::Hang => debug!("Hang detected : {current_input:?}"),
         }
+        if self.is_crash() {
+            report_provenance(current_input, corpus);
+        }
+    }
+}
+
+/// Prints out the lineage of the crashing input `current_input`, from the
+/// original seed through the input files promoted into the corpus by finding
+/// new coverage, and the generation of the crashing input.
+///
+/// Ancestors removed from the corpus with the sequential mutation strategies
+/// cannot be followed, and the lineage starts from the oldest one still known.
+fn report_provenance(current_input: &MutatingInput, corpus: &Corpus) {
+    let mut lineage: Vec<String> = Vec::new();
+    if let Some(parent) = current_input.parent() {
+        lineage = corpus.ancestry(parent);
+        lineage.reverse();
+        lineage.push(parent.to_string());
+    }
+    lineage.push(current_input.name().to_string());
+    if current_input.is_mutated() {
+        warn!(
+            "PROVENANCE : {} -> mutation #{} (generation {})",
+            lineage.join(" -> "),
+            current_input.mutation_count(),
+            current_input.generation() + 1
+        );
+    } else {
+        warn!("PROVENANCE : {} (generation {})", lineage.join(" -> "), current_input.generation());
     }
 }
 
@@ -376,9 +1718,12 @@ impl From<GuestException> for AbortReason {
     /// Converts [`GuestException`] to [`AbortReason`].
     fn from(value: GuestException) -> Self {
         match value {
+            GuestException::Debug => Self::UnexpectedDebugException,
             GuestException::BreakPoint => Self::UnexpectedBreakpoint,
             GuestException::InvalidOpcode => Self::InvalidInstruction,
             GuestException::PageFault => Self::InvalidPageAccess,
+            GuestException::GeneralProtection => Self::GeneralProtectionFault,
+            GuestException::DivideError => Self::DivideError,
         }
     }
 }
@@ -0,0 +1,324 @@
+//! The module containing a minimal decompressor of the DEFLATE format.
+//!
+//! This is used to read pages from compressed snapshot files, where each page
+//! is compressed as an independent raw DEFLATE stream (ie, without the zlib or
+//! gzip header). The implementation follows the structure of `puff.c` in zlib
+//! and favors simplicity over speed.
+//! See: RFC 1951 DEFLATE Compressed Data Format Specification version 1.3
+
+/// The reason of failure of [`inflate`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum InflateError {
+    /// The input ended before the end of the last block.
+    InputTooShort,
+    /// The decompressed data does not fit in the output buffer.
+    OutputTooLong,
+    /// The input is not valid DEFLATE data.
+    InvalidData,
+}
+
+/// Decompresses the raw DEFLATE stream `input` into `output`, and returns the
+/// size of the decompressed data.
+pub(crate) fn inflate(input: &[u8], output: &mut [u8]) -> Result<usize, InflateError> {
+    let mut state = State {
+        input,
+        input_position: 0,
+        bit_buffer: 0,
+        bit_count: 0,
+        output,
+        output_position: 0,
+    };
+
+    loop {
+        let last = state.bits(1)?;
+        match state.bits(2)? {
+            0 => state.stored()?,
+            1 => state.fixed()?,
+            2 => state.dynamic()?,
+            _ => return Err(InflateError::InvalidData),
+        }
+        if last == 1 {
+            break;
+        }
+    }
+    Ok(state.output_position)
+}
+
+// The maximum number of bits in a code.
+const MAX_BITS: usize = 15;
+
+// The number of literal/length codes, including two invalid ones.
+const MAX_LENGTH_CODES: usize = 288;
+
+// The number of distance codes, including two invalid ones.
+const MAX_DISTANCE_CODES: usize = 30;
+
+// The base lengths and the numbers of extra bits for length codes 257..285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+// The base distances and the numbers of extra bits for distance codes 0..29.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// The order of code length code lengths in the dynamic block header.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// The input and output state of decompression.
+struct State<'a> {
+    input: &'a [u8],
+    input_position: usize,
+    /// Bits read from the input but not consumed yet.
+    bit_buffer: u32,
+    /// The number of valid bits in [`State::bit_buffer`].
+    bit_count: u32,
+    output: &'a mut [u8],
+    output_position: usize,
+}
+
+impl State<'_> {
+    /// Consumes `count` (up to 16) bits from the input.
+    fn bits(&mut self, count: u32) -> Result<u32, InflateError> {
+        let mut value = self.bit_buffer;
+        while self.bit_count < count {
+            let byte = *self
+                .input
+                .get(self.input_position)
+                .ok_or(InflateError::InputTooShort)?;
+            self.input_position += 1;
+            value |= u32::from(byte) << self.bit_count;
+            self.bit_count += 8;
+        }
+        self.bit_buffer = value >> count;
+        self.bit_count -= count;
+        Ok(value & ((1 << count) - 1))
+    }
+
+    /// Writes a single byte into the output.
+    fn put(&mut self, byte: u8) -> Result<(), InflateError> {
+        let out = self
+            .output
+            .get_mut(self.output_position)
+            .ok_or(InflateError::OutputTooLong)?;
+        *out = byte;
+        self.output_position += 1;
+        Ok(())
+    }
+
+    /// Processes a stored (uncompressed) block.
+    fn stored(&mut self) -> Result<(), InflateError> {
+        // Discard leftover bits to the byte boundary.
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+
+        let header = self
+            .input
+            .get(self.input_position..self.input_position + 4)
+            .ok_or(InflateError::InputTooShort)?;
+        let length = u16::from_le_bytes([header[0], header[1]]);
+        let complement = u16::from_le_bytes([header[2], header[3]]);
+        if length != !complement {
+            return Err(InflateError::InvalidData);
+        }
+        self.input_position += 4;
+
+        let length = usize::from(length);
+        let data = self
+            .input
+            .get(self.input_position..self.input_position + length)
+            .ok_or(InflateError::InputTooShort)?;
+        self.output
+            .get_mut(self.output_position..self.output_position + length)
+            .ok_or(InflateError::OutputTooLong)?
+            .copy_from_slice(data);
+        self.input_position += length;
+        self.output_position += length;
+        Ok(())
+    }
+
+    /// Processes a block compressed with the fixed Huffman codes.
+    fn fixed(&mut self) -> Result<(), InflateError> {
+        let mut lengths = [0u8; MAX_LENGTH_CODES + MAX_DISTANCE_CODES];
+        lengths[..144].fill(8);
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        lengths[280..MAX_LENGTH_CODES].fill(8);
+        lengths[MAX_LENGTH_CODES..].fill(5);
+
+        let length_code = Huffman::new(&lengths[..MAX_LENGTH_CODES])?;
+        let distance_code = Huffman::new(&lengths[MAX_LENGTH_CODES..])?;
+        self.codes(&length_code, &distance_code)
+    }
+
+    /// Processes a block compressed with the dynamic Huffman codes described
+    /// in the block header.
+    fn dynamic(&mut self) -> Result<(), InflateError> {
+        let length_count = self.bits(5)? as usize + 257;
+        let distance_count = self.bits(5)? as usize + 1;
+        let code_length_count = self.bits(4)? as usize + 4;
+        if length_count > 286 || distance_count > MAX_DISTANCE_CODES {
+            return Err(InflateError::InvalidData);
+        }
+
+        // Read the code lengths of the code length code, and build the code.
+        let mut lengths = [0u8; MAX_LENGTH_CODES + MAX_DISTANCE_CODES];
+        for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+            lengths[index] = self.bits(3)? as u8;
+        }
+        let code_length_code = Huffman::new(&lengths[..CODE_LENGTH_ORDER.len()])?;
+
+        // Read the code lengths of the literal/length and distance codes.
+        let total_count = length_count + distance_count;
+        let mut index = 0;
+        while index < total_count {
+            let symbol = code_length_code.decode(self)?;
+            if symbol < 16 {
+                lengths[index] = symbol as u8;
+                index += 1;
+                continue;
+            }
+
+            let (length, repeat) = match symbol {
+                16 => {
+                    if index == 0 {
+                        return Err(InflateError::InvalidData);
+                    }
+                    (lengths[index - 1], 3 + self.bits(2)? as usize)
+                }
+                17 => (0, 3 + self.bits(3)? as usize),
+                _ => (0, 11 + self.bits(7)? as usize),
+            };
+            if index + repeat > total_count {
+                return Err(InflateError::InvalidData);
+            }
+            lengths[index..index + repeat].fill(length);
+            index += repeat;
+        }
+
+        // The end-of-block code is required.
+        if lengths[256] == 0 {
+            return Err(InflateError::InvalidData);
+        }
+
+        let length_code = Huffman::new(&lengths[..length_count])?;
+        let distance_code = Huffman::new(&lengths[length_count..total_count])?;
+        self.codes(&length_code, &distance_code)
+    }
+
+    /// Decodes literals and length/distance pairs until the end-of-block code.
+    fn codes(
+        &mut self,
+        length_code: &Huffman,
+        distance_code: &Huffman,
+    ) -> Result<(), InflateError> {
+        loop {
+            let symbol = usize::from(length_code.decode(self)?);
+            match symbol {
+                0..=255 => self.put(symbol as u8)?,
+                256 => return Ok(()),
+                _ => {
+                    let symbol = symbol - 257;
+                    if symbol >= LENGTH_BASE.len() {
+                        return Err(InflateError::InvalidData);
+                    }
+                    let length = usize::from(LENGTH_BASE[symbol])
+                        + self.bits(u32::from(LENGTH_EXTRA[symbol]))? as usize;
+
+                    let symbol = usize::from(distance_code.decode(self)?);
+                    if symbol >= DISTANCE_BASE.len() {
+                        return Err(InflateError::InvalidData);
+                    }
+                    let distance = usize::from(DISTANCE_BASE[symbol])
+                        + self.bits(u32::from(DISTANCE_EXTRA[symbol]))? as usize;
+                    if distance > self.output_position {
+                        return Err(InflateError::InvalidData);
+                    }
+
+                    // Copy byte by byte, as the source and destination may
+                    // overlap.
+                    for _ in 0..length {
+                        self.put(self.output[self.output_position - distance])?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The canonical Huffman code, represented by the number of codes of each
+/// length and the symbols ordered by their codes.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: [u16; MAX_LENGTH_CODES],
+}
+
+impl Huffman {
+    /// Builds the code from the code length of each symbol.
+    fn new(lengths: &[u8]) -> Result<Self, InflateError> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+
+        // Reject an over-subscribed code. Incomplete codes are allowed, as a
+        // distance code may consist of a single code.
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left <<= 1;
+            left -= i32::from(count);
+            if left < 0 {
+                return Err(InflateError::InvalidData);
+            }
+        }
+
+        // Sort symbols by their lengths, then by their values.
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = [0u16; MAX_LENGTH_CODES];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                let offset = &mut offsets[usize::from(length)];
+                symbols[usize::from(*offset)] = symbol as u16;
+                *offset += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    /// Decodes a single symbol from the input.
+    fn decode(&self, state: &mut State<'_>) -> Result<u16, InflateError> {
+        // `code` is the code read so far, `first` is the first code of the
+        // current length, and `index` is the index of the first symbol of the
+        // current length in `symbols`.
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for &count in &self.counts[1..] {
+            code |= i32::from(state.bits(1)? == 1);
+            let count = i32::from(count);
+            if code - count < first {
+                return Ok(self.symbols[(index + (code - first)).unsigned_abs() as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err(InflateError::InvalidData)
+    }
+}
@@ -4,10 +4,17 @@
 // https://github.com/iankronquist/rustyvisor/blob/83b53ac104d85073858ba83326a28a6e08d1af12/pcuart/src/lib.rs
 
 use crate::{
-    config::LOGGING_LEVEL,
-    x86_instructions::{inb, outb},
+    config::{
+        LOGGING_LEVEL, LOG_RATE_LIMIT_WINDOW_IN_TSC, PANIC_LOG_BUFFER_SIZE,
+        SEPARATE_COVERAGE_SERIAL_PORT,
+    },
+    x86_instructions::{inb, outb, rdtsc},
+};
+use core::{
+    fmt,
+    fmt::Write,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
 };
-use core::{fmt, fmt::Write};
 use spin::Mutex;
 
 /// Initializes the logger instance.
@@ -17,23 +24,82 @@ pub(crate) fn init_uart_logger() {
         .unwrap();
 }
 
+/// Sets the maximum number of bytes sent to COM1 per
+/// [`LOG_RATE_LIMIT_WINDOW_IN_TSC`]. Zero disables the limit. See
+/// [`crate::config::Config::log_rate_limit`].
+pub(crate) fn set_log_rate_limit(limit: u64) {
+    UART_LOGGER.rate_limiter.lock().limit = limit;
+}
+
+/// Sends the message to COM1 as-is, without the APIC ID and level prefix. The
+/// message is subject to the rate limit like ones at the info level.
+pub(crate) fn log_raw(args: fmt::Arguments<'_>) {
+    UART_LOGGER.write_limited(log::Level::Info, |port| writeln!(port, "{args}"));
+}
+
+/// Sends the last log messages kept with the `panic_log_buffer` feature to
+/// COM1. This does not take the lock of the logger, so that it can be used on
+/// panic while any processor holds it.
+pub(crate) fn dump_panic_log_buffer() {
+    if !cfg!(feature = "panic_log_buffer") {
+        return;
+    }
+
+    // If the buffer has wrapped around, start from the oldest byte, skipping
+    // the partially overwritten line.
+    let end = PANIC_LOG_BUFFER.position.load(Ordering::SeqCst);
+    let mut uart = Uart::new(UartComPort::Com1);
+    let _ = writeln!(uart, "\n=== PANIC CONTEXT ===");
+    let start = end.saturating_sub(PANIC_LOG_BUFFER_SIZE);
+    let mut skipping = start != 0;
+    for position in start..end {
+        let byte = PANIC_LOG_BUFFER.bytes[position % PANIC_LOG_BUFFER_SIZE].load(Ordering::Relaxed);
+        if skipping {
+            skipping = byte != b'\n';
+            continue;
+        }
+        uart.write_byte(byte);
+    }
+}
+
+/// Logs coverage information. It is sent to COM2 as-is if
+/// [`SEPARATE_COVERAGE_SERIAL_PORT`] is enabled. Otherwise, it is logged at the
+/// info level like any other messages.
+pub(crate) fn log_coverage(args: fmt::Arguments<'_>) {
+    if SEPARATE_COVERAGE_SERIAL_PORT {
+        let _ = writeln!(UART_LOGGER.coverage_port.lock(), "{args}");
+    } else {
+        log::info!("{args}");
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(u16)]
 enum UartComPort {
     Com1 = 0x3f8,
+    Com2 = 0x2f8,
 }
 
 #[derive(Default)]
 struct Uart {
     io_port_base: u16,
+    /// The total number of bytes ever sent.
+    bytes_written: u64,
 }
 
 impl Uart {
     const fn new(port: UartComPort) -> Self {
         Self {
             io_port_base: port as u16,
+            bytes_written: 0,
         }
     }
+
+    // Writes `byte` to the serial port.
+    fn write_byte(&mut self, byte: u8) {
+        while (inb(self.io_port_base + UART_OFFSET_LINE_STATUS) & 0x20) == 0 {}
+        outb(self.io_port_base + UART_OFFSET_TRANSMITTER_HOLDING_BUFFER, byte);
+    }
 }
 
 const UART_OFFSET_TRANSMITTER_HOLDING_BUFFER: u16 = 0;
@@ -42,27 +108,111 @@ const UART_OFFSET_LINE_STATUS: u16 = 5;
 impl Write for Uart {
     // Writes bytes `string` to the serial port.
     fn write_str(&mut self, string: &str) -> Result<(), fmt::Error> {
+        if cfg!(feature = "panic_log_buffer") && self.io_port_base == UartComPort::Com1 as u16 {
+            PANIC_LOG_BUFFER.append(string.as_bytes());
+        }
         for byte in string.bytes() {
-            while (inb(self.io_port_base + UART_OFFSET_LINE_STATUS) & 0x20) == 0 {}
-            outb(self.io_port_base + UART_OFFSET_TRANSMITTER_HOLDING_BUFFER, byte);
+            self.write_byte(byte);
         }
+        self.bytes_written += string.len() as u64;
         Ok(())
     }
 }
 
+/// The ring buffer to keep the last bytes sent to COM1. Bytes are appended
+/// without locking, by reserving space with an atomic counter, so that a
+/// processor panicking while appending never blocks the dump. Messages from
+/// multiple processors may be interleaved.
+struct PanicLogBuffer {
+    bytes: [AtomicU8; PANIC_LOG_BUFFER_SIZE],
+    /// The total number of bytes ever appended.
+    position: AtomicUsize,
+}
+
+impl PanicLogBuffer {
+    const fn new() -> Self {
+        Self {
+            bytes: [const { AtomicU8::new(0) }; PANIC_LOG_BUFFER_SIZE],
+            position: AtomicUsize::new(0),
+        }
+    }
+
+    fn append(&self, bytes: &[u8]) {
+        let start = self.position.fetch_add(bytes.len(), Ordering::SeqCst);
+        for (offset, byte) in bytes.iter().enumerate() {
+            self.bytes[(start + offset) % PANIC_LOG_BUFFER_SIZE].store(*byte, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The state to limit the number of bytes sent to COM1 per
+/// [`LOG_RATE_LIMIT_WINDOW_IN_TSC`], so that busy-polling the serial port does
+/// not dominate the runtime, eg, when the logging level is `Trace`.
+struct RateLimiter {
+    /// The maximum number of bytes per window, or zero for no limit.
+    limit: u64,
+    /// The TSC value at the start of the current window.
+    window_start_tsc: u64,
+    /// The value of [`Uart::bytes_written`] at the start of the current window.
+    window_start_bytes: u64,
+    /// The number of messages dropped in the current window.
+    suppressed_count: u64,
+}
+
 struct UartLogger {
     port: Mutex<Uart>,
+    coverage_port: Mutex<Uart>,
+    rate_limiter: Mutex<RateLimiter>,
 }
 impl UartLogger {
-    const fn new(port: UartComPort) -> Self {
+    const fn new(port: UartComPort, coverage_port: UartComPort) -> Self {
         Self {
             port: Mutex::new(Uart::new(port)),
+            coverage_port: Mutex::new(Uart::new(coverage_port)),
+            rate_limiter: Mutex::new(RateLimiter {
+                limit: 0,
+                window_start_tsc: 0,
+                window_start_bytes: 0,
+                suppressed_count: 0,
+            }),
         }
     }
 
     fn lock(&self) -> spin::MutexGuard<'_, Uart> {
         self.port.lock()
     }
+
+    // Sends a message at `level` to COM1 with `write`, unless the rate limit is
+    // exceeded in the current window. Messages at the warn level or higher are
+    // never dropped. When a new window starts, the number of messages dropped
+    // in the previous window is reported.
+    fn write_limited(&self, level: log::Level, write: impl FnOnce(&mut Uart) -> fmt::Result) {
+        let mut port = self.lock();
+        let mut limiter = self.rate_limiter.lock();
+        if limiter.limit != 0 {
+            let now = rdtsc();
+            if now - limiter.window_start_tsc >= LOG_RATE_LIMIT_WINDOW_IN_TSC {
+                if limiter.suppressed_count != 0 {
+                    let _ = writeln!(
+                        port,
+                        "#{}:WARN: {} lines suppressed",
+                        apic_id(),
+                        limiter.suppressed_count
+                    );
+                }
+                limiter.window_start_tsc = now;
+                limiter.window_start_bytes = port.bytes_written;
+                limiter.suppressed_count = 0;
+            }
+            if level > log::Level::Warn
+                && port.bytes_written - limiter.window_start_bytes >= limiter.limit
+            {
+                limiter.suppressed_count += 1;
+                return;
+            }
+        }
+        let _ = write(&mut port);
+    }
 }
 impl log::Log for UartLogger {
     fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
@@ -71,7 +221,9 @@ impl log::Log for UartLogger {
 
     fn log(&self, record: &log::Record<'_>) {
         if self.enabled(record.metadata()) {
-            let _ = writeln!(self.lock(), "#{}:{}: {}", apic_id(), record.level(), record.args());
+            self.write_limited(record.level(), |port| {
+                writeln!(port, "#{}:{}: {}", apic_id(), record.level(), record.args())
+            });
         }
     }
 
@@ -79,10 +231,12 @@ impl log::Log for UartLogger {
 }
 
 /// Gets an APIC ID.
-fn apic_id() -> u32 {
+pub(crate) fn apic_id() -> u32 {
     // See: (AMD) CPUID Fn0000_0001_EBX LocalApicId, LogicalProcessorCount, CLFlush
     // See: (Intel) Table 3-8. Information Returned by CPUID Instruction
     x86::cpuid::cpuid!(0x1).ebx >> 24
 }
 
-static UART_LOGGER: UartLogger = UartLogger::new(UartComPort::Com1);
+static UART_LOGGER: UartLogger = UartLogger::new(UartComPort::Com1, UartComPort::Com2);
+
+static PANIC_LOG_BUFFER: PanicLogBuffer = PanicLogBuffer::new();
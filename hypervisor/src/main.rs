@@ -12,16 +12,21 @@ compile_error!("This project must target the 64bit-width pointer environment.");
 extern crate alloc;
 
 mod allocator;
+mod cmplog;
 mod config;
 mod corpus;
 mod disk;
+mod emulator;
 mod global_state;
 mod hardware_vt;
 mod hypervisor;
+mod inflate;
 mod logger;
 mod mutation_engine;
 mod panic;
 mod patch;
+mod repro;
+mod rng;
 mod shell;
 mod snapshot;
 mod stats;
@@ -30,10 +35,12 @@ mod vm;
 mod x86_instructions;
 
 use crate::{
+    config::Config,
     global_state::GlobalState,
-    logger::init_uart_logger,
+    logger::{init_uart_logger, set_log_rate_limit},
     system_table::{init_system_table, system_table},
 };
+use alloc::vec::Vec;
 use core::ffi::c_void;
 use hypervisor::start_hypervisor;
 use log::{debug, error, info};
@@ -41,13 +48,16 @@ use system_table::system_table_unsafe;
 use uefi::{
     prelude::*,
     proto::{loaded_image::LoadedImage, pi::mp::MpServices},
-    table::boot::{OpenProtocolAttributes, OpenProtocolParams},
+    table::boot::{EventType, OpenProtocolAttributes, OpenProtocolParams, Tpl},
 };
 use x86::current::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE};
 
 /// The entry point of the program.
 #[unsafe(no_mangle)]
 extern "efiapi" fn efi_main(image: Handle, system_table: SystemTable<Boot>) -> Status {
+    const USAGE: &str =
+        "Usage> rhv.efi <snapshot_file>[,<snapshot_file>]... <patch_file> <corpus_dir> [replay_file] [key=value]...";
+
     // Initialize the logger and the system services.
     init_uart_logger();
     info!("rhv loaded🔥");
@@ -58,18 +68,40 @@ extern "efiapi" fn efi_main(image: Handle, system_table: SystemTable<Boot>) -> S
     // Get command line parameters.
     let args = shell::get_args();
     debug!("Parameters: {args:?}");
-    if args.len() != 4 {
-        error!("Usage> rhv.efi <snapshot_file> <patch_file> <corpus_dir>");
+    if args.len() < 4 {
+        error!("{USAGE}");
         return Status::INVALID_PARAMETER;
     }
 
-    let snapshot_path = args[1].as_str();
+    let snapshot_paths: Vec<&str> = args[1].split(',').collect();
     let patch_path = args[2].as_str();
     let corpus_path = args[3].as_str();
 
+    // The optional replay file name, followed by optional parameters that
+    // override the default configuration.
+    let mut params = &args[4..];
+    let replay_file_name = match params.first() {
+        Some(name) if !name.contains('=') => {
+            params = &params[1..];
+            Some(name.as_str())
+        }
+        _ => None,
+    };
+    let config = match Config::parse(params) {
+        Ok(config) => config,
+        Err(message) => {
+            error!("{message}");
+            error!("{USAGE}");
+            return Status::INVALID_PARAMETER;
+        }
+    };
+    log::set_max_level(config.log_level);
+    set_log_rate_limit(config.log_rate_limit);
+    debug!("{config:?}");
+
     // Initialize the global state and start the hypervisor on all logical
     // processors.
-    match GlobalState::new(snapshot_path, patch_path, corpus_path) {
+    match GlobalState::new(&snapshot_paths, patch_path, corpus_path, replay_file_name, config) {
         Ok(mut global) => start_hypervisor_on_all_processors(&mut global),
         Err(err) => {
             error!("{err:#?}");
@@ -78,12 +110,16 @@ extern "efiapi" fn efi_main(image: Handle, system_table: SystemTable<Boot>) -> S
     }
 }
 
-/// Starts the hypervisor with [`start_hypervisor`] on all logical processors.
+/// Starts the hypervisor with [`start_hypervisor`] on all logical processors
+/// including the current one, or only on the current processor when replaying
+/// an input file or with the `dry_run` feature.
 fn start_hypervisor_on_all_processors(global: &mut GlobalState) -> ! {
-    if global.number_of_cores() == 1 {
+    if global.number_of_cores() == 1
+        || global.replay_file_name().is_some()
+        || cfg!(feature = "dry_run")
+    {
         start_hypervisor(global)
     } else {
-        // Run `start_hypervisor_on_ap` on all application processors.
         // Safety: Code is single threaded.
         let st = unsafe { system_table_unsafe() };
         let bs = st.boot_services();
@@ -99,15 +135,20 @@ fn start_hypervisor_on_all_processors(global: &mut GlobalState) -> ! {
         }
         .unwrap();
 
-        // NOTE: We lose the current processor. EFI_MP_SERVICES_STARTUP_ALL_APS
-        // (== startup_all_aps) cannot be used in the non-blocking mode at this
-        // stage, and `start_hypervisor` never returns. So, this API never returns
-        // either, and the calling processor is stuck at here. We could fix this
-        // by sending INIT-SIPI-SIPI manually.
+        // Run `start_hypervisor_on_ap` on all application processors in the
+        // non-blocking mode by specifying an event, so that
+        // EFI_MP_SERVICES_STARTUP_ALL_APS (== startup_all_aps) returns as soon
+        // as the APs are dispatched, instead of waiting for `start_hypervisor`
+        // that never returns. Then, start the hypervisor on the current
+        // processor too, so that it is not lost. The event is never signaled,
+        // and is not waited for. The firmware may still poll the APs from its
+        // timer handler on this processor, and only observes them busy.
+        let event =
+            unsafe { bs.create_event(EventType::empty(), Tpl::CALLBACK, None, None) }.unwrap();
         let procedure_argument = core::ptr::from_mut::<GlobalState>(global).cast::<c_void>();
-        mp.startup_all_aps(false, start_hypervisor_on_ap, procedure_argument, None, None)
+        mp.startup_all_aps(false, start_hypervisor_on_ap, procedure_argument, Some(event), None)
             .unwrap();
-        panic!("Should not return from startup_all_aps()")
+        start_hypervisor(global)
     }
 }
 
@@ -158,3 +199,20 @@ fn size_to_pages(size: usize) -> usize {
 
     (size >> BASE_PAGE_SHIFT) + usize::from((size & PAGE_MASK) != 0)
 }
+
+/// Computes CRC-32 (IEEE 802.3) of `bytes`, which is what `zlib.crc32` in
+/// Python returns.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 0 {
+                crc >> 1
+            } else {
+                (crc >> 1) ^ 0xedb8_8320
+            };
+        }
+    }
+    !crc
+}
@@ -1,15 +1,17 @@
 //! The module containing [`MutationEngine`] and [`MutatingInput`] types.
 
 use crate::{
-    config::MAX_ITERATION_COUNT_PER_FILE,
+    config::{Config, EFFECTIVE_OFFSET_PERCENT},
     corpus::{Corpus, InputFile},
     global_state::GlobalState,
-    x86_instructions::rdtsc,
+    logger::apic_id,
+    rng::Rng,
     Page,
 };
-use alloc::{boxed::Box, format};
-use core::{fmt, ptr::addr_of, sync::atomic::AtomicU64};
+use alloc::{boxed::Box, format, vec::Vec};
+use core::{fmt, ops::Range, ptr::addr_of, sync::atomic::AtomicU64};
 use log::debug;
+use x86::current::paging::BASE_PAGE_SIZE;
 
 /// The context structure representing input data per logical processor.
 pub(crate) struct MutationEngine {
@@ -19,40 +21,157 @@ pub(crate) struct MutationEngine {
     /// current input file. Data in this region is mutated and exposed to the
     /// guest.
     input_pages: Box<[Page]>,
+    /// The number of fuzzing iterations to be done for single input. See
+    /// [`crate::config::MAX_ITERATION_COUNT_PER_FILE`].
+    max_iteration_count_per_file: u64,
+    /// How input data is mutated.
+    strategy: MutationStrategy,
+    /// The random number generator of this processor.
+    rng: Rng,
+    /// The GPA range of the snapshot memory input data is written over, if
+    /// [`Config::mutation_target_gpa`] is specified.
+    target: Option<Range<usize>>,
 }
 
 impl MutationEngine {
-    pub(crate) fn new(corpus: &Corpus) -> Self {
+    pub(crate) fn new(corpus: &Corpus, config: &Config) -> Self {
         let count = corpus.data_pages().len();
         let input_pages = unsafe { Box::<[Page]>::new_zeroed_slice(count).assume_init() };
 
         Self {
             current_input: MutatingInput::default(),
             input_pages,
+            max_iteration_count_per_file: config.max_iteration_count_per_file,
+            strategy: config.mutation_strategy,
+            rng: Rng::new(config.seed ^ u64::from(apic_id())),
+            target: (config.mutation_target_size != 0).then(|| {
+                let gpa = config.mutation_target_gpa as usize;
+                gpa..gpa + config.mutation_target_size as usize
+            }),
         }
     }
 
     /// Maps the input data into the guest memory and modifies its contents for
-    /// fuzzing.
+    /// fuzzing. `comparands` are values that may be inserted into the input
+    /// data as they are.
+    ///
+    /// Returns false if fuzzing is complete, that is, no more input file is
+    /// available.
     pub(crate) fn map_and_mutate_input(
         &mut self,
         corpus: &Corpus,
         active_thread_count: &AtomicU64,
-    ) {
+        comparands: &[u64],
+    ) -> bool {
         if self.current_input.is_done() {
             // If no more mutation is possible, pick up the new input. In this
             // case, run the guest without mutation first as a baseline.
-            let input = if cfg!(feature = "random_byte_modification") {
-                corpus.select_file()
-            } else {
-                corpus.consume_file(active_thread_count)
+            let input = match self.strategy {
+                MutationStrategy::BitFlip | MutationStrategy::InterestingValues
+                    if cfg!(feature = "shared_corpus") =>
+                {
+                    corpus.next_file()
+                }
+                MutationStrategy::BitFlip | MutationStrategy::InterestingValues => {
+                    let Some(input) = corpus.consume_file(active_thread_count) else {
+                        return false;
+                    };
+                    input
+                }
+                MutationStrategy::RandomByte => corpus.select_file(&mut self.rng),
             };
             self.copy_input_to_guest_memory(&input, corpus.data_gva());
-            self.current_input = MutatingInput::new(input);
+            self.current_input = MutatingInput::new(input, self.strategy, self.size_limit());
+            self.update_iteration_budget(corpus);
+
+            // Processors may take the same input file with the `shared_corpus`
+            // feature. Start the sequence at a random point so that they do
+            // not repeat the same mutations.
+            if cfg!(feature = "shared_corpus") && self.current_input.total_count != 0 {
+                let total_count = self.current_input.total_count as usize;
+                self.current_input.start = self.rng.below(total_count) as u64;
+            }
         } else {
             // Otherwise, mutate the input.
-            self.mutate_input();
+            self.mutate_input(comparands);
         }
+        true
+    }
+
+    /// Maps the input data into the guest memory as-is. No mutation is made
+    /// until [`MutationEngine::map_and_mutate_input`] is called.
+    pub(crate) fn map_input(&mut self, input: InputFile, input_data_gva: u64) {
+        self.copy_input_to_guest_memory(&input, input_data_gva);
+        self.current_input = MutatingInput::new(input, self.strategy, self.size_limit());
+    }
+
+    /// Returns a copy of the current input with mutation applied, that is, the
+    /// contents the guest observes in this iteration.
+    pub(crate) fn data(&self) -> InputFile {
+        let size = self.current_input.size;
+        let data =
+            unsafe { core::slice::from_raw_parts(self.input_pages.as_ptr().cast::<u8>(), size) };
+        InputFile {
+            data: data.to_vec(),
+            name: format!(
+                "{}_{}",
+                self.current_input.input.name, self.current_input.mutation_count
+            ),
+            parent: Some(self.current_input.input.name.clone()),
+            generation: self.current_input.input.generation + 1,
+            effective_offsets: self.current_input.input.effective_offsets.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the GPA of the snapshot memory and the current input with
+    /// mutation applied to write over it, if [`Config::mutation_target_gpa`]
+    /// is specified. The input is already truncated to
+    /// [`Config::mutation_target_size`].
+    pub(crate) fn target_data(&self) -> Option<(usize, &[u8])> {
+        let target = self.target.as_ref()?;
+        let size = self.current_input.size;
+        let data =
+            unsafe { core::slice::from_raw_parts(self.input_pages.as_ptr().cast::<u8>(), size) };
+        Some((target.start, data))
+    }
+
+    // Returns the maximum size of input data to mutate. Bytes beyond
+    // [`Config::mutation_target_size`] are never written to the target, so
+    // mutating them would waste iterations.
+    fn size_limit(&self) -> usize {
+        self.target.as_ref().map_or(usize::MAX, Range::len)
+    }
+
+    /// Records that the current input ran without mutation spending `exec_tsc`
+    /// in the guest, and updates how many times it is mutated accordingly.
+    pub(crate) fn record_baseline(&mut self, corpus: &Corpus, exec_tsc: u64) {
+        corpus.record_exec_tsc(&mut self.current_input.input, exec_tsc);
+        self.update_iteration_budget(corpus);
+    }
+
+    /// Records that the current mutation found `new_coverage` new edges and hit
+    /// count buckets, marks the offsets it modified as effective, and updates
+    /// how many times the input is mutated accordingly.
+    pub(crate) fn record_new_coverage(&mut self, corpus: &Corpus, new_coverage: u64) {
+        let offsets = self.current_input.mutated_offsets();
+        corpus.record_new_coverage(&mut self.current_input.input, new_coverage, &offsets);
+        if new_coverage != 0 {
+            for offset in offsets {
+                if !self.current_input.effective_offsets.contains(&offset) {
+                    self.current_input.effective_offsets.push(offset);
+                }
+            }
+        }
+        self.update_iteration_budget(corpus);
+    }
+
+    // Scales the maximum iteration count per file with the weight of the
+    // current input (the power schedule).
+    fn update_iteration_budget(&mut self, corpus: &Corpus) {
+        let weight = corpus.weight(&self.current_input.input);
+        self.current_input.iteration_budget =
+            (self.max_iteration_count_per_file * weight / 100).max(1);
     }
 
     // Returns a pointer to the page corresponds to `pfn` from input data.
@@ -92,42 +211,167 @@ impl MutationEngine {
     }
 
     // Mutates input data in the input data pages.
-    fn mutate_input(&mut self) {
-        if cfg!(feature = "random_byte_modification") {
-            self.byte_change_input();
-        } else {
-            self.bit_flip_input();
+    fn mutate_input(&mut self, comparands: &[u64]) {
+        match self.strategy {
+            MutationStrategy::BitFlip => self.bit_flip_input(),
+            MutationStrategy::RandomByte => self.byte_change_input(comparands),
+            MutationStrategy::InterestingValues => self.interesting_value_input(),
         }
 
         self.current_input.mutation_count += 1;
     }
 
     // Mutates input data in the input data pages with random manner.
-    fn byte_change_input(&mut self) {
-        let input_pages = unsafe {
+    fn byte_change_input(&mut self, comparands: &[u64]) {
+        let all_pages = unsafe {
             core::slice::from_raw_parts_mut(
                 self.input_pages.as_mut_ptr().cast::<u8>(),
-                self.current_input.input.data.len(),
+                self.input_pages.len() * BASE_PAGE_SIZE,
             )
         };
 
         // Restore previous mutation if any.
         if self.current_input.mutation_count >= 1 {
-            for i in 0..self.current_input.max_mutation_count {
-                let mutation_offset = self.current_input.offsets[i];
-                input_pages[mutation_offset] = self.current_input.original[i];
+            self.restore_bytes(all_pages);
+            self.restore_size(all_pages);
+        }
+
+        // Occasionally, grow or shrink the input data instead of modifying
+        // bytes. Empty input data has no byte to modify, and is always grown
+        // if possible, or left as is otherwise.
+        let size = self.current_input.size;
+        if cfg!(feature = "input_size_mutation") && (size == 0 || self.rng.below(8) == 0) {
+            self.resize_input(all_pages);
+            return;
+        }
+        if size == 0 {
+            return;
+        }
+
+        let input_pages = &mut all_pages[..size];
+
+        // Occasionally, overwrite bytes at a random location with one of values
+        // observed as operands of comparison instructions, in the smallest size
+        // that holds the value.
+        if !comparands.is_empty() && self.rng.below(4) == 0 {
+            let value = comparands[self.rng.below(comparands.len())];
+            let size = (u64::BITS - value.leading_zeros())
+                .div_ceil(8)
+                .next_power_of_two() as usize;
+            if size <= input_pages.len() {
+                let offset = self.rng.below(input_pages.len() - size + 1);
+                self.current_input.max_mutation_count = size;
+                for (i, &byte) in value.to_le_bytes()[..size].iter().enumerate() {
+                    self.current_input.offsets[i] = offset + i;
+                    self.current_input.original[i] = input_pages[offset + i];
+                    input_pages[offset + i] = byte;
+                }
+                return;
             }
         }
 
         // Mutate a byte at random locations with random bytes (0x00..0xff).
+        // Prefer offsets where mutation found new coverage before, if any.
         self.current_input.max_mutation_count =
-            1 + rdtsc() as usize % self.current_input.offsets.len();
+            1 + self.rng.below(self.current_input.offsets.len());
         for i in 0..self.current_input.max_mutation_count {
-            let mutation_offset = rdtsc() as usize % input_pages.len();
+            let mutation_offset = self.pick_offset(input_pages.len());
             self.current_input.offsets[i] = mutation_offset;
             self.current_input.original[i] = input_pages[mutation_offset];
-            input_pages[mutation_offset] = rdtsc() as u8;
+            input_pages[mutation_offset] = self.rng.next_u64() as u8;
+        }
+    }
+
+    // Returns a random offset within `size` bytes of input data, which is one
+    // of the effective offsets for `EFFECTIVE_OFFSET_PERCENT` percent of time.
+    fn pick_offset(&mut self, size: usize) -> usize {
+        let effective_offsets = &self.current_input.effective_offsets;
+        if !effective_offsets.is_empty() && self.rng.below(100) < EFFECTIVE_OFFSET_PERCENT {
+            let offset = effective_offsets[self.rng.below(effective_offsets.len())];
+            if offset < size {
+                return offset;
+            }
+        }
+        self.rng.below(size)
+    }
+
+    // Mutates input data in the input data pages by overwriting 1, 2 or 4 bytes
+    // with one of the interesting values, in the order of the width, offset and
+    // value.
+    fn interesting_value_input(&mut self) {
+        let all_pages = unsafe {
+            core::slice::from_raw_parts_mut(
+                self.input_pages.as_mut_ptr().cast::<u8>(),
+                self.input_pages.len() * BASE_PAGE_SIZE,
+            )
+        };
+
+        // Restore previous mutation if any.
+        self.restore_bytes(all_pages);
+
+        let index = self
+            .current_input
+            .sequence_index(self.current_input.mutation_count);
+        let Some((offset, bytes, width)) = interesting_value(self.current_input.size, index) else {
+            return;
+        };
+        self.current_input.max_mutation_count = width;
+        for (i, &byte) in bytes[..width].iter().enumerate() {
+            self.current_input.offsets[i] = offset + i;
+            self.current_input.original[i] = all_pages[offset + i];
+            all_pages[offset + i] = byte;
+        }
+    }
+
+    // Restores bytes modified by the previous mutation.
+    fn restore_bytes(&mut self, all_pages: &mut [u8]) {
+        for i in 0..self.current_input.max_mutation_count {
+            let mutation_offset = self.current_input.offsets[i];
+            all_pages[mutation_offset] = self.current_input.original[i];
+        }
+        self.current_input.max_mutation_count = 0;
+    }
+
+    // Changes the size of the input data to a random value within the input
+    // data pages. When shrinking, the truncated bytes are zero-cleared as if the
+    // input data were originally that size. When growing, random bytes are
+    // appended. Empty input data only grows, and input data at or above the
+    // size limit only shrinks to within it.
+    fn resize_input(&mut self, all_pages: &mut [u8]) {
+        let size = self.current_input.size;
+        let max_size = all_pages.len().min(self.size_limit());
+        if max_size == 0 {
+            return;
+        }
+        let new_size = if size == 0 {
+            1 + self.rng.below(max_size)
+        } else if size >= max_size || (size > 1 && self.rng.below(2) == 0) {
+            1 + self.rng.below((size.min(max_size) - 1).max(1))
+        } else {
+            size + 1 + self.rng.below(max_size - size)
+        };
+        if new_size < size {
+            all_pages[new_size..size].fill(0);
+        } else {
+            all_pages[size..new_size]
+                .iter_mut()
+                .for_each(|byte| *byte = self.rng.next_u64() as u8);
+        }
+        self.current_input.size = new_size;
+    }
+
+    // Restores the size of the input data changed by
+    // [`MutationEngine::resize_input`], as well as bytes truncated or appended.
+    fn restore_size(&mut self, all_pages: &mut [u8]) {
+        let original_size = self.current_input.input.data.len().min(self.size_limit());
+        let data = &self.current_input.input.data[..original_size];
+        let size = self.current_input.size;
+        if size < data.len() {
+            all_pages[size..data.len()].copy_from_slice(&data[size..]);
+        } else {
+            all_pages[data.len()..size].fill(0);
         }
+        self.current_input.size = data.len();
     }
 
     // Mutates input data in the input data pages with bit flipping.
@@ -135,21 +379,90 @@ impl MutationEngine {
         let input_pages = self.input_pages.as_mut();
 
         // Locate the bit position in the snapshot to flip a bit, and do it.
-        let page_offset = self.current_input.mutation_count / 8 / 4096;
-        let byte_offset = self.current_input.mutation_count / 8 % 4096;
-        let bit_offset = self.current_input.mutation_count % 8;
-        let input_page = &mut input_pages[page_offset as usize];
-        input_page.0[byte_offset as usize] ^= 1 << bit_offset;
+        let (offset, bit_offset) = self
+            .current_input
+            .bit_position(self.current_input.mutation_count);
+        let page_offset = offset / 4096;
+        let byte_offset = offset % 4096;
+        let input_page = &mut input_pages[page_offset];
+        input_page.0[byte_offset] ^= 1 << bit_offset;
 
         // Restore previous mutation if any.
         if self.current_input.mutation_count >= 1 {
-            let prev_page_offset = (self.current_input.mutation_count - 1) / 8 / 4096;
-            let prev_byte_offset = (self.current_input.mutation_count - 1) / 8 % 4096;
-            let prev_bit_offset = (self.current_input.mutation_count - 1) % 8;
-            let prev_input_page = &mut input_pages[prev_page_offset as usize];
-            prev_input_page.0[prev_byte_offset as usize] ^= 1 << prev_bit_offset;
+            let (prev_offset, prev_bit_offset) = self
+                .current_input
+                .bit_position(self.current_input.mutation_count - 1);
+            let prev_page_offset = prev_offset / 4096;
+            let prev_byte_offset = prev_offset % 4096;
+            let prev_input_page = &mut input_pages[prev_page_offset];
+            prev_input_page.0[prev_byte_offset] ^= 1 << prev_bit_offset;
+        }
+    }
+}
+
+/// The values written into input data with
+/// [`MutationStrategy::InterestingValues`], which are known to often trigger
+/// boundary-condition bugs. Same as ones used by AFL. Values of 2 and 4 bytes
+/// are written in both little and big endian.
+const INTERESTING_8: [i8; 9] = [-128, -1, 0, 1, 16, 32, 64, 100, 127];
+const INTERESTING_16: [i16; 10] = [-32768, -129, 128, 255, 256, 512, 1000, 1024, 4096, 32767];
+const INTERESTING_32: [i32; 8] = [
+    i32::MIN,
+    -100_663_046,
+    -32769,
+    32768,
+    65535,
+    65536,
+    100_663_045,
+    i32::MAX,
+];
+
+// Returns the number of variations written at each offset with the width of
+// `width` bytes.
+fn interesting_value_count(width: usize) -> usize {
+    match width {
+        1 => INTERESTING_8.len(),
+        2 => INTERESTING_16.len() * 2,
+        _ => INTERESTING_32.len() * 2,
+    }
+}
+
+// Returns the total number of mutations with
+// [`MutationStrategy::InterestingValues`] for input data of `size` bytes.
+fn interesting_value_total_count(size: usize) -> u64 {
+    [1, 2, 4]
+        .into_iter()
+        .map(|width| (interesting_value_count(width) * (size + 1).saturating_sub(width)) as u64)
+        .sum()
+}
+
+// Returns the offset and bytes to write for the `index`-th mutation with
+// [`MutationStrategy::InterestingValues`] for input data of `size` bytes, or
+// None if `index` is out of range. Only the first `width` bytes of the returned
+// array are to be written.
+fn interesting_value(size: usize, index: u64) -> Option<(usize, [u8; 4], usize)> {
+    let mut index = index as usize;
+    for width in [1, 2, 4] {
+        let count = interesting_value_count(width);
+        let offsets = (size + 1).saturating_sub(width);
+        if index >= count * offsets {
+            index -= count * offsets;
+            continue;
+        }
+        let (offset, value) = (index / count, index % count);
+        let mut bytes = [0u8; 4];
+        match width {
+            1 => bytes[..1].copy_from_slice(&INTERESTING_8[value].to_le_bytes()),
+            2 if value % 2 == 0 => {
+                bytes[..2].copy_from_slice(&INTERESTING_16[value / 2].to_le_bytes());
+            }
+            2 => bytes[..2].copy_from_slice(&INTERESTING_16[value / 2].to_be_bytes()),
+            _ if value % 2 == 0 => bytes = INTERESTING_32[value / 2].to_le_bytes(),
+            _ => bytes = INTERESTING_32[value / 2].to_be_bytes(),
         }
+        return Some((offset, bytes, width));
     }
+    None
 }
 
 /// Resolves the PA that should map the given guest pfn within the input data
@@ -185,61 +498,180 @@ pub(crate) struct MutatingInput {
     offsets: [usize; 8],
     /// The array of original bytes saved before modification in this iteration.
     original: [u8; 8],
-    /// Total bit count in [`MutatingInput::input`].
-    total_bits: u64,
+    /// The number of iterations to be done with [`MutatingInput::input`] with
+    /// [`MutationStrategy::BitFlip`] (total bit count) or
+    /// [`MutationStrategy::InterestingValues`].
+    total_count: u64,
+    /// The point where the sequence of [`MutatingInput::total_count`]
+    /// mutations starts, then wraps around. Zero unless the `shared_corpus`
+    /// feature is enabled.
+    start: u64,
+    /// The size of the input data exposed to the guest in this iteration. This
+    /// differs from the size of [`MutatingInput::input`] when the input data
+    /// is grown or shrunk with the `input_size_mutation` feature.
+    size: usize,
+    /// The number of iterations to be done with [`MutatingInput::input`] with
+    /// [`MutationStrategy::RandomByte`]. See [`Corpus::weight`].
+    iteration_budget: u64,
+    /// How [`MutatingInput::input`] is mutated.
+    strategy: MutationStrategy,
+    /// The offsets set in [`InputFile::effective_offsets`] of
+    /// [`MutatingInput::input`], to pick one of them quickly.
+    effective_offsets: Vec<usize>,
 }
 
 impl MutatingInput {
-    fn new(input: InputFile) -> Self {
-        let total_bits = input.data.len() as u64 * 8;
+    // Creates the state of mutation for `input`, where only the first
+    // `size_limit` bytes are mutated.
+    fn new(input: InputFile, strategy: MutationStrategy, size_limit: usize) -> Self {
+        let size = input.data.len().min(size_limit);
+        let total_count = match strategy {
+            MutationStrategy::InterestingValues => interesting_value_total_count(size),
+            _ => size as u64 * 8,
+        };
+        let effective_offsets = input
+            .effective_offsets
+            .iter()
+            .enumerate()
+            .filter_map(|(offset, effective)| effective.then_some(offset))
+            .collect();
         Self {
             input,
-            total_bits,
+            total_count,
+            size,
+            strategy,
+            effective_offsets,
             ..Default::default()
         }
     }
 
+    // Returns the offsets of input data modified in this iteration.
+    fn mutated_offsets(&self) -> Vec<usize> {
+        match self.strategy {
+            MutationStrategy::BitFlip if self.is_mutated() => {
+                alloc::vec![self.bit_position(self.mutation_count - 1).0]
+            }
+            MutationStrategy::BitFlip => Vec::new(),
+            MutationStrategy::RandomByte | MutationStrategy::InterestingValues => {
+                self.offsets[..self.max_mutation_count].to_vec()
+            }
+        }
+    }
+
+    // Returns the index of the bit to flip, or the interesting value to write,
+    // in the iteration of `mutation_count`, counted from `start`.
+    fn sequence_index(&self, mutation_count: u64) -> u64 {
+        (mutation_count + self.start) % self.total_count.max(1)
+    }
+
+    // Returns the byte offset and the bit to flip with
+    // [`MutationStrategy::BitFlip`] in the iteration of `mutation_count`.
+    fn bit_position(&self, mutation_count: u64) -> (usize, u8) {
+        let index = self.sequence_index(mutation_count) as usize;
+        (index / 8, (index % 8) as u8)
+    }
+
     pub(crate) fn is_mutated(&self) -> bool {
         self.mutation_count != 0
     }
 
-    pub(crate) fn data(&self) -> InputFile {
-        InputFile {
-            data: self.input.data.clone(),
-            name: format!("{}_{}", self.input.name, self.mutation_count),
-        }
+    pub(crate) fn name(&self) -> &str {
+        &self.input.name
+    }
+
+    pub(crate) fn parent(&self) -> Option<&str> {
+        self.input.parent.as_deref()
+    }
+
+    pub(crate) fn generation(&self) -> u32 {
+        self.input.generation
+    }
+
+    pub(crate) fn mutation_count(&self) -> u64 {
+        self.mutation_count
     }
 
     pub(crate) fn size(&self) -> u64 {
-        self.input.data.len() as u64
+        self.size as u64
     }
 
     fn is_done(&self) -> bool {
-        if cfg!(feature = "random_byte_modification") {
-            self.mutation_count == MAX_ITERATION_COUNT_PER_FILE || self.input.data.is_empty()
-        } else {
-            self.mutation_count == self.total_bits
+        match self.strategy {
+            MutationStrategy::BitFlip | MutationStrategy::InterestingValues => {
+                self.mutation_count == self.total_count
+            }
+            MutationStrategy::RandomByte => {
+                self.mutation_count >= self.iteration_budget || self.input.data.is_empty()
+            }
         }
     }
 }
 
 impl fmt::Debug for MutatingInput {
     fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if cfg!(feature = "random_byte_modification") {
-            write!(
+        match self.strategy {
+            MutationStrategy::BitFlip => {
+                let (offset, bit) = self.bit_position(self.mutation_count.saturating_sub(1));
+                write!(
+                    format,
+                    "{:?} #{} (bit {} at offset {:?} bytes)",
+                    self.input.name, self.mutation_count, bit, offset
+                )
+            }
+            MutationStrategy::RandomByte | MutationStrategy::InterestingValues => write!(
                 format,
-                "{:?} (mutation_count:{} offsets:{:?} bytes:{:?})",
-                self.input.name, self.max_mutation_count, self.offsets, self.original,
-            )
+                "{:?} (mutation_count:{} offsets:{:?} bytes:{:?} size:{:#x})",
+                self.input.name, self.max_mutation_count, self.offsets, self.original, self.size,
+            ),
+        }
+    }
+}
+
+/// The strategies to mutate input data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MutationStrategy {
+    /// Flips every bit of an input file one by one, and moves to the next
+    /// input file. Deterministic and better for reproducibility and testing.
+    BitFlip,
+    /// Modifies random bytes at random offsets a number of times decided by
+    /// the power schedule, and selects the next input file randomly.
+    RandomByte,
+    /// Overwrites 1, 2 and 4 bytes at every offset of an input file with
+    /// values known to often trigger boundary-condition bugs one by one, and
+    /// moves to the next input file. Deterministic like
+    /// [`MutationStrategy::BitFlip`].
+    InterestingValues,
+}
+
+impl MutationStrategy {
+    /// Returns the strategy of the name used as a command line parameter.
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bit_flip" => Some(Self::BitFlip),
+            "random_byte" => Some(Self::RandomByte),
+            "interesting_values" => Some(Self::InterestingValues),
+            _ => None,
+        }
+    }
+
+    /// Returns the name used as a command line parameter.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::BitFlip => "bit_flip",
+            Self::RandomByte => "random_byte",
+            Self::InterestingValues => "interesting_values",
+        }
+    }
+}
+
+impl Default for MutationStrategy {
+    /// Returns [`MutationStrategy::RandomByte`] if `random_byte_modification`
+    /// is enabled. Otherwise, [`MutationStrategy::BitFlip`].
+    fn default() -> Self {
+        if cfg!(feature = "random_byte_modification") {
+            Self::RandomByte
         } else {
-            write!(
-                format,
-                "{:?} #{} (bit {} at offset {:?} bytes)",
-                self.input.name,
-                self.mutation_count,
-                self.mutation_count.saturating_sub(1) % 8,
-                self.mutation_count / 8
-            )
+            Self::BitFlip
         }
     }
 }
@@ -1,11 +1,17 @@
 //! The module containing the [`panic_handler`] function.
 
-use crate::x86_instructions::{cli, hlt};
+use crate::{
+    logger::dump_panic_log_buffer,
+    x86_instructions::{cli, hlt},
+};
 use alloc::string::ToString;
 use log::error;
 
 #[panic_handler]
 fn panic_handler(info: &core::panic::PanicInfo<'_>) -> ! {
+    // Send the last log messages first, as logging below may never complete if
+    // the logger is locked.
+    dump_panic_log_buffer();
     if let Some(location) = info.location() {
         error!(
             "panicked at '{}', {}:{}:{}",
@@ -1,25 +1,49 @@
 //! The module containing types and functions to apply and revert patches.
 
 use crate::{
+    crc32,
     disk::{open_file, read_file_to_vec},
+    snapshot::Snapshot,
     Page,
 };
-use alloc::vec::Vec;
-use log::{error, info, trace};
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::ops::{Range, RangeInclusive};
+use log::{error, info, trace, warn};
 use serde::{Deserialize, Serialize};
 use uefi::proto::media::file::Directory;
-use x86::current::paging::BASE_PAGE_SHIFT;
+use x86::current::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE};
 
 /// The collection of [`PatchEntry`]. See also README.md.
 #[derive(Debug, Serialize, Deserialize)]
 #[allow(clippy::unsafe_derive_deserialize)]
 pub(crate) struct PatchSet {
     entries: Vec<PatchEntry>,
+    /// GPA ranges to be mapped as execute-only, typically code sections of the
+    /// target. Any read from or write to them is treated as a bug.
+    #[serde(default)]
+    execute_only: Vec<Range<u64>>,
+    /// The target module the patches are for. Used as the module of the
+    /// coverage reported in the drcov format.
+    #[serde(default)]
+    module: Option<PatchModule>,
+    /// The map from an address to an index of [`PatchSet::entries`], for
+    /// O(log n) lookup on every #BP and #UD instead of a linear search.
+    #[serde(skip)]
+    index: BTreeMap<u64, usize>,
+    /// The CRC-32 of the patch file. See [`PatchSet::checksum`].
+    #[serde(skip)]
+    checksum: u32,
 }
 
 impl PatchSet {
     /// Creates [`PatchSet`] from the patch file specified by `patch_path`.
-    pub(crate) fn new(dir: &mut Directory, patch_path: &str) -> Result<Self, uefi::Error> {
+    /// Entries outside the memory captured in `snapshot` are reported, as they
+    /// are never applied.
+    pub(crate) fn new(
+        dir: &mut Directory,
+        patch_path: &str,
+        snapshot: &Snapshot,
+    ) -> Result<Self, uefi::Error> {
         let mut patch_file = open_file(dir, patch_path)?;
         // Safety: Code is single threaded.
         let contents = unsafe { read_file_to_vec(&mut patch_file) }?;
@@ -31,6 +55,8 @@ impl PatchSet {
                 uefi::Status::DEVICE_ERROR
             })?;
         patch_set.entries.sort_by(|a, b| a.address.cmp(&b.address));
+        patch_set.build_index();
+        patch_set.checksum = crc32(&contents);
 
         info!("Patch entry count {}", patch_set.entries.len());
         if !patch_set.entries.is_empty() {
@@ -41,59 +67,336 @@ impl PatchSet {
             );
         }
 
+        let (module_name, module_range) = patch_set.module();
+        info!(
+            "Target module {module_name} range {:#x} - {:#x}",
+            module_range.start, module_range.end
+        );
+
+        for range in &patch_set.execute_only {
+            info!("Execute-only range {:#x} - {:#x}", range.start, range.end);
+        }
+
+        patch_set.validate(snapshot);
         Ok(patch_set)
     }
 
+    // Checks that every entry is within the memory captured in `snapshot`, and
+    // warns otherwise. Such an entry typically has a wrong address, eg, one
+    // not rebased correctly, and never takes effect, as patches are applied
+    // only when pages are paged in from the snapshot.
+    fn validate(&self, snapshot: &Snapshot) {
+        // Only the first few entries are reported, as a wrong base address can
+        // make all entries invalid.
+        const MAX_REPORTED_ENTRY_COUNT: usize = 10;
+
+        let invalid: Vec<&PatchEntry> = self
+            .entries
+            .iter()
+            .filter(|entry| !entry.pfns().all(|pfn| snapshot.contains(pfn)))
+            .collect();
+        for entry in invalid.iter().take(MAX_REPORTED_ENTRY_COUNT) {
+            warn!("Patch {:#x} is outside the snapshot", entry.address);
+        }
+        if invalid.is_empty() {
+            info!("Validated {} patch entries", self.entries.len());
+        } else {
+            warn!(
+                "{} of {} patch entries are outside the snapshot",
+                invalid.len(),
+                self.entries.len()
+            );
+        }
+    }
+
+    /// Adds the end marker at `address` specified outside the patch file (ie,
+    /// in the snapshot file). Ignored if the patch file already patches the
+    /// address.
+    pub(crate) fn add_end_marker(&mut self, address: u64) {
+        if self.find(address).is_some() {
+            warn!("End marker {address:#x} is already in the patch file");
+            return;
+        }
+        info!("End marker {address:#x}");
+        // The original byte is left empty, as the end marker is never reverted.
+        let entry = PatchEntry {
+            address,
+            patch: alloc::vec![0xcc],
+            original: Vec::new(),
+            kind: PatchKind::EndMarker,
+        };
+        let position = self.entries.partition_point(|e| e.address < address);
+        self.entries.insert(position, entry);
+        self.build_index();
+    }
+
+    // Rebuilds `index` from `entries`, which must be sorted by address.
+    fn build_index(&mut self) {
+        self.index.clear();
+        for (i, entry) in self.entries.iter().enumerate() {
+            let _ = self.index.entry(entry.address).or_insert(i);
+        }
+    }
+
     /// Applies patches for the given PFN if any.
     pub(crate) fn apply(&self, pfn: usize, page: &mut Page) {
-        // Find `PatchEntry`s that are within the page specified by `pfn`.
+        let mut count = 0;
+        for entry in self.entries_in_page(pfn) {
+            entry.write_patch(pfn, page);
+            count += 1;
+        }
+        if count != 0 {
+            trace!("Patch applied at {count} locations");
+        }
+    }
+
+    /// Returns whether any patch overlaps with the page specified by `pfn`.
+    pub(crate) fn is_patched(&self, pfn: usize) -> bool {
+        self.entries_in_page(pfn).next().is_some()
+    }
+
+    // Returns `PatchEntry`s that overlap with the page specified by `pfn`.
+    fn entries_in_page(&self, pfn: usize) -> impl Iterator<Item = &PatchEntry> {
         // `self.entries` is sorted so the range (low and high indexes) can be
-        // efficiently searched with `partition_point`.
-        let to_pfn = |address| address as usize >> BASE_PAGE_SHIFT;
-        let low = self.entries.partition_point(|e| to_pfn(e.address) < pfn);
-        let high = self.entries.partition_point(|e| to_pfn(e.address) <= pfn);
-
-        // Apply found patches for this page if any.
-        self.entries[low..high].iter().for_each(|entry| {
-            let page_offset = (entry.address & 0xfff) as usize;
-            let length = entry.length;
-            let patch = entry.patch.to_le_bytes();
-            page.0[page_offset..page_offset + length].copy_from_slice(&patch[..length]);
-        });
-        if !self.entries[low..high].is_empty() {
-            trace!("Patch applied at {} locations", self.entries[low..high].len());
+        // efficiently searched with `partition_point`. Because a patch is at
+        // most a page long, only ones starting within the previous page may
+        // overlap with this page too.
+        let page_start = (pfn << BASE_PAGE_SHIFT) as u64;
+        let low = self
+            .entries
+            .partition_point(|e| e.address + (BASE_PAGE_SIZE as u64) <= page_start);
+        let high = self
+            .entries
+            .partition_point(|e| e.address < page_start + BASE_PAGE_SIZE as u64);
+        self.entries[low..high]
+            .iter()
+            .filter(move |entry| entry.pfns().contains(&pfn))
+    }
+
+    pub(crate) fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    /// Returns whether any execute-only range is specified.
+    pub(crate) fn has_execute_only_ranges(&self) -> bool {
+        !self.execute_only.is_empty()
+    }
+
+    /// Returns whether any of `page_count` pages starting at `pfn` overlaps
+    /// with an execute-only range.
+    pub(crate) fn is_execute_only(&self, pfn: usize, page_count: usize) -> bool {
+        let start = (pfn << BASE_PAGE_SHIFT) as u64;
+        let end = ((pfn + page_count) << BASE_PAGE_SHIFT) as u64;
+        self.execute_only
+            .iter()
+            .any(|range| range.start < end && start < range.end)
+    }
+
+    /// Returns addresses of the patches of `kind`.
+    pub(crate) fn addresses_of(&self, kind: PatchKind) -> impl Iterator<Item = u64> + '_ {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.kind == kind)
+            .map(|entry| entry.address)
+    }
+
+    /// Returns the name and address range of the target module. When the patch
+    /// file does not specify the module, the range spanned by the entries is
+    /// returned instead.
+    pub(crate) fn module(&self) -> (&str, Range<u64>) {
+        if let Some(module) = &self.module {
+            return (&module.name, module.base..module.base + module.size);
+        }
+        match (self.entries.first(), self.entries.last()) {
+            (Some(first), Some(last)) => {
+                ("target", first.address..last.address + last.patch.len() as u64)
+            }
+            _ => ("target", 0..0),
         }
     }
 
     /// Finds a patch entry corresponds to the address specified by `rip`.
     pub(crate) fn find(&self, rip: u64) -> Option<&PatchEntry> {
-        self.entries.iter().find(|e| e.address == rip)
+        self.index.get(&rip).map(|&i| &self.entries[i])
     }
 }
 
+/// The module patched by a [`PatchSet`], ie, the fuzzing target.
+#[derive(Serialize, Deserialize, Debug)]
+struct PatchModule {
+    /// The file name of the module.
+    name: String,
+    /// The address the module is loaded at.
+    base: u64,
+    /// The size of the module in bytes.
+    size: u64,
+}
+
 /// The patch entry describing GPA and contents of the patch, as well as
 /// original bytes to restore when reverting the patch.
+///
+/// A patch may be up to a page long, and may straddle a page boundary.
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(try_from = "RawPatchEntry")]
 pub(crate) struct PatchEntry {
     address: u64,
-    length: usize,
-    patch: u32,
-    original: u32, // used only when `patch` is 0xCC
+    patch: Vec<u8>,
+    original: Vec<u8>, // used only when reverting the patch
+    /// What #BP caused by this patch means. Defaults to
+    /// [`PatchKind::Breakpoint`] when omitted.
+    kind: PatchKind,
+}
+
+/// The meaning of #BP caused by a patch (ie, when `patch` starts with 0xCC).
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PatchKind {
+    /// The patch is for coverage tracking. #BP means a new basic block is
+    /// executed.
+    #[default]
+    Breakpoint,
+    /// The patch is placed where the fuzzing target completes, such as its
+    /// return address. #BP means the iteration finished successfully.
+    EndMarker,
+    /// The patch is placed where the guest should never reach, such as an
+    /// assertion failure handler. #BP means a bug is found.
+    Abort,
+    /// The patch is placed on a comparison instruction. #BP means values of its
+    /// operands should be recorded (see `cmplog.rs`). The patch is kept, and
+    /// the original instruction is single-stepped.
+    Compare,
+    /// The patch is placed on a basic block to be profiled. #BP means the
+    /// block is executed, and the number of times it is executed across all
+    /// iterations is counted. The patch is kept, and the original instruction
+    /// is single-stepped.
+    Profile,
 }
 
 impl PatchEntry {
+    /// Returns the GPA of the patch.
+    pub(crate) fn address(&self) -> u64 {
+        self.address
+    }
+
+    /// Returns the kind of the patch.
+    pub(crate) fn kind(&self) -> PatchKind {
+        self.kind
+    }
+
+    /// Returns the original bytes at the address.
+    pub(crate) fn original(&self) -> &[u8] {
+        &self.original
+    }
+
+    /// Returns the range of PFNs the patch spans, which is either one or two
+    /// pages.
+    pub(crate) fn pfns(&self) -> RangeInclusive<usize> {
+        let first = self.address as usize >> BASE_PAGE_SHIFT;
+        let last = (self.address as usize + self.patch.len() - 1) >> BASE_PAGE_SHIFT;
+        first..=last
+    }
+
     /// Reverts the patch by rewriting the GPA with the original bytes.
+    ///
+    /// All pages in [`PatchEntry::pfns`] must already be read from the snapshot
+    /// file. Otherwise, the patch is applied again when they are read.
     pub(crate) fn revert(&self, snapshot: &mut [Page]) {
         // The following code may concurrently modify the shared resources, ie,
         // snapshot, but there will be no modification that conflicts with other
         // processors, so we are good without lock.
-        let pfn = self.address >> BASE_PAGE_SHIFT;
-        let page = &mut snapshot[pfn as usize];
-        let page_offset = (self.address & 0xfff) as usize;
-        let length = self.length;
-        let original = self.original.to_le_bytes();
-
-        // Rewrite the patched address with the original bytes
-        page.0[page_offset..page_offset + length].copy_from_slice(&original[..length]);
+        for pfn in self.pfns() {
+            self.write_original(pfn, &mut snapshot[pfn]);
+        }
+    }
+
+    /// Rewrites the part of the patched address within `pfn` in `page`, which
+    /// backs the page, with the original bytes.
+    pub(crate) fn write_original(&self, pfn: usize, page: &mut Page) {
+        Self::write(self.address, pfn, page, &self.original);
+    }
+
+    /// Rewrites the part of the patched address within `pfn` in `page`, which
+    /// backs the page, with the patch.
+    pub(crate) fn write_patch(&self, pfn: usize, page: &mut Page) {
+        Self::write(self.address, pfn, page, &self.patch);
+    }
+
+    // Copies the part of `bytes` to be placed at `address` that falls into the
+    // page `pfn`.
+    fn write(address: u64, pfn: usize, page: &mut Page, bytes: &[u8]) {
+        let page_start = pfn << BASE_PAGE_SHIFT;
+        let start = (address as usize).max(page_start);
+        let end = (address as usize + bytes.len()).min(page_start + BASE_PAGE_SIZE);
+        if start >= end {
+            return;
+        }
+        let src = start - address as usize..end - address as usize;
+        page.0[start - page_start..end - page_start].copy_from_slice(&bytes[src]);
+    }
+}
+
+/// The patch entry as written in the patch file.
+///
+/// `patch` and `original` are arrays of bytes. For backward compatibility,
+/// they may also be integers, in which case the lower `length` (up to 4) bytes
+/// of them in little endian are used.
+#[derive(Deserialize)]
+struct RawPatchEntry {
+    address: u64,
+    #[serde(default)]
+    length: Option<usize>,
+    patch: RawBytes,
+    original: RawBytes,
+    #[serde(default)]
+    kind: PatchKind,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawBytes {
+    Integer(u32),
+    Bytes(Vec<u8>),
+}
+
+impl RawBytes {
+    fn into_vec(self, length: Option<usize>) -> Result<Vec<u8>, String> {
+        let bytes = match self {
+            Self::Integer(value) => {
+                let length = length.ok_or("length is required for an integer patch")?;
+                value
+                    .to_le_bytes()
+                    .get(..length)
+                    .ok_or("length must be 4 or less for an integer patch")?
+                    .to_vec()
+            }
+            Self::Bytes(bytes) => bytes,
+        };
+        if length.is_some_and(|length| length != bytes.len()) {
+            return Err(format!("{bytes:x?} is not {length:?} bytes"));
+        }
+        Ok(bytes)
+    }
+}
+
+impl TryFrom<RawPatchEntry> for PatchEntry {
+    type Error = String;
+
+    fn try_from(raw: RawPatchEntry) -> Result<Self, Self::Error> {
+        let patch = raw.patch.into_vec(raw.length)?;
+        let original = raw.original.into_vec(raw.length)?;
+        if patch.is_empty() || patch.len() > BASE_PAGE_SIZE {
+            return Err(format!("Patch at {:#x} is {} bytes", raw.address, patch.len()));
+        }
+        if patch.len() != original.len() {
+            return Err(format!(
+                "Patch at {:#x} and its original bytes differ in length",
+                raw.address
+            ));
+        }
+        Ok(Self {
+            address: raw.address,
+            patch,
+            original,
+            kind: raw.kind,
+        })
     }
 }
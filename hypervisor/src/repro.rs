@@ -0,0 +1,58 @@
+//! The module containing functions to save files needed to reproduce crashes.
+//!
+//! For each unique crash, the following files are written under
+//! `repro\<crash_id>` on the volume this module is loaded from:
+//! - the input file exactly as the guest observed it, and
+//! - `startup.nsh` that runs rhv in the replay mode with the input file, using
+//!   the same snapshot file, patch file and configuration.
+//!
+//! The directory can be copied into another disk image with the snapshot and
+//! patch files to re-trigger the crash without fuzzing.
+
+use crate::{
+    corpus::InputFile,
+    disk::{create_dir, write_file},
+    global_state::GlobalState,
+};
+use alloc::format;
+use log::info;
+
+/// The directory where the bundles are written.
+pub(crate) const REPRO_DIR: &str = "repro";
+
+/// Writes the files needed to reproduce the crash `crash_id` caused by
+/// `input` against the snapshot `snapshot_index`.
+pub(crate) fn save_repro_bundle(
+    global: &GlobalState,
+    snapshot_index: usize,
+    crash_id: &str,
+    input: &InputFile,
+) -> Result<(), uefi::Error> {
+    let bundle_path = format!("{REPRO_DIR}\\{crash_id}");
+    let startup_nsh = format!(
+        "# Reproduces {crash_id}. Copy this file to the root of the disk that has the\r\n\
+         # snapshot and patch files, and this directory as {bundle_path}.\r\n\
+         fs0:\r\n\
+         rhv.efi {} {} {bundle_path} {} {}\r\n",
+        global.snapshot_path(snapshot_index),
+        global.patch_path(),
+        input.name,
+        global.config().to_params(),
+    );
+
+    let mut volume = global.volume();
+    let _unused = create_dir(&mut volume, REPRO_DIR)?;
+    let mut bundle_dir = create_dir(&mut volume, &bundle_path)?;
+
+    // The input file from a subdirectory of the corpus directory is written
+    // into the same subdirectory, so that it is found with the same name.
+    let mut parent = 0;
+    while let Some(separator) = input.name[parent..].find('\\') {
+        parent += separator + 1;
+        let _unused = create_dir(&mut bundle_dir, &input.name[..parent - 1])?;
+    }
+    write_file(&mut bundle_dir, &input.name, &input.data)?;
+    write_file(&mut bundle_dir, "startup.nsh", startup_nsh.as_bytes())?;
+    info!("Saved the reproduction files in {bundle_path:?}");
+    Ok(())
+}
@@ -0,0 +1,41 @@
+//! The module containing the [`Rng`] type.
+
+/// The pseudo random number generator based on xorshift64*.
+///
+/// Each processor owns an instance seeded from [`crate::config::Config::seed`]
+/// and its APIC ID, so that the same seed produces the same sequence of
+/// mutations on the processor.
+#[derive(Debug, Clone)]
+pub(crate) struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Creates the generator from `seed`. Any value, including zero, is valid.
+    pub(crate) fn new(seed: u64) -> Self {
+        // Scramble the seed with splitmix64, so that similar seeds (eg, ones
+        // only differing in APIC IDs) do not produce similar sequences, and the
+        // state is never zero, which xorshift cannot leave.
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        Self {
+            state: if z == 0 { 1 } else { z },
+        }
+    }
+
+    /// Returns the next random value.
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns the next random value less than `bound`, which must not be
+    /// zero.
+    pub(crate) fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
@@ -3,13 +3,15 @@
 // Wraps UEFI Shell protocols to gain command line parameters specified by an
 // user. Modern UEFI implements EFI_SHELL_PARAMETERS_PROTOCOL and not the other,
 // while some older system such as VMware UEFI implements EFI_SHELL_INTERFACE
-// and not the other.
+// and not the other. When started without the shell, for example, from a boot
+// option, neither is available, and the load options of the image are used.
 use crate::system_table::system_table_unsafe;
 use alloc::{
     string::{String, ToString},
     vec::Vec,
 };
-use core::ffi::c_void;
+use core::{ffi::c_void, mem::take};
+use log::debug;
 use uefi::{
     proto::{loaded_image::LoadedImage, Protocol},
     table::boot::{OpenProtocolAttributes, OpenProtocolParams},
@@ -17,12 +19,16 @@ use uefi::{
 };
 
 /// Gets argc/argv using `EFI_SHELL_INTERFACE` or
-/// `EFI_SHELL_PARAMETERS_PROTOCOL`. <https://github.com/tianocore/edk2/blob/7c0ad2c33810ead45b7919f8f8d0e282dae52e71/ShellPkg/Library/UefiShellCEntryLib/UefiShellCEntryLib.c>
+/// `EFI_SHELL_PARAMETERS_PROTOCOL`, or the load options of this image if
+/// neither is available. Returns an empty vector if none of them is. <https://github.com/tianocore/edk2/blob/7c0ad2c33810ead45b7919f8f8d0e282dae52e71/ShellPkg/Library/UefiShellCEntryLib/UefiShellCEntryLib.c>
 pub(crate) fn get_args() -> Vec<String> {
-    match get_args_with_protocol::<ShellInterface>() {
-        Ok(args) => args,
-        Err(_) => get_args_with_protocol::<ShellParametersProtocol>().unwrap(),
-    }
+    get_args_with_protocol::<ShellInterface>()
+        .or_else(|_| get_args_with_protocol::<ShellParametersProtocol>())
+        .or_else(|_| {
+            debug!("Shell protocols are not available. Using the load options");
+            get_args_from_load_options()
+        })
+        .unwrap_or_default()
 }
 
 // Gets argc/argv using the given protocol.
@@ -43,6 +49,69 @@ fn get_args_with_protocol<T: ShellProtocol + Protocol>() -> uefi::Result<Vec<Str
     Ok(shell.args())
 }
 
+// Gets argc/argv by splitting the load options of this image, which the boot
+// manager sets from the optional data of the boot option. If the first argument
+// is not an EFI image, the name of this image is assumed to be omitted, as is
+// usual for boot options, and is prepended.
+fn get_args_from_load_options() -> uefi::Result<Vec<String>> {
+    // Safety: Code is single threaded.
+    let st = unsafe { system_table_unsafe() };
+    let bs = st.boot_services();
+    let loaded_image = unsafe {
+        bs.open_protocol::<LoadedImage>(
+            OpenProtocolParams {
+                handle: bs.image_handle(),
+                agent: bs.image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )?
+    };
+    let options = loaded_image
+        .load_options_as_cstr16()
+        .map_err(|_err| uefi::Status::NOT_FOUND)?;
+    let mut args = split_args(&options.to_string());
+    match args.first() {
+        None => return Err(uefi::Error::from(uefi::Status::NOT_FOUND)),
+        Some(arg) if !arg.to_ascii_lowercase().ends_with(".efi") => {
+            args.insert(0, "rhv.efi".to_string());
+        }
+        Some(_) => {}
+    }
+    Ok(args)
+}
+
+// Splits `command_line` into arguments separated by whitespace. Whitespace
+// between a pair of `"` is part of an argument, as in the shell.
+fn split_args(command_line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut in_quotes = false;
+    for c in command_line.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                in_arg = true;
+            }
+            _ if c.is_whitespace() && !in_quotes => {
+                if in_arg {
+                    args.push(take(&mut arg));
+                    in_arg = false;
+                }
+            }
+            _ => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    args
+}
+
 // This protocol holds command line parameters.
 trait ShellProtocol {
     fn args(&self) -> Vec<String>;
@@ -1,16 +1,27 @@
 //! The module containing types and functions to read the snapshot file.
 
 use crate::{
-    disk::{get_file_info, open_file, read_page_from_snapshot},
+    config::{Config, MASK_GUEST_INTERRUPTS, SNAPSHOT_PREFETCH_PAGE_COUNT},
+    crc32,
+    disk::{
+        get_file_info, open_file, read_compressed_page_from_snapshot, read_from_file,
+        read_page_from_snapshot, read_pages_from_snapshot,
+    },
     global_state::GlobalState,
-    size_to_pages, Page,
+    hardware_vt::{is_valid_xcr0, GuestRegisters},
+    size_to_pages,
+    x86_instructions::{rdmsr, xcr0},
+    Page,
 };
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 use bit_vec::BitVec;
-use core::ptr::addr_of;
-use log::{debug, error, info};
+use core::{alloc::Layout, ops::Range, ptr::addr_of};
+use log::{debug, error, info, warn};
 use uefi::proto::media::file::{Directory, RegularFile};
-use x86::current::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE};
+use x86::current::{
+    paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE, LARGE_PAGE_SIZE, PAGE_SIZE_ENTRIES},
+    rflags::RFlags,
+};
 
 /// The current state and contents of the snapshot.
 ///
@@ -21,12 +32,101 @@ use x86::current::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE};
 pub(crate) struct Snapshot {
     pub(crate) memory: Box<[Page]>,
     pub(crate) registers: SnapshotRegisters,
+    /// How the guest receives input data.
+    pub(crate) harness: Harness,
     memory_ranges: Vec<SnapshotMemoryRange>,
+    /// The guest physical address ranges of MMIO captured in the snapshot
+    /// file. Those are not backed by the snapshot file.
+    mmio_ranges: Vec<Range<u64>>,
+    /// The page filled with zero, used to back MMIO.
+    zero_page: Box<Page>,
+    /// The locations of compressed pages, in the order of pages in
+    /// [`Snapshot::memory_ranges`], if the snapshot file is compressed.
+    compressed_pages: Option<Vec<CompressedPage>>,
+    /// The CRC-32 of each page, in the order of pages in
+    /// [`Snapshot::memory_ranges`], if the snapshot file has them and the
+    /// `snapshot_checksum` feature is enabled.
+    checksums: Option<Vec<u32>>,
     read_bitmap: BitVec,
+    /// The number of pages read from the snapshot file so far. See
+    /// [`Snapshot::resolved_page_count`].
     resolved_page_count: u64,
     file: RegularFile,
 }
 
+/// How the guest receives input data and where it ends, optionally described
+/// in the snapshot file. Defaults to the convention of the bundled sample,
+/// where input data is placed right after the snapshot memory, RDI and RSI
+/// receive its address and size, and the end marker comes from the patch file.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Harness {
+    /// The address where input data is placed, if specified. The guest is
+    /// expected to identity-map it.
+    pub(crate) input_gva: Option<u64>,
+    /// The number of the general purpose register that receives the address
+    /// of input data, as encoded in instructions (eg, 7 for RDI).
+    pub(crate) address_register: usize,
+    /// The number of the general purpose register that receives the size of
+    /// input data.
+    pub(crate) size_register: usize,
+    /// The address of the end marker, if specified, in addition to ones in the
+    /// patch file.
+    pub(crate) end_marker: Option<u64>,
+    /// The values to set to general purpose registers at the start of each
+    /// iteration, indexed by register numbers. See
+    /// [`Config::register_values`].
+    pub(crate) register_values: [Option<u64>; 16],
+    /// The address to start each iteration at instead of the captured RIP, if
+    /// specified. See [`Config::entry_rip`].
+    pub(crate) entry_rip: Option<u64>,
+    /// The stack pointer to start each iteration with instead of the captured
+    /// RSP, if specified. See [`Config::entry_rsp`].
+    pub(crate) entry_rsp: Option<u64>,
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Self {
+            input_gva: None,
+            address_register: 7,
+            size_register: 6,
+            end_marker: None,
+            register_values: [None; 16],
+            entry_rip: None,
+            entry_rsp: None,
+        }
+    }
+}
+
+impl Harness {
+    /// Overrides the registers and the entry point with ones given through the
+    /// command line parameters `config`, if any.
+    pub(crate) fn override_with(&mut self, config: &Config) {
+        if let Some(register) = config.address_register {
+            self.address_register = register;
+        }
+        if let Some(register) = config.size_register {
+            self.size_register = register;
+        }
+        self.register_values = config.register_values;
+        if config.entry_rip != 0 {
+            self.entry_rip = Some(config.entry_rip);
+        }
+        if config.entry_rsp != 0 {
+            self.entry_rsp = Some(config.entry_rsp);
+        }
+    }
+
+    /// Sets [`Harness::register_values`] to `registers`.
+    pub(crate) fn seed_registers(&self, registers: &mut GuestRegisters) {
+        for (number, value) in self.register_values.iter().enumerate() {
+            if let Some(value) = value {
+                *registers.gpr_mut(number) = *value;
+            }
+        }
+    }
+}
+
 /// The collection of register values captured in the snapshot file.
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
@@ -75,6 +175,60 @@ pub(crate) struct SnapshotRegisters {
     pub(crate) r13: u64, // +0x100
     pub(crate) r14: u64,
     pub(crate) r15: u64, // +0x110
+    pub(crate) pat: u64,
+    /// The TSC value when the snapshot was taken. Older snapshot files do not
+    /// capture it and leave it zero.
+    pub(crate) tsc: u64, // +0x120
+    /// The XCR0 value when the snapshot was taken. Older snapshot files do not
+    /// capture it and leave it zero.
+    pub(crate) xcr0: u64,
+}
+
+impl SnapshotRegisters {
+    /// Returns the bits that determine the processor mode of the guest, that
+    /// is, CR0.PE, CR0.PG and EFER.LMA.
+    pub(crate) fn mode(&self) -> (u64, u64) {
+        const CR0_PE: u64 = 1 << 0;
+        const CR0_PG: u64 = 1 << 31;
+        const EFER_LMA: u64 = 1 << 10;
+
+        (self.cr0 & (CR0_PE | CR0_PG), self.efer & EFER_LMA)
+    }
+
+    /// Returns the XCR0 value captured in the snapshot. If it is not captured
+    /// or not supported by the current processor, the value of the current
+    /// processor is returned instead, or the reset value if it is not
+    /// accessible.
+    pub(crate) fn guest_xcr0(&self) -> u64 {
+        const XCR0_RESET_VALUE: u64 = 1;
+
+        if is_valid_xcr0(self.xcr0) {
+            self.xcr0
+        } else {
+            xcr0().unwrap_or(XCR0_RESET_VALUE)
+        }
+    }
+
+    /// Returns the IA32_PAT value captured in the snapshot. Older snapshot
+    /// files do not capture it and leave it zero, and if so, the value of the
+    /// current processor is returned instead.
+    pub(crate) fn guest_pat(&self) -> u64 {
+        if self.pat == 0 {
+            rdmsr(x86::msr::IA32_PAT)
+        } else {
+            self.pat
+        }
+    }
+
+    /// Returns the RFLAGS value captured in the snapshot, with the IF bit
+    /// cleared if [`MASK_GUEST_INTERRUPTS`] is enabled.
+    pub(crate) fn guest_rflags(&self) -> u64 {
+        if MASK_GUEST_INTERRUPTS {
+            self.rflags & !RFlags::FLAGS_IF.bits()
+        } else {
+            self.rflags
+        }
+    }
 }
 
 impl Snapshot {
@@ -95,34 +249,112 @@ impl Snapshot {
         let mut page = Page::new();
         read_page_from_snapshot(&mut snapshot_file, &mut page, size_in_pages - 1)?;
         let metadata = unsafe { core::mem::transmute::<Page, SnapshotMetadataRaw>(page) };
-        if metadata.magic != SNAPSHOT_SIGNATURE {
-            error!("{snapshot_path:?} is not a snapshot file (signature not found)");
-            return Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER));
-        }
+        let compressed = match metadata.magic {
+            SNAPSHOT_SIGNATURE => false,
+            SNAPSHOT_COMPRESSED_SIGNATURE => true,
+            _ => {
+                error!("{snapshot_path:?} is not a snapshot file (signature not found)");
+                return Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER));
+            }
+        };
 
         // Capture physical memory ranges saved in the snapshot.
-        let mut memory_ranges: Vec<SnapshotMemoryRange> = Vec::new();
-        metadata.memory_ranges.iter().for_each(|range| {
-            if range.page_count != 0 {
-                debug!(
-                    "Memory range: {:#x} - {:#x}",
-                    range.page_base,
-                    range.page_base + range.page_count * (BASE_PAGE_SIZE as u64)
-                );
-                memory_ranges.push(range.clone());
-            }
-        });
+        let memory_ranges =
+            read_memory_ranges(&mut snapshot_file, snapshot_path, &metadata, size_in_pages)?;
+        let range_page_count = metadata.range_page_count as usize;
+        let checksum_page_count = metadata.checksum_page_count as usize;
+
+        // Read the CRC-32 of each page right before the additional memory
+        // ranges, if any and requested.
+        let checksums = read_checksums(
+            &mut snapshot_file,
+            snapshot_path,
+            &metadata,
+            &memory_ranges,
+            size_in_pages,
+        )?;
+
+        // Capture MMIO ranges saved in the snapshot, if any. Older snapshot files
+        // leave them zero.
+        let mmio_ranges: Vec<Range<u64>> = metadata
+            .mmio_ranges
+            .iter()
+            .filter(|range| range.page_count != 0)
+            .map(|range| {
+                let end = range.page_base + range.page_count * (BASE_PAGE_SIZE as u64);
+                debug!("MMIO range: {:#x} - {:#x}", range.page_base, end);
+                range.page_base..end
+            })
+            .collect();
+
+        // If the snapshot file is compressed, read the index of compressed pages
+        // right before the checksums, the additional memory ranges and the
+        // metadata. Otherwise, the snapshot file contains the physical memory
+        // as-is, except them.
+        let (memory_size_in_pages, compressed_pages) = if compressed {
+            let page_count = memory_ranges
+                .iter()
+                .map(|range| range.page_count as usize)
+                .sum::<usize>();
+            let index_size = page_count * size_of::<CompressedPage>();
+            let index_offset = (size_in_pages
+                - 1
+                - range_page_count
+                - checksum_page_count
+                - size_to_pages(index_size))
+                << BASE_PAGE_SHIFT;
+            let mut compressed_pages = vec![CompressedPage::default(); page_count];
+            // Safety: `CompressedPage` is plain data and valid for any bytes.
+            let index = unsafe {
+                core::slice::from_raw_parts_mut(
+                    compressed_pages.as_mut_ptr().cast::<u8>(),
+                    index_size,
+                )
+            };
+            read_from_file(&mut snapshot_file, index_offset as u64, index)?;
+            info!("Compressed snapshot with {page_count} pages");
+
+            let memory_size_in_pages = memory_ranges
+                .iter()
+                .map(|range| (range.page_base >> BASE_PAGE_SHIFT) + range.page_count)
+                .max()
+                .unwrap_or(0) as usize;
+            (memory_size_in_pages, Some(compressed_pages))
+        } else {
+            // do not include the metadata size
+            (size_in_pages - 1 - range_page_count - checksum_page_count, None)
+        };
 
         // Allocates the buffer for snapshot memory. Contents will be populated
         // on-demand. No zero initialization as it is very slow (huge memory).
-        let memory_size_in_pages = size_in_pages - 1; // do not include the metadata size
-        let memory = unsafe { Box::<[Page]>::new_uninit_slice(memory_size_in_pages).assume_init() };
+        // The buffer is aligned to 2MB so that 2MB-aligned guest physical
+        // memory ranges can be mapped with large pages.
+        let layout =
+            Layout::from_size_align(memory_size_in_pages * BASE_PAGE_SIZE, LARGE_PAGE_SIZE)
+                .unwrap();
+        #[allow(clippy::cast_ptr_alignment)]
+        let memory_ptr = unsafe { alloc::alloc::alloc(layout) }.cast::<Page>();
+        if memory_ptr.is_null() {
+            alloc::alloc::handle_alloc_error(layout);
+        }
+        // Safety: The global allocator frees pages in the same way regardless of
+        // alignment as long as it is a multiple of 4KB.
+        let memory = unsafe {
+            Box::from_raw(core::ptr::slice_from_raw_parts_mut(memory_ptr, memory_size_in_pages))
+        };
 
+        let harness = metadata.harness.parse(snapshot_path)?;
+        debug!("{harness:#x?}");
         debug!("{:#x?}", metadata.registers);
         let mut snapshot = Self {
             registers: metadata.registers,
+            harness,
             memory,
             memory_ranges,
+            mmio_ranges,
+            zero_page: Box::new(Page::new()),
+            compressed_pages,
+            checksums,
             read_bitmap: BitVec::from_elem(memory_size_in_pages, false),
             resolved_page_count: 0,
             file: snapshot_file,
@@ -135,66 +367,462 @@ impl Snapshot {
         Ok(snapshot)
     }
 
-    // Checks whether the given page is captured in the snapshot file.
-    fn contains(&self, pfn: usize) -> bool {
+    /// Returns the number of pages read from the snapshot file so far, that is,
+    /// the working set of all VMs within the snapshot memory.
+    pub(crate) fn resolved_page_count(&self) -> u64 {
+        self.resolved_page_count
+    }
+
+    /// Returns RIP to start each iteration at, that is, the captured one unless
+    /// overridden by [`Harness::entry_rip`].
+    pub(crate) fn entry_rip(&self) -> u64 {
+        self.harness.entry_rip.unwrap_or(self.registers.rip)
+    }
+
+    /// Returns RSP to start each iteration with, that is, the captured one
+    /// unless overridden by [`Harness::entry_rsp`].
+    pub(crate) fn entry_rsp(&self) -> u64 {
+        self.harness.entry_rsp.unwrap_or(self.registers.rsp)
+    }
+
+    /// Checks whether the given page is captured in the snapshot file.
+    pub(crate) fn contains(&self, pfn: usize) -> bool {
         self.memory_ranges.iter().any(|range| {
             let base = (range.page_base >> BASE_PAGE_SHIFT) as usize;
             (base..base + range.page_count as usize).contains(&pfn)
         })
     }
 
+    // Checks whether all of the given pages are captured in a single memory
+    // range in the snapshot file.
+    fn contains_range(&self, pfns: &Range<usize>) -> bool {
+        self.memory_ranges.iter().any(|range| {
+            let base = (range.page_base >> BASE_PAGE_SHIFT) as usize;
+            base <= pfns.start && pfns.end <= base + range.page_count as usize
+        })
+    }
+
+    // Returns the index of the given page among pages captured in the snapshot
+    // file, in the order of the memory ranges.
+    fn page_index(&self, pfn: usize) -> Option<usize> {
+        let mut index = 0;
+        for range in &self.memory_ranges {
+            let base = (range.page_base >> BASE_PAGE_SHIFT) as usize;
+            let count = range.page_count as usize;
+            if (base..base + count).contains(&pfn) {
+                return Some(index + pfn - base);
+            }
+            index += count;
+        }
+        None
+    }
+
     // Resolves the page that should back the given guest `pfn`.
     fn resolve_page(&mut self, pfn: usize) -> Result<&mut Page, uefi::Error> {
+        let compressed_page = match &self.compressed_pages {
+            Some(pages) => {
+                let Some(index) = self.page_index(pfn) else {
+                    error!("PFN {pfn:#x} is outside the compressed snapshot");
+                    return Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER));
+                };
+                Some(pages[index])
+            }
+            None => None,
+        };
         let page = &mut self.memory[pfn];
-        read_page_from_snapshot(&mut self.file, page, pfn)?;
+        match compressed_page {
+            Some(compressed_page) => read_compressed_page_from_snapshot(
+                &mut self.file,
+                page,
+                compressed_page.offset,
+                compressed_page.length as usize,
+            )?,
+            None => read_page_from_snapshot(&mut self.file, page, pfn)?,
+        }
+        self.verify_checksum(pfn)?;
         self.read_bitmap.set(pfn, true);
         self.resolved_page_count += 1;
-        Ok(page)
+        Ok(&mut self.memory[pfn])
+    }
+
+    // Resolves the pages that should back the given contiguous guest `pfns`.
+    // If the snapshot file is not compressed, those are read with a single
+    // read.
+    fn resolve_pages(&mut self, pfns: Range<usize>) -> Result<&mut [Page], uefi::Error> {
+        if self.compressed_pages.is_some() {
+            for pfn in pfns.clone() {
+                let _ = self.resolve_page(pfn)?;
+            }
+        } else {
+            read_pages_from_snapshot(&mut self.file, &mut self.memory[pfns.clone()], pfns.start)?;
+            for pfn in pfns.clone() {
+                self.verify_checksum(pfn)?;
+                self.read_bitmap.set(pfn, true);
+            }
+            self.resolved_page_count += pfns.len() as u64;
+        }
+        Ok(&mut self.memory[pfns])
+    }
+
+    // Checks that contents of the given page just read from the snapshot file
+    // match its CRC-32, if the snapshot file has them. This is done before
+    // patches are applied.
+    fn verify_checksum(&self, pfn: usize) -> Result<(), uefi::Error> {
+        let Some(checksums) = &self.checksums else {
+            return Ok(());
+        };
+        let Some(&expected) = self.page_index(pfn).and_then(|index| checksums.get(index)) else {
+            error!("PFN {pfn:#x} is outside the checksum table");
+            return Err(uefi::Error::from(uefi::Status::CRC_ERROR));
+        };
+        let actual = crc32(&self.memory[pfn].0);
+        if actual != expected {
+            error!("Snapshot corruption at PFN {pfn:#x} (CRC-32 {actual:08x}, expected {expected:08x})");
+            return Err(uefi::Error::from(uefi::Status::CRC_ERROR));
+        }
+        Ok(())
+    }
+
+    // Returns the range of pages to be read together with the given `pfn`.
+    // That is, `pfn` and up to `SNAPSHOT_PREFETCH_PAGE_COUNT` pages following
+    // it that are within the same memory range and not read yet.
+    fn prefetch_range(&self, pfn: usize) -> Range<usize> {
+        let range_end = self
+            .memory_ranges
+            .iter()
+            .find_map(|range| {
+                let base = (range.page_base >> BASE_PAGE_SHIFT) as usize;
+                let end = base + range.page_count as usize;
+                (base..end).contains(&pfn).then_some(end)
+            })
+            .unwrap_or(pfn + 1);
+        let end = range_end.min(pfn + 1 + SNAPSHOT_PREFETCH_PAGE_COUNT);
+        let end = (pfn + 1..end)
+            .find(|&next_pfn| self.read_bitmap[next_pfn])
+            .unwrap_or(end);
+        pfn..end
     }
 }
 
 // Resolves snapshot contents that should back the given guest `pfn` from the
-// snapshot file and applies patches as needed.
-pub(crate) fn resolve_page_from_snapshot(global: &GlobalState, pfn: usize) -> Option<*const Page> {
-    if !global.snapshot().contains(pfn) {
+// snapshot file `index` and applies patches as needed. Following pages are also
+// read ahead (see `SNAPSHOT_PREFETCH_PAGE_COUNT`). Returns None if the page is
+// not captured in the snapshot file, or failed to be read from it.
+pub(crate) fn resolve_page_from_snapshot(
+    global: &GlobalState,
+    index: usize,
+    pfn: usize,
+) -> Option<*const Page> {
+    if !global.snapshot(index).contains(pfn) {
         return None;
     }
 
     // Locking for modifying `global` is required.
-    let mut snapshot = global.snapshot_mut();
+    let mut snapshot = global.snapshot_mut(index);
 
     if !snapshot.read_bitmap[pfn] {
-        let page = snapshot.resolve_page(pfn).unwrap();
-        global.patch_set().apply(pfn, page);
+        let pfns = snapshot.prefetch_range(pfn);
+        let pages = match snapshot.resolve_pages(pfns.clone()) {
+            Ok(pages) => pages,
+            Err(err) => {
+                error!("Failed to read PFN {pfn:#x} from the snapshot: {:#?}", err.status());
+                return None;
+            }
+        };
+        for (pfn, page) in pfns.zip(pages) {
+            global.patch_set().apply(pfn, page);
+        }
     }
 
     Some(addr_of!(snapshot.memory[pfn]))
 }
 
+// Resolves the zero-filled page that should back the given guest `pfn` if it is
+// within the MMIO ranges captured in the snapshot file `index`.
+pub(crate) fn resolve_page_from_mmio(
+    global: &GlobalState,
+    index: usize,
+    pfn: usize,
+) -> Option<*const Page> {
+    let snapshot = global.snapshot(index);
+    let gpa = (pfn << BASE_PAGE_SHIFT) as u64;
+    if snapshot
+        .mmio_ranges
+        .iter()
+        .any(|range| range.contains(&gpa))
+    {
+        Some(addr_of!(*snapshot.zero_page))
+    } else {
+        None
+    }
+}
+
+// Resolves snapshot contents of the whole 2MB region containing the given
+// guest `pfn` from the snapshot file `index` and applies patches as needed, if
+// the region can be backed by a single large page. Returns the first page of
+// the region, or None if it cannot be, including when any page in it failed to
+// be read, so that the caller falls back to a 4KB page.
+pub(crate) fn resolve_large_page_from_snapshot(
+    global: &GlobalState,
+    index: usize,
+    pfn: usize,
+) -> Option<*const Page> {
+    let base_pfn = pfn & !(PAGE_SIZE_ENTRIES - 1);
+    let pfns = base_pfn..base_pfn + PAGE_SIZE_ENTRIES;
+    if !global.snapshot(index).contains_range(&pfns) {
+        return None;
+    }
+
+    // Execute-only pages must be mapped individually with 4KB pages.
+    if global
+        .patch_set()
+        .is_execute_only(base_pfn, PAGE_SIZE_ENTRIES)
+    {
+        return None;
+    }
+
+    let base = addr_of!(global.snapshot(index).memory[base_pfn]);
+    if (base as usize % LARGE_PAGE_SIZE) != 0 {
+        return None;
+    }
+
+    // Resolve pages in batches as a nested page fault on each of them would,
+    // instead of holding the lock while reading the whole region, so that other
+    // processors are not blocked for long.
+    for pfn in pfns {
+        if !global.snapshot(index).read_bitmap[pfn] {
+            let _ = resolve_page_from_snapshot(global, index, pfn)?;
+        }
+    }
+
+    Some(base)
+}
+
+// Reads physical memory ranges saved in the snapshot file. Those are stored in
+// `metadata`, and if they did not fit, also in the additional pages right
+// before the metadata. Older snapshot files have no additional page.
+fn read_memory_ranges(
+    snapshot_file: &mut RegularFile,
+    snapshot_path: &str,
+    metadata: &SnapshotMetadataRaw,
+    size_in_pages: usize,
+) -> Result<Vec<SnapshotMemoryRange>, uefi::Error> {
+    let range_page_count = metadata.range_page_count as usize;
+    if range_page_count >= size_in_pages - 1 {
+        error!("{snapshot_path:?} is corrupted (invalid memory range page count)");
+        return Err(uefi::Error::from(uefi::Status::VOLUME_CORRUPTED));
+    }
+    let mut additional_ranges =
+        vec![SnapshotMemoryRange::default(); range_page_count * MEMORY_RANGES_PER_PAGE];
+    // Safety: `SnapshotMemoryRange` is plain data and valid for any bytes.
+    let buffer = unsafe {
+        core::slice::from_raw_parts_mut(
+            additional_ranges.as_mut_ptr().cast::<u8>(),
+            range_page_count * BASE_PAGE_SIZE,
+        )
+    };
+    let range_pages_offset = (size_in_pages - 1 - range_page_count) << BASE_PAGE_SHIFT;
+    read_from_file(snapshot_file, range_pages_offset as u64, buffer)?;
+
+    // Capture physical memory ranges saved in the metadata and the additional
+    // pages.
+    let mut memory_ranges: Vec<SnapshotMemoryRange> = Vec::new();
+    metadata
+        .memory_ranges
+        .iter()
+        .chain(&additional_ranges)
+        .for_each(|range| {
+            if range.page_count != 0 {
+                debug!(
+                    "Memory range: {:#x} - {:#x}",
+                    range.page_base,
+                    range.page_base + range.page_count * (BASE_PAGE_SIZE as u64)
+                );
+                memory_ranges.push(range.clone());
+            }
+        });
+    Ok(memory_ranges)
+}
+
+// Reads the CRC-32 of each page saved in the snapshot file, if the
+// `snapshot_checksum` feature is enabled. Those are stored in the pages right
+// before the additional memory range pages, in the order of pages in
+// `memory_ranges`. Older snapshot files have no such page.
+fn read_checksums(
+    snapshot_file: &mut RegularFile,
+    snapshot_path: &str,
+    metadata: &SnapshotMetadataRaw,
+    memory_ranges: &[SnapshotMemoryRange],
+    size_in_pages: usize,
+) -> Result<Option<Vec<u32>>, uefi::Error> {
+    let range_page_count = metadata.range_page_count as usize;
+    let checksum_page_count = metadata.checksum_page_count as usize;
+    if checksum_page_count >= size_in_pages - 1 - range_page_count {
+        error!("{snapshot_path:?} is corrupted (invalid checksum page count)");
+        return Err(uefi::Error::from(uefi::Status::VOLUME_CORRUPTED));
+    }
+    if !cfg!(feature = "snapshot_checksum") {
+        return Ok(None);
+    }
+    if checksum_page_count == 0 {
+        warn!("{snapshot_path:?} has no checksum. Pages are not verified");
+        return Ok(None);
+    }
+
+    let page_count = memory_ranges
+        .iter()
+        .map(|range| range.page_count as usize)
+        .sum::<usize>();
+    if size_to_pages(page_count * size_of::<u32>()) != checksum_page_count {
+        error!("{snapshot_path:?} is corrupted (checksum page count mismatch)");
+        return Err(uefi::Error::from(uefi::Status::VOLUME_CORRUPTED));
+    }
+    let mut checksums = vec![0u32; page_count];
+    // Safety: `u32` is plain data and valid for any bytes.
+    let buffer = unsafe {
+        core::slice::from_raw_parts_mut(
+            checksums.as_mut_ptr().cast::<u8>(),
+            page_count * size_of::<u32>(),
+        )
+    };
+    let checksums_offset =
+        (size_in_pages - 1 - range_page_count - checksum_page_count) << BASE_PAGE_SHIFT;
+    read_from_file(snapshot_file, checksums_offset as u64, buffer)?;
+    info!("Verifying {page_count} snapshot pages with CRC-32");
+    Ok(Some(checksums))
+}
+
 // The magic value at the beginning of the metadata page in the snapshot file.
 const SNAPSHOT_SIGNATURE: u64 = 0x544F_4853_5041_4E53; // 'SNAPSHOT'
 
-// The maximum number of memory ranges in the snapshot file.
+// The magic value at the beginning of the metadata page in the compressed
+// snapshot file.
+const SNAPSHOT_COMPRESSED_SIGNATURE: u64 = 0x5A54_4853_5041_4E53; // 'SNAPSHTZ'
+
+// The maximum number of memory ranges in the metadata page. More ranges are
+// stored in the additional pages (see `SnapshotMetadataRaw::range_page_count`).
 const MAX_MEMORY_DESCRIPTOR_COUNT: usize = 47;
 
+// The number of memory ranges in each additional page.
+const MEMORY_RANGES_PER_PAGE: usize = BASE_PAGE_SIZE / size_of::<SnapshotMemoryRange>();
+
+// The offset to the MMIO ranges in the metadata page.
+const MMIO_RANGES_OFFSET: usize = 0x800;
+
+// The maximum number of MMIO ranges in the snapshot file.
+const MAX_MMIO_RANGE_COUNT: usize = 32;
+
 /// The contents of the last 4KB of the snapshot file.
 #[derive(Debug)]
 #[repr(C, align(4096))]
 struct SnapshotMetadataRaw {
     /// The magic value. Must be [`SNAPSHOT_SIGNATURE`]
     magic: u64,
-    _padding1: u64,
+    /// The number of additional pages right before this page, each of which
+    /// holds [`MEMORY_RANGES_PER_PAGE`] memory ranges that did not fit in
+    /// [`SnapshotMetadataRaw::memory_ranges`]. Unused entries are zero. Zero
+    /// if all ranges fit in this page.
+    range_page_count: u64,
     /// The ranges of physical memory captured in the snapshot file.
     memory_ranges: [SnapshotMemoryRange; MAX_MEMORY_DESCRIPTOR_COUNT],
     /// The collection of register values stored in the snapshot file.
     registers: SnapshotRegisters,
+    /// How the guest receives input data. Optional.
+    harness: SnapshotHarnessRaw,
+    _padding2: [u8; MMIO_RANGES_OFFSET
+        - size_of::<u64>() * 2
+        - size_of::<SnapshotMemoryRange>() * MAX_MEMORY_DESCRIPTOR_COUNT
+        - size_of::<SnapshotRegisters>()
+        - size_of::<SnapshotHarnessRaw>()],
+    /// The ranges of guest physical memory that are MMIO. Optional.
+    mmio_ranges: [SnapshotMemoryRange; MAX_MMIO_RANGE_COUNT],
+    /// The number of pages right before the additional memory range pages,
+    /// which hold the CRC-32 of each page captured in the snapshot file.
+    /// Optional.
+    checksum_page_count: u64,
+    _padding3: [u8; BASE_PAGE_SIZE
+        - MMIO_RANGES_OFFSET
+        - size_of::<SnapshotMemoryRange>() * MAX_MMIO_RANGE_COUNT
+        - size_of::<u64>()],
 }
 const _: () = assert!(size_of::<SnapshotMetadataRaw>() == 0x1000);
+const _: () = assert!(core::mem::offset_of!(SnapshotMetadataRaw, checksum_page_count) == 0xa00);
+
+/// The description of how the guest receives input data, stored right after
+/// the registers in the metadata (offset 0x430). Older snapshot files leave it
+/// zero.
+#[derive(Debug)]
+#[repr(C)]
+struct SnapshotHarnessRaw {
+    /// Bit 0 indicates that `input_gva` is valid, bit 1 indicates that
+    /// `address_register` and `size_register` are valid, and bit 2 indicates
+    /// that `end_marker` is valid.
+    flags: u64,
+    /// See [`Harness::input_gva`]. Must be page aligned.
+    input_gva: u64,
+    /// See [`Harness::address_register`].
+    address_register: u8,
+    /// See [`Harness::size_register`].
+    size_register: u8,
+    _reserved: [u8; 6],
+    /// See [`Harness::end_marker`].
+    end_marker: u64,
+}
+const _: () = assert!(size_of::<SnapshotHarnessRaw>() == 0x20);
+const _: () = assert!(core::mem::offset_of!(SnapshotMetadataRaw, harness) == 0x430);
+
+impl SnapshotHarnessRaw {
+    // Converts this into `Harness`, falling back to the default for
+    // fields that are not valid.
+    fn parse(&self, snapshot_path: &str) -> Result<Harness, uefi::Error> {
+        const INPUT_GVA_VALID_FLAG: u64 = 1 << 0;
+        const REGISTERS_VALID_FLAG: u64 = 1 << 1;
+        const END_MARKER_VALID_FLAG: u64 = 1 << 2;
+        // RSP is not supported as it is not saved in `GuestRegisters`.
+        const RSP_REGISTER: u8 = 4;
+
+        let mut harness = Harness::default();
+        if self.flags & INPUT_GVA_VALID_FLAG != 0 {
+            if self.input_gva % BASE_PAGE_SIZE as u64 != 0 {
+                error!("{snapshot_path:?} has unaligned input GVA {:#x}", self.input_gva);
+                return Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER));
+            }
+            harness.input_gva = Some(self.input_gva);
+        }
+        if self.flags & REGISTERS_VALID_FLAG != 0 {
+            for register in [self.address_register, self.size_register] {
+                if register >= 16 || register == RSP_REGISTER {
+                    error!("{snapshot_path:?} has unsupported input register {register}");
+                    return Err(uefi::Error::from(uefi::Status::INVALID_PARAMETER));
+                }
+            }
+            harness.address_register = usize::from(self.address_register);
+            harness.size_register = usize::from(self.size_register);
+        }
+        if self.flags & END_MARKER_VALID_FLAG != 0 {
+            harness.end_marker = Some(self.end_marker);
+        }
+        Ok(harness)
+    }
+}
 
 /// A range of physical memory captured in the snapshot file.
-#[derive(Debug, Clone)]
+#[derive(Debug, Default, Clone)]
 #[repr(C)]
 struct SnapshotMemoryRange {
     page_base: u64,
     page_count: u64,
 }
+
+/// The location of a page compressed in the DEFLATE format in the compressed
+/// snapshot file.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+struct CompressedPage {
+    /// The offset to the compressed data from the beginning of the file.
+    offset: u64,
+    /// The size of the compressed data in bytes.
+    length: u32,
+    _reserved: u32,
+}
+const _: () = assert!(size_of::<CompressedPage>() == 0x10);
@@ -1,15 +1,19 @@
 //! The module containing the [`RunStats`] type.
 
 use crate::{
-    config::{CONSOLE_OUTPUT_INTERVAL, SERIAL_OUTPUT_INTERVAL},
+    config::{CONSOLE_OUTPUT_INTERVAL, DRCOV_OUTPUT_INTERVAL},
+    crc32,
     global_state::GlobalState,
+    hardware_vt::VmExitReason,
+    logger::{log_coverage, log_raw},
     system_table::system_table,
     x86_instructions::rdtsc,
 };
-use alloc::{format, vec::Vec};
+use alloc::{format, string::String, vec::Vec};
 use core::{fmt::Write, sync::atomic::Ordering};
 use log::info;
 use uefi::table::runtime::Time;
+use x86::current::paging::BASE_PAGE_SIZE;
 
 /// Statistics of one or overall fuzzing iteration.
 #[derive(Default, Clone)]
@@ -20,12 +24,44 @@ pub(crate) struct RunStats {
     pub(crate) total_tsc: u64,
     /// The elapsed time spent in the host in TSC.
     pub(crate) host_spent_tsc: u64,
+    /// The elapsed time spent in the host to revert dirty pages in TSC. This
+    /// is part of [`RunStats::host_spent_tsc`].
+    pub(crate) revert_tsc: u64,
     /// The number of VM exit occurred.
     pub(crate) vmexit_count: u64,
+    /// The number of VM exit occurred for each reason, indexed by
+    /// [`VmExitReason::index`].
+    pub(crate) vmexit_counts: [u64; VmExitReason::COUNT],
+    /// The histogram of time spent in the host to handle each VM exit. See
+    /// [`RunStats::record_exit_latency`].
+    pub(crate) exit_latency_histogram: [u64; EXIT_LATENCY_BUCKET_COUNT],
     /// The number of basic blocks that are newly executed.
     pub(crate) newly_executed_basic_blks: Vec<u64>,
+    /// The number of edges between basic blocks that are newly executed.
+    pub(crate) newly_executed_edge_count: u64,
+    /// The number of basic blocks whose hit count reached a new bucket. Always
+    /// zero unless [`crate::config::HIT_COUNT_COVERAGE`] is enabled.
+    pub(crate) newly_reached_bucket_count: u64,
     /// The number of iteration that ended with hang.
     pub(crate) hang_count: u64,
+    /// The largest elapsed time spent in the guest by a single iteration in
+    /// TSC.
+    pub(crate) max_guest_tsc: u64,
+    /// The number of iteration that ended without hang but close to it.
+    pub(crate) slow_count: u64,
+}
+
+/// Statistics of fuzzing iterations performed by a single processor.
+#[derive(Default, Clone, Copy)]
+pub(crate) struct CoreStats {
+    /// The APIC ID of the processor.
+    pub(crate) apic_id: u32,
+    /// The number of iterations completed by the processor.
+    pub(crate) iteration_count: u64,
+    /// The number of iterations that ended with hang.
+    pub(crate) hang_count: u64,
+    /// The number of iterations that ended without hang but close to it.
+    pub(crate) slow_count: u64,
 }
 
 impl RunStats {
@@ -36,13 +72,49 @@ impl RunStats {
         }
     }
 
+    /// Records `tsc` spent in the host to handle a VM exit into
+    /// [`RunStats::exit_latency_histogram`]. The bucket `n` counts VM exits
+    /// that took less than 2^n TSC, and 2^(n-1) or more, so that percentiles
+    /// are estimated within a factor of two without keeping each sample.
+    pub(crate) fn record_exit_latency(&mut self, tsc: u64) {
+        let bucket = (u64::BITS - tsc.leading_zeros()) as usize;
+        self.exit_latency_histogram[bucket.min(EXIT_LATENCY_BUCKET_COUNT - 1)] += 1;
+    }
+
+    /// Returns the upper bound of the bucket of
+    /// [`RunStats::exit_latency_histogram`] that contains the `percentile`th
+    /// percentile of time spent to handle VM exits, as a string. The last
+    /// bucket has no upper bound.
+    fn exit_latency_percentile(&self, percentile: u64) -> String {
+        let total: u64 = self.exit_latency_histogram.iter().sum();
+        if total == 0 {
+            return "N/A".into();
+        }
+        let mut count = 0;
+        for (bucket, bucket_count) in self.exit_latency_histogram.iter().enumerate() {
+            count += bucket_count;
+            if count * 100 >= total * percentile {
+                return if bucket == EXIT_LATENCY_BUCKET_COUNT - 1 {
+                    format!(">={}", 1u64 << (bucket - 1))
+                } else {
+                    format!("<{}", 1u64 << bucket)
+                };
+            }
+        }
+        unreachable!()
+    }
+
     /// Updates the statistics, and if needed, prints them out.
+    ///
+    /// This also checks whether any processor is stuck.
     pub(crate) fn report(
         &self,
         global: &GlobalState,
         used_dirty_page_count: usize,
         iter_count: u64,
     ) {
+        global.check_stuck_cores();
+
         if iter_count == 1 {
             if !cfg!(feature = "stdout_stats_report") {
                 system_table().stdout().clear().unwrap();
@@ -52,32 +124,67 @@ impl RunStats {
                 )
                 .unwrap();
             }
-            info!("HH:MM:SS,     Run#, Dirty Page#, New BB#, Total TSC, Guest TSC, VM exit#,");
+            if !cfg!(feature = "json_stats") {
+                info!(
+                    "HH:MM:SS,     Run#, Dirty Page#, New BB#,   ΔBB#, New Edge#, Total TSC, Guest TSC, VM exit#,"
+                );
+            }
         }
 
-        // Serial output.
+        // Serial output. The basic block delta is counted from the last
+        // periodic output, so that it stays non-zero while coverage grows.
+        let periodic = (iter_count % global.config().serial_output_interval) == 0;
         if log::log_enabled!(log::Level::Trace)
             || !self.newly_executed_basic_blks.is_empty()
-            || (iter_count % SERIAL_OUTPUT_INTERVAL) == 0
+            || periodic
         {
+            if periodic {
+                global.check_revert_cost();
+            }
             let time = time();
-            info!(
-                "{:02}:{:02}:{:02}, {:>8}, {:>11}, {:>7}, {:>9}, {:>9}, {:>8},",
-                time.hour(),
-                time.minute(),
-                time.second(),
-                iter_count,
-                used_dirty_page_count,
-                self.newly_executed_basic_blks.len(),
-                self.total_tsc,
-                self.total_tsc - self.host_spent_tsc,
-                self.vmexit_count,
-            );
+            let basic_blk_delta = global.basic_blk_delta(periodic);
+            if cfg!(feature = "json_stats") {
+                // One JSON object per line, without the usual log prefix, so
+                // that each line can be parsed as-is. Formatted directly rather
+                // than through serde to avoid allocation.
+                log_raw(format_args!(
+                    "{{\"t\":{},\"iter\":{},\"dirty\":{},\"new_bb\":{},\"delta_bb\":{},\"new_edge\":{},\"total_tsc\":{},\"guest_tsc\":{},\"vmexit\":{}}}",
+                    time_to_u64(time),
+                    iter_count,
+                    used_dirty_page_count,
+                    self.newly_executed_basic_blks.len(),
+                    basic_blk_delta,
+                    self.newly_executed_edge_count,
+                    self.total_tsc,
+                    self.total_tsc - self.host_spent_tsc,
+                    self.vmexit_count,
+                ));
+            } else {
+                info!(
+                    "{:02}:{:02}:{:02}, {:>8}, {:>11}, {:>7}, {:>6}, {:>9}, {:>9}, {:>9}, {:>8},",
+                    time.hour(),
+                    time.minute(),
+                    time.second(),
+                    iter_count,
+                    used_dirty_page_count,
+                    self.newly_executed_basic_blks.len(),
+                    basic_blk_delta,
+                    self.newly_executed_edge_count,
+                    self.total_tsc,
+                    self.total_tsc - self.host_spent_tsc,
+                    self.vmexit_count,
+                );
+            }
             if !self.newly_executed_basic_blks.is_empty() {
-                info!("COVERAGE: {:x?}", self.newly_executed_basic_blks);
+                log_coverage(format_args!("COVERAGE: {:x?}", self.newly_executed_basic_blks));
             }
         }
 
+        // Coverage output in the drcov format.
+        if DRCOV_OUTPUT_INTERVAL != 0 && (iter_count % DRCOV_OUTPUT_INTERVAL) == 0 {
+            Self::drcov(global);
+        }
+
         // Stdout output.
         if cfg!(feature = "stdout_stats_report")
             && (iter_count == 1 || (iter_count % CONSOLE_OUTPUT_INTERVAL) == 0)
@@ -86,9 +193,68 @@ impl RunStats {
         }
     }
 
-    // Prints out current statistics to the console.
+    /// Prints out all basic blocks executed so far to the serial output in the
+    /// drcov (version 2) text format, so that tools such as Lighthouse can
+    /// load it after removing the `DRCOV: ` prefix.
+    ///
+    /// Basic blocks are reported relative to the target module specified in
+    /// the patch file with the size of one byte, as actual sizes are unknown.
+    fn drcov(global: &GlobalState) {
+        let (module_name, module_range) = global.patch_set().module();
+        let global_stats = global.clone_stats();
+        let blocks = &global_stats.newly_executed_basic_blks;
+        log_coverage(format_args!("DRCOV: DRCOV VERSION: 2"));
+        log_coverage(format_args!("DRCOV: DRCOV FLAVOR: drcov"));
+        log_coverage(format_args!("DRCOV: Module Table: version 2, count 1"));
+        log_coverage(format_args!(
+            "DRCOV: Columns: id, base, end, entry, checksum, timestamp, path"
+        ));
+        log_coverage(format_args!(
+            "DRCOV:   0, {:#018x}, {:#018x}, 0x0000000000000000, 0x00000000, 0x00000000, {module_name}",
+            module_range.start, module_range.end,
+        ));
+        log_coverage(format_args!("DRCOV: BB Table: {} bbs", blocks.len()));
+        for block in blocks {
+            log_coverage(format_args!(
+                "DRCOV: module[  0]: {:#018x}, 1",
+                block.wrapping_sub(module_range.start)
+            ));
+        }
+    }
+
+    /// Prints out all basic blocks executed so far to the serial output as a
+    /// binary blob of [`GlobalState::export_coverage_blob`], so that coverage
+    /// of two campaigns can be diffed offline.
+    ///
+    /// The blob is the array of little-endian 64-bit offsets, framed as below:
+    /// ```text
+    /// COVBLOB-BEGIN <size of the blob in bytes> <CRC-32 of the blob in hex>
+    /// COVBLOB: <up to 32 bytes of the blob in hex>
+    /// ...
+    /// COVBLOB-END
+    /// ```
+    pub(crate) fn coverage_blob(global: &GlobalState) {
+        let blob: Vec<u8> = global
+            .export_coverage_blob()
+            .iter()
+            .flat_map(|block| block.to_le_bytes())
+            .collect();
+        log_coverage(format_args!("COVBLOB-BEGIN {} {:08x}", blob.len(), crc32(&blob)));
+        for chunk in blob.chunks(COVERAGE_BLOB_BYTES_PER_LINE) {
+            let mut line = String::with_capacity(chunk.len() * 2);
+            for byte in chunk {
+                write!(line, "{byte:02x}").unwrap();
+            }
+            log_coverage(format_args!("COVBLOB: {line}"));
+        }
+        log_coverage(format_args!("COVBLOB-END"));
+    }
+
+    // Prints out current statistics, including those of each processor, to the
+    // console.
     fn stdout(global: &GlobalState, iter_count: u64) {
         let global_stats = global.clone_stats();
+        let resolved_page_count = global.resolved_page_count();
         let time = time();
         let time_u64 = time_to_u64(time);
         let elapsed_seconds = if time_u64 > global.start_time() {
@@ -96,37 +262,90 @@ impl RunStats {
         } else {
             1
         };
-        let text = format!(
+        let mut text = format!(
             "
                         Last update: {:02}:{:02}:{:02}
                     Total Iteration: {}
         Total executed basic blocks: {}
+               Total executed edges: {}
                    Total hang count: {}
+                   Total slow count: {}
              Remaining corpus files: {}
+             Seed selection entropy: {}
                 Active thread count: {}
+            Snapshot pages resolved: {} ({} KB)
               Average VM exit count: {}
  Average iteration count per second: {}
 Average overall cycle per iteration: {}
   Average guest cycle per iteration: {}
+  Maximum guest cycle per iteration: {}
+ Average revert cycle per iteration: {}
+    VM exit host cycle p50/p90/p99: {} / {} / {}
 ",
             time.hour(),
             time.minute(),
             time.second(),
             iter_count,
             global_stats.newly_executed_basic_blks.len(),
+            global_stats.newly_executed_edge_count,
             global_stats.hang_count,
+            global_stats.slow_count,
             global.corpus().remaining_files_count(),
+            global
+                .corpus()
+                .selection_entropy()
+                .map_or_else(|| "N/A".into(), |entropy| format!("{entropy}%")),
             global.active_thread_count.load(Ordering::SeqCst),
+            resolved_page_count,
+            resolved_page_count * BASE_PAGE_SIZE as u64 / 1024,
             global_stats.vmexit_count / iter_count,
             iter_count / elapsed_seconds,
             global_stats.total_tsc / iter_count,
             (global_stats.total_tsc - global_stats.host_spent_tsc) / iter_count,
+            global_stats.max_guest_tsc,
+            global_stats.revert_tsc / iter_count,
+            global_stats.exit_latency_percentile(50),
+            global_stats.exit_latency_percentile(90),
+            global_stats.exit_latency_percentile(99),
         );
+        text.push_str("\n APIC ID,   Iteration#, Iteration/s,    Hang#,    Slow#\n");
+        for core in global.core_stats() {
+            writeln!(
+                text,
+                "{:>8}, {:>12}, {:>11}, {:>8}, {:>8}",
+                core.apic_id,
+                core.iteration_count,
+                core.iteration_count / elapsed_seconds,
+                core.hang_count,
+                core.slow_count,
+            )
+            .unwrap();
+        }
+        text.push_str("\n          VM exit reason,   Exit count, Percentage\n");
+        for (name, count) in VmExitReason::NAMES
+            .iter()
+            .zip(global_stats.vmexit_counts)
+            .filter(|(_, count)| *count != 0)
+        {
+            writeln!(
+                text,
+                "{name:>24}, {count:>12}, {:>9}%",
+                count * 100 / global_stats.vmexit_count,
+            )
+            .unwrap();
+        }
         system_table().stdout().clear().unwrap();
         write!(system_table().stdout(), "{text}").unwrap();
     }
 }
 
+// The number of buckets of `RunStats::exit_latency_histogram`. VM exits that
+// took 2^30 TSC or more are all counted into the last bucket.
+const EXIT_LATENCY_BUCKET_COUNT: usize = 32;
+
+// The number of bytes of the coverage blob printed in one `COVBLOB:` line.
+const COVERAGE_BLOB_BYTES_PER_LINE: usize = 32;
+
 /// Returns the current time if `time_report` is enabled. Otherwise, an invalid
 /// time.
 pub(crate) fn time() -> Time {
@@ -1,16 +1,27 @@
 //! The module containing the [`Vm`] type.
 
 use crate::{
+    config::{
+        COVERAGE_BITMAP_SIZE, EDGE_COVERAGE, GUEST_PRINT_LINE_LENGTH, HIT_COUNT_COVERAGE,
+        INTERCEPTED_MSRS, USE_LARGE_DIRTY_PAGES,
+    },
+    global_state::GlobalState,
     hardware_vt::{
         svm::Svm, vmx::Vmx, HardwareVt, NestedPagingStructure, NestedPagingStructureEntry,
-        NestedPagingStructureEntryType,
+        NestedPagingStructureEntryFlags, NestedPagingStructureEntryType,
     },
     Page,
 };
-use alloc::boxed::Box;
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec,
+    vec::Vec,
+};
 use core::ptr::addr_of;
-use log::trace;
-use x86::current::paging::BASE_PAGE_SHIFT;
+use log::{info, trace};
+use x86::current::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE, LARGE_PAGE_SIZE, PAGE_SIZE_ENTRIES};
 
 /// The representation of a virtual machine, made up of collection of registers,
 /// which is managed through [`HardwareVt`], preallocated
@@ -35,11 +46,87 @@ pub(crate) struct Vm {
     /// Preallocated pages to be used for copy-on-write.
     dirty_pages: Box<[Page]>,
 
-    /// The pairs of modified nested PTEs and original PAs due to copy-on-write.
-    dirty_entries: Box<[(*mut NestedPagingStructureEntry, u64)]>,
+    /// The modified nested PTEs, and original PAs and flags due to
+    /// copy-on-write.
+    dirty_entries: Box<[DirtyEntry]>,
 
     /// How many [`Vm::dirty_pages`] has been consumed.
     used_dirty_page_count: usize,
+
+    /// Preallocated 2MB pages to be used for copy-on-write of memory mapped
+    /// with 2MB pages. Empty unless [`USE_LARGE_DIRTY_PAGES`] is enabled.
+    dirty_large_pages: Box<[LargePage]>,
+
+    /// The pairs of modified nested PDEs and original PAs due to copy-on-write
+    /// with [`Vm::dirty_large_pages`].
+    dirty_large_entries: Box<[(*mut NestedPagingStructureEntry, u64)]>,
+
+    /// How many [`Vm::dirty_large_pages`] has been consumed.
+    used_dirty_large_page_count: usize,
+
+    /// The index of the snapshot the VM runs, out of ones given through the
+    /// command line parameters. GPA -> PA translations built so far map this
+    /// snapshot's memory.
+    pub(crate) snapshot_index: usize,
+
+    /// The number of iterations the VM has started in the fuzzing loop. Used
+    /// to switch snapshots independently of other processors.
+    pub(crate) iter_count: u64,
+
+    /// The address of the basic block executed last in the current iteration,
+    /// or zero if none is executed yet. Used to compute edge IDs.
+    pub(crate) prev_basic_blk: u64,
+
+    /// The TSC value presented to the VM in the current iteration, through
+    /// `RDTSC`, `RDTSCP` and the IA32_TSC MSR.
+    pub(crate) virtual_tsc: u64,
+
+    /// How long the guest may run in the current iteration, in TSC. See
+    /// [`crate::config::Config::exec_timeout_in_tsc`].
+    pub(crate) exec_timeout_in_tsc: u64,
+
+    /// The values of [`INTERCEPTED_MSRS`] written by the VM in the current
+    /// iteration, in the same order.
+    pub(crate) msr_values: [u64; INTERCEPTED_MSRS.len()],
+
+    /// Whether the #BP patches are kept and the number of times each basic
+    /// block is executed is counted into [`Vm::hit_counts`], instead of
+    /// reverting the patches on first execution. Enabled if either
+    /// [`HIT_COUNT_COVERAGE`] or [`EDGE_COVERAGE`] is.
+    pub(crate) keep_patches: bool,
+
+    /// Whether [`Vm::hit_counts`] are recorded into the global hit count
+    /// buckets as coverage. Requires [`Vm::keep_patches`]. Initialized with
+    /// [`HIT_COUNT_COVERAGE`].
+    pub(crate) count_hits: bool,
+
+    /// Whether the VM runs input files to minimize the corpus. Hit counts are
+    /// then only collected into [`Vm::hit_counts`], and are neither recorded
+    /// into the global hit count buckets nor reported as new basic blocks.
+    pub(crate) minimizing: bool,
+
+    /// The number of times each basic block is executed in the current
+    /// iteration. Used only when [`Vm::keep_patches`] is enabled.
+    pub(crate) hit_counts: BTreeMap<u64, u64>,
+
+    /// The bitmap of edges this VM has already recorded into the global
+    /// bitmap, in the same layout. Checked before the global bitmap, so that
+    /// edges this processor executed before do not cause atomic updates on the
+    /// bitmap shared by all processors. See [`Vm::record_edge`].
+    local_edges: Box<[u64]>,
+
+    /// The basic blocks this VM has seen executed by any VM, as a pre-filter
+    /// of [`GlobalState::is_basic_blk_executed`] with
+    /// [`Vm::keep_patches`]. See [`Vm::is_basic_blk_new`].
+    local_basic_blks: BTreeSet<u64>,
+
+    /// The address of the patch being single-stepped over, if any. The patch
+    /// is reverted in this VM's memory until the single-step completes.
+    pub(crate) stepping_over: Option<u64>,
+
+    /// The bytes written to [`crate::config::GUEST_PRINT_IO_PORT`] in the
+    /// current iteration that are not logged yet.
+    pub(crate) guest_output: Vec<u8>,
 }
 
 impl Vm {
@@ -49,9 +136,15 @@ impl Vm {
         // attempts to modify more pages than this, the VM is aborted.
         const DIRTY_PAGE_COUNT: usize = 1024;
 
+        // The number of pre-allocated 2MB pages used to back modified 2MB pages,
+        // if enabled. If the VM attempts to modify more 2MB pages than this, they
+        // are split into 4KB pages.
+        const DIRTY_LARGE_PAGE_COUNT: usize = if USE_LARGE_DIRTY_PAGES { 8 } else { 0 };
+
         // The number of pre-allocated nested paging structures. The more memory the VM
         // accesses, the more tables we need. If the VM attempts to access more
-        // memory than this can manage, the hypervisor will panic.
+        // memory than this can manage, the VM is aborted, and translations are
+        // built from scratch in the next iteration (see `Vm::reset`).
         const NPS_COUNT: usize = 1024;
 
         // Use VMX on Intel and SMV on AMD.
@@ -71,9 +164,17 @@ impl Vm {
         let dirty_pages =
             unsafe { Box::<[Page]>::new_zeroed_slice(DIRTY_PAGE_COUNT).assume_init() };
 
-        let dirty_entries = unsafe {
-            Box::<[(*mut NestedPagingStructureEntry, u64)]>::new_zeroed_slice(dirty_pages.len())
-                .assume_init()
+        let dirty_entries =
+            unsafe { Box::<[DirtyEntry]>::new_zeroed_slice(dirty_pages.len()).assume_init() };
+
+        let dirty_large_pages =
+            unsafe { Box::<[LargePage]>::new_zeroed_slice(DIRTY_LARGE_PAGE_COUNT).assume_init() };
+
+        let dirty_large_entries = unsafe {
+            Box::<[(*mut NestedPagingStructureEntry, u64)]>::new_zeroed_slice(
+                dirty_large_pages.len(),
+            )
+            .assume_init()
         };
 
         Self {
@@ -84,46 +185,156 @@ impl Vm {
             dirty_pages,
             dirty_entries,
             used_dirty_page_count: 0,
+            dirty_large_pages,
+            dirty_large_entries,
+            used_dirty_large_page_count: 0,
+            snapshot_index: 0,
+            iter_count: 0,
+            prev_basic_blk: 0,
+            virtual_tsc: 0,
+            exec_timeout_in_tsc: 0,
+            msr_values: [0; INTERCEPTED_MSRS.len()],
+            keep_patches: HIT_COUNT_COVERAGE || EDGE_COVERAGE,
+            count_hits: HIT_COUNT_COVERAGE,
+            minimizing: false,
+            hit_counts: BTreeMap::new(),
+            local_edges: vec![0; COVERAGE_BITMAP_SIZE / 64].into_boxed_slice(),
+            local_basic_blks: BTreeSet::new(),
+            stepping_over: None,
+            guest_output: Vec::with_capacity(GUEST_PRINT_LINE_LENGTH),
+        }
+    }
+
+    /// Marks the edge `edge_id` as executed, and returns whether it has never
+    /// been executed before by any VM. See [`GlobalState::record_edge`].
+    ///
+    /// An edge already recorded by this VM is known to be in the global bitmap,
+    /// so the global bitmap is only updated for edges new to this VM. Global
+    /// novelty is still decided by the global bitmap.
+    pub(crate) fn record_edge(&mut self, global: &GlobalState, edge_id: u64) -> bool {
+        let index = edge_id as usize % COVERAGE_BITMAP_SIZE;
+        let mask = 1u64 << (index % 64);
+        let local_bits = &mut self.local_edges[index / 64];
+        if *local_bits & mask != 0 {
+            return false;
+        }
+        *local_bits |= mask;
+        global.record_edge(edge_id)
+    }
+
+    /// Returns whether the basic block `basic_blk` has never been executed in
+    /// any previous iteration by any VM, or in the previous sessions. Used
+    /// with [`Vm::keep_patches`].
+    ///
+    /// The basic block is remembered locally either way, since it is recorded
+    /// globally at the end of the current iteration if it is new. Then, later
+    /// executions do not take the lock of the global hit counts.
+    pub(crate) fn is_basic_blk_new(&mut self, global: &GlobalState, basic_blk: u64) -> bool {
+        if !self.local_basic_blks.insert(basic_blk) {
+            return false;
         }
+        !global.is_basic_blk_executed(basic_blk) && !global.is_basic_blk_known(basic_blk)
     }
 
+    /// Appends `bytes` written to [`crate::config::GUEST_PRINT_IO_PORT`] to
+    /// [`Vm::guest_output`], and logs complete lines.
+    pub(crate) fn print_guest_output(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if byte == b'\n' {
+                self.flush_guest_output();
+            } else {
+                self.guest_output.push(byte);
+                if self.guest_output.len() == GUEST_PRINT_LINE_LENGTH {
+                    self.flush_guest_output();
+                }
+            }
+        }
+    }
+
+    /// Logs and clears bytes in [`Vm::guest_output`], if any.
+    pub(crate) fn flush_guest_output(&mut self) {
+        if !self.guest_output.is_empty() {
+            info!("GUEST: {}", String::from_utf8_lossy(&self.guest_output).trim_end());
+            self.guest_output.clear();
+        }
+    }
+
+    /// Returns the number of 4KB pages modified by the VM, counting each 2MB
+    /// dirty page as 512 pages.
     pub(crate) fn used_dirty_page_count(&self) -> usize {
-        self.used_dirty_page_count
+        self.used_dirty_page_count + self.used_dirty_large_page_count * PAGE_SIZE_ENTRIES
     }
 
     pub(crate) fn nested_pml4_addr(&mut self) -> *mut NestedPagingStructure {
         core::ptr::from_mut(self.nested_pml4.as_mut())
     }
 
+    /// Discards all GPA -> PA translations built so far, along with all dirty
+    /// pages, so that translations are built from scratch on nested page
+    /// faults again, for example, against another snapshot. Preallocated
+    /// nested paging structures and dirty pages become all available again.
+    pub(crate) fn reset(&mut self) {
+        self.nested_pml4.entries.fill(NestedPagingStructureEntry(0));
+        for table in &mut self.nested_paging_structures[..self.used_nps_count] {
+            table.entries.fill(NestedPagingStructureEntry(0));
+        }
+        self.used_nps_count = 0;
+        self.used_dirty_page_count = 0;
+        self.used_dirty_large_page_count = 0;
+        self.vt.invalidate_caches();
+    }
+
+    /// Returns whether all of [`Vm::nested_paging_structures`] have been
+    /// consumed.
+    pub(crate) fn nps_exhausted(&self) -> bool {
+        self.used_nps_count >= self.nested_paging_structures.len()
+    }
+
     /// Revert all dirty nested PTEs to point to the original physical
     /// addresses.
     pub(crate) fn revert_dirty_memory(&mut self) {
         // Iterate over all saved dirty PTEs and revert its translations to the
-        // original PAes.
-        let flags = self
-            .vt
-            .nps_entry_flags(NestedPagingStructureEntryType::RxWriteBack);
+        // original PAes and flags, which may be execute-only.
         for i in 0..self.used_dirty_page_count {
             let dirty_entry = &self.dirty_entries[i];
             let dirty_pte = unsafe { dirty_entry.0.as_mut() }.unwrap();
             let original_pa = dirty_entry.1 << BASE_PAGE_SHIFT;
-            dirty_pte.set_translation(original_pa, flags);
+            dirty_pte.set_translation(original_pa, dirty_entry.2);
+        }
+
+        // Do the same for dirty PDEs, making them map the original 2MB pages
+        // again. Those are never execute-only.
+        let flags = self
+            .vt
+            .nps_entry_flags(NestedPagingStructureEntryType::RxWriteBack);
+        for i in 0..self.used_dirty_large_page_count {
+            let dirty_entry = &self.dirty_large_entries[i];
+            let dirty_pde = unsafe { dirty_entry.0.as_mut() }.unwrap();
+            let original_pa = dirty_entry.1 << BASE_PAGE_SHIFT;
+            dirty_pde.set_translation(original_pa, flags);
         }
 
         // Updating the nested paging structure entries may warrant cache invalidation.
-        if self.used_dirty_page_count != 0 {
+        if self.used_dirty_page_count != 0 || self.used_dirty_large_page_count != 0 {
             self.vt.invalidate_caches();
             self.used_dirty_page_count = 0;
+            self.used_dirty_large_page_count = 0;
         }
     }
 
-    /// Builds nested paging translation for `gpa` to translate to `pa`.
+    /// Builds nested paging translation for `gpa` to translate to `pa` with
+    /// the permissions specified by `entry_type`.
     ///
     /// This function does so by walking through whole PML4 -> PDPT -> PD -> PT
     /// as a processor does, and allocating tables and initializing table
     /// entries as needed.
     #[allow(clippy::similar_names)]
-    pub(crate) fn build_translation(&mut self, gpa: usize, pa: *const Page) {
+    pub(crate) fn build_translation(
+        &mut self,
+        gpa: usize,
+        pa: *const Page,
+        entry_type: NestedPagingStructureEntryType,
+    ) -> Result<(), PoolExhausted> {
         let pml4i = (gpa >> 39) & 0b1_1111_1111;
         let pdpti = (gpa >> 30) & 0b1_1111_1111;
         let pdi = (gpa >> 21) & 0b1_1111_1111;
@@ -161,34 +372,90 @@ impl Vm {
                                     +-----------+
         */
         let pml4 = unsafe { self.nested_pml4_addr().as_mut() }.unwrap();
-        let pml4e = self.walk_table(pml4, pml4i);
+        let pml4e = self.walk_table(pml4, pml4i)?;
 
         // Locate PDPT, index it, build PDPTe as needed
         let pdpt = pml4e.next_table_mut();
-        let pdpte = self.walk_table(pdpt, pdpti);
+        let pdpte = self.walk_table(pdpt, pdpti)?;
 
         // Locate PD, index it, build PDe as needed
         let pd = pdpte.next_table_mut();
-        let pde = self.walk_table(pd, pdi);
+        let pde = self.walk_table(pd, pdi)?;
 
         // Locate PT, index it, build PTe as needed
         let pt = pde.next_table_mut();
         let pte = &mut pt.entries[pti];
         assert!(pte.0 == 0);
 
+        // `entry_type` is non-writable so that copy-on-write is done for dirty
+        // pages.
+        let flags = self.vt.nps_entry_flags(entry_type);
+        pte.set_translation(pa as u64, flags);
+        Ok(())
+    }
+
+    /// Builds nested paging translation for the 2MB region containing `gpa` to
+    /// translate to the 2MB region starting at `pa` with a single large page.
+    ///
+    /// Returns `Ok(false)` without updating anything if any page in the region
+    /// is already mapped with 4KB pages.
+    #[allow(clippy::similar_names)]
+    pub(crate) fn build_large_translation(
+        &mut self,
+        gpa: usize,
+        pa: *const Page,
+    ) -> Result<bool, PoolExhausted> {
+        let pml4i = (gpa >> 39) & 0b1_1111_1111;
+        let pdpti = (gpa >> 30) & 0b1_1111_1111;
+        let pdi = (gpa >> 21) & 0b1_1111_1111;
+
+        // Locate PML4, index it, build PML4e as needed
+        let pml4 = unsafe { self.nested_pml4_addr().as_mut() }.unwrap();
+        let pml4e = self.walk_table(pml4, pml4i)?;
+
+        // Locate PDPT, index it, build PDPTe as needed
+        let pdpt = pml4e.next_table_mut();
+        let pdpte = self.walk_table(pdpt, pdpti)?;
+
+        // Locate PD, index it.
+        let pd = pdpte.next_table_mut();
+        let pde = &mut pd.entries[pdi];
+        if pde.0 != 0 {
+            return Ok(false);
+        }
+
         // Make it non-writable so that copy-on-write is done for dirty pages.
         let flags = self
             .vt
             .nps_entry_flags(NestedPagingStructureEntryType::RxWriteBack);
-        pte.set_translation(pa as u64, flags);
+        pde.set_translation(pa as u64, flags);
+        pde.set_large_page(true);
+        Ok(true)
     }
 
     /// Updates nested paging translation for `gpa` to translate to a dirty page
-    /// and copies the original contents at `copy_from` into the new dirty page.
+    /// with permissions specified by `entry_type`, and copies the original
+    /// contents at `copy_from` into the new dirty page.
+    ///
+    /// If `gpa` is mapped with a 2MB page and [`Vm::dirty_large_pages`] is
+    /// available, the whole 2MB region is copied into a 2MB dirty page instead.
     #[allow(clippy::similar_names)]
-    pub(crate) fn copy_on_write(&mut self, gpa: usize, copy_from: *const Page) -> bool {
+    pub(crate) fn copy_on_write(
+        &mut self,
+        gpa: usize,
+        copy_from: *const Page,
+        entry_type: NestedPagingStructureEntryType,
+    ) -> Result<(), PoolExhausted> {
+        if self.used_dirty_large_page_count < self.dirty_large_pages.len() {
+            if let Some(pde) = self.pde_mut(gpa).filter(|pde| pde.large_page()) {
+                let pde = core::ptr::from_mut(pde);
+                self.copy_on_write_large(unsafe { pde.as_mut() }.unwrap(), entry_type);
+                return Ok(());
+            }
+        }
+
         if self.used_dirty_page_count >= self.dirty_pages.len() {
-            return false;
+            return Err(PoolExhausted::DirtyPages);
         }
 
         let pml4i = (gpa >> 39) & 0b1_1111_1111;
@@ -198,31 +465,36 @@ impl Vm {
 
         // Locate PML4, index it, build PML4e as needed
         let pml4 = unsafe { self.nested_pml4_addr().as_mut() }.unwrap();
-        let pml4e = self.walk_table(pml4, pml4i);
+        let pml4e = self.walk_table(pml4, pml4i)?;
 
         // Locate PDPT, index it, build PDPTe as needed
         let pdpt = pml4e.next_table_mut();
-        let pdpte = self.walk_table(pdpt, pdpti);
+        let pdpte = self.walk_table(pdpt, pdpti)?;
 
-        // Locate PD, index it, build PDe as needed
+        // Locate PD, index it, build PDe as needed. If the PDe maps a 2MB page,
+        // split it into 4KB pages, so that only the 4KB page being written is
+        // copied.
         let pd = pdpte.next_table_mut();
-        let pde = self.walk_table(pd, pdi);
+        let pde = self.walk_table(pd, pdi)?;
+        if pde.large_page() {
+            self.split_large_page(pde)?;
+        }
 
         // Locate PT, index it.
         let pt = pde.next_table_mut();
         let pte = &mut pt.entries[pti];
 
-        // Saves nested PTE and the original (current) PA for reverting.
-        self.dirty_entries[self.used_dirty_page_count] = (core::ptr::from_mut(pte), pte.pfn());
+        // Saves nested PTE and the original (current) PA and flags for
+        // reverting.
+        self.dirty_entries[self.used_dirty_page_count] =
+            (core::ptr::from_mut(pte), pte.pfn(), pte.flags());
 
         // Update translation to point to `dirty_pages`, which is allocated for
         // each logical processor and never be shared with others. Thus, updating
         // nested paging structures (which are also exclusive to each logical processor)
         // and pointing to the dirty page will isolate write access to this guest
         // only. The modified page will be only visible from this guest.
-        let flags = self
-            .vt
-            .nps_entry_flags(NestedPagingStructureEntryType::RwxWriteBack);
+        let flags = self.vt.nps_entry_flags(entry_type);
         let new_page = &mut self.dirty_pages[self.used_dirty_page_count];
         pte.set_translation(core::ptr::from_ref(new_page) as u64, flags);
         self.used_dirty_page_count += 1;
@@ -233,27 +505,216 @@ impl Vm {
             core::ptr::copy_nonoverlapping(copy_from, core::ptr::from_mut(new_page), 1);
         };
 
-        true
+        Ok(())
+    }
+
+    /// Updates the nested PDE `pde` mapping a 2MB page to translate to a 2MB
+    /// dirty page, and copies the original contents of the 2MB page into it.
+    fn copy_on_write_large(
+        &mut self,
+        pde: &mut NestedPagingStructureEntry,
+        entry_type: NestedPagingStructureEntryType,
+    ) {
+        // Saves nested PDE and the original (current) PA for reverting.
+        let original_pfn = pde.pfn();
+        self.dirty_large_entries[self.used_dirty_large_page_count] =
+            (core::ptr::from_mut(pde), original_pfn);
+
+        // Update translation to point to the dirty 2MB page, which is exclusive
+        // to this logical processor, as done for 4KB pages.
+        let flags = self.vt.nps_entry_flags(entry_type);
+        let new_page = &mut self.dirty_large_pages[self.used_dirty_large_page_count];
+        pde.set_translation(core::ptr::from_ref(new_page) as u64, flags);
+        self.used_dirty_large_page_count += 1;
+
+        // Copy contents of the previous 2MB physical address range into the new
+        // one.
+        let copy_from = (original_pfn << BASE_PAGE_SHIFT) as *const LargePage;
+        unsafe {
+            core::ptr::copy_nonoverlapping(copy_from, core::ptr::from_mut(new_page), 1);
+        };
+    }
+
+    /// Returns the dirty page that backs `gpa`. If `gpa` is not backed by a
+    /// dirty page yet, performs copy-on-write from `copy_from` with
+    /// `entry_type` first.
+    ///
+    /// `gpa` must already be mapped.
+    pub(crate) fn dirty_page_mut(
+        &mut self,
+        gpa: usize,
+        copy_from: *const Page,
+        entry_type: NestedPagingStructureEntryType,
+    ) -> Result<&mut Page, PoolExhausted> {
+        // Copy-on-write may back `gpa` with either a 4KB or 2MB dirty page, so
+        // look it up again afterwards.
+        if self.dirty_page_ptr(gpa).is_none() {
+            self.copy_on_write(gpa, copy_from, entry_type)?;
+        }
+        let page = self
+            .dirty_page_ptr(gpa)
+            .expect("copy-on-write should back gpa with a dirty page");
+        // Safety: The pointer is into `dirty_pages` or `dirty_large_pages`
+        // owned by `self`, which is mutably borrowed for the returned lifetime.
+        Ok(unsafe { &mut *page })
+    }
+
+    // Returns the pointer to the 4KB dirty page that backs `gpa`, either as a
+    // 4KB dirty page or within a 2MB dirty page, if any.
+    #[allow(clippy::similar_names)]
+    fn dirty_page_ptr(&mut self, gpa: usize) -> Option<*mut Page> {
+        let pde = self.pde_mut(gpa).map(core::ptr::from_mut);
+        if let Some(index) = self.dirty_large_entries[..self.used_dirty_large_page_count]
+            .iter()
+            .position(|(dirty_pde, _)| Some(*dirty_pde) == pde)
+        {
+            let pti = (gpa >> 12) & 0b1_1111_1111;
+            return Some(core::ptr::from_mut(&mut self.dirty_large_pages[index].0[pti]));
+        }
+
+        let pte = self.pte_mut(gpa).map(core::ptr::from_mut);
+        let index = self.dirty_entries[..self.used_dirty_page_count]
+            .iter()
+            .position(|(dirty_pte, _, _)| Some(*dirty_pte) == pte)?;
+        Some(core::ptr::from_mut(&mut self.dirty_pages[index]))
+    }
+
+    /// Reads guest memory at `gpa` into `buffer` as this VM currently observes
+    /// it, including modifications in dirty pages, by walking the nested paging
+    /// structures. Returns the number of bytes read, which is less than the
+    /// size of `buffer` if a page is not mapped.
+    pub(crate) fn read_guest_memory(&mut self, gpa: usize, buffer: &mut [u8]) -> usize {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            let gpa = gpa + i;
+            let pa = if let Some(pde) = self.pde_mut(gpa).filter(|pde| pde.large_page()) {
+                ((pde.pfn() as usize) << BASE_PAGE_SHIFT) + (gpa & (LARGE_PAGE_SIZE - 1))
+            } else if let Some(pte) = self.pte_mut(gpa).filter(|pte| pte.0 != 0) {
+                ((pte.pfn() as usize) << BASE_PAGE_SHIFT) + (gpa & (BASE_PAGE_SIZE - 1))
+            } else {
+                return i;
+            };
+            // Safety: The page is mapped into the guest, thus is valid. UEFI
+            // runs with identity mapping, so the PA is also the VA.
+            *byte = unsafe { *(pa as *const u8) };
+        }
+        buffer.len()
+    }
+
+    /// Returns whether `gpa` is mapped with either a 4KB or 2MB page.
+    pub(crate) fn is_mapped(&mut self, gpa: usize) -> bool {
+        self.is_mapped_with_large_page(gpa) || self.pte_mut(gpa).is_some_and(|pte| pte.0 != 0)
+    }
+
+    /// Returns whether `gpa` is mapped with a 2MB page.
+    pub(crate) fn is_mapped_with_large_page(&mut self, gpa: usize) -> bool {
+        self.pde_mut(gpa).is_some_and(|pde| pde.large_page())
+    }
+
+    /// Prints the nested paging structure entries walked to translate `gpa`,
+    /// if the trace level logging is enabled.
+    pub(crate) fn dump_translation(&self, gpa: usize) {
+        const LEVELS: [(&str, usize); 4] = [("PML4E", 39), ("PDPTE", 30), ("PDE", 21), ("PTE", 12)];
+
+        if !log::log_enabled!(log::Level::Trace) {
+            return;
+        }
+
+        trace!("Nested paging structure entries for GPA {gpa:#x}");
+        let mut table: &NestedPagingStructure = &self.nested_pml4;
+        for (name, shift) in LEVELS {
+            let entry = &table.entries[(gpa >> shift) & 0b1_1111_1111];
+            let flags = entry.flags();
+            trace!(
+                "  {name:<5} {:#018x} (PFN {:#x}, permission {:#05b}, memory type {}, large page {})",
+                entry.0,
+                entry.pfn(),
+                flags.permission,
+                flags.memory_type,
+                entry.large_page(),
+            );
+            if entry.0 == 0 || entry.large_page() || shift == 12 {
+                break;
+            }
+            table = entry.next_table();
+        }
+    }
+
+    /// Locates the nested PTE that maps `gpa`, if `gpa` is mapped with a 4KB
+    /// page.
+    fn pte_mut(&mut self, gpa: usize) -> Option<&mut NestedPagingStructureEntry> {
+        let pti = (gpa >> 12) & 0b1_1111_1111;
+
+        let pde = self.pde_mut(gpa)?;
+        if pde.large_page() {
+            return None;
+        }
+        Some(&mut pde.next_table_mut().entries[pti])
+    }
+
+    /// Locates the nested PDE that maps `gpa`, if it is initialized.
+    #[allow(clippy::similar_names)]
+    fn pde_mut(&mut self, gpa: usize) -> Option<&mut NestedPagingStructureEntry> {
+        let pml4i = (gpa >> 39) & 0b1_1111_1111;
+        let pdpti = (gpa >> 30) & 0b1_1111_1111;
+        let pdi = (gpa >> 21) & 0b1_1111_1111;
+
+        let pml4 = unsafe { self.nested_pml4_addr().as_mut() }.unwrap();
+        let pml4e = &mut pml4.entries[pml4i];
+        if pml4e.0 == 0 {
+            return None;
+        }
+        let pdpte = &mut pml4e.next_table_mut().entries[pdpti];
+        if pdpte.0 == 0 {
+            return None;
+        }
+        let pde = &mut pdpte.next_table_mut().entries[pdi];
+        if pde.0 == 0 {
+            return None;
+        }
+        Some(pde)
+    }
+
+    /// Replaces the 2MB page mapped by `pde` with a new PT that maps the same
+    /// memory with 512 4KB pages, preserving permissions and the memory type.
+    fn split_large_page(
+        &mut self,
+        pde: &mut NestedPagingStructureEntry,
+    ) -> Result<(), PoolExhausted> {
+        if self.used_nps_count >= self.nested_paging_structures.len() {
+            return Err(PoolExhausted::NestedPagingStructures);
+        }
+        let large_page_pa = pde.pfn() << BASE_PAGE_SHIFT;
+        let flags = pde.flags();
+        let pt = &mut self.nested_paging_structures[self.used_nps_count];
+        for (i, pte) in pt.entries.iter_mut().enumerate() {
+            pte.set_translation(large_page_pa + (i << BASE_PAGE_SHIFT) as u64, flags);
+        }
+        let pt_addr = addr_of!(*pt) as u64;
+        self.used_nps_count += 1;
+
+        pde.set_large_page(false);
+        pde.set_translation(pt_addr, self.vt.nps_entry_flags(NestedPagingStructureEntryType::Rwx));
+        Ok(())
     }
 
     /// Locates a nested paging structure entry from `table` using `index`.
     ///
     /// This function initializes the entry if it is not yet. `table` must be
-    /// either a nested PML4, PDPT, or PD. Not PT.
+    /// either a nested PML4, PDPT, or PD. Not PT. Fails if a new table is
+    /// required but all preallocated ones are already used.
     fn walk_table<'a>(
         &mut self,
         table: &'a mut NestedPagingStructure,
         index: usize,
-    ) -> &'a mut NestedPagingStructureEntry {
+    ) -> Result<&'a mut NestedPagingStructureEntry, PoolExhausted> {
         let entry = &mut table.entries[index];
 
         // If there is no information about the next table in the entry, add that.
         // An unused `nested_paging_structures` is used as a next table.
         if entry.0 == 0 {
-            assert!(
-                self.used_nps_count < self.nested_paging_structures.len(),
-                "All preallocated nested paging structures exhausted",
-            );
+            if self.used_nps_count >= self.nested_paging_structures.len() {
+                return Err(PoolExhausted::NestedPagingStructures);
+            }
             let next_table = addr_of!(self.nested_paging_structures[self.used_nps_count]) as u64;
             entry.set_translation(
                 next_table,
@@ -261,10 +722,29 @@ impl Vm {
             );
             self.used_nps_count += 1;
         }
-        entry
+        Ok(entry)
     }
 }
 
+/// The modified nested PTE, and its original PA and flags.
+type DirtyEntry = (*mut NestedPagingStructureEntry, u64, NestedPagingStructureEntryFlags);
+
+/// The structure representing a single 2MB page.
+#[derive(Clone, Copy)]
+#[repr(C, align(0x20_0000))]
+struct LargePage([Page; PAGE_SIZE_ENTRIES]);
+
+/// The preallocated resources of [`Vm`] that has been used up.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum PoolExhausted {
+    /// All [`Vm::nested_paging_structures`] are used. They are never freed, so
+    /// any further access to unmapped memory fails.
+    NestedPagingStructures,
+
+    /// All [`Vm::dirty_pages`] are used within the current iteration.
+    DirtyPages,
+}
+
 /// Checks whether the current processor is Intel-processors (as opposed to
 /// AMD).
 fn is_intel() -> bool {
@@ -47,6 +47,18 @@ pub(crate) fn cr3() -> u64 {
     unsafe { x86::controlregs::cr3() }
 }
 
+/// Writes a value to the CR2 register.
+pub(crate) fn cr2_write(val: u64) {
+    // Safety: this project runs at CPL0.
+    unsafe { x86::controlregs::cr2_write(val) };
+}
+
+/// Writes a value to the DR6 register.
+pub(crate) fn dr6_write(val: u64) {
+    // Safety: this project runs at CPL0.
+    unsafe { asm!("mov dr6, {}", in(reg) val, options(nomem, nostack)) };
+}
+
 /// Reads the CR4 register.
 pub(crate) fn cr4() -> Cr4 {
     // Safety: this project runs at CPL0.
@@ -59,6 +71,22 @@ pub(crate) fn cr4_write(val: Cr4) {
     unsafe { x86::controlregs::cr4_write(val) };
 }
 
+/// Reads the XCR0 register, or returns None if CR4.OSXSAVE is not set.
+pub(crate) fn xcr0() -> Option<u64> {
+    if !cr4().contains(Cr4::CR4_ENABLE_OS_XSAVE) {
+        return None;
+    }
+    // Safety: this project runs at CPL0, and CR4.OSXSAVE is set.
+    Some(unsafe { core::arch::x86_64::_xgetbv(0) })
+}
+
+/// Writes a value to the XCR0 register. The value must be valid (see
+/// [`crate::hardware_vt::is_valid_xcr0`]), and CR4.OSXSAVE must be set.
+pub(crate) fn xcr0_write(val: u64) {
+    // Safety: this project runs at CPL0. The caller ensures the rest.
+    unsafe { core::arch::x86_64::_xsetbv(0, val) };
+}
+
 /// Disables maskable interrupts.
 pub(crate) fn cli() {
     // Safety: this project runs at CPL0.
@@ -100,8 +128,7 @@ pub(crate) fn sgdt<T>(gdtr: &mut DescriptorTablePointer<T>) {
 /// Set "magic_break: enabled=1" in the Bochs configuration file.
 // inline_always: to avoid having to step through to `RET` to the caller.
 // doc_markdown: clippy confused with "magic_break".
-// dead_code: ad-hoc debug support code. Normally not used.
-#[allow(clippy::inline_always, clippy::doc_markdown, dead_code)]
+#[allow(clippy::inline_always, clippy::doc_markdown)]
 #[inline(always)]
 pub(crate) fn bochs_breakpoint() {
     unsafe { asm!("xchg %bx, %bx", options(att_syntax, nomem, nostack)) };
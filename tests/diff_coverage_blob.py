@@ -0,0 +1,43 @@
+"""Lists basic blocks executed in one rhv session but not in another"""
+import re
+import struct
+import sys
+import zlib
+
+
+def load_coverage_blob(filename: str) -> set:
+    """Returns block offsets in the last coverage blob in the serial log file."""
+
+    blob = None
+    with open(filename, encoding="UTF-8", errors="replace") as file:
+        for line in file:
+            if match := re.search(r"COVBLOB-BEGIN (\d+) ([0-9a-f]{8})", line):
+                size, crc, data = int(match.group(1)), int(match.group(2), 16), b""
+            elif match := re.search(r"COVBLOB: ([0-9a-f]+)", line):
+                data += bytes.fromhex(match.group(1))
+            elif "COVBLOB-END" in line:
+                if len(data) != size or zlib.crc32(data) != crc:
+                    sys.exit(f"{filename}: corrupted coverage blob")
+                blob = data
+    if blob is None:
+        sys.exit(f"{filename}: no coverage blob found")
+    return {offset for (offset,) in struct.iter_unpack("<Q", blob)}
+
+
+def main():
+    """Lists basic blocks executed in one rhv session but not in another"""
+
+    if len(sys.argv) != 3:
+        sys.exit(f"Usage: {sys.argv[0]} <serial log A> <serial log B>")
+    blocks_a = load_coverage_blob(sys.argv[1])
+    blocks_b = load_coverage_blob(sys.argv[2])
+    for offset in sorted(blocks_b - blocks_a):
+        print(f"{offset:#x}")
+    print(
+        f"{len(blocks_b - blocks_a)} blocks only in B, {len(blocks_a - blocks_b)} only in A",
+        file=sys.stderr,
+    )
+
+
+if __name__ == "__main__":
+    main()
@@ -23,14 +23,18 @@ def main():
 
         patch_entry = {}
         patch_entry["address"] = block_addr
-        patch_entry["length"] = len(patch)
-        patch_entry["patch"] = int.from_bytes(patch, byteorder="little")
-        patch_entry["original"] = int.from_bytes(original, byteorder="little")
+        patch_entry["patch"] = list(patch)
+        patch_entry["original"] = list(original)
         patch_entries.append(patch_entry)
 
     # Build the JSON object and write it to a file.
     json_data = {}
     json_data["entries"] = patch_entries
+    json_data["module"] = {
+        "name": idc.get_root_filename(),
+        "base": idaapi.get_imagebase(),
+        "size": idc.get_inf_attr(idc.INF_MAX_EA) - idaapi.get_imagebase(),
+    }
 
     patch_name = idc.get_idb_path() + "_patch.json"
     with open(patch_name, "w", encoding="utf-8") as outfile:
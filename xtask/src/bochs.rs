@@ -1,6 +1,9 @@
-use crate::{copy_artifacts_to, DynError, TestVm, UnixCommand};
+use crate::{
+    check_replay_result, copy_artifacts_to, parse_replay_result, wait_for_replay_result, DynError,
+    TestVm, UnixCommand,
+};
 use std::{
-    env, fmt,
+    env, fmt, fs,
     io::{BufRead, BufReader},
     path::Path,
     process::{Command, Stdio},
@@ -18,9 +21,17 @@ impl TestVm for Bochs {
         copy_artifacts_to("./tests/samples/bochs_disk.img", release)
     }
 
-    fn run(&self) -> Result<(), DynError> {
+    fn run(&self, cpus: Option<u32>, dry_run: bool) -> Result<(), DynError> {
+        static DBG_CMD: &str = "./bochs/dbg_command.txt";
+
+        // Handle Ctrl-C before starting Bochs, so that the result is reported
+        // even if Ctrl-C is pressed while booting.
+        let (tx, rx) = channel();
+        let result_tx = tx.clone();
+        ctrlc::set_handler(move || tx.send(None).unwrap())?;
+
         // Start a threads that tries to connect to Bochs in an infinite loop.
-        let _unused = thread::spawn(|| loop {
+        let _unused = thread::spawn(move || loop {
             let client = if env::consts::OS == "macos" {
                 "nc"
             } else {
@@ -38,44 +49,87 @@ impl TestVm for Bochs {
             let reader = BufReader::new(output.stdout.unwrap());
             reader.lines().map_while(Result::ok).for_each(|line| {
                 println!("{:>4}: {line}\r", now.elapsed().unwrap_or_default().as_secs());
+                if let Some(result) = parse_replay_result(&line) {
+                    let _unused = result_tx.send(Some(result));
+                }
             });
 
             thread::sleep(Duration::from_secs(1));
         });
 
+        // Use the configuration file as-is, or its copy with the updated number
+        // of processors.
         let cpu_type = self.cpu.to_string().to_lowercase();
-        let _unused = thread::spawn(move || {
-            // Start Bochs from the "tests" directory in background.
-            static DBG_CMD: &str = "./bochs/dbg_command.txt";
-            let bochs = if cfg!(target_os = "windows") {
-                r"C:\class\Bochs\bochs\obj-release\bochs.exe"
-            } else {
-                "bochs"
-            };
-            let bxrc = format!("./bochs/{}_{cpu_type}.bxrc", env::consts::OS);
-            let output = Command::new(bochs)
-                .args(["-q", "-unlock", "-rc", DBG_CMD, "-f", &bxrc])
-                .current_dir(Path::new("./tests"))
-                .stdout(Stdio::piped())
-                .spawn()
-                .unwrap();
+        let mut bxrc = format!("./bochs/{}_{cpu_type}.bxrc", env::consts::OS);
+        if let Some(count) = cpus {
+            let config = fs::read_to_string(Path::new("./tests").join(&bxrc))?;
+            let path = env::temp_dir().join("rhv.bxrc");
+            fs::write(&path, set_cpu_count(&config, count))?;
+            bxrc = path.to_str().unwrap().to_string();
+        }
 
+        // Start Bochs from the "tests" directory in background.
+        let bochs = if cfg!(target_os = "windows") {
+            r"C:\class\Bochs\bochs\obj-release\bochs.exe"
+        } else {
+            "bochs"
+        };
+        let mut child = Command::new(bochs)
+            .args(["-q", "-unlock", "-rc", DBG_CMD, "-f", &bxrc])
+            .current_dir(Path::new("./tests"))
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let stdout = child.stdout.take().unwrap();
+        let _unused = thread::spawn(move || {
             // Read and print stdout as they come in. This does not return.
-            let reader = BufReader::new(output.stdout.unwrap());
+            let reader = BufReader::new(stdout);
             reader
                 .lines()
                 .map_while(Result::ok)
                 .for_each(|line| println!("{line}\r"));
         });
 
-        let (tx, rx) = channel();
-        ctrlc::set_handler(move || tx.send(()).unwrap())?;
-        rx.recv()?;
+        // Wait until Ctrl-C is pressed, or the result of the dry-run is logged.
+        let result = wait_for_replay_result(&rx, dry_run);
 
-        Ok(())
+        // Stop Bochs unless Ctrl-C is pressed, that is, the dry-run completed or
+        // timed out. This is best effort and failures are ignored. On Ctrl-C,
+        // Bochs receives the signal on its own.
+        if !matches!(result, Ok(None)) {
+            let _unused = child.kill();
+            let _unused = child.wait();
+        }
+        check_replay_result(result?, dry_run)
     }
 }
 
+// Returns the Bochs configuration `config` with the `count` option of the `cpu`
+// line replaced with `count`.
+fn set_cpu_count(config: &str, count: u32) -> String {
+    config
+        .lines()
+        .map(|line| match line.strip_prefix("cpu: ") {
+            Some(options) => {
+                let options: Vec<String> = options
+                    .split(", ")
+                    .map(|option| {
+                        if option.starts_with("count=") {
+                            format!("count={count}")
+                        } else {
+                            option.to_string()
+                        }
+                    })
+                    .collect();
+                format!("cpu: {}", options.join(", "))
+            }
+            None => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+        + "\n"
+}
+
 #[derive(Debug)]
 pub(crate) enum Cpu {
     Intel,
@@ -86,3 +140,17 @@ impl fmt::Display for Cpu {
         fmt::Debug::fmt(self, f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::bochs::set_cpu_count;
+
+    #[test]
+    fn test_set_cpu_count() {
+        let config = "memory: guest=512\ncpu: count=1, ips=20000000, model=ryzen\n";
+        assert_eq!(
+            set_cpu_count(config, 4),
+            "memory: guest=512\ncpu: count=4, ips=20000000, model=ryzen\n"
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use crate::DynError;
+use clap::ValueEnum;
+use std::{collections::BTreeSet, fmt::Write, fs, ops::Range, path::Path};
+
+/// The format of the coverage file created by `cargo xtask coverage`.
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum CoverageFormat {
+    /// The drcov (version 2) text format, as the hypervisor prints with
+    /// `DRCOV:`
+    Drcov,
+    /// Module-relative offsets in hexadecimal, one per line
+    Offsets,
+}
+
+/// Extracts basic blocks from `COVERAGE:` lines of the serial log `log`,
+/// converts them into offsets relative to the base of the target module in the
+/// `Target module` line, and writes them into `output` in `format`.
+pub(crate) fn convert_coverage(
+    log: &Path,
+    format: CoverageFormat,
+    output: &Path,
+) -> Result<(), DynError> {
+    let log = fs::read_to_string(log)?;
+    let (module, offsets) = parse_log(&log)?;
+    let contents = match format {
+        CoverageFormat::Drcov => to_drcov(&module, &offsets),
+        CoverageFormat::Offsets => offsets.iter().fold(String::new(), |mut text, offset| {
+            writeln!(text, "{offset:#x}").unwrap();
+            text
+        }),
+    };
+    fs::write(output, contents)?;
+    println!("Wrote {} basic blocks into {}", offsets.len(), output.display());
+    Ok(())
+}
+
+/// The name and address range of the target module.
+#[derive(Debug, PartialEq)]
+struct Module {
+    name: String,
+    range: Range<u64>,
+}
+
+// Returns the target module and offsets of basic blocks from it found in `log`.
+// If the log contains multiple sessions, each basic block is converted with the
+// module of the session it belongs to.
+fn parse_log(log: &str) -> Result<(Module, BTreeSet<u64>), DynError> {
+    let mut module: Option<Module> = None;
+    let mut offsets = BTreeSet::new();
+    for line in log.lines() {
+        if let Some((_, name_and_range)) = line.split_once("Target module ") {
+            let (name, range) = name_and_range
+                .rsplit_once(" range ")
+                .ok_or(format!("invalid target module: {line}"))?;
+            let (start, end) = range
+                .split_once(" - ")
+                .ok_or(format!("invalid target module: {line}"))?;
+            module = Some(Module {
+                name: name.to_string(),
+                range: parse_hex(start)?..parse_hex(end)?,
+            });
+        } else if let Some((_, blocks)) = line.split_once("COVERAGE: ") {
+            let module_base = module
+                .as_ref()
+                .ok_or("COVERAGE: found before the target module")?
+                .range
+                .start;
+            let blocks = blocks.trim().trim_start_matches('[').trim_end_matches(']');
+            for block in blocks.split(", ").filter(|block| !block.is_empty()) {
+                let _ = offsets.insert(parse_hex(block)?.wrapping_sub(module_base));
+            }
+        }
+    }
+    let module = module.ok_or("the target module is not found in the log")?;
+    Ok((module, offsets))
+}
+
+// Formats `offsets` in the drcov (version 2) text format, in the same way as
+// the `DRCOV:` lines the hypervisor prints.
+fn to_drcov(module: &Module, offsets: &BTreeSet<u64>) -> String {
+    let mut text = String::new();
+    text += "DRCOV VERSION: 2\n";
+    text += "DRCOV FLAVOR: drcov\n";
+    text += "Module Table: version 2, count 1\n";
+    text += "Columns: id, base, end, entry, checksum, timestamp, path\n";
+    writeln!(
+        text,
+        "  0, {:#018x}, {:#018x}, 0x0000000000000000, 0x00000000, 0x00000000, {}",
+        module.range.start, module.range.end, module.name
+    )
+    .unwrap();
+    writeln!(text, "BB Table: {} bbs", offsets.len()).unwrap();
+    for offset in offsets {
+        writeln!(text, "module[  0]: {offset:#018x}, 1").unwrap();
+    }
+    text
+}
+
+// Parses a hexadecimal number with or without the `0x` prefix, as `{:x?}`
+// omits it.
+fn parse_hex(text: &str) -> Result<u64, DynError> {
+    let text = text.trim();
+    Ok(u64::from_str_radix(text.strip_prefix("0x").unwrap_or(text), 16)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coverage::{parse_log, to_drcov, Module};
+
+    #[test]
+    fn test_parse_log() {
+        let log = "\
+INFO: rhv image range 0x9000000 - 0x9100000
+INFO: Target module target.exe range 0x1000000 - 0x1100000
+INFO: COVERAGE: [1000100, 1000200]
+INFO: some other line
+INFO: COVERAGE: [0x1000200, 1000080]
+";
+        let (module, offsets) = parse_log(log).unwrap();
+        assert_eq!(
+            module,
+            Module {
+                name: "target.exe".to_string(),
+                range: 0x100_0000..0x110_0000
+            }
+        );
+        assert_eq!(offsets.into_iter().collect::<Vec<_>>(), [0x80, 0x100, 0x200]);
+
+        // Each session is converted with its own module base.
+        let log = "\
+Target module target.exe range 0x1000000 - 0x1100000
+COVERAGE: [1000100]
+Target module target.exe range 0x2000000 - 0x2100000
+COVERAGE: [2000300]
+";
+        let (module, offsets) = parse_log(log).unwrap();
+        assert_eq!(module.range, 0x200_0000..0x210_0000);
+        assert_eq!(offsets.into_iter().collect::<Vec<_>>(), [0x100, 0x300]);
+
+        assert!(parse_log("COVERAGE: [1000100]\n").is_err());
+        assert!(parse_log("no coverage\n").is_err());
+        assert!(parse_log("rhv image range 0x1000 - 0x2000\nCOVERAGE: [1010]\n").is_err());
+    }
+
+    #[test]
+    fn test_to_drcov() {
+        let (module, offsets) =
+            parse_log("Target module target.exe range 0x1000 - 0x2000\nCOVERAGE: [1010]\n")
+                .unwrap();
+        assert_eq!(
+            to_drcov(&module, &offsets),
+            "\
+DRCOV VERSION: 2
+DRCOV FLAVOR: drcov
+Module Table: version 2, count 1
+Columns: id, base, end, entry, checksum, timestamp, path
+  0, 0x0000000000001000, 0x0000000000002000, 0x0000000000000000, 0x00000000, 0x00000000, target.exe
+BB Table: 1 bbs
+module[  0]: 0x0000000000000010, 1
+"
+        );
+    }
+}
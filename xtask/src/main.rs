@@ -8,18 +8,30 @@
 
 use bochs::{Bochs, Cpu};
 use clap::{Parser, Subcommand};
+use coverage::{convert_coverage, CoverageFormat};
+use mksnapshot::make_snapshot;
+use qemu::Qemu;
 use std::{
     env, fs,
     path::{Path, PathBuf},
     process::Command,
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::Duration,
 };
 use vmware::Vmware;
 
 mod bochs;
+mod coverage;
+mod mksnapshot;
+mod qemu;
 mod vmware;
 
 type DynError = Box<dyn std::error::Error>;
 
+// How long to wait for the result of the dry-run before giving up, as the
+// hypervisor may hang without logging it.
+const DRY_RUN_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
 #[derive(Parser)]
 #[command(author, about, long_about = None)]
 struct Cli {
@@ -27,6 +39,17 @@ struct Cli {
     #[arg(short, long)]
     release: bool,
 
+    /// The number of logical processors of the VM. Defaults to what the VM
+    /// configuration file specifies
+    #[arg(long, global = true)]
+    cpus: Option<u32>,
+
+    /// Build the hypervisor with the `dry_run` feature, run every input file in
+    /// the corpus once, and exit with a non-zero status if any of them does
+    /// not reach the end marker
+    #[arg(long, global = true)]
+    dry_run: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -39,14 +62,64 @@ enum Commands {
     BochsAmd,
     /// Start a `VMware` VM
     Vmware,
+    /// Start a QEMU VM with KVM. Requires nested virtualization to be enabled
+    Qemu {
+        /// The CPU model to emulate
+        #[arg(long, default_value = "host")]
+        cpu: String,
+    },
+    /// Create a snapshot file from a raw physical memory dump and register
+    /// values
+    Mksnapshot {
+        /// The raw physical memory dump, where the file offset is the physical
+        /// address
+        #[arg(long)]
+        memory: PathBuf,
+        /// The JSON file of register values, eg, `{"rip": "0x401000", "cs":
+        /// 16}`
+        #[arg(long)]
+        registers: PathBuf,
+        /// The physical address range captured in the dump as `START-END` in
+        /// hexadecimal. Can be repeated. Defaults to the whole dump
+        #[arg(long)]
+        range: Vec<String>,
+        /// The snapshot file to create
+        #[arg(long, default_value = "snapshot.img")]
+        output: PathBuf,
+    },
+    /// Convert `COVERAGE:` lines in a serial log into a coverage file with
+    /// offsets relative to the image base
+    Coverage {
+        /// The serial log captured while fuzzing
+        #[arg(long)]
+        log: PathBuf,
+        /// The format of the coverage file
+        #[arg(long, value_enum, default_value = "drcov")]
+        format: CoverageFormat,
+        /// The coverage file to create
+        #[arg(long, default_value = "coverage.txt")]
+        output: PathBuf,
+    },
 }
 
 fn main() {
     let cli = Cli::parse();
     let result = match &cli.command {
-        Commands::BochsIntel => start_vm(&Bochs { cpu: Cpu::Intel }, cli.release),
-        Commands::BochsAmd => start_vm(&Bochs { cpu: Cpu::Amd }, cli.release),
-        Commands::Vmware => start_vm(&Vmware {}, cli.release),
+        Commands::BochsIntel => start_vm(&Bochs { cpu: Cpu::Intel }, &cli),
+        Commands::BochsAmd => start_vm(&Bochs { cpu: Cpu::Amd }, &cli),
+        Commands::Vmware => start_vm(&Vmware {}, &cli),
+        Commands::Qemu { cpu } => start_vm(&Qemu { cpu: cpu.clone() }, &cli),
+        Commands::Mksnapshot {
+            memory,
+            registers,
+            range,
+            output,
+        } => make_snapshot(memory, registers, range, output),
+        Commands::Coverage {
+            log,
+            format,
+            output,
+        } => convert_coverage(log, *format, output),
     };
     if let Err(e) = result {
         eprintln!("{e}");
@@ -56,17 +129,22 @@ fn main() {
 
 trait TestVm {
     fn deploy(&self, release: bool) -> Result<(), DynError>;
-    fn run(&self) -> Result<(), DynError>;
+    /// Starts the VM with `cpus` logical processors, or with the number the VM
+    /// configuration file specifies if `None`. Returns when Ctrl-C is pressed,
+    /// or with `dry_run`, when the hypervisor reports the result of the
+    /// dry-run. Returns an error if the dry-run fails, times out or is
+    /// interrupted with Ctrl-C.
+    fn run(&self, cpus: Option<u32>, dry_run: bool) -> Result<(), DynError>;
 }
 
-fn start_vm<T: TestVm>(vm: &T, release: bool) -> Result<(), DynError> {
-    build_hypervisor(release)?;
+fn start_vm<T: TestVm>(vm: &T, cli: &Cli) -> Result<(), DynError> {
+    build_hypervisor(cli.release, cli.dry_run)?;
     extract_samples()?;
-    vm.deploy(release)?;
-    vm.run()
+    vm.deploy(cli.release)?;
+    vm.run(cli.cpus, cli.dry_run)
 }
 
-fn build_hypervisor(release: bool) -> Result<(), DynError> {
+fn build_hypervisor(release: bool, dry_run: bool) -> Result<(), DynError> {
     // Building rhv only is important because we are running xtask, which cannot
     // be overwritten while running.
     let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
@@ -75,6 +153,9 @@ fn build_hypervisor(release: bool) -> Result<(), DynError> {
     if release {
         let _ = command.arg("--release");
     }
+    if dry_run {
+        let _ = command.args(["--features", "dry_run"]);
+    }
     let ok = command.current_dir(project_root_dir()).status()?.success();
     if !ok {
         Err("cargo build failed")?;
@@ -82,6 +163,47 @@ fn build_hypervisor(release: bool) -> Result<(), DynError> {
     Ok(())
 }
 
+// Returns whether the dry-run succeeded if `line` of the serial output is the
+// marker logged by the hypervisor at the end of the dry-run. A panic of the
+// hypervisor is a failure, as the marker is never logged after it.
+fn parse_replay_result(line: &str) -> Option<bool> {
+    if line.contains("panicked at") {
+        return Some(false);
+    }
+    line.split_once("REPLAY RESULT: ")
+        .map(|(_, result)| result.trim() == "OK")
+}
+
+// Waits for the value sent by the serial output reader or the Ctrl-C handler.
+// `None` means Ctrl-C. With `dry_run`, gives up after `DRY_RUN_TIMEOUT`.
+fn wait_for_replay_result(
+    rx: &Receiver<Option<bool>>,
+    dry_run: bool,
+) -> Result<Option<bool>, DynError> {
+    if !dry_run {
+        return Ok(rx.recv()?);
+    }
+    match rx.recv_timeout(DRY_RUN_TIMEOUT) {
+        Ok(result) => Ok(result),
+        Err(RecvTimeoutError::Timeout) => {
+            Err(format!("the dry-run did not complete in {} seconds", DRY_RUN_TIMEOUT.as_secs()))?
+        }
+        Err(err) => Err(err)?,
+    }
+}
+
+// Converts the value received by `wait_for_replay_result` into the result of
+// `TestVm::run`. `None` means Ctrl-C, which fails the dry-run.
+fn check_replay_result(result: Option<bool>, dry_run: bool) -> Result<(), DynError> {
+    match result {
+        Some(true) => println!("✅ All input files reached the end marker"),
+        Some(false) => Err("an input file did not reach the end marker, or rhv panicked")?,
+        None if dry_run => Err("the dry-run was interrupted")?,
+        None => {}
+    }
+    Ok(())
+}
+
 fn project_root_dir() -> PathBuf {
     // Get the path to rhv/xtask directory and resolve its parent directory.
     let root_dir = Path::new(&env!("CARGO_MANIFEST_DIR"))
@@ -175,9 +297,17 @@ cfg_if::cfg_if! {
 
 #[cfg(test)]
 mod tests {
-    use crate::unix_path;
+    use crate::{parse_replay_result, unix_path};
     use std::path::Path;
 
+    #[test]
+    fn test_parse_replay_result() {
+        assert_eq!(parse_replay_result("REPLAY RESULT: OK\r"), Some(true));
+        assert_eq!(parse_replay_result(" 120: REPLAY RESULT: FAIL"), Some(false));
+        assert_eq!(parse_replay_result("#0:INFO : Replaying \"a.bin\""), None);
+        assert_eq!(parse_replay_result("#1:ERROR: panicked at 'oops', src/vm.rs:1:1"), Some(false));
+    }
+
     #[test]
     fn test_unix_path() {
         if cfg!(target_os = "windows") {
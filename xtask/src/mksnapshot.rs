@@ -0,0 +1,257 @@
+use crate::DynError;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    ops::Range,
+    path::Path,
+};
+
+const PAGE_SIZE: u64 = 0x1000;
+const PAGE_SIZE_USIZE: usize = 0x1000;
+
+// The magic value at the beginning of the metadata page. 'SNAPSHOT'
+const SNAPSHOT_SIGNATURE: u64 = 0x544F_4853_5041_4E53;
+
+// The maximum number of memory ranges in the metadata page. More ranges are
+// stored in the additional pages right before it.
+const MAX_MEMORY_DESCRIPTOR_COUNT: usize = 47;
+
+// The size of a memory range entry (the page base and the page count).
+const MEMORY_RANGE_SIZE: usize = 16;
+
+// The offset to the registers in the metadata page.
+const REGISTERS_OFFSET: usize = 0x300;
+
+// The offsets of registers from `REGISTERS_OFFSET`, as laid out in
+// `SnapshotRegisters` of the hypervisor. The descriptor table registers and
+// segment selectors are handled separately.
+const REGISTER_OFFSETS: &[(&str, usize)] = &[
+    ("efer", 0x30),
+    ("sysenter_cs", 0x38),
+    ("cr0", 0x40),
+    ("cr3", 0x48),
+    ("cr4", 0x50),
+    ("fs_base", 0x58),
+    ("gs_base", 0x60),
+    ("ldtr_base", 0x68),
+    ("tr_base", 0x70),
+    ("rsp", 0x78),
+    ("rip", 0x80),
+    ("rflags", 0x88),
+    ("sysenter_esp", 0x90),
+    ("sysenter_eip", 0x98),
+    ("rax", 0xa0),
+    ("rbx", 0xa8),
+    ("rcx", 0xb0),
+    ("rdx", 0xb8),
+    ("rdi", 0xc0),
+    ("rsi", 0xc8),
+    ("rbp", 0xd0),
+    ("r8", 0xd8),
+    ("r9", 0xe0),
+    ("r10", 0xe8),
+    ("r11", 0xf0),
+    ("r12", 0xf8),
+    ("r13", 0x100),
+    ("r14", 0x108),
+    ("r15", 0x110),
+    ("pat", 0x118),
+    ("tsc", 0x120),
+    ("xcr0", 0x128),
+];
+
+// The segment selectors, each of which is 16-bit and starts at 0x20.
+const SELECTORS: &[&str] = &["es", "cs", "ss", "ds", "fs", "gs", "ldtr", "tr"];
+
+/// Creates the snapshot file `output` from the raw physical memory dump
+/// `memory`, the JSON file of register values `registers`, and the physical
+/// address ranges `ranges` captured in the dump. If `ranges` is empty, the
+/// whole dump is captured.
+pub(crate) fn make_snapshot(
+    memory: &Path,
+    registers: &Path,
+    ranges: &[String],
+    output: &Path,
+) -> Result<(), DynError> {
+    let registers: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(registers)?)?;
+    let mut dump = File::open(memory)?;
+    let dump_size = dump.metadata()?.len();
+    let padded_dump_size = dump_size.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    let ranges = if ranges.is_empty() {
+        let whole = 0..padded_dump_size;
+        vec![whole]
+    } else {
+        ranges
+            .iter()
+            .map(|range| parse_range(range))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let (range_pages, metadata) = build_metadata(&ranges, &registers)?;
+
+    // Copy the dump as-is up to the end of the last range, as the hypervisor
+    // reads each page at the offset of its physical address. The last page may
+    // be partial in the dump.
+    let memory_size = ranges.iter().map(|range| range.end).max().unwrap_or(0);
+    if memory_size > padded_dump_size {
+        Err(format!(
+            "the memory range ends at {memory_size:#x}, beyond the dump ({dump_size:#x} bytes)"
+        ))?;
+    }
+    let mut writer = BufWriter::new(File::create(output)?);
+    let copied = io::copy(&mut (&mut dump).take(memory_size), &mut writer)?;
+    let _ = io::copy(&mut io::repeat(0).take(memory_size - copied), &mut writer)?;
+    writer.write_all(&range_pages)?;
+    writer.write_all(&metadata)?;
+    writer.flush()?;
+    println!(
+        "Created {} with {} memory ranges ({memory_size:#x} bytes)",
+        output.display(),
+        ranges.len()
+    );
+    Ok(())
+}
+
+// Builds the additional memory range pages and the metadata page.
+fn build_metadata(
+    ranges: &[Range<u64>],
+    registers: &serde_json::Value,
+) -> Result<(Vec<u8>, Vec<u8>), DynError> {
+    let mut metadata = vec![0u8; PAGE_SIZE_USIZE];
+    write_u64(&mut metadata, 0, SNAPSHOT_SIGNATURE);
+
+    // Put the first ranges into the metadata page, and the rest into the
+    // additional pages, whose number is stored at offset 8.
+    let (first, rest) = ranges.split_at(ranges.len().min(MAX_MEMORY_DESCRIPTOR_COUNT));
+    write_ranges(&mut metadata[0x10..], first);
+    let ranges_per_page = PAGE_SIZE_USIZE / MEMORY_RANGE_SIZE;
+    let range_page_count = rest.len().div_ceil(ranges_per_page);
+    let mut range_pages = vec![0u8; range_page_count * PAGE_SIZE_USIZE];
+    write_ranges(&mut range_pages, rest);
+    write_u64(&mut metadata, 8, range_page_count as u64);
+
+    write_registers(&mut metadata[REGISTERS_OFFSET..], registers)?;
+    Ok((range_pages, metadata))
+}
+
+// Writes `ranges` into `buffer` as the pairs of the page base and page count.
+fn write_ranges(buffer: &mut [u8], ranges: &[Range<u64>]) {
+    for (i, range) in ranges.iter().enumerate() {
+        let offset = i * MEMORY_RANGE_SIZE;
+        write_u64(buffer, offset, range.start);
+        write_u64(buffer, offset + 8, (range.end - range.start) / PAGE_SIZE);
+    }
+}
+
+// Writes register values in the JSON object `registers` into `buffer` in the
+// layout of `SnapshotRegisters`. Registers not in the object are left zero.
+fn write_registers(buffer: &mut [u8], registers: &serde_json::Value) -> Result<(), DynError> {
+    let registers = registers
+        .as_object()
+        .ok_or("the register file must be a JSON object")?;
+    for (name, value) in registers {
+        let value = parse_value(value).ok_or(format!("invalid value for {name}: {value}"))?;
+        match name.as_str() {
+            // The descriptor table registers are the 16-bit limit followed by
+            // the 64-bit base, each padded to 16 bytes.
+            "gdtr_limit" => write_u16(buffer, 0x0, value)?,
+            "gdtr_base" => write_u64(buffer, 0x2, value),
+            "idtr_limit" => write_u16(buffer, 0x10, value)?,
+            "idtr_base" => write_u64(buffer, 0x12, value),
+            _ => {
+                if let Some(index) = SELECTORS.iter().position(|&s| s == name) {
+                    write_u16(buffer, 0x20 + index * 2, value)?;
+                } else if let Some((_, offset)) = REGISTER_OFFSETS.iter().find(|(r, _)| r == name) {
+                    write_u64(buffer, *offset, value);
+                } else {
+                    Err(format!("unknown register {name}"))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Parses the register value either as a JSON number or a hexadecimal string
+// with the `0x` prefix, as JSON has no hexadecimal literal.
+fn parse_value(value: &serde_json::Value) -> Option<u64> {
+    match value {
+        serde_json::Value::Number(number) => number.as_u64(),
+        serde_json::Value::String(string) => {
+            u64::from_str_radix(string.strip_prefix("0x")?, 16).ok()
+        }
+        _ => None,
+    }
+}
+
+// Parses the page aligned physical address range `START-END` in hexadecimal,
+// where `END` is exclusive.
+fn parse_range(range: &str) -> Result<Range<u64>, DynError> {
+    let parse = |address: &str| u64::from_str_radix(address.trim_start_matches("0x"), 16);
+    let (start, end) = range
+        .split_once('-')
+        .ok_or(format!("invalid memory range {range}"))?;
+    let (start, end) = (parse(start)?, parse(end)?);
+    if start >= end || start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 {
+        Err(format!("memory range {range} is empty or not page aligned"))?;
+    }
+    Ok(start..end)
+}
+
+fn write_u64(buffer: &mut [u8], offset: usize, value: u64) {
+    buffer[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u16(buffer: &mut [u8], offset: usize, value: u64) -> Result<(), DynError> {
+    let value = u16::try_from(value)?;
+    buffer[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mksnapshot::{build_metadata, parse_range, REGISTERS_OFFSET};
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("0x1000-0x3000").unwrap(), 0x1000..0x3000);
+        assert_eq!(parse_range("0-9f000").unwrap(), 0..0x9_f000);
+        assert!(parse_range("0x1000").is_err());
+        assert!(parse_range("0x1000-0x1000").is_err());
+        assert!(parse_range("0x1001-0x2000").is_err());
+    }
+
+    #[test]
+    fn test_build_metadata() {
+        let registers = serde_json::json!({
+            "gdtr_base": "0xfffff80000001000",
+            "gdtr_limit": 0x57,
+            "cs": 0x10,
+            "rip": "0x401000",
+            "xcr0": 7,
+        });
+        let ranges: Vec<_> = (0..50).map(|i| i * 0x2000..i * 0x2000 + 0x1000).collect();
+        let (range_pages, metadata) = build_metadata(&ranges, &registers).unwrap();
+        let read = |buffer: &[u8], offset: usize| {
+            u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap())
+        };
+
+        assert_eq!(metadata.len(), 0x1000);
+        assert_eq!(&metadata[..8], b"SNAPSHOT");
+        assert_eq!(read(&metadata, 8), 1);
+        assert_eq!(read(&metadata, 0x10 + 46 * 16), 46 * 0x2000);
+        assert_eq!(read(&metadata, 0x10 + 46 * 16 + 8), 1);
+        assert_eq!(range_pages.len(), 0x1000);
+        assert_eq!(read(&range_pages, 2 * 16), 49 * 0x2000);
+        assert_eq!(read(&range_pages, 3 * 16), 0);
+
+        let registers = &metadata[REGISTERS_OFFSET..];
+        assert_eq!(&registers[..2], &[0x57, 0]);
+        assert_eq!(read(registers, 2), 0xffff_f800_0000_1000);
+        assert_eq!(&registers[0x22..0x24], &[0x10, 0]);
+        assert_eq!(read(registers, 0x80), 0x40_1000);
+        assert_eq!(read(registers, 0x128), 7);
+
+        assert!(build_metadata(&ranges, &serde_json::json!({ "rxx": 0 })).is_err());
+        assert!(build_metadata(&ranges, &serde_json::json!({ "cs": 0x10000 })).is_err());
+    }
+}
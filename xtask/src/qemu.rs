@@ -0,0 +1,133 @@
+use crate::{
+    check_replay_result, copy_artifacts_to, parse_replay_result, wait_for_replay_result, DynError,
+    TestVm,
+};
+use std::{
+    env,
+    io::{BufRead, BufReader},
+    process::{Command, Stdio},
+    sync::mpsc::channel,
+    thread,
+    time::SystemTime,
+};
+
+// The disk image shared with Bochs, containing the snapshot, patch and corpus
+// files.
+const DISK_IMAGE: &str = "./tests/samples/bochs_disk.img";
+
+pub(crate) struct Qemu {
+    /// The CPU model passed to the `-cpu` option, eg, `host`.
+    pub(crate) cpu: String,
+}
+
+impl TestVm for Qemu {
+    fn deploy(&self, release: bool) -> Result<(), DynError> {
+        copy_artifacts_to(DISK_IMAGE, release)
+    }
+
+    fn run(&self, cpus: Option<u32>, dry_run: bool) -> Result<(), DynError> {
+        // Handle Ctrl-C before starting QEMU, so that it is stopped even if
+        // Ctrl-C is pressed while booting.
+        let (tx, rx) = channel();
+        let result_tx = tx.clone();
+        ctrlc::set_handler(move || tx.send(None).unwrap())?;
+
+        // Start QEMU in background. The guest serial output is written to stdout.
+        println!("🕒 Starting a QEMU VM");
+        let mut child = Command::new("qemu-system-x86_64")
+            .args(self.args(&ovmf_code_path(), &ovmf_vars_path(), cpus.unwrap_or(1)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let stdout = child.stdout.take().unwrap();
+        let _unused = thread::spawn(move || {
+            let now = SystemTime::now();
+
+            // Read and print stdout as they come in. This does not return.
+            let reader = BufReader::new(stdout);
+            reader.lines().map_while(Result::ok).for_each(|line| {
+                println!("{:>4}: {line}\r", now.elapsed().unwrap_or_default().as_secs());
+                if let Some(result) = parse_replay_result(&line) {
+                    let _unused = result_tx.send(Some(result));
+                }
+            });
+        });
+
+        // Wait until Ctrl-C is pressed, or the result of the dry-run is logged.
+        let result = wait_for_replay_result(&rx, dry_run);
+
+        // Stop the VM. This is best effort and failures are ignored.
+        println!("🕒 Shutting down the VM");
+        let _unused = child.kill();
+        let _unused = child.wait();
+        check_replay_result(result?, dry_run)
+    }
+}
+
+impl Qemu {
+    /// Returns the command line arguments for `qemu-system-x86_64`.
+    fn args(&self, ovmf_code: &str, ovmf_vars: &str, cpus: u32) -> Vec<String> {
+        // OVMF is loaded as flash, as `-bios` is not supported by split OVMF
+        // builds. The variable store and disk image are opened with
+        // `snapshot=on` so that any writes made by the VM are discarded.
+        [
+            "-enable-kvm",
+            "-machine",
+            "q35",
+            "-cpu",
+            &self.cpu,
+            "-smp",
+            &cpus.to_string(),
+            "-m",
+            "2G",
+            "-drive",
+            &format!("if=pflash,format=raw,readonly=on,file={ovmf_code}"),
+            "-drive",
+            &format!("if=pflash,format=raw,snapshot=on,file={ovmf_vars}"),
+            "-drive",
+            &format!("if=ide,format=raw,snapshot=on,file={DISK_IMAGE}"),
+            "-display",
+            "none",
+            "-serial",
+            "stdio",
+        ]
+        .into_iter()
+        .map(ToString::to_string)
+        .collect()
+    }
+}
+
+// Returns the path to the OVMF firmware code. Can be overridden with the
+// `OVMF_PATH` environment variable.
+fn ovmf_code_path() -> String {
+    env::var("OVMF_PATH").unwrap_or_else(|_| "/usr/share/OVMF/OVMF_CODE.fd".to_string())
+}
+
+// Returns the path to the OVMF variable store template. Can be overridden with
+// the `OVMF_VARS_PATH` environment variable.
+fn ovmf_vars_path() -> String {
+    env::var("OVMF_VARS_PATH").unwrap_or_else(|_| "/usr/share/OVMF/OVMF_VARS.fd".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::qemu::Qemu;
+
+    #[test]
+    fn test_args() {
+        let qemu = Qemu {
+            cpu: "host".to_string(),
+        };
+        let args = qemu.args("OVMF_CODE.fd", "OVMF_VARS.fd", 4);
+        assert!(args.contains(&"-enable-kvm".to_string()));
+        assert!(!args.contains(&"-bios".to_string()));
+        assert!(args.windows(2).any(|pair| pair == ["-cpu", "host"]));
+        let code = "if=pflash,format=raw,readonly=on,file=OVMF_CODE.fd";
+        assert!(args.windows(2).any(|pair| pair == ["-drive", code]));
+        let vars = "if=pflash,format=raw,snapshot=on,file=OVMF_VARS.fd";
+        assert!(args.windows(2).any(|pair| pair == ["-drive", vars]));
+        assert!(args.windows(2).any(|pair| pair == ["-smp", "4"]));
+        assert!(args.windows(2).any(|pair| pair == ["-serial", "stdio"]));
+    }
+}
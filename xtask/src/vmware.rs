@@ -1,4 +1,7 @@
-use crate::{copy_artifacts_to, DynError, TestVm, UnixCommand};
+use crate::{
+    check_replay_result, copy_artifacts_to, parse_replay_result, wait_for_replay_result, DynError,
+    TestVm, UnixCommand,
+};
 use std::{
     env,
     fs::{self},
@@ -10,6 +13,10 @@ use std::{
     time::{Duration, SystemTime},
 };
 
+// How long to wait for the first serial output before asking the user to select
+// the boot entry manually.
+const BOOT_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub(crate) struct Vmware {}
 
 impl TestVm for Vmware {
@@ -51,7 +58,7 @@ impl TestVm for Vmware {
         Ok(())
     }
 
-    fn run(&self) -> Result<(), DynError> {
+    fn run(&self, cpus: Option<u32>, dry_run: bool) -> Result<(), DynError> {
         let vmrun = if cfg!(target_os = "windows") {
             r"C:\Program Files (x86)\VMware\VMware Workstation\vmrun.exe"
         } else if wsl::is_wsl() {
@@ -71,6 +78,24 @@ impl TestVm for Vmware {
             .args(["stop", vmx_path.as_str(), "nogui"])
             .output()?;
 
+        // Configure the VM to boot the EFI shell automatically, and update the
+        // number of processors if requested. The configuration file is extracted
+        // from the samples and not tracked, so it is updated in place.
+        let path = format!(
+            "./tests/samples/vmware/NoOS_{}.vmx",
+            if wsl::is_wsl() {
+                "windows"
+            } else {
+                env::consts::OS
+            }
+        );
+        let mut config = fs::read_to_string(&path)?;
+        config = set_option(&config, "efi.shell.activeByDefault", "TRUE");
+        if let Some(count) = cpus {
+            config = set_option(&config, "numvcpus", &count.to_string());
+        }
+        fs::write(&path, config)?;
+
         // If the serial output file exists, delete it to avoid a popup
         let log_file = if cfg!(target_os = "windows") {
             r"\\wsl$\Ubuntu\tmp\serial.log"
@@ -81,6 +106,18 @@ impl TestVm for Vmware {
             fs::remove_file(log_file)?;
         }
 
+        // Handle Ctrl-C before starting the VM, so that it is stopped even if
+        // Ctrl-C is pressed while booting. Ctrl-C also ends the wait for the
+        // first serial output.
+        let (tx, rx) = channel();
+        let result_tx = tx.clone();
+        let (first_line_tx, first_line_rx) = channel();
+        let ctrlc_first_line_tx = first_line_tx.clone();
+        ctrlc::set_handler(move || {
+            let _unused = ctrlc_first_line_tx.send(());
+            tx.send(None).unwrap();
+        })?;
+
         // Start the VM
         println!("🕒 Starting a VMware VM");
         let product_type = if cfg!(target_os = "macos") {
@@ -96,12 +133,12 @@ impl TestVm for Vmware {
             Err(format!("vmrun failed: {output:#?}"))?;
         }
 
-        // Wait until the serial output file is created. Then, enter loop to read it.
-        while !Path::new(log_file).exists() {
-            thread::sleep(Duration::from_secs(1));
-        }
-
-        let _unused = thread::spawn(|| {
+        let _unused = thread::spawn(move || {
+            // Wait until the serial output file is created. Then, enter loop to
+            // read it.
+            while !Path::new(log_file).exists() {
+                thread::sleep(Duration::from_secs(1));
+            }
             let output = UnixCommand::new("tail")
                 .args(["-f", "/tmp/serial.log"])
                 .stdin(Stdio::piped())
@@ -114,14 +151,22 @@ impl TestVm for Vmware {
             // Read and print stdout as they come in. This does not return.
             let reader = BufReader::new(output.stdout.unwrap());
             reader.lines().map_while(Result::ok).for_each(|line| {
+                let _unused = first_line_tx.send(());
                 println!("{:>4}: {line}\r", now.elapsed().unwrap_or_default().as_secs());
+                if let Some(result) = parse_replay_result(&line) {
+                    let _unused = result_tx.send(Some(result));
+                }
             });
         });
 
-        println!("🕒 Please select 'EFI Internal Shell (Unsupported option)' on VMware...");
-        let (tx, rx) = channel();
-        ctrlc::set_handler(move || tx.send(()).unwrap())?;
-        rx.recv()?;
+        // The EFI shell should start rhv without interaction. If nothing is
+        // logged for a while, the firmware may still be waiting at the boot menu.
+        if first_line_rx.recv_timeout(BOOT_TIMEOUT).is_err() {
+            println!("🕒 Please select 'EFI Internal Shell (Unsupported option)' on VMware...");
+        }
+
+        // Wait until Ctrl-C is pressed, or the result of the dry-run is logged.
+        let result = wait_for_replay_result(&rx, dry_run);
 
         // Stop the VM if requested. This is best effort and failures are ignored.
         println!("🕒 Shutting down the VM");
@@ -129,10 +174,22 @@ impl TestVm for Vmware {
             .args(["stop", vmx_path.as_str(), "nogui"])
             .output()?;
 
-        Ok(())
+        check_replay_result(result?, dry_run)
     }
 }
 
+// Returns the VMware configuration `config` with the option `key` set to
+// `value`.
+fn set_option(config: &str, key: &str, value: &str) -> String {
+    let mut lines: Vec<String> = config
+        .lines()
+        .filter(|line| line.split('=').next().map(str::trim) != Some(key))
+        .map(ToString::to_string)
+        .collect();
+    lines.push(format!("{key} = \"{value}\""));
+    lines.join("\n") + "\n"
+}
+
 fn windows_path(path: &str) -> String {
     if wsl::is_wsl() {
         let output = UnixCommand::new("wslpath")
@@ -151,7 +208,20 @@ fn windows_path(path: &str) -> String {
 
 #[cfg(test)]
 mod tests {
-    use crate::vmware::windows_path;
+    use crate::vmware::{set_option, windows_path};
+
+    #[test]
+    fn test_set_option() {
+        let config = ".encoding = \"UTF-8\"\nnumvcpus = \"1\"\nmemsize = \"512\"\n";
+        assert_eq!(
+            set_option(config, "numvcpus", "4"),
+            ".encoding = \"UTF-8\"\nmemsize = \"512\"\nnumvcpus = \"4\"\n"
+        );
+        assert_eq!(
+            set_option(config, "efi.shell.activeByDefault", "TRUE"),
+            ".encoding = \"UTF-8\"\nnumvcpus = \"1\"\nmemsize = \"512\"\nefi.shell.activeByDefault = \"TRUE\"\n"
+        );
+    }
 
     #[test]
     fn test_windows_path() {